@@ -28,17 +28,20 @@
 //! Your task is to add the code (in `main.rs` as well) that dials a server and reads the message
 //! being written.
 
+extern crate chat_common;
 extern crate futures;
 extern crate libp2p;
 extern crate tokio_core;
 extern crate tokio_io;
 
+use chat_common::{KeyType, Keypair};
 use futures::{Future, Stream};
 use tokio_io::io;
 use tokio_core::reactor::Core;
 
 use libp2p::Multiaddr;
 use libp2p::core::Transport;
+use libp2p::secio::SecioConfig;
 
 fn main() {
     // We start by building the tokio engine that will be powering the networking of
@@ -55,7 +58,18 @@ fn main() {
     //
     // Note that we pass a handle to the tokio engine so that the networking can use it to
     // spawn sockets.
-    let transport = libp2p::tcp::TcpConfig::new(core.handle());
+    let tcp = libp2p::tcp::TcpConfig::new(core.handle());
+
+    // Data sent over raw TCP travels in cleartext, and anyone on the path can read or modify it.
+    // To solve this, we *upgrade* every connection with *secio*. Secio performs a handshake at the
+    // beginning of the connection, where each side proves its identity with its keypair, then
+    // encrypts everything that goes through the socket.
+    //
+    // In this chapter we don't need a stable identity, so we just generate a new keypair.
+    let keypair = Keypair::generate(KeyType::default()).expect("failed to generate a keypair");
+    let transport = tcp.with_upgrade(SecioConfig {
+        key: keypair.to_secio_keypair(),
+    });
 
     // Libp2p uses what is called a multiaddress to represent the address of a node, or the address
     // to listen to.
@@ -90,11 +104,16 @@ fn main() {
             // futures of connections into a stream of connections.
             negotiated
         })
-        .for_each(|(data_stream, remote_addr)| {
+        .for_each(|(secio_output, remote_addr)| {
+            // Because of the secio upgrade, we don't receive the raw socket but a `SecioOutput`.
+            // It contains the public key of the remote, which the handshake has authenticated,
+            // and the encrypted stream.
+            //
             // For each incoming connection, write "Hello world" to it and return a future that
             // represents the moment when the writing finished.
-            println!("Successfully received incoming connection from {}", remote_addr);
-            io::write_all(data_stream, b"hello world")
+            println!("Successfully received incoming connection from {} ({})",
+                     remote_addr, secio_output.remote_key.into_peer_id().to_base58());
+            io::write_all(secio_output.stream, b"hello world")
                 .map(|_| ())
         });
     
@@ -112,10 +131,10 @@ fn main() {
     // - Use `transport.dial()` to dial the address. This returns a future that represents when the
     //   connection has been opened.
     //   Hint: don't forget to `unwrap()` the output of `dial()`.
-    // - The future produces a tuple of a socket (of the same type as `data_stream` above) and
-    //   the address of the remote.
-    //   Use `tokio_io::io::read_to_end` to read what the socket receives and print it to stdout (hint:
-    //   this should be "Hello world").
+    // - The future produces a tuple of a `SecioOutput` (like `secio_output` above) and the address
+    //   of the remote.
+    //   Use `tokio_io::io::read_to_end` to read what its `stream` receives and print it to stdout
+    //   (hint: this should be "Hello world").
     //
     //  Tips:
     //
@@ -29,24 +29,45 @@
 //! stream that wraps around the socket, various information about the remote, a future that must
 //! be driven to completion, etc.
 
+extern crate chat_common;
 extern crate futures;
 extern crate libp2p;
-extern crate rand;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_stdin;
+extern crate tokio_timer;
 
+use chat_common::{identity, peer_addr, signed, KeyType};
+use chat_common::dedup::{self, DedupConfig, SeenCache};
+use chat_common::message::{self, ChatMessage, Composer};
+use chat_common::presence::{self, announce, Presence, Status};
+use chat_common::rooms::{self, Rooms, DEFAULT_ROOM};
+use chat_common::signed::VerifyMode;
 use futures::{Future, Stream};
+use std::env;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::time::Duration;
 use tokio_core::reactor::Core;
 
 use libp2p::{Multiaddr, PeerId};
 use libp2p::core::Transport;
-use libp2p::floodsub::{FloodSubUpgrade, FloodSubController, TopicBuilder};
+use libp2p::floodsub::{FloodSubUpgrade, FloodSubController};
+use libp2p::secio::SecioConfig;
+
+// One possible solution of the workshop action item below. Nothing calls it; see the module.
+#[allow(dead_code)]
+mod solution;
 
 fn main() {
+    // Parse the command line. See the `Options` struct below.
+    let options = Options::from_args();
+
     // Same as in chapter 1.
     let mut core = Core::new().unwrap();
-    let transport = libp2p::tcp::TcpConfig::new(core.handle());
+    let tcp = libp2p::tcp::TcpConfig::new(core.handle());
 
     let listen_multiaddr: Multiaddr = "/ip4/0.0.0.0/tcp/0"
         .parse()
@@ -58,31 +79,66 @@ fn main() {
     // Tweaking the transport is done by first creating a `FloodSubUpgrade`, then calling
     // `with_upgrade`.
     //
-    // As part of the protocol, which need to pass a *PeerId* to `FloodSubUpgrade::news()`. In this
-    // workshop we just generate it randomly.
-    let (floodsub_upgrade, floodsub_rx) = {
-        let key = (0..2048).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
-        FloodSubUpgrade::new(PeerId::from_public_key(&key))
-    };
-    let upgraded_transport = transport
-        .with_upgrade(floodsub_upgrade.clone());
+    // As part of the protocol, which need to pass a *PeerId* to `FloodSubUpgrade::new()`. The
+    // PeerId is the hash of the public key of the node. The keypair is generated the first time
+    // the program runs and stored in a file, so that we keep the same identity when restarting.
+    // See the `chat-common` crate if you are curious about how this works.
+    let keypair = identity::load_or_generate(
+        &options.key_file,
+        options.key_type,
+        options.new_identity,
+    ).expect("failed to load or generate the identity of the node");
+    let local_peer_id: PeerId = keypair.peer_id();
+    println!("Local peer id is {}", local_peer_id.to_base58());
+    let (floodsub_upgrade, floodsub_rx) = FloodSubUpgrade::new(local_peer_id.clone());
+
+    // Like in chapter 1, every connection is first upgraded with secio so that the traffic is
+    // encrypted.
+    //
+    // The secio handshake also authenticates the public key of the remote. We use `and_then` to
+    // append `/ipfs/<peer-id>` to the address of the remote, so that the `PeerId` is known by
+    // everything that is built on top of this connection. The addresses we dial can end with
+    // `/ipfs/<peer-id>` too: `StripPeerId` dials them without it, and the connection is rejected
+    // if the remote turns out to be another node.
+    //
+    // Then comes *muxing*. Muxing consists in making multiple streams (called *substreams*) go
+    // through the same socket, so that we don't need to open a new connection every time we want
+    // to use a protocol with a node we're already connected to. Here we use the *mplex* protocol.
+    // Calling `into_connection_reuse()` makes the transport open a new substream instead of a new
+    // connection when we dial an address we're already connected to.
+    let muxed_transport = peer_addr::StripPeerId::new(tcp)
+        .with_upgrade(SecioConfig { key: keypair.to_secio_keypair() })
+        .and_then(|secio_output, _endpoint, remote_addr| {
+            let remote_peer_id = secio_output.remote_key.into_peer_id();
+            peer_addr::authenticated(remote_addr, &remote_peer_id)
+                .map(|remote_addr| (secio_output.stream, remote_addr))
+        })
+        .with_upgrade(libp2p::mplex::BufferedMultiplexConfig::<[_; 256]>::new())
+        .into_connection_reuse();
+
+    // The keypair is also used to sign the messages we publish, from several places.
+    let keypair = Rc::new(keypair);
+
+    // Finally, floodsub is negotiated on each substream.
+    let upgraded_transport = muxed_transport.with_upgrade(floodsub_upgrade.clone());
 
     // We now create a *swarm*. A swarm is a convenient object that is responsible for handling all
     // the incoming and outgoing connections in a single point.
     // In other words, instead of using the transport to listen and dial, we will use the swarm.
     //
-    // The `swarm` function requires not just a `Transport` but a `MuxedTransport`. *Muxing*
-    // consists in making multiple streams go through the same socket so that we don't need to open
-    // a new connection every time. In order to add support for muxing with any transport, we can
-    // just call the `with_dummy_muxing()` method of the `Transport` trait.
-    let upgr_trans_with_muxing = upgraded_transport.with_dummy_muxing();
+    // The `swarm` function requires not just a `Transport` but a `MuxedTransport`, which is what
+    // `into_connection_reuse()` gave us.
     let (swarm_controller, swarm_future) = libp2p::swarm(
-        upgr_trans_with_muxing.clone(),
-        |future, _remote_addr| {
+        upgraded_transport.clone(),
+        |future, remote_addr| {
             // The first parameter of this closure (`future`) is the output of the floodsub
-            // upgrade. If we didn't apply any upgrade on the transport, it would be the raw socket
-            // instead.
-            //
+            // upgrade. If we didn't apply any upgrade on the transport, it would be the raw
+            // substream instead. The second parameter is the address of the remote, which ends
+            // with the `PeerId` that secio authenticated.
+            if let Some(remote_peer_id) = peer_addr::peer_id_of(&remote_addr) {
+                println!("Connected to {} at {}", remote_peer_id.to_base58(), remote_addr);
+            }
+
             // In the case of floodsub, the output is a future that must be driven to completion
             // for the protocol to work.
             // Coincidentially, the return value of this closure must be a future that is going to
@@ -102,26 +158,115 @@ fn main() {
     let floodsub_controller = FloodSubController::new(&floodsub_upgrade);
 
     // All the messages dispatched through the floodsub protocol belong to what is called a
-    // *topic*. This is what we create here.
-    let topic = TopicBuilder::new("workshop-chapter2-topic")
-        .build();
-
+    // *topic*. Each chat room is a topic, and the `Rooms` object of `chat-common` keeps track of
+    // the rooms we are in.
+    //
     // We need to subscribe to a topic in order to receive the messages that belong to it.
     // Subscribing to a topic broadcasts a message over the network to signal all the connected
-    // nodes that we are interested in this topic.
-    floodsub_controller.subscribe(&topic);
+    // nodes that we are interested in this topic. This is what joining a room does.
+    let rooms = Rooms::new(floodsub_controller);
+    rooms.join(DEFAULT_ROOM);
 
     // Let's tweak `floodsub_rx` so that we print on stdout the messages we receive.
-    let floodsub_rx = floodsub_rx
-        .for_each(|msg| {
-            if let Ok(msg) = String::from_utf8(msg.data) {
-                println!("> {}", msg);
-            } else {
-                println!("Received non-utf8 message");
+    //
+    // Every message is wrapped in an envelope signed by its author (see the `signed` module of
+    // `chat-common`). We check that the signature matches `msg.source`, so that nobody can pretend
+    // to be someone else. Depending on `--verify`, messages that fail this check are either
+    // displayed with a warning or dropped.
+    //
+    // Inside of the envelope is a `ChatMessage` (see the `message` module of `chat-common`), which
+    // contains the nickname of the author, the time, and the text of the message.
+    //
+    // The messages received on the presence topic of a room aren't displayed. Instead, they keep
+    // track of who is in the room (see the `presence` module of `chat-common`).
+    //
+    // The same message can reach us through several of the nodes we're connected to. Each
+    // message carries a sequence number, and we remember the ones we've recently displayed,
+    // along with the `PeerId` that signed them, in order to drop the copies (see the `dedup`
+    // module of `chat-common`).
+    let composer = Composer::new(local_peer_id.clone());
+    let presence = Presence::new();
+    let verify_mode = options.verify_mode;
+    let floodsub_rx = {
+        let rooms = rooms.clone();
+        let composer = composer.clone();
+        let presence = presence.clone();
+        let mut seen = SeenCache::new(options.dedup);
+        floodsub_rx.for_each(move |msg| {
+            if let Some(room) = rooms.presence_room_of(&msg.topics) {
+                let topic = rooms::presence_topic(&room);
+                let message = signed::verify(&msg.source, &topic, &msg.data)
+                    .ok()
+                    .and_then(|payload| ChatMessage::decode(&payload).ok());
+                match message {
+                    Some(ref message) if message.author == msg.source => {
+                        if let Some(notice) = presence.on_message(&room, message) {
+                            println!("[{}] {}", notice.room(), notice);
+                        }
+                    }
+                    _ => {}
+                }
+                return Ok(());
             }
 
+            // Messages are displayed in the first of their topics that we are in. We can still
+            // receive a few messages of a room right after leaving it.
+            let room = match rooms.room_of(&msg.topics) {
+                Some(room) => room,
+                None => return Ok(()),
+            };
+
+            let topic = rooms::room_topic(&room);
+            let (payload, verified) = match signed::verify(&msg.source, &topic, &msg.data) {
+                Ok(payload) => (payload, true),
+                Err(err) => {
+                    eprintln!("Message from {} failed verification: {}",
+                              msg.source.to_base58(), err);
+                    match (verify_mode, err.into_unverified_payload()) {
+                        (VerifyMode::Warn, Some(payload)) => (payload, false),
+                        _ => return Ok(()),
+                    }
+                }
+            };
+
+            let (text, verified) = match ChatMessage::decode(&payload) {
+                Ok(message) => {
+                    if dedup::is_duplicate(&mut seen, &msg.source, &message, verified) {
+                        return Ok(());
+                    }
+                    composer.observe(&message);
+                    (message.to_string(), verified && message.author == msg.source)
+                }
+                Err(err) => (message::describe_undecodable(&payload, &err), verified),
+            };
+            let prefix = if verified { "" } else { "[unverified] " };
+            println!("[{}] {}{}", room, prefix, text);
+
             Ok(())
-        });
+        })
+    };
+
+    // Every `HEARTBEAT_INTERVAL`, we announce that we're still in our rooms, and we forget about
+    // the members that didn't do the same for too long. The interval is driven by a timer from
+    // the `tokio-timer` crate.
+    let heartbeat_future = {
+        let rooms = rooms.clone();
+        let keypair = keypair.clone();
+        let composer = composer.clone();
+        let presence = presence.clone();
+        tokio_timer::Timer::default()
+            .interval(presence::HEARTBEAT_INTERVAL)
+            .map_err(|err| IoError::new(IoErrorKind::Other, err))
+            .for_each(move |()| {
+                for room in rooms.names() {
+                    announce(&rooms, &keypair, &composer, &room, Status::Here);
+                }
+                for notice in presence.expire() {
+                    println!("[{}] {}", notice.room(), notice);
+                }
+                Ok(())
+            })
+    };
 
     // *** WORKSHOP ACTION ITEM HERE ***
     //
@@ -145,6 +290,76 @@ fn main() {
     // future through the tokio core.
     let final_future = swarm_future
         .select(floodsub_rx).map_err(|(err, _)| err).and_then(|(_, n)| n)
-        .select(stdin_future).map_err(|(err, _)| err).and_then(|(_, n)| n);
+        .select(stdin_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(heartbeat_future).map_err(|(err, _)| err).and_then(|(_, n)| n);
     core.run(final_future).unwrap();
 }
+
+/// Options passed on the command line.
+struct Options {
+    /// Path to the file that contains the keypair of the node.
+    key_file: PathBuf,
+    /// If true, discard the existing keypair and generate a new one.
+    new_identity: bool,
+    /// Type of keypair to generate if we don't have one yet.
+    key_type: KeyType,
+    /// What to do with messages whose signature is invalid.
+    verify_mode: VerifyMode,
+    /// Size and expiry of the cache of the messages we've displayed, used to drop the duplicates.
+    dedup: DedupConfig,
+    /// Multiaddresses to dial on startup.
+    to_dial: Vec<String>,
+}
+
+impl Options {
+    /// Parses `std::env::args()`. Prints the usage and exits the process if they are invalid.
+    fn from_args() -> Options {
+        let mut options = Options {
+            key_file: identity::default_key_path(),
+            new_identity: false,
+            key_type: KeyType::default(),
+            verify_mode: VerifyMode::default(),
+            dedup: DedupConfig::default(),
+            to_dial: Vec::new(),
+        };
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_ref() {
+                "--key-file" => match args.next() {
+                    Some(path) => options.key_file = PathBuf::from(path),
+                    None => Options::usage_and_exit(),
+                },
+                "--new-identity" => options.new_identity = true,
+                "--key-type" => match args.next().map(|ty| ty.parse()) {
+                    Some(Ok(key_type)) => options.key_type = key_type,
+                    _ => Options::usage_and_exit(),
+                },
+                "--verify" => match args.next().map(|mode| mode.parse()) {
+                    Some(Ok(mode)) => options.verify_mode = mode,
+                    _ => Options::usage_and_exit(),
+                },
+                "--dedup-capacity" => match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) if n > 0 => options.dedup.capacity = n,
+                    _ => Options::usage_and_exit(),
+                },
+                "--dedup-ttl" => match args.next().map(|secs| secs.parse()) {
+                    Some(Ok(secs)) if secs > 0 => options.dedup.ttl = Duration::from_secs(secs),
+                    _ => Options::usage_and_exit(),
+                },
+                "--help" | "-h" => Options::usage_and_exit(),
+                _ if arg.starts_with("--") => Options::usage_and_exit(),
+                _ => options.to_dial.push(arg),
+            }
+        }
+
+        options
+    }
+
+    fn usage_and_exit() -> ! {
+        eprintln!("Usage: chapter-2 [--key-file <path>] [--new-identity] \
+                   [--key-type ed25519|secp256k1] [--verify warn|strict] \
+                   [--dedup-capacity <n>] [--dedup-ttl <seconds>] [<multiaddr>...]");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! One possible solution of the workshop action item of `main`.
+//!
+//! Nothing calls this module, so that the exercise is still yours to write. Once you're done, or
+//! if you're stuck, you can compare with it, or plug it in by replacing the place-holder in
+//! `main` with:
+//!
+//! ```ignore
+//! solution::dial_all(&options, |addr| {
+//!     swarm_controller.dial(addr, upgraded_transport.clone())
+//! });
+//! let stdin_future = solution::stdin_future(rooms, keypair, composer, presence);
+//! ```
+//!
+//! It also handles rooms and presence, which come later in the workshop.
+
+use chat_common::Keypair;
+use chat_common::message::{ChatMessage, Composer, Kind};
+use chat_common::presence::{announce, Presence, PresenceCommand, Status};
+use chat_common::rooms::{self, RoomCommand, Rooms};
+use chat_common::signed;
+use futures::{Future, Stream};
+use libp2p::Multiaddr;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::mem;
+use std::rc::Rc;
+use tokio_stdin;
+use Options;
+
+/// Dials all the addresses that were passed on the command line with `dial`, which should go
+/// through the swarm so that the connections are handled by the same closure as the incoming
+/// connections.
+pub fn dial_all<F>(options: &Options, dial: F)
+where
+    F: Fn(Multiaddr) -> Result<(), Multiaddr>,
+{
+    for addr in options.to_dial.iter() {
+        let addr: Multiaddr = match addr.parse() {
+            Ok(addr) => addr,
+            Err(_) => {
+                eprintln!("Invalid multiaddress: {}", addr);
+                continue;
+            }
+        };
+        if let Err(addr) = dial(addr) {
+            eprintln!("Unsupported multiaddress: {}", addr);
+        }
+    }
+}
+
+/// Reads the lines written on stdin. Lines that start with `/` are commands, see `handle_line`.
+/// The others are published in the active room, signed with our keypair. The `tokio-stdin` crate
+/// produces the bytes of stdin one by one, so we accumulate them until we reach the end of a line.
+pub fn stdin_future(
+    rooms: Rooms,
+    keypair: Rc<Keypair>,
+    composer: Composer,
+    presence: Presence,
+) -> impl Future<Item = (), Error = IoError> {
+    let mut buffer = Vec::new();
+    tokio_stdin::spawn_stdin_stream_unbounded()
+        .map_err(|_| IoError::new(IoErrorKind::Other, "failed to read stdin"))
+        .for_each(move |byte| {
+            if byte != b'\r' && byte != b'\n' {
+                buffer.push(byte);
+            } else if !buffer.is_empty() {
+                let line = mem::replace(&mut buffer, Vec::new());
+                let line = String::from_utf8_lossy(&line);
+                handle_line(&rooms, &keypair, &composer, &presence, &line);
+            }
+            Ok(())
+        })
+}
+
+/// Handles a line written on stdin.
+///
+/// The following commands are supported:
+///
+/// - `/join <room>` joins a room and makes it the active room. Joining a room we're already in
+///   only makes it the active room.
+/// - `/leave [<room>]` leaves a room, or the active room if none is given.
+/// - `/rooms` lists the rooms we're in.
+/// - `/nick <name>` changes our nickname.
+/// - `/who [<room>]` lists the members present in a room, or in all our rooms if none is given.
+///
+/// Any other line is published as text in the active room.
+fn handle_line(
+    rooms: &Rooms,
+    keypair: &Keypair,
+    composer: &Composer,
+    presence: &Presence,
+    line: &str,
+) {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some("/join") => match words.next() {
+            Some(room) => {
+                let command = RoomCommand::Join(room.to_owned());
+                handle_room_command(rooms, keypair, composer, presence, command);
+            }
+            None => eprintln!("Usage: /join <room>"),
+        },
+        Some("/leave") => {
+            let command = RoomCommand::Leave(words.next().map(String::from));
+            handle_room_command(rooms, keypair, composer, presence, command);
+        }
+        Some("/rooms") => {
+            handle_room_command(rooms, keypair, composer, presence, RoomCommand::List);
+        }
+        Some("/nick") => match line["/nick".len()..].trim() {
+            "" => eprintln!("Usage: /nick <name>"),
+            nickname => {
+                let command = PresenceCommand::Nick(nickname.to_owned());
+                for line in presence.execute(command, rooms, keypair, composer) {
+                    println!("{}", line);
+                }
+            }
+        },
+        Some("/who") => {
+            let command = PresenceCommand::Who(words.next().map(String::from));
+            for line in presence.execute(command, rooms, keypair, composer) {
+                println!("{}", line);
+            }
+        }
+        _ => publish(rooms, keypair, composer.compose(Kind::Text, line.as_bytes().to_vec())),
+    }
+}
+
+/// Handles the `/join`, `/leave` and `/rooms` commands. We announce our presence in the rooms we
+/// join, and our departure from the rooms we leave.
+fn handle_room_command(
+    rooms: &Rooms,
+    keypair: &Keypair,
+    composer: &Composer,
+    presence: &Presence,
+    command: RoomCommand,
+) {
+    let on_join = |room: &str| announce(rooms, keypair, composer, room, Status::Here);
+    let on_leave = |room: &str| {
+        announce(rooms, keypair, composer, room, Status::Leaving);
+        presence.forget_room(room);
+    };
+    for line in rooms.execute(command, &on_join, &on_leave) {
+        println!("{}", line);
+    }
+}
+
+/// Signs `message` and publishes it in the active room.
+fn publish(rooms: &Rooms, keypair: &Keypair, message: ChatMessage) {
+    match rooms.active() {
+        Some(room) => {
+            let data = signed::sign(keypair, &rooms::room_topic(&room), &message.encode());
+            rooms.publish_to(&room, data);
+        }
+        None => eprintln!("Not in any room; use /join <room> first"),
+    }
+}
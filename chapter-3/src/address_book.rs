@@ -0,0 +1,263 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Address book of the nodes we've been connected to, persisted in a JSON file.
+//!
+//! Every time we connect to a node, we record the addresses it listens on, the time we last saw
+//! it, and how many times we managed to connect to it. On startup, the nodes that we've seen
+//! recently are dialed again, so that there is no need to pass their addresses on the command
+//! line.
+
+use chat_common::{peer_addr, storage};
+use libp2p_core::{Multiaddr, PeerId};
+use serde_json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Maximum number of addresses we remember for each node.
+const MAX_ADDRS_PER_PEER: usize = 8;
+
+/// Returns the path of the address book to use when none is passed on the command line.
+pub fn default_path() -> PathBuf {
+    storage::config_dir().join("peers.json")
+}
+
+/// What we know about a node.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Entry {
+    /// Addresses the node told us it listens on, the most recent first.
+    pub addrs: Vec<String>,
+    /// Last time we were connected to this node, in seconds since the UNIX epoch.
+    pub last_seen: u64,
+    /// Number of times we tried to dial this node from the address book.
+    pub dial_attempts: u32,
+    /// Number of times we got connected to this node.
+    pub connections: u32,
+}
+
+/// The address book. Cloning it gives access to the same entries.
+#[derive(Clone)]
+pub struct AddressBook {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    /// Where to save the address book. `None` if it only lives in memory.
+    path: Option<PathBuf>,
+    /// Entries, indexed by the base58 representation of the `PeerId`.
+    entries: HashMap<String, Entry>,
+    /// True if the entries have changed since we last saved them.
+    dirty: bool,
+}
+
+impl AddressBook {
+    /// Loads the address book from `path`. Starts with an empty book if the file doesn't exist.
+    pub fn open(path: PathBuf) -> Result<AddressBook, IoError> {
+        let entries = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(file)
+                .map_err(|err| IoError::new(IoErrorKind::InvalidData, err))?,
+            Err(ref err) if err.kind() == IoErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(AddressBook::with_entries(Some(path), entries))
+    }
+
+    /// Creates an empty address book that is never saved.
+    pub fn in_memory() -> AddressBook {
+        AddressBook::with_entries(None, HashMap::new())
+    }
+
+    fn with_entries(path: Option<PathBuf>, entries: HashMap<String, Entry>) -> AddressBook {
+        AddressBook {
+            inner: Arc::new(Mutex::new(Inner {
+                path: path,
+                entries: entries,
+                dirty: false,
+            })),
+        }
+    }
+
+    /// Records that we got connected to `peer_id`.
+    ///
+    /// The address of the connection isn't recorded: when the node dialed us, it is an ephemeral
+    /// port that can't be dialed back. The addresses come from `record_listen_addrs` instead.
+    pub fn record_connection(&self, peer_id: &PeerId) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.dirty = true;
+        let entry = inner.entries.entry(peer_id.to_base58()).or_insert_with(Entry::default);
+        entry.last_seen = now();
+        entry.connections += 1;
+    }
+
+    /// Records the addresses that `peer_id` told us it is listening on, ignoring the ones that
+    /// can't be dialed, such as `0.0.0.0`.
+    pub fn record_listen_addrs(&self, peer_id: &PeerId, addrs: &[Multiaddr]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.dirty = true;
+        let entry = inner.entries.entry(peer_id.to_base58()).or_insert_with(Entry::default);
+        for addr in addrs.iter().rev().filter(|addr| peer_addr::is_dialable(addr)) {
+            push_front(&mut entry.addrs, peer_addr::without_peer_id(addr).to_string());
+        }
+    }
+
+    /// Records that we're about to dial `peer_id`.
+    pub fn record_dial_attempt(&self, peer_id: &PeerId) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entry) = inner.entries.get_mut(&peer_id.to_base58()) {
+            entry.dial_attempts += 1;
+        }
+        inner.dirty = true;
+    }
+
+    /// Returns the nodes we've been connected to in the last `max_age`, the most recently seen
+    /// first, along with their known addresses.
+    pub fn recent_peers(&self, max_age: Duration) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let inner = self.inner.lock().unwrap();
+        let threshold = now().saturating_sub(max_age.as_secs());
+
+        let mut peers = inner
+            .entries
+            .iter()
+            .filter(|&(_, entry)| entry.connections > 0 && entry.last_seen >= threshold)
+            .filter_map(|(peer_id, entry)| {
+                let peer_id = peer_addr::parse_peer_id(peer_id)?;
+                Some((entry.last_seen, peer_id, parse_addrs(&entry.addrs)))
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by(|a, b| b.0.cmp(&a.0));
+        peers.into_iter().map(|(_, peer_id, addrs)| (peer_id, addrs)).collect()
+    }
+
+    /// Returns the known addresses of `peer_id`, the most recent first.
+    pub fn addrs(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        let inner = self.inner.lock().unwrap();
+        match inner.entries.get(&peer_id.to_base58()) {
+            Some(entry) => parse_addrs(&entry.addrs),
+            None => Vec::new(),
+        }
+    }
+
+    /// Writes the address book to disk if it has changed since the last time.
+    pub fn save(&self) -> Result<(), IoError> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.dirty {
+            return Ok(());
+        }
+        if let Some(ref path) = inner.path {
+            let json = serde_json::to_vec_pretty(&inner.entries)
+                .map_err(|err| IoError::new(IoErrorKind::Other, err))?;
+            storage::write_atomic(path, &json, false)?;
+        }
+        inner.dirty = false;
+        Ok(())
+    }
+}
+
+/// Inserts `addr` at the front of `addrs`, removing it from its previous position.
+fn push_front(addrs: &mut Vec<String>, addr: String) {
+    addrs.retain(|a| *a != addr);
+    addrs.insert(0, addr);
+    addrs.truncate(MAX_ADDRS_PER_PEER);
+}
+
+/// Parses the stored addresses, skipping the invalid ones and the ones that can't be dialed, which
+/// older versions used to store.
+fn parse_addrs(addrs: &[String]) -> Vec<Multiaddr> {
+    addrs
+        .iter()
+        .filter_map(|a| a.parse().ok())
+        .filter(peer_addr::is_dialable)
+        .collect()
+}
+
+/// Returns the number of seconds since the UNIX epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_common::key::{KeyType, Keypair};
+
+    fn random_peer_id() -> PeerId {
+        Keypair::generate(KeyType::Ed25519).unwrap().peer_id()
+    }
+
+    /// Adds `peer_id` to `book`, last seen `age` seconds ago.
+    fn insert(book: &AddressBook, peer_id: &PeerId, age: u64, connections: u32) {
+        let entry = Entry {
+            addrs: vec!["/ip4/10.0.0.1/tcp/4001".to_owned()],
+            last_seen: now() - age,
+            dial_attempts: 0,
+            connections: connections,
+        };
+        book.inner.lock().unwrap().entries.insert(peer_id.to_base58(), entry);
+    }
+
+    #[test]
+    fn recent_peers_most_recent_first() {
+        let book = AddressBook::in_memory();
+        let (older, newer) = (random_peer_id(), random_peer_id());
+        insert(&book, &older, 120, 1);
+        insert(&book, &newer, 60, 1);
+
+        let peers = book.recent_peers(Duration::from_secs(3600));
+        let ids = peers.iter().map(|&(ref peer_id, _)| peer_id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids, vec![newer, older]);
+        assert_eq!(peers[0].1, vec!["/ip4/10.0.0.1/tcp/4001".parse::<Multiaddr>().unwrap()]);
+    }
+
+    #[test]
+    fn recent_peers_skips_old_and_never_connected_nodes() {
+        let book = AddressBook::in_memory();
+        let (recent, old, never) = (random_peer_id(), random_peer_id(), random_peer_id());
+        insert(&book, &recent, 60, 1);
+        insert(&book, &old, 7200, 3);
+        insert(&book, &never, 0, 0);
+
+        let peers = book.recent_peers(Duration::from_secs(3600));
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].0, recent);
+    }
+
+    #[test]
+    fn record_dial_attempt() {
+        let book = AddressBook::in_memory();
+        let (known, unknown) = (random_peer_id(), random_peer_id());
+        insert(&book, &known, 60, 1);
+        book.record_dial_attempt(&known);
+        book.record_dial_attempt(&known);
+        book.record_dial_attempt(&unknown);
+
+        let inner = book.inner.lock().unwrap();
+        assert_eq!(inner.entries[&known.to_base58()].dial_attempts, 2);
+        assert!(!inner.entries.contains_key(&unknown.to_base58()));
+        assert!(inner.dirty);
+    }
+}
@@ -0,0 +1,436 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Client for the control socket of a chat node, started with `--rpc`, `--rpc-socket <path>` or
+//! `--daemon`.
+//!
+//! ```text
+//! chatctl [--socket <path>] send [--room <room>] <text>...
+//! chatctl [--socket <path>] tail -f [<room>]
+//! chatctl [--socket <path>] peers
+//! chatctl [--socket <path>] dial <multiaddr>
+//! chatctl [--socket <path>] join <room>
+//! chatctl [--socket <path>] leave <room>
+//! chatctl [--socket <path>] rooms
+//! chatctl [--socket <path>] listeners
+//! ```
+//!
+//! `send` publishes in the active room of the node if there is no `--room`. `tail -f` prints the
+//! messages that the node receives, in all the rooms or in `<room>`, until it is interrupted.
+//! Each command sends a JSON-RPC request to the node; see the `rpc` module of the node for the
+//! protocol.
+
+extern crate chat_common;
+#[macro_use]
+extern crate serde_json;
+
+#[cfg(all(unix, not(target_os = "emscripten")))]
+use chat_common::message;
+#[cfg(all(unix, not(target_os = "emscripten")))]
+use chat_common::storage;
+#[cfg(all(unix, not(target_os = "emscripten")))]
+use serde_json::Value;
+#[cfg(all(unix, not(target_os = "emscripten")))]
+use std::env;
+#[cfg(all(unix, not(target_os = "emscripten")))]
+use std::io::{self, BufRead, BufReader, Write};
+#[cfg(all(unix, not(target_os = "emscripten")))]
+use std::os::unix::net::UnixStream;
+#[cfg(all(unix, not(target_os = "emscripten")))]
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// What the user asks the node to do.
+#[cfg(all(unix, not(target_os = "emscripten")))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    Send { room: Option<String>, text: String },
+    Tail { room: Option<String> },
+    Peers,
+    Dial(String),
+    Join(String),
+    Leave(String),
+    Rooms,
+    Listeners,
+}
+
+#[cfg(all(unix, not(target_os = "emscripten")))]
+fn main() {
+    let (path, action) = match parse_args(env::args().skip(1).collect()) {
+        Ok(parsed) => parsed,
+        Err(()) => usage_and_exit(),
+    };
+    let path = path.unwrap_or_else(storage::default_control_socket);
+    let mut client = match Client::connect(&path) {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("chatctl: can't connect to {}: {}; is the node running?", path.display(),
+                      err);
+            process::exit(1);
+        }
+    };
+    if let Err(err) = run(&mut client, action) {
+        eprintln!("chatctl: {}", err);
+        process::exit(1);
+    }
+}
+
+/// There is no control socket in the browser.
+#[cfg(not(all(unix, not(target_os = "emscripten"))))]
+fn main() {
+    eprintln!("chatctl: the control socket is only available on Unix");
+    process::exit(1);
+}
+
+/// Parses the arguments, without the name of the program, into the path of the socket if it is
+/// given and the action. Returns an error if the usage should be printed.
+#[cfg(all(unix, not(target_os = "emscripten")))]
+fn parse_args(mut args: Vec<String>) -> Result<(Option<PathBuf>, Action), ()> {
+    let path = take_option(&mut args, "--socket")?.map(PathBuf::from);
+    if args.is_empty() {
+        return Err(());
+    }
+
+    let command = args.remove(0);
+    let action = match command.as_ref() {
+        "send" => {
+            let room = take_option(&mut args, "--room")?;
+            if args.is_empty() {
+                return Err(());
+            }
+            Action::Send {
+                room: room,
+                text: args.join(" "),
+            }
+        }
+        // The node doesn't serve its log, so we can only follow the new messages.
+        "tail" if args.first().map(|arg| arg == "-f").unwrap_or(false) => match args.len() {
+            1 => Action::Tail { room: None },
+            2 => Action::Tail { room: Some(args.remove(1)) },
+            _ => return Err(()),
+        },
+        "peers" if args.is_empty() => Action::Peers,
+        "dial" if args.len() == 1 => Action::Dial(args.remove(0)),
+        "join" if args.len() == 1 => Action::Join(args.remove(0)),
+        "leave" if args.len() == 1 => Action::Leave(args.remove(0)),
+        "rooms" if args.is_empty() => Action::Rooms,
+        "listeners" if args.is_empty() => Action::Listeners,
+        _ => return Err(()),
+    };
+    Ok((path, action))
+}
+
+/// Removes `--name <value>` from `args`, and returns the value. Returns an error if `--name` is
+/// the last argument.
+#[cfg(all(unix, not(target_os = "emscripten")))]
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, ()> {
+    let pos = match args.iter().position(|arg| arg == name) {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    if pos + 1 >= args.len() {
+        return Err(());
+    }
+    args.remove(pos);
+    Ok(Some(args.remove(pos)))
+}
+
+#[cfg(all(unix, not(target_os = "emscripten")))]
+fn usage_and_exit() -> ! {
+    eprintln!("Usage: chatctl [--socket <path>] <command>\n\n\
+               Commands:\n  \
+               send [--room <room>] <text>...\n  \
+               tail -f [<room>]\n  \
+               peers\n  \
+               dial <multiaddr>\n  \
+               join <room>\n  \
+               leave <room>\n  \
+               rooms\n  \
+               listeners");
+    process::exit(1);
+}
+
+/// Returns the method and the parameters of the request for `action`.
+#[cfg(all(unix, not(target_os = "emscripten")))]
+fn request(action: &Action) -> (&'static str, Value) {
+    match *action {
+        Action::Send { ref room, ref text } => ("publish", json!({ "room": room, "text": text })),
+        Action::Tail { ref room } => ("messages_subscribe", json!({ "room": room })),
+        Action::Peers => ("peers", Value::Null),
+        Action::Dial(ref addr) => ("dial", json!({ "addr": addr })),
+        Action::Join(ref room) => ("subscribe", json!({ "room": room })),
+        Action::Leave(ref room) => ("unsubscribe", json!({ "room": room })),
+        Action::Rooms => ("rooms", Value::Null),
+        Action::Listeners => ("listeners", Value::Null),
+    }
+}
+
+/// Returns the lines to print for the `result` of the request for `action`, or an error if the
+/// node couldn't do what was asked.
+#[cfg(all(unix, not(target_os = "emscripten")))]
+fn render(action: &Action, result: &Value) -> Result<Vec<String>, String> {
+    let lines = match *action {
+        Action::Send { .. } | Action::Tail { .. } | Action::Dial(_) | Action::Join(_) => {
+            Vec::new()
+        }
+        Action::Leave(ref room) => {
+            if result["left"] == false {
+                return Err(format!("the node isn't in room {}", room));
+            }
+            Vec::new()
+        }
+        Action::Peers => as_array(result)
+            .iter()
+            .map(|peer| format!("{} {}", as_str(&peer["peer_id"]), as_str(&peer["addr"])))
+            .collect(),
+        Action::Rooms => as_array(&result["rooms"])
+            .iter()
+            .map(|room| {
+                let marker = if *room == result["active"] { "*" } else { " " };
+                format!("{} {}", marker, as_str(room))
+            })
+            .collect(),
+        Action::Listeners => as_array(result).iter().map(|addr| as_str(addr).to_owned()).collect(),
+    };
+    Ok(lines)
+}
+
+/// Returns the line to print for a notification received by `tail -f`, or `None` if it isn't a
+/// message.
+#[cfg(all(unix, not(target_os = "emscripten")))]
+fn render_notification(notification: &Value) -> Option<String> {
+    if notification["method"] != "message" {
+        return None;
+    }
+    // Nodes that predate `message::sanitize` send the text of the messages as is.
+    let params = &notification["params"]["message"];
+    let room = message::sanitize(params["room"].as_str().unwrap_or("direct"));
+    let prefix = if params["verified"] == false { "[unverified] " } else { "" };
+    let display = message::sanitize(as_str(&params["display"]));
+    Some(format!("[{}] {}{}", room, prefix, display))
+}
+
+/// Sends the request for `action`, and prints its result.
+#[cfg(all(unix, not(target_os = "emscripten")))]
+fn run(client: &mut Client, action: Action) -> Result<(), String> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let (method, params) = request(&action);
+    let result = client.call(method, params)?;
+    if let Action::Tail { .. } = action {
+        loop {
+            if let Some(line) = render_notification(&client.next()?) {
+                writeln!(stdout, "{}", line).map_err(|err| err.to_string())?;
+            }
+        }
+    }
+    for line in render(&action, &result)? {
+        writeln!(stdout, "{}", line).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "emscripten")))]
+fn as_str(value: &Value) -> &str {
+    value.as_str().unwrap_or("")
+}
+
+#[cfg(all(unix, not(target_os = "emscripten")))]
+fn as_array(value: &Value) -> &[Value] {
+    value.as_array().map(|array| &array[..]).unwrap_or(&[])
+}
+
+/// Connection to the control socket of the node.
+#[cfg(all(unix, not(target_os = "emscripten")))]
+struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    /// Identifier of the last request.
+    last_id: u64,
+}
+
+#[cfg(all(unix, not(target_os = "emscripten")))]
+impl Client {
+    fn connect(path: &Path) -> Result<Client, io::Error> {
+        let stream = UnixStream::connect(path)?;
+        Ok(Client {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            last_id: 0,
+        })
+    }
+
+    /// Calls `method`, and returns its result, or the message of the error.
+    fn call(&mut self, method: &str, params: Value) -> Result<Value, String> {
+        self.last_id += 1;
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": self.last_id,
+            "method": method,
+            "params": params,
+        });
+        let mut line = request.to_string();
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).map_err(|err| err.to_string())?;
+
+        // The notifications of the subscriptions can arrive before the response.
+        loop {
+            let response = self.next()?;
+            if response["id"] != self.last_id {
+                continue;
+            }
+            if let Some(error) = response.get("error") {
+                return Err(error["message"].as_str().unwrap_or("unknown error").to_owned());
+            }
+            return Ok(response["result"].clone());
+        }
+    }
+
+    /// Reads the next response or notification sent by the node.
+    fn next(&mut self) -> Result<Value, String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Err("the node closed the connection".to_owned()),
+            Ok(_) => {
+                serde_json::from_str(&line).map_err(|err| format!("invalid response: {}", err))
+            }
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+#[cfg(all(test, unix, not(target_os = "emscripten")))]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<(Option<PathBuf>, Action), ()> {
+        parse_args(args.iter().map(|arg| arg.to_string()).collect())
+    }
+
+    fn action(args: &[&str]) -> Action {
+        let (path, action) = parse(args).unwrap();
+        assert_eq!(path, None);
+        action
+    }
+
+    #[test]
+    fn send() {
+        let (method, params) = request(&action(&["send", "hello", "world"]));
+        assert_eq!(method, "publish");
+        assert_eq!(params, json!({ "room": null, "text": "hello world" }));
+
+        let (_, params) = request(&action(&["send", "hi", "--room", "rust"]));
+        assert_eq!(params, json!({ "room": "rust", "text": "hi" }));
+    }
+
+    #[test]
+    fn other_commands() {
+        let cases = vec![
+            (vec!["tail", "-f"], "messages_subscribe", json!({ "room": null })),
+            (vec!["tail", "-f", "rust"], "messages_subscribe", json!({ "room": "rust" })),
+            (vec!["peers"], "peers", Value::Null),
+            (vec!["dial", "/ip4/1.2.3.4/tcp/1"], "dial", json!({ "addr": "/ip4/1.2.3.4/tcp/1" })),
+            (vec!["join", "rust"], "subscribe", json!({ "room": "rust" })),
+            (vec!["leave", "rust"], "unsubscribe", json!({ "room": "rust" })),
+            (vec!["rooms"], "rooms", Value::Null),
+            (vec!["listeners"], "listeners", Value::Null),
+        ];
+        for (args, method, params) in cases {
+            assert_eq!(request(&action(&args)), (method, params), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn socket_option() {
+        let (path, action) = parse(&["--socket", "/tmp/chat.sock", "peers"]).unwrap();
+        assert_eq!(path, Some(PathBuf::from("/tmp/chat.sock")));
+        assert_eq!(action, Action::Peers);
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let cases: &[&[&str]] = &[
+            &[],
+            &["--socket"],
+            &["--socket", "/tmp/chat.sock"],
+            &["unknown"],
+            &["send"],
+            &["send", "--room", "rust"],
+            &["send", "hi", "--room"],
+            &["tail"],
+            &["tail", "rust"],
+            &["tail", "-f", "rust", "extra"],
+            &["peers", "extra"],
+            &["dial"],
+            &["join", "a", "b"],
+            &["leave"],
+            &["rooms", "extra"],
+            &["listeners", "extra"],
+        ];
+        for args in cases {
+            assert_eq!(parse(args), Err(()), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn render_results() {
+        let peers = json!([{ "peer_id": "QmA", "addr": "/ip4/1.2.3.4/tcp/1" }]);
+        assert_eq!(render(&Action::Peers, &peers), Ok(vec!["QmA /ip4/1.2.3.4/tcp/1".to_owned()]));
+
+        let rooms = json!({ "rooms": ["lobby", "rust"], "active": "rust" });
+        assert_eq!(render(&Action::Rooms, &rooms),
+                   Ok(vec!["  lobby".to_owned(), "* rust".to_owned()]));
+
+        let listeners = json!(["/ip4/0.0.0.0/tcp/1"]);
+        assert_eq!(render(&Action::Listeners, &listeners),
+                   Ok(vec!["/ip4/0.0.0.0/tcp/1".to_owned()]));
+
+        assert_eq!(render(&Action::Join("rust".to_owned()), &Value::Null), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn render_leave() {
+        let leave = Action::Leave("rust".to_owned());
+        assert_eq!(render(&leave, &json!({ "left": true })), Ok(Vec::new()));
+        assert_eq!(render(&leave, &json!({ "left": false })),
+                   Err("the node isn't in room rust".to_owned()));
+    }
+
+    #[test]
+    fn render_notifications() {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "message",
+            "params": { "message": { "room": "rust", "verified": true, "display": "<a> hi" } },
+        });
+        assert_eq!(render_notification(&notification), Some("[rust] <a> hi".to_owned()));
+
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "message",
+            "params": { "message": { "verified": false, "display": "<a> hi\u{1b}[2J" } },
+        });
+        let line = render_notification(&notification).unwrap();
+        assert!(line.starts_with("[direct] [unverified] <a> hi"));
+        assert!(!line.contains('\u{1b}'));
+
+        assert_eq!(render_notification(&json!({ "method": "peer_connected" })), None);
+    }
+}
@@ -0,0 +1,462 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Persistent log of the messages of the rooms, with full-text search.
+//!
+//! Every message we display is appended to a file, one JSON record per line. Each record is
+//! written with a single `write`, so a crash can at worst leave a truncated last line, which is
+//! discarded the next time the log is opened. The file is only flushed to the disk by `sync`,
+//! which the caller invokes periodically, as doing so for every message would stall the event
+//! loop.
+//!
+//! The whole log is loaded in memory on startup, along with an inverted index of the words of
+//! the messages, which is used by `/search`.
+
+use chat_common::message::{ChatMessage, Kind};
+use chat_common::{message, peer_addr, storage};
+use libp2p_core::PeerId;
+use serde_json;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::str;
+use std::sync::{Arc, Mutex};
+
+/// Returns the path of the log to use when none is passed on the command line.
+pub fn default_path() -> PathBuf {
+    storage::config_dir().join("chat-log.jsonl")
+}
+
+/// A line of the log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    room: String,
+    /// Identifier of the message, in hexadecimal. Empty for messages without an identifier.
+    id: String,
+    /// Base58 representation of the `PeerId` of the author.
+    author: String,
+    nickname: String,
+    lamport: u64,
+    /// Time of the message, in milliseconds since the UNIX epoch.
+    timestamp: u64,
+    kind: u32,
+    /// Content of the message. Invalid UTF-8 is replaced.
+    text: String,
+    /// Sequence number of the message. Absent from the records written before it existed.
+    #[serde(default)]
+    seq: u64,
+    /// False if the signature of the message was invalid.
+    verified: bool,
+}
+
+impl Record {
+    fn to_message(&self) -> Option<ChatMessage> {
+        Some(ChatMessage {
+            id: from_hex(&self.id)?,
+            author: peer_addr::parse_peer_id(&self.author)?,
+            nickname: self.nickname.clone(),
+            lamport: self.lamport,
+            timestamp: self.timestamp,
+            kind: Kind::from_u32(self.kind),
+            payload: self.text.clone().into_bytes(),
+            seq: self.seq,
+        })
+    }
+}
+
+/// A message found in the log.
+#[derive(Debug, Clone)]
+pub struct LoggedMessage {
+    pub room: String,
+    pub message: ChatMessage,
+    /// False if the signature of the message was invalid.
+    pub verified: bool,
+}
+
+/// The log. Cloning it gives access to the same log.
+#[derive(Clone)]
+pub struct ChatLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    /// File to append to. `None` if the log only lives in memory.
+    file: Option<File>,
+    records: Vec<Record>,
+    /// Author and identifier of the messages in the log, to not log a message twice.
+    known: HashSet<(String, String)>,
+    /// For each word, the indices within `records` of the messages that contain it.
+    index: HashMap<String, Vec<usize>>,
+    /// Where the last `/history` stopped, in order to continue from there if it's repeated.
+    page_cursor: Option<(String, usize)>,
+    /// Number of lines of the file that couldn't be parsed when it was opened.
+    malformed: usize,
+}
+
+impl ChatLog {
+    /// Loads the log from `path`, or creates it if it doesn't exist.
+    ///
+    /// Lines that can't be parsed are skipped. Their number is returned by `malformed_lines`.
+    pub fn open(path: PathBuf) -> Result<ChatLog, IoError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+
+        // Everything after the last newline is a record that was being written when the process
+        // stopped.
+        let complete_len = content.iter().rposition(|&b| b == b'\n').map_or(0, |pos| pos + 1);
+        if complete_len != content.len() {
+            file.set_len(complete_len as u64)?;
+        }
+
+        let mut log = Inner::new(Some(file));
+        for line in content[..complete_len].split(|&b| b == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_slice(line) {
+                Ok(record) => log.push(record),
+                Err(_) => log.malformed += 1,
+            }
+        }
+
+        Ok(ChatLog {
+            inner: Arc::new(Mutex::new(log)),
+        })
+    }
+
+    /// Returns the number of lines of the file that were skipped by `open` because they couldn't
+    /// be parsed.
+    pub fn malformed_lines(&self) -> usize {
+        self.inner.lock().unwrap().malformed
+    }
+
+    /// Creates an empty log that is never written to disk.
+    pub fn in_memory() -> ChatLog {
+        ChatLog {
+            inner: Arc::new(Mutex::new(Inner::new(None))),
+        }
+    }
+
+    /// Appends a message of `room` to the log, unless it's already there.
+    ///
+    /// `source` is the node that published the message. It's the author of messages that aren't
+    /// `ChatMessage`s, in which case the current time is used.
+    pub fn append(
+        &self,
+        room: &str,
+        source: &PeerId,
+        message: Option<&ChatMessage>,
+        text: &str,
+        verified: bool,
+    ) -> Result<(), IoError> {
+        let record = match message {
+            Some(message) => Record {
+                room: room.to_owned(),
+                id: to_hex(&message.id),
+                author: message.author.to_base58(),
+                nickname: message.nickname.clone(),
+                lamport: message.lamport,
+                timestamp: message.timestamp,
+                kind: message.kind.to_u32(),
+                text: String::from_utf8_lossy(&message.payload).into_owned(),
+                seq: message.seq,
+                verified: verified,
+            },
+            None => Record {
+                room: room.to_owned(),
+                id: String::new(),
+                author: source.to_base58(),
+                nickname: message::default_nickname(source),
+                lamport: 0,
+                timestamp: message::now_millis(),
+                kind: Kind::Text.to_u32(),
+                text: text.to_owned(),
+                seq: 0,
+                verified: verified,
+            },
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        let key = (record.author.clone(), record.id.clone());
+        if !record.id.is_empty() && inner.known.contains(&key) {
+            return Ok(());
+        }
+
+        if let Some(ref mut file) = inner.file {
+            let mut line = serde_json::to_vec(&record)
+                .map_err(|err| IoError::new(IoErrorKind::Other, err))?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+        inner.push(record);
+        Ok(())
+    }
+
+    /// Flushes the messages appended so far to the disk.
+    pub fn sync(&self) -> Result<(), IoError> {
+        match self.inner.lock().unwrap().file {
+            Some(ref file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the last `limit` messages that contain all the words of `terms`, oldest first.
+    ///
+    /// Returns nothing if `terms` doesn't contain any word.
+    pub fn search(&self, terms: &str, limit: usize) -> Vec<LoggedMessage> {
+        let inner = self.inner.lock().unwrap();
+        let mut matches: Option<Vec<usize>> = None;
+        for word in words(terms) {
+            let found = inner.index.get(&word).cloned().unwrap_or_default();
+            matches = Some(match matches {
+                None => found,
+                Some(prev) => prev
+                    .into_iter()
+                    .filter(|n| found.binary_search(n).is_ok())
+                    .collect(),
+            });
+        }
+
+        let matches = matches.unwrap_or_default();
+        let start = matches.len().saturating_sub(limit);
+        matches[start..]
+            .iter()
+            .filter_map(|&n| inner.logged_message(n))
+            .collect()
+    }
+
+    /// Returns `count` messages of `room`, oldest first.
+    ///
+    /// The first call returns the most recent messages. Calling it again for the same room
+    /// returns the messages that precede the ones returned by the previous call. Once the first
+    /// message of the room has been returned, the next call starts again from the most recent
+    /// messages.
+    pub fn page(&self, room: &str, count: usize) -> Vec<LoggedMessage> {
+        let mut inner = self.inner.lock().unwrap();
+        let end = match inner.page_cursor {
+            Some((ref r, end)) if r == room => end,
+            _ => inner.records.len(),
+        };
+
+        let mut indices = inner.records[..end]
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|&(_, record)| record.room == room)
+            .map(|(n, _)| n)
+            .take(count)
+            .collect::<Vec<_>>();
+        indices.reverse();
+
+        inner.page_cursor = match indices.first() {
+            Some(&first) if inner.records[..first].iter().any(|record| record.room == room) => {
+                Some((room.to_owned(), first))
+            }
+            _ => None,
+        };
+        indices.into_iter().filter_map(|n| inner.logged_message(n)).collect()
+    }
+}
+
+impl Inner {
+    fn new(file: Option<File>) -> Inner {
+        Inner {
+            file: file,
+            records: Vec::new(),
+            known: HashSet::new(),
+            index: HashMap::new(),
+            page_cursor: None,
+            malformed: 0,
+        }
+    }
+
+    /// Adds a record to the memory, and indexes it.
+    fn push(&mut self, record: Record) {
+        let n = self.records.len();
+        let mut record_words = words(&record.text).collect::<Vec<_>>();
+        record_words.extend(words(&record.nickname));
+        record_words.sort();
+        record_words.dedup();
+        for word in record_words {
+            self.index.entry(word).or_insert_with(Vec::new).push(n);
+        }
+        if !record.id.is_empty() {
+            self.known.insert((record.author.clone(), record.id.clone()));
+        }
+        self.records.push(record);
+    }
+
+    fn logged_message(&self, n: usize) -> Option<LoggedMessage> {
+        let record = &self.records[n];
+        Some(LoggedMessage {
+            room: record.room.clone(),
+            message: record.to_message()?,
+            verified: record.verified,
+        })
+    }
+}
+
+/// Splits `text` into lowercase words, for indexing and searching.
+fn words<'a>(text: &'a str) -> impl Iterator<Item = String> + 'a {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_common::key::{KeyType, Keypair};
+    use chat_common::message::Composer;
+    use std::env;
+    use std::process;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir()
+            .join(format!("chapter-3-chat-log-{}", process::id()))
+            .join(name)
+    }
+
+    /// Appends a message to `log` for each of `texts`, all of them in `room`.
+    fn append(log: &ChatLog, composer: &Composer, room: &str, texts: &[&str]) {
+        for text in texts {
+            let message = composer.compose(Kind::Text, text.as_bytes().to_vec());
+            log.append(room, &message.author, Some(&message), text, true).unwrap();
+        }
+    }
+
+    fn texts(page: &[LoggedMessage]) -> Vec<String> {
+        page.iter()
+            .map(|logged| String::from_utf8(logged.message.payload.clone()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn pages_go_backwards_then_start_over() {
+        let log = ChatLog::in_memory();
+        let composer = Composer::new(Keypair::generate(KeyType::Ed25519).unwrap().peer_id());
+        append(&log, &composer, "rust", &["one", "two"]);
+        append(&log, &composer, "other", &["elsewhere"]);
+        append(&log, &composer, "rust", &["three", "four", "five"]);
+
+        assert_eq!(texts(&log.page("rust", 2)), ["four", "five"]);
+        assert_eq!(texts(&log.page("rust", 2)), ["two", "three"]);
+        assert_eq!(texts(&log.page("rust", 2)), ["one"]);
+        // The start of the room was reached.
+        assert_eq!(texts(&log.page("rust", 2)), ["four", "five"]);
+    }
+
+    #[test]
+    fn page_ending_on_the_first_message_starts_over() {
+        let log = ChatLog::in_memory();
+        let composer = Composer::new(Keypair::generate(KeyType::Ed25519).unwrap().peer_id());
+        append(&log, &composer, "rust", &["one", "two", "three", "four"]);
+
+        assert_eq!(texts(&log.page("rust", 2)), ["three", "four"]);
+        assert_eq!(texts(&log.page("rust", 2)), ["one", "two"]);
+        assert_eq!(texts(&log.page("rust", 2)), ["three", "four"]);
+    }
+
+    #[test]
+    fn paging_another_room_starts_over() {
+        let log = ChatLog::in_memory();
+        let composer = Composer::new(Keypair::generate(KeyType::Ed25519).unwrap().peer_id());
+        append(&log, &composer, "rust", &["one", "two", "three"]);
+        append(&log, &composer, "other", &["elsewhere"]);
+
+        assert_eq!(texts(&log.page("rust", 1)), ["three"]);
+        assert_eq!(texts(&log.page("other", 1)), ["elsewhere"]);
+        assert_eq!(texts(&log.page("rust", 1)), ["three"]);
+        assert!(log.page("empty", 1).is_empty());
+    }
+
+    #[test]
+    fn search_matches_all_the_words() {
+        let log = ChatLog::in_memory();
+        let composer = Composer::new(Keypair::generate(KeyType::Ed25519).unwrap().peer_id());
+        append(&log, &composer, "rust", &["Hello world", "hello there", "the world"]);
+
+        assert_eq!(texts(&log.search("hello", 10)), ["Hello world", "hello there"]);
+        assert_eq!(texts(&log.search("WORLD, hello", 10)), ["Hello world"]);
+        assert_eq!(texts(&log.search("world", 1)), ["the world"]);
+        assert!(log.search("?!", 10).is_empty());
+    }
+
+    #[test]
+    fn messages_are_logged_once() {
+        let log = ChatLog::in_memory();
+        let composer = Composer::new(Keypair::generate(KeyType::Ed25519).unwrap().peer_id());
+        let message = composer.compose(Kind::Text, b"hello".to_vec());
+        log.append("rust", &message.author, Some(&message), "hello", true).unwrap();
+        log.append("rust", &message.author, Some(&message), "hello", true).unwrap();
+        assert_eq!(log.page("rust", 10).len(), 1);
+    }
+
+    #[test]
+    fn reopened_log_skips_malformed_and_truncated_lines() {
+        let path = temp_path("reopen.jsonl");
+        let _ = fs::remove_file(&path);
+        let composer = Composer::new(Keypair::generate(KeyType::Ed25519).unwrap().peer_id());
+        {
+            let log = ChatLog::open(path.clone()).unwrap();
+            append(&log, &composer, "rust", &["one"]);
+            log.sync().unwrap();
+        }
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(b"not json\n").unwrap();
+        }
+        {
+            let log = ChatLog::open(path.clone()).unwrap();
+            append(&log, &composer, "rust", &["two"]);
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(b"{\"room\":\"ru").unwrap();
+        }
+
+        let log = ChatLog::open(path.clone()).unwrap();
+        assert_eq!(log.malformed_lines(), 1);
+        assert_eq!(texts(&log.page("rust", 10)), ["one", "two"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,598 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! The commands typed on stdin.
+//!
+//! A line that starts with `/` is a command, and any other line is a message for the active room.
+//! A line that starts with `//` is a message whose first slash is removed, so that one can still
+//! send text that starts with a slash. `parse` only checks the syntax of the commands; running
+//! them is up to `main`. The same commands are available natively and in the browser, except for
+//! `/listen` as the browser can't listen.
+
+use chat_common::peer_addr;
+use chat_common::presence::PresenceCommand;
+use chat_common::rooms::RoomCommand;
+use libp2p_core::{Multiaddr, PeerId};
+use std::error;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Description of a command, for `/help`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CommandInfo {
+    /// Name of the command, including the slash.
+    pub name: &'static str,
+    /// Arguments of the command. Optional arguments are between brackets.
+    pub args: &'static str,
+    /// What the command does.
+    pub description: &'static str,
+}
+
+impl fmt::Display for CommandInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let usage = if self.args.is_empty() {
+            self.name.to_owned()
+        } else {
+            format!("{} {}", self.name, self.args)
+        };
+        write!(f, "{:<32} {}", usage, self.description)
+    }
+}
+
+/// The commands, in the order `/help` lists them.
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "/help",
+        args: "[<command>]",
+        description: "lists the commands, or describes one of them",
+    },
+    CommandInfo {
+        name: "/quit",
+        args: "",
+        description: "leaves the rooms and stops the node",
+    },
+    CommandInfo {
+        name: "/dial",
+        args: "<multiaddr>",
+        description: "connects to the node at this address",
+    },
+    CommandInfo {
+        name: "/listen",
+        args: "<multiaddr>",
+        description: "accepts connections on this address (not in the browser)",
+    },
+    CommandInfo {
+        name: "/peers",
+        args: "",
+        description: "lists the nodes we're connected to",
+    },
+    CommandInfo {
+        name: "/peerinfo",
+        args: "<peer-id>",
+        description: "shows what a node told us about itself",
+    },
+    CommandInfo {
+        name: "/join",
+        args: "<room>",
+        description: "joins a room and makes it the active room",
+    },
+    CommandInfo {
+        name: "/leave",
+        args: "[<room>]",
+        description: "leaves a room, by default the active one",
+    },
+    CommandInfo {
+        name: "/rooms",
+        args: "",
+        description: "lists the rooms we're in",
+    },
+    CommandInfo {
+        name: "/topic",
+        args: "[<room>]",
+        description: "shows the active room, or switches to another room we're in",
+    },
+    CommandInfo {
+        name: "/me",
+        args: "<action>",
+        description: "describes what you're doing, in the third person",
+    },
+    CommandInfo {
+        name: "/nick",
+        args: "<name>",
+        description: "changes your nickname, a single word of at most 32 characters",
+    },
+    CommandInfo {
+        name: "/who",
+        args: "[<room>]",
+        description: "lists the members of a room, by default of all our rooms",
+    },
+    CommandInfo {
+        name: "/msg",
+        args: "<peer-or-nick> <text>",
+        description: "sends a message to a single node",
+    },
+    CommandInfo {
+        name: "/send",
+        args: "<peer-or-nick> <path>",
+        description: "offers a file to a node",
+    },
+    CommandInfo {
+        name: "/accept",
+        args: "<id> [<peer-id>]",
+        description: "downloads a file that was offered to us, by the given node if several were",
+    },
+    CommandInfo {
+        name: "/transfers",
+        args: "",
+        description: "lists the file transfers",
+    },
+    CommandInfo {
+        name: "/encrypt",
+        args: "",
+        description: "makes the active room end-to-end encrypted",
+    },
+    CommandInfo {
+        name: "/invite",
+        args: "<peer-id>",
+        description: "gives the key of the active room to a node",
+    },
+    CommandInfo {
+        name: "/remove",
+        args: "<peer-id>",
+        description: "takes the key of the active room away from a node",
+    },
+    CommandInfo {
+        name: "/members",
+        args: "",
+        description: "lists the nodes that have the key of the active room",
+    },
+    CommandInfo {
+        name: "/requestkey",
+        args: "",
+        description: "asks the members of the active room for its key",
+    },
+    CommandInfo {
+        name: "/acceptkey",
+        args: "<peer-id>",
+        description: "uses the key of the active room that a node gave us",
+    },
+    CommandInfo {
+        name: "/search",
+        args: "<terms>",
+        description: "searches the chat log",
+    },
+    CommandInfo {
+        name: "/history",
+        args: "<room> [<n>]",
+        description: "shows the previous messages of a room from the chat log",
+    },
+];
+
+/// Returns the description of the command `name`. The slash is optional.
+pub fn info(name: &str) -> Option<&'static CommandInfo> {
+    COMMANDS
+        .iter()
+        .find(|info| info.name == name || info.name[1..] == *name)
+}
+
+/// A line typed on stdin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    /// Text to publish in the active room.
+    Message(String),
+    /// A command.
+    Command(Command),
+}
+
+/// A command, with its arguments. See `COMMANDS` for what each one does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Help(Option<String>),
+    Quit,
+    Dial(Multiaddr),
+    Listen(Multiaddr),
+    Peers,
+    PeerInfo(PeerId),
+    Room(RoomCommand),
+    Topic(Option<String>),
+    Me(String),
+    Presence(PresenceCommand),
+    Msg { to: String, text: String },
+    Send { to: String, path: PathBuf },
+    Accept { id: u64, sender: Option<PeerId> },
+    Transfers,
+    Encrypt,
+    Invite(PeerId),
+    Remove(PeerId),
+    Members,
+    RequestKey,
+    AcceptKey(PeerId),
+    Search(String),
+    History { room: String, count: Option<usize> },
+}
+
+/// Parses a line typed on stdin.
+pub fn parse(line: &str) -> Result<Input, CommandError> {
+    if line.starts_with("//") {
+        return Ok(Input::Message(line[1..].to_owned()));
+    } else if !line.starts_with('/') {
+        return Ok(Input::Message(line.to_owned()));
+    }
+
+    let (name, args) = split_first_word(line);
+    let info = match COMMANDS.iter().find(|info| info.name == name) {
+        Some(info) => info,
+        None => return Err(CommandError::Unknown(name.to_owned())),
+    };
+    let words = args.split_whitespace().collect::<Vec<_>>();
+    let usage = || CommandError::Usage(info);
+    // The argument of the commands that take at most one.
+    let optional = || match words.len() {
+        0 | 1 => Ok(words.first().cloned()),
+        _ => Err(usage()),
+    };
+    let required = || -> Result<&str, CommandError> { optional()?.ok_or_else(usage) };
+    let nothing = |command| if words.is_empty() { Ok(command) } else { Err(usage()) };
+    let peer_id = || -> Result<PeerId, CommandError> {
+        let peer = required()?;
+        peer_addr::parse_peer_id(peer).ok_or_else(|| CommandError::invalid("peer id", peer))
+    };
+    let rest = |text: &str| match text.trim() {
+        "" => Err(usage()),
+        text => Ok(text.to_owned()),
+    };
+
+    let command = match name {
+        "/help" => Command::Help(optional()?.map(String::from)),
+        "/quit" => nothing(Command::Quit)?,
+        "/dial" => Command::Dial(parse_arg(required()?, "multiaddress")?),
+        "/listen" => Command::Listen(parse_arg(required()?, "multiaddress")?),
+        "/peers" => nothing(Command::Peers)?,
+        "/peerinfo" => Command::PeerInfo(peer_id()?),
+        "/join" => Command::Room(RoomCommand::Join(required()?.to_owned())),
+        "/leave" => Command::Room(RoomCommand::Leave(optional()?.map(String::from))),
+        "/rooms" => nothing(Command::Room(RoomCommand::List))?,
+        "/topic" => Command::Topic(optional()?.map(String::from)),
+        "/me" => Command::Me(rest(args)?),
+        "/nick" => Command::Presence(PresenceCommand::Nick(rest(args)?)),
+        "/who" => Command::Presence(PresenceCommand::Who(optional()?.map(String::from))),
+        "/msg" => {
+            let (to, text) = split_first_word(args);
+            Command::Msg {
+                to: rest(to)?,
+                text: rest(text)?,
+            }
+        }
+        "/send" => {
+            let (to, path) = split_first_word(args);
+            Command::Send {
+                to: rest(to)?,
+                path: PathBuf::from(rest(path)?),
+            }
+        }
+        "/accept" => {
+            let id = match words.first() {
+                Some(id) if words.len() <= 2 => id,
+                _ => return Err(usage()),
+            };
+            let sender = match words.get(1) {
+                Some(sender) => match peer_addr::parse_peer_id(sender) {
+                    Some(sender) => Some(sender),
+                    None => return Err(CommandError::invalid("peer id", sender)),
+                },
+                None => None,
+            };
+            match u64::from_str_radix(id, 16) {
+                Ok(id) => Command::Accept { id: id, sender: sender },
+                Err(_) => return Err(CommandError::invalid("transfer id", id)),
+            }
+        }
+        "/transfers" => nothing(Command::Transfers)?,
+        "/encrypt" => nothing(Command::Encrypt)?,
+        "/invite" => Command::Invite(peer_id()?),
+        "/remove" => Command::Remove(peer_id()?),
+        "/members" => nothing(Command::Members)?,
+        "/requestkey" => nothing(Command::RequestKey)?,
+        "/acceptkey" => Command::AcceptKey(peer_id()?),
+        "/search" => {
+            // Only the words of the terms are searched; punctuation alone matches nothing.
+            let terms = rest(args)?;
+            if !terms.chars().any(char::is_alphanumeric) {
+                return Err(usage());
+            }
+            Command::Search(terms)
+        }
+        "/history" => match words.len() {
+            1 => Command::History {
+                room: words[0].to_owned(),
+                count: None,
+            },
+            2 => Command::History {
+                room: words[0].to_owned(),
+                count: Some(parse_arg(words[1], "number of messages")?),
+            },
+            _ => return Err(usage()),
+        },
+        _ => unreachable!("all the commands of COMMANDS are handled"),
+    };
+    Ok(Input::Command(command))
+}
+
+/// Error while parsing a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// The line starts with a slash, but isn't a known command.
+    Unknown(String),
+    /// The command has missing or extra arguments.
+    Usage(&'static CommandInfo),
+    /// An argument of the command is invalid.
+    InvalidArgument {
+        /// What the argument should have been.
+        expected: &'static str,
+        /// The argument.
+        value: String,
+    },
+}
+
+impl CommandError {
+    fn invalid(expected: &'static str, value: &str) -> CommandError {
+        CommandError::InvalidArgument {
+            expected: expected,
+            value: value.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::Unknown(ref name) => write!(
+                f,
+                "Unknown command {}; /help lists the commands, and a line that starts with // is \
+                 sent as a message",
+                name
+            ),
+            CommandError::Usage(info) if info.args.is_empty() => {
+                write!(f, "Usage: {} (without arguments)", info.name)
+            }
+            CommandError::Usage(info) => write!(f, "Usage: {} {}", info.name, info.args),
+            CommandError::InvalidArgument { expected, ref value } => {
+                write!(f, "Invalid {}: {}", expected, value)
+            }
+        }
+    }
+}
+
+impl error::Error for CommandError {
+    fn description(&self) -> &str {
+        match *self {
+            CommandError::Unknown(_) => "unknown command",
+            CommandError::Usage(_) => "invalid number of arguments",
+            CommandError::InvalidArgument { .. } => "invalid argument",
+        }
+    }
+}
+
+/// Splits `text` into its first word and the rest, with the whitespace around them removed.
+fn split_first_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.find(char::is_whitespace) {
+        Some(pos) => (&text[..pos], text[pos..].trim()),
+        None => (text, ""),
+    }
+}
+
+/// Parses `value`, which should be a valid `expected`.
+fn parse_arg<T>(value: &str, expected: &'static str) -> Result<T, CommandError>
+where
+    T: ::std::str::FromStr,
+{
+    value
+        .parse()
+        .map_err(|_| CommandError::invalid(expected, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_common::key::{KeyType, Keypair};
+
+    fn random_peer_id() -> PeerId {
+        Keypair::generate(KeyType::Ed25519).unwrap().peer_id()
+    }
+
+    fn command(line: &str) -> Command {
+        match parse(line) {
+            Ok(Input::Command(command)) => command,
+            other => panic!("{:?} parsed as {:?}", line, other),
+        }
+    }
+
+    fn usage(name: &str) -> Result<Input, CommandError> {
+        Err(CommandError::Usage(info(name).unwrap()))
+    }
+
+    fn invalid(expected: &'static str, value: &str) -> Result<Input, CommandError> {
+        Err(CommandError::invalid(expected, value))
+    }
+
+    #[test]
+    fn messages() {
+        assert_eq!(parse("hello"), Ok(Input::Message("hello".to_owned())));
+        assert_eq!(parse(" /join a"), Ok(Input::Message(" /join a".to_owned())));
+        // A double slash sends the rest of the line, including the slash.
+        assert_eq!(parse("//join a"), Ok(Input::Message("/join a".to_owned())));
+        assert_eq!(parse("//"), Ok(Input::Message("/".to_owned())));
+    }
+
+    #[test]
+    fn unknown_commands() {
+        assert_eq!(parse("/frobnicate now"), Err(CommandError::Unknown("/frobnicate".to_owned())));
+        assert_eq!(parse("/"), Err(CommandError::Unknown("/".to_owned())));
+        // Names must match exactly.
+        assert_eq!(parse("/joinroom"), Err(CommandError::Unknown("/joinroom".to_owned())));
+    }
+
+    #[test]
+    fn every_command_is_handled() {
+        for info in COMMANDS {
+            let _ = parse(info.name);
+        }
+    }
+
+    #[test]
+    fn usage_errors() {
+        assert_eq!(parse("/quit now"), usage("quit"));
+        assert_eq!(parse("/join"), usage("join"));
+        assert_eq!(parse("/join a b"), usage("join"));
+        assert_eq!(parse("/nick   "), usage("nick"));
+        assert_eq!(parse("/who a b"), usage("who"));
+        assert_eq!(parse("/requestkey x"), usage("requestkey"));
+        assert_eq!(
+            parse("/quit now").unwrap_err().to_string(),
+            "Usage: /quit (without arguments)"
+        );
+        assert_eq!(parse("/join").unwrap_err().to_string(), "Usage: /join <room>");
+    }
+
+    #[test]
+    fn room_commands() {
+        assert_eq!(command("/join a"), Command::Room(RoomCommand::Join("a".to_owned())));
+        assert_eq!(command("/leave"), Command::Room(RoomCommand::Leave(None)));
+        assert_eq!(
+            command("/leave  a "),
+            Command::Room(RoomCommand::Leave(Some("a".to_owned())))
+        );
+        assert_eq!(command("/rooms"), Command::Room(RoomCommand::List));
+    }
+
+    #[test]
+    fn presence_commands() {
+        assert_eq!(
+            command("/nick  alice "),
+            Command::Presence(PresenceCommand::Nick("alice".to_owned()))
+        );
+        // Nicknames are checked when the command runs, so that the error explains what's wrong.
+        assert_eq!(
+            command("/nick bob smith"),
+            Command::Presence(PresenceCommand::Nick("bob smith".to_owned()))
+        );
+        assert_eq!(command("/who"), Command::Presence(PresenceCommand::Who(None)));
+        assert_eq!(
+            command("/who a"),
+            Command::Presence(PresenceCommand::Who(Some("a".to_owned())))
+        );
+    }
+
+    #[test]
+    fn msg_and_send_split_the_recipient() {
+        assert_eq!(
+            command("/msg alice  hello   world "),
+            Command::Msg {
+                to: "alice".to_owned(),
+                text: "hello   world".to_owned(),
+            }
+        );
+        assert_eq!(parse("/msg alice"), usage("msg"));
+        assert_eq!(parse("/msg"), usage("msg"));
+        assert_eq!(
+            command("/send bob /tmp/my notes.txt"),
+            Command::Send {
+                to: "bob".to_owned(),
+                path: PathBuf::from("/tmp/my notes.txt"),
+            }
+        );
+        assert_eq!(parse("/send bob"), usage("send"));
+    }
+
+    #[test]
+    fn accept_takes_a_hexadecimal_id() {
+        let sender = random_peer_id();
+        assert_eq!(command("/accept 00ff"), Command::Accept { id: 255, sender: None });
+        assert_eq!(
+            command(&format!("/accept DEADbeef {}", sender.to_base58())),
+            Command::Accept {
+                id: 0xdead_beef,
+                sender: Some(sender),
+            }
+        );
+        assert_eq!(
+            command("/accept ffffffffffffffff"),
+            Command::Accept {
+                id: u64::max_value(),
+                sender: None,
+            }
+        );
+        assert_eq!(parse("/accept 12g"), invalid("transfer id", "12g"));
+        assert_eq!(parse("/accept 10000000000000000"), invalid("transfer id", "10000000000000000"));
+        assert_eq!(parse("/accept 1 alice"), invalid("peer id", "alice"));
+        assert_eq!(parse("/accept"), usage("accept"));
+        assert_eq!(parse("/accept 1 2 3"), usage("accept"));
+    }
+
+    #[test]
+    fn history_takes_an_optional_count() {
+        assert_eq!(
+            command("/history a"),
+            Command::History {
+                room: "a".to_owned(),
+                count: None,
+            }
+        );
+        assert_eq!(
+            command("/history a 20"),
+            Command::History {
+                room: "a".to_owned(),
+                count: Some(20),
+            }
+        );
+        assert_eq!(parse("/history a -1"), invalid("number of messages", "-1"));
+        assert_eq!(parse("/history"), usage("history"));
+        assert_eq!(parse("/history a 1 2"), usage("history"));
+    }
+
+    #[test]
+    fn peer_id_arguments() {
+        let peer_id = random_peer_id();
+        let base58 = peer_id.to_base58();
+        assert_eq!(command(&format!("/invite {}", base58)), Command::Invite(peer_id.clone()));
+        assert_eq!(command(&format!("/remove {}", base58)), Command::Remove(peer_id.clone()));
+        assert_eq!(command(&format!("/acceptkey {}", base58)), Command::AcceptKey(peer_id));
+        assert_eq!(command("/requestkey"), Command::RequestKey);
+        assert_eq!(parse("/invite alice"), invalid("peer id", "alice"));
+        assert_eq!(parse("/peerinfo"), usage("peerinfo"));
+    }
+
+    #[test]
+    fn search_needs_words() {
+        assert_eq!(command("/search  hello world "), Command::Search("hello world".to_owned()));
+        assert_eq!(command("/search don't"), Command::Search("don't".to_owned()));
+        assert_eq!(parse("/search ?!"), usage("search"));
+        assert_eq!(parse("/search"), usage("search"));
+    }
+
+    #[test]
+    fn multiaddress_arguments() {
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        assert_eq!(command("/dial /ip4/127.0.0.1/tcp/4001"), Command::Dial(addr.clone()));
+        assert_eq!(command("/listen /ip4/127.0.0.1/tcp/4001"), Command::Listen(addr));
+        assert_eq!(parse("/dial localhost"), invalid("multiaddress", "localhost"));
+    }
+}
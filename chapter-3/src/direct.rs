@@ -0,0 +1,382 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Direct messages between two nodes.
+//!
+//! Direct messages don't go through the publish-subscribe protocol. Instead, the sender opens a
+//! substream with the recipient using the protocol of this module, sends the message (in a signed
+//! envelope, like the messages of the rooms) in a single frame, and waits for the recipient to
+//! acknowledge it with an empty frame. Frames are prefixed with their length as a big endian
+//! `u32`. The recipient only acknowledges the messages whose signature it has verified, and
+//! closes the substream without acknowledging the others.
+//!
+//! If we aren't connected to the recipient, the substream is opened on a new connection to one
+//! of its known addresses. The message is considered lost if it hasn't been acknowledged after
+//! `DELIVERY_TIMEOUT`.
+
+use bytes::Bytes;
+use chat_common::peer_addr;
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Sink, Stream};
+use libp2p_core::{ConnectionUpgrade, Endpoint, Multiaddr, PeerId};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::iter;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_io::codec::length_delimited::Framed;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::Timer;
+
+/// Name of the protocol, as negotiated on substreams.
+pub const PROTOCOL_NAME: &str = "/rustfest-chat/direct/1.0.0";
+
+/// How long we wait for the recipient to acknowledge a message.
+pub const DELIVERY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Returns the topic that the envelope of a direct message to `recipient` is signed for.
+///
+/// Direct messages aren't published on a topic, but signing them for their recipient prevents
+/// the recipient from replaying them to another node, or in a room.
+pub fn signing_topic(recipient: &PeerId) -> String {
+    format!("/direct/{}", recipient.to_base58())
+}
+
+/// A direct message that we received. It hasn't been verified yet.
+///
+/// The sender is only told that the message has been delivered once `acknowledge` is called.
+#[derive(Debug)]
+pub struct Incoming {
+    /// Node that sent the message.
+    pub source: PeerId,
+    /// Signed envelope of the message.
+    pub data: Vec<u8>,
+    /// Signalled to send the acknowledgement. `None` if it already has been.
+    ack: Option<oneshot::Sender<()>>,
+}
+
+impl Incoming {
+    /// Acknowledges the message to its sender. Call this only once the message is verified.
+    pub fn acknowledge(&mut self) {
+        if let Some(ack) = self.ack.take() {
+            let _ = ack.send(());
+        }
+    }
+}
+
+/// Upgrade that negotiates the protocol on a substream.
+///
+/// When listening, the messages we receive are sent to the receiver returned by `new`. When
+/// dialing, we send the message passed to `send`.
+#[derive(Clone)]
+pub struct DirectUpgrade {
+    outgoing: Option<Outgoing>,
+    incoming: mpsc::UnboundedSender<Incoming>,
+}
+
+/// A message we're sending. The same message can be sent on several substreams if we dial
+/// several addresses of the recipient, but it's only delivered once.
+#[derive(Clone)]
+struct Outgoing {
+    recipient: PeerId,
+    data: Arc<Vec<u8>>,
+    delivery: Arc<Mutex<Delivery>>,
+}
+
+struct Delivery {
+    /// Signalled once the message has been acknowledged. `None` if it already has been.
+    acknowledged: Option<oneshot::Sender<()>>,
+    /// Why the last attempt failed, if any.
+    last_error: Option<String>,
+}
+
+impl DirectUpgrade {
+    /// Creates an upgrade that accepts direct messages.
+    pub fn new() -> (DirectUpgrade, mpsc::UnboundedReceiver<Incoming>) {
+        let (tx, rx) = mpsc::unbounded();
+        let upgrade = DirectUpgrade {
+            outgoing: None,
+            incoming: tx,
+        };
+        (upgrade, rx)
+    }
+
+    /// Returns an upgrade that sends `data` to `recipient` when dialing, and a future that
+    /// finishes when the recipient has acknowledged it.
+    ///
+    /// The future produces an error if the message isn't acknowledged within
+    /// `DELIVERY_TIMEOUT`.
+    pub fn send(
+        &self,
+        recipient: PeerId,
+        data: Vec<u8>,
+        timer: &Timer,
+    ) -> (DirectUpgrade, impl Future<Item = (), Error = IoError>) {
+        let (tx, rx) = oneshot::channel();
+        let delivery = Arc::new(Mutex::new(Delivery {
+            acknowledged: Some(tx),
+            last_error: None,
+        }));
+        let upgrade = DirectUpgrade {
+            outgoing: Some(Outgoing {
+                recipient: recipient,
+                data: Arc::new(data),
+                delivery: delivery.clone(),
+            }),
+            incoming: self.incoming.clone(),
+        };
+
+        let timeout = timer.sleep(DELIVERY_TIMEOUT).then(move |_| {
+            let reason = delivery
+                .lock()
+                .unwrap()
+                .last_error
+                .take()
+                .unwrap_or_else(|| "the node can't be reached".to_owned());
+            Err::<(), _>(IoError::new(IoErrorKind::TimedOut, reason))
+        });
+        let acknowledged = rx.map_err(|_| IoError::new(IoErrorKind::Other, "message dropped"));
+        let future = acknowledged
+            .select(timeout)
+            .map(|_| ())
+            .map_err(|(err, _)| err);
+        (upgrade, future)
+    }
+}
+
+impl<C> ConnectionUpgrade<C> for DirectUpgrade
+where
+    C: AsyncRead + AsyncWrite + 'static,
+{
+    type NamesIter = iter::Once<(Bytes, ())>;
+    type UpgradeIdentifier = ();
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        iter::once((Bytes::from(PROTOCOL_NAME), ()))
+    }
+
+    type Output = Box<Future<Item = (), Error = IoError>>;
+    type Future = future::FutureResult<Self::Output, IoError>;
+
+    fn upgrade(
+        self,
+        socket: C,
+        _: (),
+        endpoint: Endpoint,
+        remote_addr: &Multiaddr,
+    ) -> Self::Future {
+        let remote = match peer_addr::peer_id_of(remote_addr) {
+            Some(peer_id) => peer_id,
+            None => {
+                let msg = "the address of the remote doesn't contain its PeerId";
+                return future::err(IoError::new(IoErrorKind::InvalidInput, msg));
+            }
+        };
+        let framed = Framed::<_, Vec<u8>>::new(socket);
+
+        let future: Self::Output = match (endpoint, self.outgoing) {
+            (Endpoint::Dialer, Some(outgoing)) => {
+                let delivery = outgoing.delivery.clone();
+                let future = if remote != outgoing.recipient {
+                    let msg = format!("{} isn't the node we wanted to reach", remote.to_base58());
+                    future::Either::A(future::err(IoError::new(IoErrorKind::Other, msg)))
+                } else {
+                    future::Either::B(
+                        framed
+                            .send((*outgoing.data).clone())
+                            .and_then(|framed| framed.into_future().map_err(|(err, _)| err))
+                            .and_then(|(ack, _)| match ack {
+                                Some(_) => Ok(()),
+                                None => Err(IoError::new(
+                                    IoErrorKind::UnexpectedEof,
+                                    "the node closed the substream without acknowledging",
+                                )),
+                            }),
+                    )
+                };
+
+                Box::new(future.then(move |result| {
+                    let mut delivery = delivery.lock().unwrap();
+                    match result {
+                        Ok(()) => {
+                            if let Some(acknowledged) = delivery.acknowledged.take() {
+                                let _ = acknowledged.send(());
+                            }
+                        }
+                        Err(err) => delivery.last_error = Some(err.to_string()),
+                    }
+                    Ok(())
+                }))
+            }
+            (Endpoint::Dialer, None) => {
+                let msg = "opened a direct message substream without a message";
+                Box::new(future::err(IoError::new(IoErrorKind::Other, msg)))
+            }
+            (Endpoint::Listener, _) => {
+                let incoming = self.incoming;
+                Box::new(
+                    framed
+                        .into_future()
+                        .map_err(|(err, _)| err)
+                        .and_then(move |(frame, framed)| {
+                            let frame = frame.ok_or_else(|| {
+                                IoError::new(IoErrorKind::UnexpectedEof, "no message received")
+                            })?;
+                            let (ack_tx, ack_rx) = oneshot::channel();
+                            let _ = incoming.unbounded_send(Incoming {
+                                source: remote,
+                                data: frame.to_vec(),
+                                ack: Some(ack_tx),
+                            });
+                            Ok((framed, ack_rx))
+                        })
+                        .and_then(|(framed, ack)| {
+                            ack.then(move |result| match result {
+                                Ok(()) => future::Either::A(framed.send(Vec::new()).map(|_| ())),
+                                Err(_) => {
+                                    let msg = "the message was rejected";
+                                    let err = IoError::new(IoErrorKind::InvalidData, msg);
+                                    future::Either::B(future::err(err))
+                                }
+                            })
+                        }),
+                )
+            }
+        };
+
+        future::ok(future)
+    }
+}
+
+/// The nicknames that the nodes have used. Cloning a `Nicknames` gives access to the same list.
+///
+/// Anyone can pick any nickname, so a nickname that several nodes have used, even one after the
+/// other, is ambiguous and doesn't designate any node anymore.
+#[derive(Clone, Default)]
+pub struct Nicknames {
+    inner: Arc<Mutex<HashMap<String, Vec<PeerId>>>>,
+}
+
+impl Nicknames {
+    /// Creates an empty list.
+    #[inline]
+    pub fn new() -> Nicknames {
+        Nicknames::default()
+    }
+
+    /// Records that `peer_id` uses `nickname`. Only call this for verified messages.
+    pub fn insert(&self, nickname: &str, peer_id: &PeerId) {
+        let mut inner = self.inner.lock().unwrap();
+        let users = inner.entry(nickname.to_owned()).or_insert_with(Vec::new);
+        if !users.contains(peer_id) {
+            users.push(peer_id.clone());
+        }
+    }
+
+    /// Finds the node designated by `name`, which is either a base58 `PeerId` or a nickname
+    /// that a single node has used.
+    pub fn resolve(&self, name: &str) -> Result<PeerId, ResolveError> {
+        if let Some(peer_id) = peer_addr::parse_peer_id(name) {
+            return Ok(peer_id);
+        }
+        match self.inner.lock().unwrap().get(name) {
+            Some(users) if users.len() == 1 => Ok(users[0].clone()),
+            Some(users) => Err(ResolveError::Ambiguous(name.to_owned(), users.clone())),
+            None => Err(ResolveError::Unknown(name.to_owned())),
+        }
+    }
+}
+
+/// Why a name couldn't be resolved into a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolveError {
+    /// Neither a `PeerId` nor a nickname we've seen.
+    Unknown(String),
+    /// A nickname that all these nodes have used.
+    Ambiguous(String, Vec<PeerId>),
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResolveError::Unknown(ref name) => write!(
+                f,
+                "unknown node {}; use its peer id or a nickname seen in a room",
+                name
+            ),
+            ResolveError::Ambiguous(ref name, ref peer_ids) => {
+                let peer_ids = peer_ids.iter().map(PeerId::to_base58).collect::<Vec<_>>();
+                write!(
+                    f,
+                    "several nodes have used the nickname {}; use the peer id of the one you \
+                     mean: {}",
+                    name,
+                    peer_ids.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl Error for ResolveError {
+    fn description(&self) -> &str {
+        match *self {
+            ResolveError::Unknown(_) => "unknown node",
+            ResolveError::Ambiguous(..) => "ambiguous nickname",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_common::key::{KeyType, Keypair};
+
+    fn random_peer_id() -> PeerId {
+        Keypair::generate(KeyType::Ed25519).unwrap().peer_id()
+    }
+
+    #[test]
+    fn nickname_of_a_single_node_is_resolved() {
+        let nicknames = Nicknames::new();
+        let alice = random_peer_id();
+        nicknames.insert("alice", &alice);
+        nicknames.insert("alice", &alice);
+        assert_eq!(nicknames.resolve("alice"), Ok(alice.clone()));
+        assert_eq!(nicknames.resolve(&alice.to_base58()), Ok(alice));
+        assert_eq!(nicknames.resolve("bob"), Err(ResolveError::Unknown("bob".to_owned())));
+    }
+
+    #[test]
+    fn nickname_used_by_several_nodes_is_ambiguous() {
+        let nicknames = Nicknames::new();
+        let (alice, mallory) = (random_peer_id(), random_peer_id());
+        nicknames.insert("alice", &alice);
+        // Even if the first node has changed its nickname since.
+        nicknames.insert("not-alice", &alice);
+        nicknames.insert("alice", &mallory);
+        let expected = ResolveError::Ambiguous("alice".to_owned(), vec![alice.clone(), mallory]);
+        assert_eq!(nicknames.resolve("alice"), Err(expected));
+        assert_eq!(nicknames.resolve("not-alice"), Ok(alice));
+    }
+}
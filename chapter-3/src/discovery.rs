@@ -0,0 +1,221 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Discovery of other nodes through the Kademlia DHT.
+//!
+//! The node regularly performs *random walks*: it asks the DHT for the nodes that are the closest
+//! to a random `PeerId`. Each lookup makes us learn about new nodes, and we dial them until we're
+//! connected to enough of them. This way, if a node that everybody was connected to leaves, the
+//! others find each other again.
+
+use futures::{future, stream, Future, Stream};
+use libp2p_core::{Multiaddr, PeerId};
+use libp2p_peerstore::Peerstore;
+use libp2p_peerstore::memory_peerstore::MemoryPeerstore;
+use peers::ConnectedPeers;
+use rand;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_timer::Timer;
+
+/// Default value of `DiscoveryConfig::target_peers`.
+pub const DEFAULT_TARGET_PEERS: usize = 8;
+
+/// Configuration of the discovery.
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// We stop dialing the nodes that we discover once we're connected to this many nodes.
+    pub target_peers: usize,
+    /// Interval between two random walks.
+    pub interval: Duration,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> DiscoveryConfig {
+        DiscoveryConfig {
+            target_peers: DEFAULT_TARGET_PEERS,
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Returns a future that performs random walks on the DHT forever.
+///
+/// The first lookup is for `local_peer_id`, which is how a node bootstraps in Kademlia: it fills
+/// our routing table with the nodes that are close to us. `find_node` must start a Kademlia
+/// lookup, and `dial` must open all the protocols of the node on the given address.
+pub fn random_walk<F, Fut, D>(
+    config: DiscoveryConfig,
+    local_peer_id: PeerId,
+    find_node: F,
+    peer_store: Arc<MemoryPeerstore>,
+    connected: ConnectedPeers,
+    dial: D,
+    timer: &Timer,
+) -> impl Future<Item = (), Error = IoError>
+where
+    F: Fn(PeerId) -> Fut,
+    Fut: Future<Item = Vec<PeerId>>,
+    Fut::Error: ::std::fmt::Display,
+    D: Fn(Multiaddr) -> Result<(), Multiaddr> + Clone,
+{
+    let targets = stream::once(Ok(local_peer_id.clone())).chain(
+        timer
+            .interval(config.interval)
+            .map(|_| random_peer_id())
+            .map_err(|err| IoError::new(IoErrorKind::Other, err)),
+    );
+
+    targets.for_each(move |target| {
+        if connected.len() >= config.target_peers {
+            return future::Either::A(future::ok(()));
+        }
+
+        let peer_store = peer_store.clone();
+        let connected = connected.clone();
+        let local_peer_id = local_peer_id.clone();
+        let target_peers = config.target_peers;
+        let dial = dial.clone();
+        future::Either::B(find_node(target).then(move |result| {
+            let discovered = match result {
+                Ok(discovered) => discovered,
+                Err(err) => {
+                    ui_eprintln!("Kademlia lookup failed: {}", err);
+                    return Ok(());
+                }
+            };
+
+            let addrs_of = |peer_id: &PeerId| match peer_store.peer(peer_id) {
+                Some(peer) => peer.addrs().collect(),
+                None => Vec::new(),
+            };
+            let candidates = discovered
+                .into_iter()
+                .filter(|peer_id| *peer_id != local_peer_id)
+                .map(|peer_id| {
+                    let addrs = addrs_of(&peer_id);
+                    (peer_id, addrs)
+                });
+            dial_candidates(candidates, &connected, target_peers, &dial);
+            Ok(())
+        }))
+    })
+}
+
+/// Dials the `candidates` that we aren't connected to, on the first of their addresses that we
+/// can dial, until we're connected to or dialing `target_peers` nodes. Returns the number of
+/// nodes dialed.
+fn dial_candidates<I, D>(
+    candidates: I,
+    connected: &ConnectedPeers,
+    target_peers: usize,
+    dial: &D,
+) -> usize
+where
+    I: IntoIterator<Item = (PeerId, Vec<Multiaddr>)>,
+    D: Fn(Multiaddr) -> Result<(), Multiaddr>,
+{
+    let mut dialed = 0;
+    for (peer_id, addrs) in candidates {
+        if connected.len() + dialed >= target_peers {
+            break;
+        }
+        if connected.contains(&peer_id) {
+            continue;
+        }
+        if addrs.into_iter().any(|addr| dial(addr).is_ok()) {
+            dialed += 1;
+        }
+    }
+    dialed
+}
+
+/// Generates a random `PeerId`. Nobody has the corresponding private key, but that's not a problem
+/// as we only use it as the target of a lookup.
+fn random_peer_id() -> PeerId {
+    let key = (0..32).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+    PeerId::from_public_key(&key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    /// Dials the candidates with a transport that only supports TCP, and returns the number of
+    /// nodes dialed along with the addresses it was asked to dial.
+    fn dial(
+        candidates: Vec<(PeerId, Vec<Multiaddr>)>,
+        connected: &ConnectedPeers,
+        target_peers: usize,
+    ) -> (usize, Vec<Multiaddr>) {
+        let attempts = RefCell::new(Vec::new());
+        let dial = |addr: Multiaddr| {
+            attempts.borrow_mut().push(addr.clone());
+            if addr.to_string().contains("/tcp/") {
+                Ok(())
+            } else {
+                Err(addr)
+            }
+        };
+        let dialed = dial_candidates(candidates, connected, target_peers, &dial);
+        (dialed, attempts.into_inner())
+    }
+
+    #[test]
+    fn dials_until_the_target_is_reached() {
+        let connected = ConnectedPeers::new();
+        let known = random_peer_id();
+        connected.insert(known.clone(), addr("/ip4/10.0.0.1/tcp/1"));
+
+        let candidates = vec![
+            (known, vec![addr("/ip4/10.0.0.1/tcp/1")]),
+            (random_peer_id(), vec![addr("/ip4/10.0.0.2/tcp/2")]),
+            (random_peer_id(), Vec::new()),
+            (random_peer_id(), vec![addr("/ip4/10.0.0.4/udp/4"), addr("/ip4/10.0.0.4/tcp/4")]),
+            (random_peer_id(), vec![addr("/ip4/10.0.0.5/tcp/5")]),
+        ];
+        let (dialed, attempts) = dial(candidates, &connected, 3);
+        assert_eq!(dialed, 2);
+        assert_eq!(
+            attempts,
+            vec![
+                addr("/ip4/10.0.0.2/tcp/2"),
+                addr("/ip4/10.0.0.4/udp/4"),
+                addr("/ip4/10.0.0.4/tcp/4"),
+            ]
+        );
+    }
+
+    #[test]
+    fn dials_nothing_at_the_target() {
+        let connected = ConnectedPeers::new();
+        connected.insert(random_peer_id(), addr("/ip4/10.0.0.1/tcp/1"));
+        connected.insert(random_peer_id(), addr("/ip4/10.0.0.2/tcp/2"));
+
+        let candidates = vec![(random_peer_id(), vec![addr("/ip4/10.0.0.3/tcp/3")])];
+        assert_eq!(dial(candidates, &connected, 2), (0, Vec::new()));
+    }
+}
@@ -0,0 +1,836 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Transfer of files between two nodes.
+//!
+//! `/send <peer> <path>` sends a `FileOffer` direct message to the recipient, which describes the
+//! file: an identifier, its name, its size and its SHA-256 multihash. If the recipient accepts the
+//! offer, it opens a substream with the sender using the protocol of this module and asks for the
+//! file, starting at a given chunk. The sender then streams the file in chunks of `CHUNK_SIZE`
+//! bytes, each in a frame that starts with the SHA-256 hash of the chunk. Frames are prefixed
+//! with their length as a big endian `u32`.
+//!
+//! The recipient writes the chunks to a `.part` file as they arrive. It doesn't acknowledge them:
+//! the only backpressure is the one of the substream, as the recipient only reads a frame once
+//! the previous one is written, and the sender only produces a frame once the substream accepts
+//! the previous one. If the transfer is interrupted, it resumes from the last complete chunk of the
+//! `.part` file. Once all the chunks have been received, the whole file is checked against the
+//! multihash of the offer before being renamed to its final name. Files are hashed on a thread of
+//! their own, so that large files don't block the event loop.
+//!
+//! Offers only live in memory: they are lost when either node restarts.
+
+use bytes::{Bytes, BytesMut};
+use chat_common::codec::{push_u64, read_u64};
+use chat_common::{peer_addr, storage};
+use futures::future::Loop;
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Async, Future, Poll, Sink, Stream};
+use libp2p_core::{ConnectionUpgrade, Endpoint, Multiaddr, PeerId};
+use rand;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_io::codec::length_delimited::Framed;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Name of the protocol, as negotiated on substreams.
+pub const PROTOCOL_NAME: &str = "/rustfest-chat/file/1.0.0";
+
+/// Number of bytes of the file in each frame.
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Length of a SHA-256 hash.
+const HASH_LEN: usize = 32;
+
+/// Multihash code of SHA-256.
+const SHA2_256_CODE: u8 = 0x12;
+
+/// Returns the directory where the files we receive are saved when none is passed on the command
+/// line.
+pub fn default_download_dir() -> PathBuf {
+    storage::config_dir().join("downloads")
+}
+
+/// Description of a file offered by a node, sent as the payload of a `FileOffer` message.
+///
+/// It is encoded as the identifier as a big endian `u64`, the size as a big endian `u64`, the
+/// length of the multihash as a `u8`, the multihash, and the name until the end of the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Offer {
+    /// Identifier of the transfer, chosen by the sender.
+    pub id: u64,
+    /// Name of the file, without its directory.
+    pub name: String,
+    /// Size of the file, in bytes.
+    pub size: u64,
+    /// SHA-256 multihash of the content of the file.
+    pub multihash: Vec<u8>,
+}
+
+impl Offer {
+    /// Encodes the offer as the payload of a `FileOffer` message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(17 + self.multihash.len() + self.name.len());
+        push_u64(&mut out, self.id);
+        push_u64(&mut out, self.size);
+        out.push(self.multihash.len() as u8);
+        out.extend_from_slice(&self.multihash);
+        out.extend_from_slice(self.name.as_bytes());
+        out
+    }
+
+    /// Decodes the payload of a `FileOffer` message. Returns `None` if it is malformed.
+    pub fn decode(data: &[u8]) -> Option<Offer> {
+        if data.len() < 17 {
+            return None;
+        }
+        let multihash_len = data[16] as usize;
+        if data.len() < 17 + multihash_len {
+            return None;
+        }
+        Some(Offer {
+            id: read_u64(&data[0..8]),
+            size: read_u64(&data[8..16]),
+            multihash: data[17..17 + multihash_len].to_vec(),
+            name: String::from_utf8(data[17 + multihash_len..].to_vec()).ok()?,
+        })
+    }
+}
+
+/// How long we wait, after accepting an offer, for a substream with the sender. Dialing can fail
+/// without us hearing about it, in which case the download is interrupted after this delay.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something that happened to one of the files we download.
+#[derive(Debug)]
+pub enum Event {
+    /// The file has been received and verified.
+    Completed { sender: PeerId, id: u64, path: PathBuf },
+    /// The transfer stopped before the end, but can be resumed.
+    Interrupted { sender: PeerId, id: u64, received: u64, error: IoError },
+    /// The file doesn't match its multihash. The partial file has been deleted.
+    Corrupted { sender: PeerId, id: u64 },
+}
+
+/// The files we offer and the files we download. Cloning a `Transfers` gives access to the same
+/// transfers.
+#[derive(Clone)]
+pub struct Transfers {
+    inner: Arc<Mutex<Inner>>,
+    events: mpsc::UnboundedSender<Event>,
+}
+
+struct Inner {
+    download_dir: PathBuf,
+    /// Files we offered, indexed by the identifier of the offer.
+    uploads: HashMap<u64, Upload>,
+    /// Files offered to us, indexed by their sender and the identifier of the offer, as
+    /// different senders could choose the same identifier.
+    downloads: HashMap<(PeerId, u64), Download>,
+}
+
+struct Upload {
+    path: PathBuf,
+    /// The only node allowed to download the file.
+    recipient: PeerId,
+}
+
+struct Download {
+    offer: Offer,
+    state: DownloadState,
+    /// Number of bytes received so far.
+    received: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DownloadState {
+    /// The offer hasn't been accepted yet.
+    Offered,
+    /// The offer has been accepted at the given time, and we're dialing the sender.
+    Connecting(Instant),
+    /// We have a substream open with the sender.
+    InProgress,
+    /// The transfer stopped before the end.
+    Interrupted,
+    /// The file has been saved at the given path.
+    Completed(PathBuf),
+}
+
+impl Transfers {
+    /// Creates an empty list of transfers. The files we receive are saved in `download_dir`.
+    pub fn new(download_dir: PathBuf) -> (Transfers, mpsc::UnboundedReceiver<Event>) {
+        let (tx, rx) = mpsc::unbounded();
+        let transfers = Transfers {
+            inner: Arc::new(Mutex::new(Inner {
+                download_dir: download_dir,
+                uploads: HashMap::new(),
+                downloads: HashMap::new(),
+            })),
+            events: tx,
+        };
+        (transfers, rx)
+    }
+
+    /// Offers the file at `path` to `recipient`. The returned future produces the offer once the
+    /// whole file has been read in order to hash it.
+    pub fn offer(
+        &self,
+        recipient: PeerId,
+        path: &Path,
+    ) -> impl Future<Item = Offer, Error = IoError> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(str::to_owned)
+            .ok_or_else(|| IoError::new(IoErrorKind::InvalidInput, "invalid file name"));
+        let size = fs::metadata(path).map(|metadata| metadata.len());
+        let path = path.to_owned();
+        let inner = self.inner.clone();
+
+        future::result(name.and_then(|name| Ok((name, size?))))
+            .and_then({
+                let path = path.clone();
+                move |(name, size)| {
+                    hash_file_in_background(path).map(move |multihash| Offer {
+                        id: rand::random(),
+                        name: name,
+                        size: size,
+                        multihash: multihash,
+                    })
+                }
+            })
+            .map(move |offer| {
+                inner.lock().unwrap().uploads.insert(offer.id, Upload {
+                    path: path,
+                    recipient: recipient,
+                });
+                offer
+            })
+    }
+
+    /// Records an offer that `sender` made to us. An offer we already know isn't reset.
+    pub fn on_offer(&self, sender: PeerId, offer: Offer) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.downloads.entry((sender, offer.id)).or_insert(Download {
+            offer: offer,
+            state: DownloadState::Offered,
+            received: 0,
+        });
+    }
+
+    /// Accepts the offer `id`, or resumes its transfer, and returns the node to download it
+    /// from. `sender` is only needed if several nodes offered a file with this identifier.
+    ///
+    /// From now on, the download counts as started, so that it can't be accepted twice.
+    pub fn accept(&self, id: u64, sender: Option<&PeerId>) -> Result<PeerId, TransferError> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut matching = inner
+            .downloads
+            .iter_mut()
+            .filter(|&(&(ref from, offer_id), _)| {
+                offer_id == id && sender.map_or(true, |sender| sender == from)
+            });
+        let (&(ref from, _), download) = matching.next().ok_or(TransferError::UnknownOffer)?;
+        if matching.next().is_some() {
+            return Err(TransferError::Ambiguous);
+        }
+        match download.state {
+            DownloadState::Offered | DownloadState::Interrupted => {
+                download.state = DownloadState::Connecting(Instant::now());
+                Ok(from.clone())
+            }
+            DownloadState::Connecting(_) | DownloadState::InProgress => {
+                Err(TransferError::InProgress)
+            }
+            DownloadState::Completed(_) => Err(TransferError::Completed),
+        }
+    }
+
+    /// Interrupts the download of `id` if we still don't have a substream with its sender
+    /// `CONNECT_TIMEOUT` after it was accepted.
+    pub fn check_connected(&self, sender: &PeerId, id: u64) {
+        let timed_out = match self.inner.lock().unwrap().downloads.get(&(sender.clone(), id)) {
+            Some(&Download {
+                state: DownloadState::Connecting(since),
+                ..
+            }) => since.elapsed() >= CONNECT_TIMEOUT,
+            _ => false,
+        };
+        if timed_out {
+            let err = IoError::new(IoErrorKind::TimedOut, "couldn't connect to the sender");
+            self.interrupt_download(sender, id, err);
+        }
+    }
+
+    /// Returns a line describing each transfer, for display.
+    pub fn describe(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        let mut lines = inner
+            .uploads
+            .iter()
+            .map(|(id, upload)| {
+                format!("{:016x} sending {} to {}", id, upload.path.display(),
+                        upload.recipient.to_base58())
+            })
+            .collect::<Vec<_>>();
+        for (&(ref sender, id), download) in inner.downloads.iter() {
+            let state = match download.state {
+                DownloadState::Offered => "offered".to_owned(),
+                DownloadState::Connecting(_) => "connecting".to_owned(),
+                DownloadState::InProgress => "receiving".to_owned(),
+                DownloadState::Interrupted => "interrupted".to_owned(),
+                DownloadState::Completed(ref path) => format!("saved as {}", path.display()),
+            };
+            lines.push(format!(
+                "{:016x} {} from {}: {}/{} bytes, {}",
+                id,
+                download.offer.name,
+                sender.to_base58(),
+                download.received,
+                download.offer.size,
+                state
+            ));
+        }
+        lines
+    }
+
+    /// Prepares the download of `id` from `sender`, which must have been accepted. Returns the
+    /// file to write to, the request to send to the sender, and whether the whole file has
+    /// already been received.
+    ///
+    /// Once the download is in progress, failures interrupt it.
+    fn start_download(&self, sender: &PeerId, id: u64) -> Result<(File, Request, bool), IoError> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let key = (sender.clone(), id);
+        let download = inner
+            .downloads
+            .get_mut(&key)
+            .ok_or_else(|| IoError::new(IoErrorKind::NotFound, TransferError::UnknownOffer))?;
+        match download.state {
+            DownloadState::Connecting(_) => download.state = DownloadState::InProgress,
+            _ => return Err(IoError::new(IoErrorKind::Other, "the download isn't starting")),
+        }
+
+        // Resume after the last complete chunk.
+        let part_path = part_path(&inner.download_dir, sender, &download.offer);
+        let resumed = fs::create_dir_all(&inner.download_dir).and_then(|()| {
+            let file = OpenOptions::new().create(true).append(true).open(&part_path)?;
+            let len = file.metadata()?.len().min(download.offer.size);
+            let start_chunk = len / CHUNK_SIZE;
+            file.set_len(start_chunk * CHUNK_SIZE)?;
+            Ok((file, start_chunk))
+        });
+        let (file, start_chunk) = match resumed {
+            Ok(resumed) => resumed,
+            Err(err) => {
+                let msg = err.to_string();
+                interrupt(&self.events, key, download, err);
+                return Err(IoError::new(IoErrorKind::Other, msg));
+            }
+        };
+
+        download.received = start_chunk * CHUNK_SIZE;
+        let request = Request {
+            id: id,
+            start_chunk: start_chunk,
+        };
+        Ok((file, request, download.received == download.offer.size))
+    }
+
+    /// Records that a chunk of `len` bytes of `id` has been written. Returns the total number
+    /// of bytes received, and the size of the file.
+    fn add_received(&self, sender: &PeerId, id: u64, len: u64) -> Result<(u64, u64), IoError> {
+        let mut inner = self.inner.lock().unwrap();
+        let download = inner
+            .downloads
+            .get_mut(&(sender.clone(), id))
+            .ok_or_else(|| IoError::new(IoErrorKind::NotFound, TransferError::UnknownOffer))?;
+        download.received += len;
+        if download.received > download.offer.size {
+            return Err(IoError::new(IoErrorKind::InvalidData, "received more than the offer"));
+        }
+        Ok((download.received, download.offer.size))
+    }
+
+    /// Called once all the chunks of `id` have been received. Verifies the file, away from the
+    /// event loop, and moves it to its final location.
+    fn finish_download(&self, sender: PeerId, id: u64) -> Box<Future<Item = (), Error = IoError>> {
+        let part_path = {
+            let inner = self.inner.lock().unwrap();
+            match inner.downloads.get(&(sender.clone(), id)) {
+                Some(download) => part_path(&inner.download_dir, &sender, &download.offer),
+                None => {
+                    let err = IoError::new(IoErrorKind::NotFound, TransferError::UnknownOffer);
+                    return Box::new(future::err(err));
+                }
+            }
+        };
+
+        let transfers = self.clone();
+        Box::new(hash_file_in_background(part_path.clone()).and_then(move |multihash| {
+            let mut inner = transfers.inner.lock().unwrap();
+            let inner = &mut *inner;
+            let key = (sender, id);
+            let download = inner
+                .downloads
+                .get_mut(&key)
+                .ok_or_else(|| IoError::new(IoErrorKind::NotFound, TransferError::UnknownOffer))?;
+            let (sender, id) = key;
+
+            if multihash != download.offer.multihash {
+                // There's no way to know which chunk is wrong, so we start again from scratch.
+                fs::remove_file(&part_path)?;
+                download.state = DownloadState::Interrupted;
+                download.received = 0;
+                let _ = transfers.events.unbounded_send(Event::Corrupted {
+                    sender: sender,
+                    id: id,
+                });
+                return Ok(());
+            }
+
+            let path = free_path(&inner.download_dir, &download.offer.name);
+            fs::rename(&part_path, &path)?;
+            download.state = DownloadState::Completed(path.clone());
+            let _ = transfers.events.unbounded_send(Event::Completed {
+                sender: sender,
+                id: id,
+                path: path,
+            });
+            Ok(())
+        }))
+    }
+
+    /// Records that the download of `id` stopped because of `error`, unless it was neither
+    /// starting nor in progress.
+    pub fn interrupt_download(&self, sender: &PeerId, id: u64, error: IoError) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (sender.clone(), id);
+        if let Some(download) = inner.downloads.get_mut(&key) {
+            interrupt(&self.events, key, download, error);
+        }
+    }
+
+    /// Opens the file of the upload `id` for `remote`, positioned at the start of `start_chunk`.
+    fn open_upload(&self, id: u64, start_chunk: u64, remote: &PeerId) -> Result<File, IoError> {
+        let inner = self.inner.lock().unwrap();
+        let upload = match inner.uploads.get(&id) {
+            Some(upload) if upload.recipient == *remote => upload,
+            _ => return Err(IoError::new(IoErrorKind::PermissionDenied, "no such offer")),
+        };
+        let mut file = File::open(&upload.path)?;
+        file.seek(SeekFrom::Start(start_chunk * CHUNK_SIZE))?;
+        Ok(file)
+    }
+}
+
+/// Marks `download` as interrupted and reports it, unless it was neither starting nor in
+/// progress.
+fn interrupt(
+    events: &mpsc::UnboundedSender<Event>,
+    (sender, id): (PeerId, u64),
+    download: &mut Download,
+    error: IoError,
+) {
+    match download.state {
+        DownloadState::Connecting(_) | DownloadState::InProgress => {}
+        _ => return,
+    }
+    download.state = DownloadState::Interrupted;
+    let _ = events.unbounded_send(Event::Interrupted {
+        sender: sender,
+        id: id,
+        received: download.received,
+        error: error,
+    });
+}
+
+/// Sent by the recipient to ask for a file. Encoded as the identifier of the offer and the
+/// index of the first chunk to send, both as big endian `u64`s.
+struct Request {
+    id: u64,
+    start_chunk: u64,
+}
+
+impl Request {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16);
+        push_u64(&mut out, self.id);
+        push_u64(&mut out, self.start_chunk);
+        out
+    }
+
+    fn decode(data: &[u8]) -> Option<Request> {
+        if data.len() != 16 {
+            return None;
+        }
+        Some(Request {
+            id: read_u64(&data[0..8]),
+            start_chunk: read_u64(&data[8..16]),
+        })
+    }
+}
+
+/// Upgrade that negotiates the protocol on a substream.
+///
+/// When listening, we send the files we offered. When dialing, we download the file passed to
+/// `with_download`.
+#[derive(Clone)]
+pub struct FileTransferUpgrade {
+    transfers: Transfers,
+    download: Option<(PeerId, u64)>,
+}
+
+impl FileTransferUpgrade {
+    /// Creates an upgrade that sends the files offered through `transfers`.
+    pub fn new(transfers: Transfers) -> FileTransferUpgrade {
+        FileTransferUpgrade {
+            transfers: transfers,
+            download: None,
+        }
+    }
+
+    /// Returns an upgrade that downloads the file `id` of `sender` when dialing. The offer must
+    /// have been accepted with `Transfers::accept`.
+    pub fn with_download(&self, sender: PeerId, id: u64) -> FileTransferUpgrade {
+        FileTransferUpgrade {
+            transfers: self.transfers.clone(),
+            download: Some((sender, id)),
+        }
+    }
+}
+
+impl<C> ConnectionUpgrade<C> for FileTransferUpgrade
+where
+    C: AsyncRead + AsyncWrite + 'static,
+{
+    type NamesIter = iter::Once<(Bytes, ())>;
+    type UpgradeIdentifier = ();
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        iter::once((Bytes::from(PROTOCOL_NAME), ()))
+    }
+
+    type Output = Box<Future<Item = (), Error = IoError>>;
+    type Future = future::FutureResult<Self::Output, IoError>;
+
+    fn upgrade(
+        self,
+        socket: C,
+        _: (),
+        endpoint: Endpoint,
+        remote_addr: &Multiaddr,
+    ) -> Self::Future {
+        let remote = match peer_addr::peer_id_of(remote_addr) {
+            Some(peer_id) => peer_id,
+            None => {
+                let msg = "the address of the remote doesn't contain its PeerId";
+                return future::err(IoError::new(IoErrorKind::InvalidInput, msg));
+            }
+        };
+        let framed = Framed::<_, Vec<u8>>::new(socket);
+        let transfers = self.transfers;
+
+        let future: Self::Output = match (endpoint, self.download) {
+            (Endpoint::Dialer, Some((sender, id))) => {
+                if remote != sender {
+                    let msg = format!("{} isn't the sender of the file", remote.to_base58());
+                    return future::err(IoError::new(IoErrorKind::Other, msg));
+                }
+                let (file, request, complete) = match transfers.start_download(&sender, id) {
+                    Ok(download) => download,
+                    Err(err) => return future::err(err),
+                };
+
+                // Nothing is left to receive for empty files, or if the transfer was interrupted
+                // just before the end.
+                let received: Box<Future<Item = (), Error = IoError>> = if complete {
+                    Box::new(future::ok(()))
+                } else {
+                    let transfers = transfers.clone();
+                    let sender = sender.clone();
+                    Box::new(framed.send(request.encode()).and_then(move |framed| {
+                        receive_chunks(transfers, sender, id, framed, file)
+                    }))
+                };
+
+                let transfers2 = transfers.clone();
+                Box::new(
+                    received
+                        .and_then({
+                            let sender = sender.clone();
+                            move |()| transfers2.finish_download(sender, id)
+                        })
+                        .then(move |result| {
+                            if let Err(err) = result {
+                                transfers.interrupt_download(&sender, id, err);
+                            }
+                            Ok(())
+                        }),
+                )
+            }
+            (Endpoint::Dialer, None) => {
+                let msg = "opened a file transfer substream without a file to download";
+                Box::new(future::err(IoError::new(IoErrorKind::Other, msg)))
+            }
+            (Endpoint::Listener, _) => Box::new(
+                framed
+                    .into_future()
+                    .map_err(|(err, _)| err)
+                    .and_then(move |(frame, framed)| {
+                        let request = frame
+                            .and_then(|frame| Request::decode(&frame))
+                            .ok_or_else(|| {
+                                IoError::new(IoErrorKind::InvalidData, "invalid request")
+                            })?;
+                        let file =
+                            transfers.open_upload(request.id, request.start_chunk, &remote)?;
+                        Ok((ChunkReader { file: file }, framed))
+                    })
+                    .and_then(|(chunks, framed)| framed.send_all(chunks))
+                    .map(|_| ()),
+            ),
+        };
+
+        future::ok(future)
+    }
+}
+
+/// Writes the chunks that the sender sends on `framed` to `file`, until the whole file has been
+/// received.
+fn receive_chunks<S>(
+    transfers: Transfers,
+    sender: PeerId,
+    id: u64,
+    framed: S,
+    file: File,
+) -> impl Future<Item = (), Error = IoError>
+where
+    S: Stream<Item = BytesMut, Error = IoError>,
+{
+    future::loop_fn((framed, file), move |(framed, mut file)| {
+        let transfers = transfers.clone();
+        let sender = sender.clone();
+        framed
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(move |(frame, framed)| {
+                let frame = frame.ok_or_else(|| {
+                    IoError::new(IoErrorKind::UnexpectedEof, "the sender closed the substream")
+                })?;
+                let len = write_chunk(&mut file, &frame)?;
+                let (received, size) = transfers.add_received(&sender, id, len)?;
+                if received < size {
+                    return Ok(Loop::Continue((framed, file)));
+                }
+                file.sync_data()?;
+                Ok(Loop::Break(()))
+            })
+    })
+}
+
+/// Checks the hash of a chunk received from the sender and appends it to `file`. Returns the
+/// length of the chunk.
+fn write_chunk(file: &mut File, frame: &BytesMut) -> Result<u64, IoError> {
+    if frame.len() < HASH_LEN || frame.len() > HASH_LEN + CHUNK_SIZE as usize {
+        return Err(IoError::new(IoErrorKind::InvalidData, "invalid chunk"));
+    }
+    let (hash, data) = frame.split_at(HASH_LEN);
+    if Sha256::digest(data).as_slice() != hash {
+        return Err(IoError::new(IoErrorKind::InvalidData, "chunk doesn't match its hash"));
+    }
+    file.write_all(data)?;
+    Ok(data.len() as u64)
+}
+
+/// Stream of the frames that contain the chunks of a file, from its current position to its
+/// end.
+struct ChunkReader {
+    file: File,
+}
+
+impl Stream for ChunkReader {
+    type Item = Vec<u8>;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, IoError> {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+        (&mut self.file).take(CHUNK_SIZE).read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            return Ok(Async::Ready(None));
+        }
+
+        let mut frame = Sha256::digest(&chunk).to_vec();
+        frame.extend_from_slice(&chunk);
+        Ok(Async::Ready(Some(frame)))
+    }
+}
+
+/// Hashes the file at `path` on a thread of its own, as reading a large file would block the
+/// event loop.
+fn hash_file_in_background(path: PathBuf) -> impl Future<Item = Vec<u8>, Error = IoError> {
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = tx.send(hash_file(&path));
+    });
+    rx.map_err(|_| IoError::new(IoErrorKind::Other, "the hashing thread panicked"))
+        .and_then(|result| result)
+}
+
+/// Returns the SHA-256 multihash of the content of the file at `path`.
+fn hash_file(path: &Path) -> Result<Vec<u8>, IoError> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::default();
+    let mut buffer = vec![0; CHUNK_SIZE as usize];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            n => hasher.input(&buffer[..n]),
+        }
+    }
+
+    let mut multihash = vec![SHA2_256_CODE, HASH_LEN as u8];
+    multihash.extend_from_slice(&hasher.result());
+    Ok(multihash)
+}
+
+/// Path of the file where the chunks of `offer` are written while we receive them.
+fn part_path(download_dir: &Path, sender: &PeerId, offer: &Offer) -> PathBuf {
+    download_dir.join(format!(".{}-{:016x}.part", sender.to_base58(), offer.id))
+}
+
+/// Returns a path in `dir` for a file called `name` that doesn't exist yet, by appending a number
+/// to the name if needed.
+///
+/// Only the last component of `name` is used, so that the sender can't make us write outside of
+/// `dir`.
+fn free_path(dir: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("download");
+    let mut path = dir.join(name);
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("{}.{}", name, n));
+        n += 1;
+    }
+    path
+}
+
+/// Error when accepting an offer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransferError {
+    /// Nobody offered us a file with this identifier.
+    UnknownOffer,
+    /// Several nodes offered a file with this identifier.
+    Ambiguous,
+    /// We're already downloading the file.
+    InProgress,
+    /// We already downloaded the file.
+    Completed,
+}
+
+impl fmt::Display for TransferError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl Error for TransferError {
+    #[inline]
+    fn description(&self) -> &str {
+        match *self {
+            TransferError::UnknownOffer => "no file was offered with this identifier",
+            TransferError::Ambiguous => "several nodes offered a file with this identifier",
+            TransferError::InProgress => "the file is already being downloaded",
+            TransferError::Completed => "the file has already been downloaded",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_common::key::{KeyType, Keypair};
+
+    fn random_peer_id() -> PeerId {
+        Keypair::generate(KeyType::Ed25519).unwrap().peer_id()
+    }
+
+    fn offer(id: u64) -> Offer {
+        Offer {
+            id: id,
+            name: "notes.txt".to_owned(),
+            size: 42,
+            multihash: vec![0x12, 0x20, 1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn offer_round_trip() {
+        let offer = offer(7);
+        assert_eq!(Offer::decode(&offer.encode()), Some(offer.clone()));
+        let encoded = offer.encode();
+        assert_eq!(Offer::decode(&encoded[..17]), None);
+    }
+
+    #[test]
+    fn offer_cannot_be_accepted_twice() {
+        let (transfers, _events) = Transfers::new(PathBuf::from("downloads"));
+        let alice = random_peer_id();
+        transfers.on_offer(alice.clone(), offer(7));
+        assert_eq!(transfers.accept(8, None), Err(TransferError::UnknownOffer));
+        assert_eq!(transfers.accept(7, None), Ok(alice));
+        assert_eq!(transfers.accept(7, None), Err(TransferError::InProgress));
+    }
+
+    #[test]
+    fn identifier_offered_by_several_nodes_needs_the_sender() {
+        let (transfers, _events) = Transfers::new(PathBuf::from("downloads"));
+        let (alice, mallory) = (random_peer_id(), random_peer_id());
+        transfers.on_offer(alice.clone(), offer(7));
+        transfers.on_offer(mallory.clone(), offer(7));
+        assert_eq!(transfers.accept(7, None), Err(TransferError::Ambiguous));
+        assert_eq!(transfers.accept(7, Some(&mallory)), Ok(mallory));
+    }
+
+    #[test]
+    fn repeated_offer_keeps_the_download_state() {
+        let (transfers, _events) = Transfers::new(PathBuf::from("downloads"));
+        let alice = random_peer_id();
+        transfers.on_offer(alice.clone(), offer(7));
+        assert!(transfers.accept(7, None).is_ok());
+        transfers.on_offer(alice.clone(), offer(7));
+        assert_eq!(transfers.accept(7, None), Err(TransferError::InProgress));
+    }
+}
@@ -0,0 +1,933 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Mesh-based publish-subscribe, in the style of gossipsub.
+//!
+//! Floodsub sends every message to every node subscribed to its topic, which doesn't scale
+//! beyond a few dozens of nodes. Instead, for each topic we're subscribed to, this router only
+//! forwards messages to a *mesh*: a small random subset of the nodes subscribed to the topic,
+//! whose size is kept between `mesh_n_low` and `mesh_n_high`. Nodes join and leave each other's
+//! mesh with `GRAFT` and `PRUNE` messages.
+//!
+//! To make up for the nodes that aren't in the mesh, we regularly send them *gossip*: an `IHAVE`
+//! message that lists the messages we've seen recently. A node that missed one of them asks for
+//! it with an `IWANT` message.
+//!
+//! When we publish on a topic we aren't subscribed to, the message is sent to a *fanout* set of
+//! nodes of that topic, which is kept as long as we keep publishing on it.
+//!
+//! The mesh and the fanout are maintained by a *heartbeat* that runs every `heartbeat_interval`.
+//!
+//! Each message is identified by its author and sequence number. The identifiers of the messages
+//! we've seen are kept in a bounded `SeenCache` (see `chat_common::dedup`), and a duplicate is
+//! neither delivered nor forwarded again. Messages also carry the number of hops they can still
+//! travel, starting at `max_hops`, so that a message can't circulate forever even once its
+//! identifier has left the caches.
+//!
+//! The API mimics the one of floodsub: `GossipSubUpgrade` is the upgrade to apply to substreams,
+//! `GossipSubReceiver` is the stream of messages we receive, and `GossipSubController` lets us
+//! subscribe and publish. The messages are the same `Message` objects as floodsub. Note however
+//! that the wire format is our own, and isn't compatible with the gossipsub implementations of
+//! other libp2p libraries.
+
+mod rpc;
+
+use self::rpc::{MessageId, Rpc};
+use bytes::Bytes;
+use chat_common::dedup::{DedupConfig, SeenCache};
+use chat_common::peer_addr;
+use chat_common::rooms::PubSub;
+use futures::sync::mpsc;
+use futures::{future, Async, Future, Poll, Stream};
+use libp2p_core::{ConnectionUpgrade, Endpoint, Multiaddr, PeerId};
+use libp2p_floodsub::{Message, Topic, TopicHash};
+use rand;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::iter;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_io::codec::length_delimited::Framed;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::{Interval, Timer};
+
+/// Name of the protocol, as negotiated on substreams.
+pub const PROTOCOL_NAME: &str = "/rustfest-chat/meshsub/1.1.0";
+
+/// Default number of hops a message can travel.
+pub const DEFAULT_MAX_HOPS: u8 = 16;
+
+/// Publish-subscribe protocol that the node uses for chatting.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PubSubRouter {
+    /// Floodsub. This is the default, as this is what chapter 2 supports.
+    FloodSub,
+    /// The mesh-based router of this module.
+    GossipSub,
+}
+
+impl Default for PubSubRouter {
+    #[inline]
+    fn default() -> PubSubRouter {
+        PubSubRouter::FloodSub
+    }
+}
+
+impl FromStr for PubSubRouter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<PubSubRouter, ()> {
+        match s {
+            "floodsub" => Ok(PubSubRouter::FloodSub),
+            "gossipsub" => Ok(PubSubRouter::GossipSub),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Parameters of the router.
+#[derive(Debug, Clone)]
+pub struct GossipSubConfig {
+    /// Number of nodes we try to have in the mesh of each topic.
+    pub mesh_n: usize,
+    /// Below this number of nodes in a mesh, the heartbeat adds nodes to it.
+    pub mesh_n_low: usize,
+    /// Above this number of nodes in a mesh, the heartbeat removes nodes from it.
+    pub mesh_n_high: usize,
+    /// Number of nodes outside of the mesh that we send gossip to at each heartbeat.
+    pub gossip_lazy: usize,
+    /// Interval between two heartbeats.
+    pub heartbeat_interval: Duration,
+    /// Number of heartbeats during which we keep a message in order to answer `IWANT` requests.
+    pub history_length: usize,
+    /// Number of heartbeats during which we advertise a message in our gossip.
+    pub history_gossip: usize,
+    /// How long we keep the fanout of a topic after we last published on it.
+    pub fanout_ttl: Duration,
+    /// Size and expiry of the cache of the messages we've seen, in order to not process them
+    /// twice.
+    pub seen: DedupConfig,
+    /// Number of hops the messages we publish can travel.
+    pub max_hops: u8,
+}
+
+impl Default for GossipSubConfig {
+    fn default() -> GossipSubConfig {
+        GossipSubConfig {
+            mesh_n: 6,
+            mesh_n_low: 4,
+            mesh_n_high: 12,
+            gossip_lazy: 6,
+            heartbeat_interval: Duration::from_secs(1),
+            history_length: 5,
+            history_gossip: 3,
+            fanout_ttl: Duration::from_secs(60),
+            seen: DedupConfig::default(),
+            max_hops: DEFAULT_MAX_HOPS,
+        }
+    }
+}
+
+/// Upgrade that negotiates the protocol on a substream.
+///
+/// The address of the remote must end with `/ipfs/<peer-id>`. The output of the upgrade is a
+/// future that must be driven to completion for as long as the substream is open.
+#[derive(Clone)]
+pub struct GossipSubUpgrade {
+    inner: Arc<Inner>,
+}
+
+impl GossipSubUpgrade {
+    /// Creates the router.
+    ///
+    /// The heartbeat is driven by the returned `GossipSubReceiver`, which therefore must be
+    /// polled even if we don't care about the messages.
+    pub fn new(
+        local_peer_id: PeerId,
+        config: GossipSubConfig,
+        timer: &Timer,
+    ) -> (GossipSubUpgrade, GossipSubReceiver) {
+        let (output_tx, output_rx) = mpsc::unbounded();
+        let heartbeat = timer.interval(config.heartbeat_interval);
+        let inner = Arc::new(Inner {
+            local_peer_id: local_peer_id,
+            state: Mutex::new(State {
+                peers: HashMap::new(),
+                subscribed: HashSet::new(),
+                mesh: HashMap::new(),
+                fanout: HashMap::new(),
+                cache: MessageCache::new(),
+                seen: SeenCache::new(config.seen),
+            }),
+            config: config,
+            output_tx: output_tx,
+            next_substream_id: AtomicUsize::new(0),
+            next_sequence_number: AtomicUsize::new(rand::random()),
+        });
+
+        let receiver = GossipSubReceiver {
+            inner: inner.clone(),
+            heartbeat: heartbeat,
+            messages: output_rx,
+        };
+        (GossipSubUpgrade { inner: inner }, receiver)
+    }
+}
+
+impl<C> ConnectionUpgrade<C> for GossipSubUpgrade
+where
+    C: AsyncRead + AsyncWrite + 'static,
+{
+    type NamesIter = iter::Once<(Bytes, ())>;
+    type UpgradeIdentifier = ();
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        iter::once((Bytes::from(PROTOCOL_NAME), ()))
+    }
+
+    type Output = Box<Future<Item = (), Error = IoError>>;
+    type Future = future::FutureResult<Self::Output, IoError>;
+
+    fn upgrade(self, socket: C, _: (), _: Endpoint, remote_addr: &Multiaddr) -> Self::Future {
+        let remote = match peer_addr::peer_id_of(remote_addr) {
+            Some(peer_id) => peer_id,
+            None => {
+                let msg = "the address of the remote doesn't contain its PeerId";
+                return future::err(IoError::new(IoErrorKind::InvalidInput, msg));
+            }
+        };
+
+        let (tx, rx) = mpsc::unbounded();
+        let substream_id = self.inner.add_substream(remote.clone(), tx);
+        let (sink, stream) = Framed::<_, Vec<u8>>::new(socket).split();
+
+        let incoming = stream.for_each({
+            let inner = self.inner.clone();
+            let remote = remote.clone();
+            move |frame| {
+                inner.handle_rpc(&remote, Rpc::decode(&frame)?);
+                Ok(())
+            }
+        });
+        let outgoing = rx
+            .map(|rpc: Rpc| rpc.encode())
+            .map_err(|()| -> IoError { unreachable!() })
+            .forward(sink)
+            .map(|_| ());
+
+        let inner = self.inner;
+        let future: Self::Output = Box::new(incoming.select(outgoing).then(move |result| {
+            inner.remove_substream(&remote, substream_id);
+            result.map(|_| ()).map_err(|(err, _)| err)
+        }));
+        future::ok(future)
+    }
+}
+
+/// Stream of the messages we receive on the topics we're subscribed to.
+pub struct GossipSubReceiver {
+    inner: Arc<Inner>,
+    heartbeat: Interval,
+    messages: mpsc::UnboundedReceiver<Message>,
+}
+
+impl Stream for GossipSubReceiver {
+    type Item = Message;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Option<Message>, IoError> {
+        loop {
+            match self.heartbeat.poll() {
+                Ok(Async::Ready(Some(()))) => self.inner.heartbeat(),
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                Err(err) => return Err(IoError::new(IoErrorKind::Other, err)),
+            }
+        }
+
+        self.messages
+            .poll()
+            .map_err(|()| -> IoError { unreachable!() })
+    }
+}
+
+/// Lets us subscribe to topics and publish messages.
+#[derive(Clone)]
+pub struct GossipSubController {
+    inner: Arc<Inner>,
+}
+
+impl GossipSubController {
+    /// Creates a controller for the router of `upgrade`.
+    #[inline]
+    pub fn new(upgrade: &GossipSubUpgrade) -> GossipSubController {
+        GossipSubController {
+            inner: upgrade.inner.clone(),
+        }
+    }
+
+    /// Starts receiving the messages of `topic`.
+    ///
+    /// The nodes we've been publishing to on this topic, if any, become the initial mesh.
+    pub fn subscribe(&self, topic: &Topic) {
+        let mut guard = self.inner.state.lock().unwrap();
+        let state = &mut *guard;
+        let topic = topic.hash().clone();
+        if !state.subscribed.insert(topic.clone()) {
+            return;
+        }
+
+        for remote in state.peers.values() {
+            remote.send(Rpc::Subscribe(topic.clone()));
+        }
+
+        let mut mesh = state
+            .fanout
+            .remove(&topic)
+            .map(|fanout| fanout.peers)
+            .unwrap_or_default();
+        let missing = self.inner.config.mesh_n.saturating_sub(mesh.len());
+        for peer in state.peers_in_topic(&topic, &mesh, missing) {
+            mesh.insert(peer);
+        }
+        for peer in mesh.iter() {
+            state.send(peer, Rpc::Graft(topic.clone()));
+        }
+        state.mesh.insert(topic, mesh);
+    }
+
+    /// Stops receiving the messages of `topic`.
+    pub fn unsubscribe(&self, topic: &Topic) {
+        let mut guard = self.inner.state.lock().unwrap();
+        let state = &mut *guard;
+        let topic = topic.hash().clone();
+        if !state.subscribed.remove(&topic) {
+            return;
+        }
+
+        for remote in state.peers.values() {
+            remote.send(Rpc::Unsubscribe(topic.clone()));
+        }
+        for peer in state.mesh.remove(&topic).unwrap_or_default() {
+            state.send(&peer, Rpc::Prune(topic.clone()));
+        }
+    }
+
+    /// Publishes `data` on `topic`. We don't need to be subscribed to the topic.
+    pub fn publish(&self, topic: &Topic, data: Vec<u8>) {
+        let sequence_number =
+            self.inner.next_sequence_number.fetch_add(1, Ordering::Relaxed) as u64;
+        let message = Message {
+            source: self.inner.local_peer_id.clone(),
+            data: data,
+            sequence_number: (0..8).rev().map(|n| (sequence_number >> (n * 8)) as u8).collect(),
+            topics: vec![topic.hash().clone()],
+        };
+        let id = MessageId::of(&message);
+        let now = Instant::now();
+
+        let hops = self.inner.config.max_hops;
+
+        let mut guard = self.inner.state.lock().unwrap();
+        let state = &mut *guard;
+        state.seen.insert(id.clone());
+
+        let topic = topic.hash().clone();
+        if state.subscribed.contains(&topic) {
+            state.forward(&message, hops, None);
+        } else {
+            let peers = match state.fanout.get(&topic) {
+                Some(fanout) if !fanout.peers.is_empty() => fanout.peers.clone(),
+                _ => state
+                    .peers_in_topic(&topic, &HashSet::new(), self.inner.config.mesh_n)
+                    .into_iter()
+                    .collect(),
+            };
+            for peer in peers.iter() {
+                state.send(peer, Rpc::Publish(message.clone(), hops));
+            }
+            state.fanout.insert(topic, Fanout {
+                peers: peers,
+                last_publish: now,
+            });
+        }
+
+        state.cache.put(id, message, hops);
+    }
+}
+
+impl PubSub for GossipSubController {
+    #[inline]
+    fn subscribe(&self, topic: &Topic) {
+        GossipSubController::subscribe(self, topic)
+    }
+
+    #[inline]
+    fn unsubscribe(&self, topic: &Topic) {
+        GossipSubController::unsubscribe(self, topic)
+    }
+
+    #[inline]
+    fn publish(&self, topic: &Topic, data: Vec<u8>) {
+        GossipSubController::publish(self, topic, data)
+    }
+}
+
+/// State shared between the upgrade, the receiver and the controller.
+struct Inner {
+    local_peer_id: PeerId,
+    config: GossipSubConfig,
+    state: Mutex<State>,
+    /// Where to send the messages of the topics we're subscribed to.
+    output_tx: mpsc::UnboundedSender<Message>,
+    next_substream_id: AtomicUsize,
+    next_sequence_number: AtomicUsize,
+}
+
+struct State {
+    /// Nodes we have a substream with.
+    peers: HashMap<PeerId, RemotePeer>,
+    /// Topics we're subscribed to.
+    subscribed: HashSet<TopicHash>,
+    /// Mesh of each topic we're subscribed to.
+    mesh: HashMap<TopicHash, HashSet<PeerId>>,
+    /// Nodes we send our messages to, for each topic we publish on without being subscribed.
+    fanout: HashMap<TopicHash, Fanout>,
+    /// Recent messages, in order to answer `IWANT` requests.
+    cache: MessageCache,
+    /// Messages we've seen recently.
+    seen: SeenCache<MessageId>,
+}
+
+struct RemotePeer {
+    /// Substreams open with this node, oldest first. Both sides might have dialed each other, in
+    /// which case we send on the most recent one.
+    substreams: Vec<(usize, mpsc::UnboundedSender<Rpc>)>,
+    /// Topics the node is subscribed to.
+    topics: HashSet<TopicHash>,
+}
+
+impl RemotePeer {
+    fn send(&self, rpc: Rpc) {
+        if let Some(&(_, ref tx)) = self.substreams.last() {
+            let _ = tx.unbounded_send(rpc);
+        }
+    }
+}
+
+struct Fanout {
+    peers: HashSet<PeerId>,
+    last_publish: Instant,
+}
+
+impl Inner {
+    /// Records a new substream with `peer`, and returns an identifier for it.
+    fn add_substream(&self, peer: PeerId, tx: mpsc::UnboundedSender<Rpc>) -> usize {
+        let id = self.next_substream_id.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+
+        // Tell the remote which topics we're subscribed to.
+        for topic in state.subscribed.iter() {
+            let _ = tx.unbounded_send(Rpc::Subscribe(topic.clone()));
+        }
+
+        state
+            .peers
+            .entry(peer)
+            .or_insert_with(|| RemotePeer {
+                substreams: Vec::new(),
+                topics: HashSet::new(),
+            })
+            .substreams
+            .push((id, tx));
+        id
+    }
+
+    /// Records that a substream with `peer` has been closed. Forgets about the node if it was
+    /// the last one.
+    fn remove_substream(&self, peer: &PeerId, substream_id: usize) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let closed = match state.peers.get_mut(peer) {
+            Some(remote) => {
+                remote.substreams.retain(|&(id, _)| id != substream_id);
+                remote.substreams.is_empty()
+            }
+            None => return,
+        };
+
+        if closed {
+            state.peers.remove(peer);
+            for mesh in state.mesh.values_mut() {
+                mesh.remove(peer);
+            }
+            for fanout in state.fanout.values_mut() {
+                fanout.peers.remove(peer);
+            }
+        }
+    }
+
+    /// Handles a message received from `from`.
+    fn handle_rpc(&self, from: &PeerId, rpc: Rpc) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        match rpc {
+            Rpc::Subscribe(topic) => {
+                if let Some(remote) = state.peers.get_mut(from) {
+                    remote.topics.insert(topic);
+                }
+            }
+            Rpc::Unsubscribe(topic) => {
+                if let Some(remote) = state.peers.get_mut(from) {
+                    remote.topics.remove(&topic);
+                }
+                if let Some(mesh) = state.mesh.get_mut(&topic) {
+                    mesh.remove(from);
+                }
+            }
+            Rpc::Publish(message, hops) => {
+                let id = MessageId::of(&message);
+                if !state.seen.insert(id.clone()) {
+                    return;
+                }
+
+                if message.topics.iter().any(|topic| state.subscribed.contains(topic)) {
+                    let _ = self.output_tx.unbounded_send(message.clone());
+                }
+                if hops == 0 {
+                    return;
+                }
+                state.forward(&message, hops - 1, Some(from));
+                state.cache.put(id, message, hops - 1);
+            }
+            Rpc::IHave(topic, ids) => {
+                if !state.subscribed.contains(&topic) {
+                    return;
+                }
+                let wanted = ids
+                    .into_iter()
+                    .filter(|id| !state.seen.contains(id))
+                    .collect::<Vec<_>>();
+                if !wanted.is_empty() {
+                    state.send(from, Rpc::IWant(wanted));
+                }
+            }
+            Rpc::IWant(ids) => {
+                for id in ids {
+                    if let Some(&(ref message, hops)) = state.cache.get(&id) {
+                        state.send(from, Rpc::Publish(message.clone(), hops));
+                    }
+                }
+            }
+            Rpc::Graft(topic) => {
+                if state.subscribed.contains(&topic) {
+                    state.mesh.entry(topic).or_insert_with(HashSet::new).insert(from.clone());
+                } else {
+                    state.send(from, Rpc::Prune(topic));
+                }
+            }
+            Rpc::Prune(topic) => {
+                if let Some(mesh) = state.mesh.get_mut(&topic) {
+                    mesh.remove(from);
+                }
+            }
+        }
+    }
+
+    /// Maintains the meshes and the fanouts, and sends gossip.
+    fn heartbeat(&self) {
+        let config = &self.config;
+        let now = Instant::now();
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+
+        let topics = state.subscribed.iter().cloned().collect::<Vec<_>>();
+        for topic in topics {
+            let mut mesh = state.mesh.remove(&topic).unwrap_or_default();
+            mesh.retain(|peer| state.is_subscribed(peer, &topic));
+
+            if mesh.len() < config.mesh_n_low {
+                let missing = config.mesh_n.saturating_sub(mesh.len());
+                for peer in state.peers_in_topic(&topic, &mesh, missing) {
+                    state.send(&peer, Rpc::Graft(topic.clone()));
+                    mesh.insert(peer);
+                }
+            } else if mesh.len() > config.mesh_n_high {
+                let excess = mesh.len().saturating_sub(config.mesh_n);
+                for peer in rand::sample(&mut rand::thread_rng(), mesh.clone(), excess) {
+                    state.send(&peer, Rpc::Prune(topic.clone()));
+                    mesh.remove(&peer);
+                }
+            }
+
+            state.mesh.insert(topic, mesh);
+        }
+
+        state
+            .fanout
+            .retain(|_, fanout| now.duration_since(fanout.last_publish) < config.fanout_ttl);
+        for (topic, fanout) in state.fanout.iter_mut() {
+            let peers = &state.peers;
+            fanout.peers.retain(|peer| {
+                peers.get(peer).map_or(false, |remote| remote.topics.contains(topic))
+            });
+        }
+
+        // Advertise the recent messages of each topic to a few nodes that don't receive them
+        // through us.
+        let fanouts = state.fanout.iter().map(|(topic, fanout)| (topic, &fanout.peers));
+        for (topic, excluded) in state.mesh.iter().chain(fanouts) {
+            let ids = state.cache.gossip_ids(topic, config.history_gossip);
+            if ids.is_empty() {
+                continue;
+            }
+            for peer in state.peers_in_topic(topic, excluded, config.gossip_lazy) {
+                state.send(&peer, Rpc::IHave(topic.clone(), ids.clone()));
+            }
+        }
+
+        state.cache.shift(config.history_length);
+        state.seen.expire();
+    }
+}
+
+impl State {
+    fn send(&self, peer: &PeerId, rpc: Rpc) {
+        if let Some(remote) = self.peers.get(peer) {
+            remote.send(rpc);
+        }
+    }
+
+    fn is_subscribed(&self, peer: &PeerId, topic: &TopicHash) -> bool {
+        self.peers
+            .get(peer)
+            .map_or(false, |remote| remote.topics.contains(topic))
+    }
+
+    /// Picks up to `amount` random nodes subscribed to `topic`, excluding the ones in `excluded`.
+    fn peers_in_topic(
+        &self,
+        topic: &TopicHash,
+        excluded: &HashSet<PeerId>,
+        amount: usize,
+    ) -> Vec<PeerId> {
+        let candidates = self
+            .peers
+            .iter()
+            .filter(|&(peer, remote)| remote.topics.contains(topic) && !excluded.contains(peer))
+            .map(|(peer, _)| peer.clone());
+        rand::sample(&mut rand::thread_rng(), candidates, amount)
+    }
+
+    /// Sends `message` to the meshes of its topics, except to its author and to `except`. The
+    /// recipients can forward it `hops` more times.
+    fn forward(&self, message: &Message, hops: u8, except: Option<&PeerId>) {
+        let mut targets = HashSet::new();
+        for topic in message.topics.iter() {
+            if let Some(mesh) = self.mesh.get(topic) {
+                targets.extend(mesh.iter().cloned());
+            }
+        }
+        targets.remove(&message.source);
+        if let Some(except) = except {
+            targets.remove(except);
+        }
+
+        for peer in targets {
+            self.send(&peer, Rpc::Publish(message.clone(), hops));
+        }
+    }
+}
+
+/// Recent messages, grouped by the heartbeat during which we saw them.
+struct MessageCache {
+    /// The messages, with the number of hops they can still travel once sent.
+    messages: HashMap<MessageId, (Message, u8)>,
+    /// Identifiers and topics of the messages of each heartbeat, most recent first.
+    history: VecDeque<Vec<(MessageId, Vec<TopicHash>)>>,
+}
+
+impl MessageCache {
+    fn new() -> MessageCache {
+        let mut history = VecDeque::new();
+        history.push_front(Vec::new());
+        MessageCache {
+            messages: HashMap::new(),
+            history: history,
+        }
+    }
+
+    fn put(&mut self, id: MessageId, message: Message, hops: u8) {
+        self.history[0].push((id.clone(), message.topics.clone()));
+        self.messages.insert(id, (message, hops));
+    }
+
+    fn get(&self, id: &MessageId) -> Option<&(Message, u8)> {
+        self.messages.get(id)
+    }
+
+    /// Returns the messages of `topic` seen during the last `heartbeats` heartbeats.
+    fn gossip_ids(&self, topic: &TopicHash, heartbeats: usize) -> Vec<MessageId> {
+        self.history
+            .iter()
+            .take(heartbeats)
+            .flat_map(|window| window.iter())
+            .filter(|&&(_, ref topics)| topics.contains(topic))
+            .map(|&(ref id, _)| id.clone())
+            .collect()
+    }
+
+    /// Starts a new heartbeat, and forgets the messages older than `history_length` heartbeats.
+    fn shift(&mut self, history_length: usize) {
+        self.history.push_front(Vec::new());
+        while self.history.len() > history_length.max(1) {
+            if let Some(window) = self.history.pop_back() {
+                for (id, _) in window {
+                    self.messages.remove(&id);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_common::key::{KeyType, Keypair};
+    use libp2p_floodsub::TopicBuilder;
+
+    fn random_peer_id() -> PeerId {
+        Keypair::generate(KeyType::Ed25519).unwrap().peer_id()
+    }
+
+    fn router(config: GossipSubConfig) -> (GossipSubController, GossipSubReceiver) {
+        let timer = Timer::default();
+        let (upgrade, receiver) = GossipSubUpgrade::new(random_peer_id(), config, &timer);
+        (GossipSubController::new(&upgrade), receiver)
+    }
+
+    /// Opens a substream with a new node, subscribed to `topics`.
+    fn connect(
+        controller: &GossipSubController,
+        topics: &[&Topic],
+    ) -> (PeerId, mpsc::UnboundedReceiver<Rpc>) {
+        let peer = random_peer_id();
+        let (tx, rx) = mpsc::unbounded();
+        controller.inner.add_substream(peer.clone(), tx);
+        for topic in topics {
+            controller.inner.handle_rpc(&peer, Rpc::Subscribe(topic.hash().clone()));
+        }
+        (peer, rx)
+    }
+
+    /// Returns the items that are waiting in `rx`.
+    fn sent<T>(rx: &mut mpsc::UnboundedReceiver<T>) -> Vec<T> {
+        let mut items = Vec::new();
+        future::poll_fn(|| {
+            while let Async::Ready(Some(item)) = rx.poll()? {
+                items.push(item);
+            }
+            Ok::<_, ()>(Async::Ready(()))
+        }).wait()
+            .unwrap();
+        items
+    }
+
+    fn encoded(rpcs: &[Rpc]) -> Vec<Vec<u8>> {
+        rpcs.iter().map(Rpc::encode).collect()
+    }
+
+    fn message(source: &PeerId, sequence_number: u8, topic: &Topic) -> Message {
+        Message {
+            source: source.clone(),
+            data: b"hello".to_vec(),
+            sequence_number: vec![sequence_number],
+            topics: vec![topic.hash().clone()],
+        }
+    }
+
+    #[test]
+    fn subscribe_grafts_the_subscribed_nodes() {
+        let (controller, _receiver) = router(GossipSubConfig::default());
+        let topic = TopicBuilder::new("room").build();
+        let (_, mut subscribed) = connect(&controller, &[&topic]);
+        let (_, mut other) = connect(&controller, &[]);
+
+        controller.subscribe(&topic);
+        let expected = [Rpc::Subscribe(topic.hash().clone()), Rpc::Graft(topic.hash().clone())];
+        assert_eq!(encoded(&sent(&mut subscribed)), encoded(&expected));
+        assert_eq!(encoded(&sent(&mut other)), encoded(&expected[..1]));
+
+        controller.unsubscribe(&topic);
+        let expected = [Rpc::Unsubscribe(topic.hash().clone()), Rpc::Prune(topic.hash().clone())];
+        assert_eq!(encoded(&sent(&mut subscribed)), encoded(&expected));
+        assert_eq!(encoded(&sent(&mut other)), encoded(&expected[..1]));
+    }
+
+    #[test]
+    fn new_substreams_learn_our_subscriptions() {
+        let (controller, _receiver) = router(GossipSubConfig::default());
+        let topic = TopicBuilder::new("room").build();
+        controller.subscribe(&topic);
+        let (_, mut rx) = connect(&controller, &[]);
+        assert_eq!(
+            encoded(&sent(&mut rx)),
+            encoded(&[Rpc::Subscribe(topic.hash().clone())])
+        );
+    }
+
+    #[test]
+    fn messages_are_delivered_and_forwarded_once() {
+        let (controller, mut receiver) = router(GossipSubConfig::default());
+        let topic = TopicBuilder::new("room").build();
+        let (author, mut author_rx) = connect(&controller, &[&topic]);
+        let (_, mut other_rx) = connect(&controller, &[&topic]);
+        controller.subscribe(&topic);
+        sent(&mut author_rx);
+        sent(&mut other_rx);
+
+        let msg = message(&author, 1, &topic);
+        controller.inner.handle_rpc(&author, Rpc::Publish(msg.clone(), 3));
+        controller.inner.handle_rpc(&author, Rpc::Publish(msg.clone(), 3));
+
+        let delivered = sent(&mut receiver.messages);
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0].data, msg.data);
+        assert!(sent(&mut author_rx).is_empty());
+        assert_eq!(encoded(&sent(&mut other_rx)), encoded(&[Rpc::Publish(msg, 2)]));
+    }
+
+    #[test]
+    fn messages_without_hops_left_are_not_forwarded() {
+        let (controller, mut receiver) = router(GossipSubConfig::default());
+        let topic = TopicBuilder::new("room").build();
+        let (author, _author_rx) = connect(&controller, &[&topic]);
+        let (_, mut other_rx) = connect(&controller, &[&topic]);
+        controller.subscribe(&topic);
+        sent(&mut other_rx);
+
+        controller.inner.handle_rpc(&author, Rpc::Publish(message(&author, 1, &topic), 0));
+        assert_eq!(sent(&mut receiver.messages).len(), 1);
+        assert!(sent(&mut other_rx).is_empty());
+    }
+
+    #[test]
+    fn messages_of_other_topics_are_not_delivered() {
+        let (controller, mut receiver) = router(GossipSubConfig::default());
+        let topic = TopicBuilder::new("room").build();
+        let (author, _author_rx) = connect(&controller, &[&topic]);
+
+        controller.inner.handle_rpc(&author, Rpc::Publish(message(&author, 1, &topic), 3));
+        assert!(sent(&mut receiver.messages).is_empty());
+    }
+
+    #[test]
+    fn ihave_and_iwant() {
+        let (controller, _receiver) = router(GossipSubConfig::default());
+        let topic = TopicBuilder::new("room").build();
+        let (author, mut author_rx) = connect(&controller, &[&topic]);
+        let (other, mut other_rx) = connect(&controller, &[]);
+        controller.subscribe(&topic);
+        sent(&mut author_rx);
+        sent(&mut other_rx);
+
+        // We ask for the messages we haven't seen.
+        let msg = message(&author, 1, &topic);
+        let ids = vec![MessageId::of(&msg)];
+        controller.inner.handle_rpc(&author, Rpc::IHave(topic.hash().clone(), ids.clone()));
+        assert_eq!(encoded(&sent(&mut author_rx)), encoded(&[Rpc::IWant(ids.clone())]));
+
+        // Once we have it, we no longer ask for it, and we send it to those who do.
+        controller.inner.handle_rpc(&author, Rpc::Publish(msg.clone(), 3));
+        controller.inner.handle_rpc(&author, Rpc::IHave(topic.hash().clone(), ids.clone()));
+        assert!(sent(&mut author_rx).is_empty());
+        controller.inner.handle_rpc(&other, Rpc::IWant(ids));
+        assert_eq!(encoded(&sent(&mut other_rx)), encoded(&[Rpc::Publish(msg, 2)]));
+    }
+
+    #[test]
+    fn graft_on_a_topic_we_are_not_in_is_pruned() {
+        let (controller, _receiver) = router(GossipSubConfig::default());
+        let topic = TopicBuilder::new("room").build();
+        let (peer, mut rx) = connect(&controller, &[&topic]);
+
+        controller.inner.handle_rpc(&peer, Rpc::Graft(topic.hash().clone()));
+        assert_eq!(encoded(&sent(&mut rx)), encoded(&[Rpc::Prune(topic.hash().clone())]));
+    }
+
+    #[test]
+    fn heartbeat_keeps_the_mesh_within_bounds() {
+        let config = GossipSubConfig {
+            mesh_n: 3,
+            mesh_n_low: 2,
+            mesh_n_high: 4,
+            ..GossipSubConfig::default()
+        };
+        let (controller, _receiver) = router(config);
+        let topic = TopicBuilder::new("room").build();
+        controller.subscribe(&topic);
+
+        let mut peers = (0..6).map(|_| connect(&controller, &[&topic])).collect::<Vec<_>>();
+        controller.inner.heartbeat();
+        let mesh_len = || controller.inner.state.lock().unwrap().mesh[topic.hash()].len();
+        assert_eq!(mesh_len(), 3);
+        let graft = Rpc::Graft(topic.hash().clone()).encode();
+        let grafted = peers
+            .iter_mut()
+            .map(|&mut (_, ref mut rx)| encoded(&sent(rx)))
+            .filter(|rpcs| rpcs.contains(&graft))
+            .count();
+        assert_eq!(grafted, 3);
+
+        // Nodes that graft us are added to the mesh, until the heartbeat prunes the excess.
+        for &(ref peer, _) in peers.iter() {
+            controller.inner.handle_rpc(peer, Rpc::Graft(topic.hash().clone()));
+        }
+        assert_eq!(mesh_len(), 6);
+        controller.inner.heartbeat();
+        assert_eq!(mesh_len(), 3);
+    }
+
+    #[test]
+    fn publishing_without_subscribing_uses_the_fanout() {
+        let (controller, _receiver) = router(GossipSubConfig::default());
+        let topic = TopicBuilder::new("room").build();
+        let (_, mut subscribed) = connect(&controller, &[&topic]);
+        let (_, mut other) = connect(&controller, &[]);
+
+        controller.publish(&topic, b"hello".to_vec());
+        let rpcs = sent(&mut subscribed);
+        assert_eq!(rpcs.len(), 1);
+        match rpcs[0] {
+            Rpc::Publish(ref message, hops) => {
+                assert_eq!(message.data, b"hello".to_vec());
+                assert_eq!(hops, DEFAULT_MAX_HOPS);
+            }
+            ref rpc => panic!("unexpected rpc: {:?}", rpc),
+        }
+        assert!(sent(&mut other).is_empty());
+
+        // The fanout becomes the mesh when we subscribe.
+        controller.subscribe(&topic);
+        assert_eq!(controller.inner.state.lock().unwrap().mesh[topic.hash()].len(), 1);
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Messages exchanged by gossipsub nodes, and their encoding.
+//!
+//! Each frame sent over the substream contains exactly one `Rpc`. The first byte of the frame
+//! identifies the kind of `Rpc`. Byte strings are prefixed with their length as a big endian
+//! `u16`, except for the data of a published message, which is prefixed with a big endian `u32`.
+//!
+//! A published message is preceded with a single byte: the number of hops it is still allowed to
+//! travel. Each node that forwards the message decrements it, and a message that arrives with
+//! zero hops left is delivered but not forwarded.
+
+use chat_common::codec::{invalid, push_short, push_u16, push_u32, Reader};
+use libp2p_core::PeerId;
+use libp2p_floodsub::{Message, TopicHash};
+use std::io::Error as IoError;
+
+/// Identifier of a message. Made of the `PeerId` of the author and the sequence number that the
+/// author assigned to the message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MessageId(Vec<u8>);
+
+impl MessageId {
+    /// Returns the identifier of `message`.
+    pub fn of(message: &Message) -> MessageId {
+        let mut id = message.source.as_bytes().to_vec();
+        id.extend_from_slice(&message.sequence_number);
+        MessageId(id)
+    }
+}
+
+/// A single message of the protocol.
+#[derive(Debug, Clone)]
+pub enum Rpc {
+    /// The sender is now subscribed to this topic.
+    Subscribe(TopicHash),
+    /// The sender is no longer subscribed to this topic.
+    Unsubscribe(TopicHash),
+    /// A message published on one or more topics, with the number of hops it can still travel.
+    Publish(Message, u8),
+    /// The sender has recently seen these messages of this topic.
+    IHave(TopicHash, Vec<MessageId>),
+    /// The sender would like us to send it these messages.
+    IWant(Vec<MessageId>),
+    /// The sender added us to its mesh of this topic.
+    Graft(TopicHash),
+    /// The sender removed us from its mesh of this topic.
+    Prune(TopicHash),
+}
+
+const SUBSCRIBE: u8 = 0;
+const UNSUBSCRIBE: u8 = 1;
+const PUBLISH: u8 = 2;
+const IHAVE: u8 = 3;
+const IWANT: u8 = 4;
+const GRAFT: u8 = 5;
+const PRUNE: u8 = 6;
+
+impl Rpc {
+    /// Encodes the message into a frame.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match *self {
+            Rpc::Subscribe(ref topic) => {
+                out.push(SUBSCRIBE);
+                push_topic(&mut out, topic);
+            }
+            Rpc::Unsubscribe(ref topic) => {
+                out.push(UNSUBSCRIBE);
+                push_topic(&mut out, topic);
+            }
+            Rpc::Publish(ref message, hops) => {
+                out.push(PUBLISH);
+                out.push(hops);
+                push_short(&mut out, message.source.as_bytes());
+                push_short(&mut out, &message.sequence_number);
+                push_u16(&mut out, message.topics.len());
+                for topic in message.topics.iter() {
+                    push_topic(&mut out, topic);
+                }
+                push_u32(&mut out, message.data.len());
+                out.extend_from_slice(&message.data);
+            }
+            Rpc::IHave(ref topic, ref ids) => {
+                out.push(IHAVE);
+                push_topic(&mut out, topic);
+                push_ids(&mut out, ids);
+            }
+            Rpc::IWant(ref ids) => {
+                out.push(IWANT);
+                push_ids(&mut out, ids);
+            }
+            Rpc::Graft(ref topic) => {
+                out.push(GRAFT);
+                push_topic(&mut out, topic);
+            }
+            Rpc::Prune(ref topic) => {
+                out.push(PRUNE);
+                push_topic(&mut out, topic);
+            }
+        }
+        out
+    }
+
+    /// Decodes a frame produced by `encode`.
+    pub fn decode(frame: &[u8]) -> Result<Rpc, IoError> {
+        let (&kind, rest) = frame.split_first().ok_or_else(invalid)?;
+        let mut reader = Reader::new(rest);
+        let rpc = match kind {
+            SUBSCRIBE => Rpc::Subscribe(read_topic(&mut reader)?),
+            UNSUBSCRIBE => Rpc::Unsubscribe(read_topic(&mut reader)?),
+            PUBLISH => {
+                let hops = reader.u8()?;
+                let source = PeerId::from_bytes(reader.short()?.to_vec()).map_err(|_| invalid())?;
+                let sequence_number = reader.short()?.to_vec();
+                let mut topics = Vec::new();
+                for _ in 0..reader.u16()? {
+                    topics.push(read_topic(&mut reader)?);
+                }
+                let len = reader.u32()?;
+                let data = reader.bytes(len)?.to_vec();
+                let message = Message {
+                    source: source,
+                    data: data,
+                    sequence_number: sequence_number,
+                    topics: topics,
+                };
+                Rpc::Publish(message, hops)
+            }
+            IHAVE => {
+                let topic = read_topic(&mut reader)?;
+                Rpc::IHave(topic, read_ids(&mut reader)?)
+            }
+            IWANT => Rpc::IWant(read_ids(&mut reader)?),
+            GRAFT => Rpc::Graft(read_topic(&mut reader)?),
+            PRUNE => Rpc::Prune(read_topic(&mut reader)?),
+            _ => return Err(invalid()),
+        };
+
+        if !reader.is_empty() {
+            return Err(invalid());
+        }
+        Ok(rpc)
+    }
+}
+
+fn push_topic(out: &mut Vec<u8>, topic: &TopicHash) {
+    push_short(out, topic.clone().into_string().as_bytes());
+}
+
+fn push_ids(out: &mut Vec<u8>, ids: &[MessageId]) {
+    push_u16(out, ids.len());
+    for id in ids {
+        push_short(out, &id.0);
+    }
+}
+
+fn read_topic(reader: &mut Reader) -> Result<TopicHash, IoError> {
+    Ok(TopicHash::from_raw(reader.string()?))
+}
+
+fn read_ids(reader: &mut Reader) -> Result<Vec<MessageId>, IoError> {
+    let mut ids = Vec::new();
+    for _ in 0..reader.u16()? {
+        ids.push(MessageId(reader.short()?.to_vec()));
+    }
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_common::key::{KeyType, Keypair};
+    use libp2p_floodsub::TopicBuilder;
+
+    fn topic(name: &str) -> TopicHash {
+        TopicBuilder::new(name).build().hash().clone()
+    }
+
+    fn message() -> Message {
+        Message {
+            source: Keypair::generate(KeyType::Ed25519).unwrap().peer_id(),
+            data: vec![7; 300],
+            sequence_number: vec![0, 0, 0, 0, 0, 0, 1, 2],
+            topics: vec![topic("a"), topic("b")],
+        }
+    }
+
+    fn all_rpcs() -> Vec<Rpc> {
+        let message = message();
+        let ids = vec![MessageId::of(&message), MessageId(vec![1, 2, 3])];
+        vec![
+            Rpc::Subscribe(topic("a")),
+            Rpc::Unsubscribe(topic("a")),
+            Rpc::Publish(message, 5),
+            Rpc::IHave(topic("b"), ids.clone()),
+            Rpc::IWant(ids),
+            Rpc::IWant(Vec::new()),
+            Rpc::Graft(topic("a")),
+            Rpc::Prune(topic("b")),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for rpc in all_rpcs() {
+            let frame = rpc.encode();
+            let decoded = Rpc::decode(&frame).unwrap();
+            assert_eq!(decoded.encode(), frame, "{:?}", rpc);
+        }
+    }
+
+    #[test]
+    fn publish_fields() {
+        let message = message();
+        match Rpc::decode(&Rpc::Publish(message.clone(), 3).encode()).unwrap() {
+            Rpc::Publish(decoded, hops) => {
+                assert_eq!(hops, 3);
+                assert_eq!(decoded.source, message.source);
+                assert_eq!(decoded.data, message.data);
+                assert_eq!(decoded.sequence_number, message.sequence_number);
+                assert_eq!(decoded.topics, message.topics);
+                assert_eq!(MessageId::of(&decoded), MessageId::of(&message));
+            }
+            rpc => panic!("unexpected rpc: {:?}", rpc),
+        }
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        for rpc in all_rpcs() {
+            let frame = rpc.encode();
+            for len in 0..frame.len() {
+                assert!(Rpc::decode(&frame[..len]).is_err(), "{:?} cut at {}", rpc, len);
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        for rpc in all_rpcs() {
+            let mut frame = rpc.encode();
+            frame.push(0);
+            assert!(Rpc::decode(&frame).is_err(), "{:?}", rpc);
+        }
+    }
+
+    #[test]
+    fn unknown_kinds_and_invalid_sources_are_rejected() {
+        let mut frame = Rpc::Subscribe(topic("a")).encode();
+        frame[0] = 42;
+        assert!(Rpc::decode(&frame).is_err());
+
+        let mut frame = Rpc::Publish(message(), 1).encode();
+        // The first byte of the source, right after the kind, the hops and the length.
+        frame[4] ^= 0xff;
+        assert!(Rpc::decode(&frame).is_err());
+    }
+}
@@ -0,0 +1,423 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Recent history of the rooms, and the protocol that lets nodes fetch it from each other.
+//!
+//! Every node keeps the last messages of each room it's in. A node that just got connected, or
+//! that just joined a room, opens a substream with the protocol of this module and sends a
+//! request that contains, for each room, the time of the most recent message it knows about.
+//! The remote answers with the messages that are more recent, then closes the substream.
+//!
+//! The messages are stored and sent along with their signed envelope, so that the node that
+//! receives them can check that they weren't tampered with.
+//!
+//! Each frame of the substream is encoded as follows. Byte strings are prefixed with their length
+//! as a big endian `u16`.
+//!
+//! - The request, sent by the dialer, is the number of rooms as a big endian `u16`, followed with
+//!   the name of each room as a byte string, a big endian `u64` timestamp in milliseconds, and the
+//!   identifier of the most recent message we have as a byte string (which might be empty).
+//! - Each frame of the response is the name of the room as a byte string, the `PeerId` of the
+//!   author as a byte string, and the signed envelope until the end of the frame.
+
+use bytes::Bytes;
+use chat_common::codec::{invalid, push_short, push_u16, push_u64, Reader};
+use chat_common::message::ChatMessage;
+use futures::sync::mpsc;
+use futures::{future, stream, Future, Sink, Stream};
+use libp2p_core::{ConnectionUpgrade, Endpoint, Multiaddr, PeerId};
+use std::collections::HashMap;
+use std::io::Error as IoError;
+use std::iter;
+use std::sync::{Arc, Mutex};
+use tokio_io::codec::length_delimited::Framed;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Name of the protocol, as negotiated on substreams.
+pub const PROTOCOL_NAME: &str = "/rustfest-chat/history/1.0.0";
+
+/// Number of messages we keep for each room.
+pub const DEFAULT_CAPACITY: usize = 200;
+
+/// A message of the history.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Node that published the message.
+    pub source: PeerId,
+    /// Signed envelope, as published.
+    pub data: Vec<u8>,
+    /// The decoded content of the envelope.
+    pub message: ChatMessage,
+}
+
+impl Entry {
+    /// Key used to order the messages.
+    fn order(&self) -> (u64, u64) {
+        (self.message.lamport, self.message.timestamp)
+    }
+
+    fn is_same(&self, other: &Entry) -> bool {
+        self.message.author == other.message.author && self.message.id == other.message.id
+    }
+}
+
+/// The last messages of each room. Cloning a `History` gives access to the same messages.
+#[derive(Clone)]
+pub struct History {
+    inner: Arc<Mutex<HashMap<String, Vec<Entry>>>>,
+    capacity: usize,
+}
+
+impl History {
+    /// Creates an empty history that keeps `capacity` messages per room.
+    pub fn new(capacity: usize) -> History {
+        History {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            capacity: capacity,
+        }
+    }
+
+    /// Adds a message to the history of `room`. The oldest message is forgotten if the room is
+    /// full.
+    ///
+    /// Returns false if the message was already in the history.
+    pub fn insert(&self, room: &str, entry: Entry) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let entries = inner.entry(room.to_owned()).or_insert_with(Vec::new);
+        if entries.iter().any(|e| e.is_same(&entry)) {
+            return false;
+        }
+
+        let pos = entries
+            .iter()
+            .rposition(|e| e.order() <= entry.order())
+            .map_or(0, |pos| pos + 1);
+        entries.insert(pos, entry);
+        if entries.len() > self.capacity {
+            entries.remove(0);
+        }
+        true
+    }
+
+    /// Builds a request for the messages of `rooms` that are more recent than the ones we have.
+    pub fn request(&self, rooms: Vec<String>) -> Request {
+        let inner = self.inner.lock().unwrap();
+        let rooms = rooms
+            .into_iter()
+            .map(|room| {
+                let last = inner.get(&room).and_then(|entries| {
+                    entries
+                        .iter()
+                        .max_by_key(|e| e.message.timestamp)
+                        .map(|e| (e.message.timestamp, e.message.id.clone()))
+                });
+                let (since, after_id) = last.unwrap_or((0, Vec::new()));
+                RoomRequest {
+                    room: room,
+                    since: since,
+                    after_id: after_id,
+                }
+            })
+            .collect();
+        Request { rooms: rooms }
+    }
+
+    /// Returns the messages that answer `request`.
+    fn answer(&self, request: &Request) -> Vec<(String, Entry)> {
+        let inner = self.inner.lock().unwrap();
+        let mut out = Vec::new();
+        for request in request.rooms.iter() {
+            let entries = match inner.get(&request.room) {
+                Some(entries) => entries,
+                None => continue,
+            };
+
+            // If we know the message the remote has, we send everything after it. Otherwise we
+            // rely on the time.
+            let start = entries
+                .iter()
+                .position(|e| !request.after_id.is_empty() && e.message.id == request.after_id);
+            let answer = match start {
+                Some(pos) => entries[pos + 1..].to_vec(),
+                None => entries
+                    .iter()
+                    .filter(|e| e.message.timestamp >= request.since)
+                    .cloned()
+                    .collect(),
+            };
+            out.extend(answer.into_iter().map(|entry| (request.room.clone(), entry)));
+        }
+        out
+    }
+}
+
+/// Request for the recent messages of some rooms.
+#[derive(Debug, Clone, Default)]
+pub struct Request {
+    rooms: Vec<RoomRequest>,
+}
+
+#[derive(Debug, Clone)]
+struct RoomRequest {
+    room: String,
+    /// We want the messages sent at this time or later, in milliseconds since the UNIX epoch.
+    since: u64,
+    /// Identifier of the most recent message we have. Empty if we don't have any.
+    after_id: Vec<u8>,
+}
+
+impl Request {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        push_u16(&mut out, self.rooms.len());
+        for room in self.rooms.iter() {
+            push_short(&mut out, room.room.as_bytes());
+            push_u64(&mut out, room.since);
+            push_short(&mut out, &room.after_id);
+        }
+        out
+    }
+
+    fn decode(frame: &[u8]) -> Result<Request, IoError> {
+        let mut reader = Reader::new(frame);
+        let mut rooms = Vec::new();
+        for _ in 0..reader.u16()? {
+            let room = reader.string()?;
+            let since = reader.u64()?;
+            rooms.push(RoomRequest {
+                room: room,
+                since: since,
+                after_id: reader.short()?.to_vec(),
+            });
+        }
+        Ok(Request { rooms: rooms })
+    }
+}
+
+/// A message received in answer to one of our requests. It hasn't been verified yet.
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub room: String,
+    /// Node that published the message.
+    pub source: PeerId,
+    /// Signed envelope, as published.
+    pub data: Vec<u8>,
+}
+
+impl Reply {
+    fn encode(room: &str, entry: &Entry) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64 + entry.data.len());
+        push_short(&mut out, room.as_bytes());
+        push_short(&mut out, entry.source.as_bytes());
+        out.extend_from_slice(&entry.data);
+        out
+    }
+
+    fn decode(frame: &[u8]) -> Result<Reply, IoError> {
+        let mut reader = Reader::new(frame);
+        let room = reader.string()?;
+        let source = PeerId::from_bytes(reader.short()?.to_vec()).map_err(|_| invalid())?;
+        Ok(Reply {
+            room: room,
+            source: source,
+            data: reader.rest().to_vec(),
+        })
+    }
+}
+
+/// Upgrade that negotiates the protocol on a substream.
+///
+/// When listening, we answer the request of the remote from the history. When dialing, we send
+/// the request passed to `with_request`, and the messages we receive in answer are sent to the
+/// receiver returned by `new`.
+#[derive(Clone)]
+pub struct HistoryUpgrade {
+    history: History,
+    request: Arc<Request>,
+    replies: mpsc::UnboundedSender<Reply>,
+}
+
+impl HistoryUpgrade {
+    /// Creates an upgrade that answers requests from `history`.
+    pub fn new(history: History) -> (HistoryUpgrade, mpsc::UnboundedReceiver<Reply>) {
+        let (tx, rx) = mpsc::unbounded();
+        let upgrade = HistoryUpgrade {
+            history: history,
+            request: Arc::new(Request::default()),
+            replies: tx,
+        };
+        (upgrade, rx)
+    }
+
+    /// Returns an upgrade that sends `request` when dialing.
+    pub fn with_request(&self, request: Request) -> HistoryUpgrade {
+        HistoryUpgrade {
+            history: self.history.clone(),
+            request: Arc::new(request),
+            replies: self.replies.clone(),
+        }
+    }
+}
+
+impl<C> ConnectionUpgrade<C> for HistoryUpgrade
+where
+    C: AsyncRead + AsyncWrite + 'static,
+{
+    type NamesIter = iter::Once<(Bytes, ())>;
+    type UpgradeIdentifier = ();
+
+    #[inline]
+    fn protocol_names(&self) -> Self::NamesIter {
+        iter::once((Bytes::from(PROTOCOL_NAME), ()))
+    }
+
+    type Output = Box<Future<Item = (), Error = IoError>>;
+    type Future = future::FutureResult<Self::Output, IoError>;
+
+    fn upgrade(self, socket: C, _: (), endpoint: Endpoint, _: &Multiaddr) -> Self::Future {
+        let (sink, stream) = Framed::<_, Vec<u8>>::new(socket).split();
+
+        let future: Self::Output = match endpoint {
+            Endpoint::Dialer => {
+                let replies = self.replies;
+                Box::new(
+                    sink.send(self.request.encode())
+                        .and_then(move |_sink| {
+                            stream.for_each(move |frame| {
+                                let _ = replies.unbounded_send(Reply::decode(&frame)?);
+                                Ok(())
+                            })
+                        }),
+                )
+            }
+            Endpoint::Listener => {
+                let history = self.history;
+                Box::new(
+                    stream
+                        .into_future()
+                        .map_err(|(err, _)| err)
+                        .and_then(move |(frame, _stream)| {
+                            let request = match frame {
+                                Some(frame) => Request::decode(&frame)?,
+                                None => Request::default(),
+                            };
+                            Ok(history.answer(&request))
+                        })
+                        .and_then(move |answer| {
+                            let frames = answer
+                                .into_iter()
+                                .map(|(room, entry)| Reply::encode(&room, &entry))
+                                .collect::<Vec<_>>();
+                            // Dropping the substream once everything is sent closes it.
+                            sink.send_all(stream::iter_ok::<_, IoError>(frames)).map(|_| ())
+                        }),
+                )
+            }
+        };
+
+        future::ok(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_common::key::{KeyType, Keypair};
+    use chat_common::message::{Composer, Kind};
+
+    fn request() -> Request {
+        Request {
+            rooms: vec![
+                RoomRequest {
+                    room: "rust".to_owned(),
+                    since: 0x0102_0304_0506_0708,
+                    after_id: vec![9, 10, 11],
+                },
+                RoomRequest {
+                    room: "général".to_owned(),
+                    since: 0,
+                    after_id: Vec::new(),
+                },
+            ],
+        }
+    }
+
+    fn entry() -> Entry {
+        let source = Keypair::generate(KeyType::Ed25519).unwrap().peer_id();
+        let message = Composer::new(source.clone()).compose(Kind::Text, b"hello".to_vec());
+        Entry {
+            source: source,
+            data: vec![1, 2, 3, 4],
+            message: message,
+        }
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let request = request();
+        let encoded = request.encode();
+        assert_eq!(&encoded[..8], &[0, 2, 0, 4, b'r', b'u', b's', b't']);
+
+        let decoded = Request::decode(&encoded).unwrap();
+        assert_eq!(decoded.rooms.len(), request.rooms.len());
+        for (decoded, room) in decoded.rooms.iter().zip(request.rooms.iter()) {
+            assert_eq!(decoded.room, room.room);
+            assert_eq!(decoded.since, room.since);
+            assert_eq!(decoded.after_id, room.after_id);
+        }
+    }
+
+    #[test]
+    fn truncated_request_is_rejected() {
+        let encoded = request().encode();
+        for len in 0..encoded.len() {
+            assert!(Request::decode(&encoded[..len]).is_err(), "length {}", len);
+        }
+    }
+
+    #[test]
+    fn reply_round_trip() {
+        let entry = entry();
+        let decoded = Reply::decode(&Reply::encode("rust", &entry)).unwrap();
+        assert_eq!(decoded.room, "rust");
+        assert_eq!(decoded.source, entry.source);
+        assert_eq!(decoded.data, entry.data);
+    }
+
+    #[test]
+    fn truncated_reply_is_rejected() {
+        let entry = entry();
+        let encoded = Reply::encode("rust", &entry);
+        // The envelope goes until the end of the frame, so only the header can be truncated.
+        let header_len = 2 + "rust".len() + 2 + entry.source.as_bytes().len();
+        for len in 0..header_len {
+            assert!(Reply::decode(&encoded[..len]).is_err(), "length {}", len);
+        }
+    }
+
+    #[test]
+    fn reply_with_invalid_peer_id_is_rejected() {
+        let mut frame = Vec::new();
+        push_short(&mut frame, b"rust");
+        push_short(&mut frame, &[1, 2, 3]);
+        frame.extend_from_slice(&[4, 5, 6]);
+        assert!(Reply::decode(&frame).is_err());
+    }
+}
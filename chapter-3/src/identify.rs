@@ -0,0 +1,260 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Information that nodes exchange about themselves with the identify protocol.
+//!
+//! Whenever we open a connection to a node, we ask it for its listen addresses, the address it
+//! sees us as, the name and version of its software, and the protocols it supports. This is
+//! mostly useful for debugging, for example to find out that a teammate runs an outdated build.
+
+#[cfg(not(target_os = "emscripten"))]
+use get_if_addrs;
+#[cfg(not(target_os = "emscripten"))]
+use libp2p_core::AddrComponent;
+use libp2p_core::{Multiaddr, PeerId};
+use libp2p_identify::IdentifyInfo;
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(not(target_os = "emscripten"))]
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Name and version of our software, as sent to other nodes.
+pub const AGENT_VERSION: &str = concat!("rustfest-chat/", env!("CARGO_PKG_VERSION"));
+
+/// Version of the chat protocol as a whole.
+pub const PROTOCOL_VERSION: &str = "/rustfest-chat/1.0.0";
+
+/// Information that a node sent us about itself.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    /// Addresses the node is listening on.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Address of our node, as seen by the remote.
+    pub observed_addr: Multiaddr,
+    /// Name and version of the software of the node.
+    pub agent_version: String,
+    /// Version of the protocol as a whole.
+    pub protocol_version: String,
+    /// Protocols supported by the node.
+    pub protocols: Vec<String>,
+}
+
+impl PeerInfo {
+    /// Builds a `PeerInfo` from the result of the identify protocol.
+    pub fn from_identify(info: IdentifyInfo, observed_addr: Multiaddr) -> PeerInfo {
+        PeerInfo {
+            listen_addrs: info.listen_addrs,
+            observed_addr: observed_addr,
+            agent_version: info.agent_version,
+            protocol_version: info.protocol_version,
+            protocols: info.protocols,
+        }
+    }
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "  agent version:    {}", self.agent_version)?;
+        writeln!(f, "  protocol version: {}", self.protocol_version)?;
+        writeln!(f, "  observed us as:   {}", self.observed_addr)?;
+        writeln!(f, "  listen addresses:")?;
+        for addr in self.listen_addrs.iter() {
+            writeln!(f, "    {}", addr)?;
+        }
+        write!(f, "  protocols:        {}", self.protocols.join(", "))
+    }
+}
+
+/// The information received from each node we've identified.
+///
+/// Cloning a `PeerInfoStore` gives access to the same store.
+#[derive(Clone, Default)]
+pub struct PeerInfoStore {
+    inner: Arc<Mutex<HashMap<PeerId, PeerInfo>>>,
+}
+
+impl PeerInfoStore {
+    /// Creates an empty store.
+    #[inline]
+    pub fn new() -> PeerInfoStore {
+        PeerInfoStore::default()
+    }
+
+    /// Records the information sent by `peer_id`, replacing what we had before.
+    #[inline]
+    pub fn insert(&self, peer_id: PeerId, info: PeerInfo) {
+        self.inner.lock().unwrap().insert(peer_id, info);
+    }
+
+    /// Returns the information sent by `peer_id`, if we have identified it.
+    #[inline]
+    pub fn get(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        self.inner.lock().unwrap().get(peer_id).cloned()
+    }
+}
+
+/// The information about ourselves that we send to other nodes.
+///
+/// Our listen addresses are only known once we started listening, which is why they are shared.
+#[derive(Clone)]
+pub struct LocalInfo {
+    public_key: Vec<u8>,
+    protocols: Vec<String>,
+    listen_addrs: Arc<Mutex<Vec<Multiaddr>>>,
+}
+
+impl LocalInfo {
+    /// Creates the local information. `public_key` must be the protobuf encoding of our public
+    /// key.
+    pub fn new(public_key: Vec<u8>, protocols: Vec<String>) -> LocalInfo {
+        LocalInfo {
+            public_key: public_key,
+            protocols: protocols,
+            listen_addrs: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Adds an address we are listening on.
+    #[inline]
+    pub fn add_listen_addr(&self, addr: Multiaddr) {
+        self.listen_addrs.lock().unwrap().push(addr);
+    }
+
+    /// Returns the addresses we are listening on.
+    #[inline]
+    pub fn listen_addrs(&self) -> Vec<Multiaddr> {
+        self.listen_addrs.lock().unwrap().clone()
+    }
+
+    /// Returns the addresses that other nodes can dial to reach us. These are our listen
+    /// addresses, where `0.0.0.0` is replaced with the addresses of our network interfaces.
+    pub fn dialable_addrs(&self) -> Vec<Multiaddr> {
+        self.listen_addrs
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(expand_unspecified)
+            .collect()
+    }
+
+    /// Builds the `IdentifyInfo` to send to a node.
+    pub fn to_identify(&self) -> IdentifyInfo {
+        IdentifyInfo {
+            public_key: self.public_key.clone(),
+            protocol_version: PROTOCOL_VERSION.to_owned(),
+            agent_version: AGENT_VERSION.to_owned(),
+            listen_addrs: self.dialable_addrs(),
+            protocols: self.protocols.clone(),
+        }
+    }
+}
+
+/// Replaces an unspecified IPv4 address (`0.0.0.0`) with the addresses of the network
+/// interfaces of the machine, as other nodes can't dial `0.0.0.0`.
+#[cfg(not(target_os = "emscripten"))]
+fn expand_unspecified(addr: &Multiaddr) -> Vec<Multiaddr> {
+    let components = addr.iter().collect::<Vec<_>>();
+    match components.first() {
+        Some(&AddrComponent::IP4(ip)) if ip.is_unspecified() => (),
+        _ => return vec![addr.clone()],
+    }
+
+    let interfaces = match get_if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(_) => return Vec::new(),
+    };
+    interfaces
+        .into_iter()
+        .filter_map(|interface| match interface.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+        .map(|ip| {
+            let mut expanded = components.clone();
+            expanded[0] = AddrComponent::IP4(ip);
+            expanded.into_iter().collect()
+        })
+        .collect()
+}
+
+/// In the browser we don't listen, so there is nothing to expand.
+#[cfg(target_os = "emscripten")]
+fn expand_unspecified(addr: &Multiaddr) -> Vec<Multiaddr> {
+    vec![addr.clone()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    fn peer_info(agent_version: &str) -> PeerInfo {
+        PeerInfo {
+            listen_addrs: vec![addr("/ip4/10.0.0.1/tcp/1"), addr("/ip4/10.0.0.1/tcp/2/ws")],
+            observed_addr: addr("/ip4/10.0.0.2/tcp/3"),
+            agent_version: agent_version.to_owned(),
+            protocol_version: PROTOCOL_VERSION.to_owned(),
+            protocols: vec!["/floodsub/1.0.0".to_owned(), "/ipfs/ping/1.0.0".to_owned()],
+        }
+    }
+
+    #[test]
+    fn peer_info_display() {
+        let expected = concat!(
+            "  agent version:    rustfest-chat/0.1.0\n",
+            "  protocol version: /rustfest-chat/1.0.0\n",
+            "  observed us as:   /ip4/10.0.0.2/tcp/3\n",
+            "  listen addresses:\n",
+            "    /ip4/10.0.0.1/tcp/1\n",
+            "    /ip4/10.0.0.1/tcp/2/ws\n",
+            "  protocols:        /floodsub/1.0.0, /ipfs/ping/1.0.0",
+        );
+        assert_eq!(peer_info("rustfest-chat/0.1.0").to_string(), expected);
+    }
+
+    #[test]
+    fn store_keeps_the_latest_info() {
+        let store = PeerInfoStore::new();
+        let peer_id = PeerId::from_public_key(&[1; 32]);
+        assert!(store.get(&peer_id).is_none());
+
+        store.insert(peer_id.clone(), peer_info("old/1.0"));
+        store.insert(peer_id.clone(), peer_info("new/2.0"));
+        assert_eq!(store.get(&peer_id).unwrap().agent_version, "new/2.0");
+        assert!(store.get(&PeerId::from_public_key(&[2; 32])).is_none());
+    }
+
+    #[test]
+    fn local_info_round_trip() {
+        let local = LocalInfo::new(vec![1, 2, 3], vec!["/floodsub/1.0.0".to_owned()]);
+        local.add_listen_addr(addr("/ip4/10.0.0.1/tcp/1"));
+
+        let info = PeerInfo::from_identify(local.to_identify(), addr("/ip4/10.0.0.2/tcp/3"));
+        assert_eq!(info.listen_addrs, vec![addr("/ip4/10.0.0.1/tcp/1")]);
+        assert_eq!(info.observed_addr, addr("/ip4/10.0.0.2/tcp/3"));
+        assert_eq!(info.agent_version, AGENT_VERSION);
+        assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(info.protocols, vec!["/floodsub/1.0.0".to_owned()]);
+    }
+}
@@ -46,34 +46,1934 @@
 //! `/ip4/1.2.3.4/tcp/1000`, you can dial `/ip4/1.2.3.4/tcp/1000/ws`.
 //!
 //! Additionally, please note that the browser doesn't support listening on any connection (even
-//! websockets). Calling `listen_on` will trigger an error at runtime. You can use
-//! `if cfg!(not(target_os = "emscripten")) { ... }` to listen only when outside of the browser.
+//! websockets). Calling `listen_on` will trigger an error at runtime, which is why we only listen
+//! when outside of the browser.
 //!
-//! Good luck!
+//! Compared to chapter 2, all the connections are multiplexed with *yamux* or *mplex*. In addition
+//! to floodsub (or, with `--pubsub gossipsub`, a mesh-based router that scales to larger networks,
+//! see the `gossipsub` module), the node regularly pings the nodes it is connected to, and runs a
+//! Kademlia DHT in order to discover other nodes. All these protocols are negotiated on substreams
+//! of the same connection.
+//!
+//! Every message carries a sequence number. The node remembers the messages it has recently
+//! displayed, along with the `PeerId` that signed them (see `chat_common::dedup`), and drops the
+//! copies that reach it through another path. With gossipsub, the router also refuses to forward
+//! the duplicates, and limits the number of hops a message can travel (`--max-hops`). Floodsub
+//! does its own deduplication inside of libp2p, but has no hop limit: its wire format, shared with
+//! the other implementations of floodsub, has no field to carry one, so `--max-hops` is ignored.
+//! A floodsub message still stops travelling once every node has seen it. `--dedup-capacity <n>`
+//! and `--dedup-ttl <seconds>` set the size and expiry of these caches.
+//!
+//! On a terminal, the node runs a full-screen UI with a pane per room, the list of the nodes we're
+//! connected to and of the members of the active room, and an input line (see the `ui` module).
+//! With `--no-tui`, or when stdin or stdout isn't a terminal, it reads stdin and prints line by
+//! line instead.
+//!
+//! Lines typed on stdin are published in the active room, unless they start with a slash, in which
+//! case they are commands (see the `commands` module, or type `/help`). `/dial <multiaddr>` and
+//! `/listen <multiaddr>` do the same as the command line, `/peers` lists the nodes we're connected
+//! to, and `/quit` stops the node.
+//!
+//! `/msg <peer-or-nick> <text>` sends a message to a single node on a substream of its own (see
+//! the `direct` module), instead of publishing it in a room.
+//!
+//! `/send <peer-or-nick> <path>` offers a file to a node, which downloads it on a substream of its
+//! own if it accepts (see the `file_transfer` module).
+//!
+//! `/encrypt` makes the active room end-to-end encrypted (see `chat_common::group`). The nodes that
+//! join it afterwards ask for its key with `/requestkey`, a member lets them in with
+//! `/invite <peer-id>`, and they start using the key with `/acceptkey <peer-id>` once they've
+//! checked who gave it to them. `/remove <peer-id>` replaces the key without a member.
+//!
+//! Outside of the browser, scripts can control the node with JSON-RPC requests sent on a Unix
+//! domain socket, if it is started with `--rpc` or `--rpc-socket <path>`: publish messages, join
+//! and leave rooms, dial, list the nodes we're connected to, and receive the messages as they
+//! arrive. See the `rpc` module. The default path of the socket is the same for all the nodes of
+//! the user, so the nodes that run on the same machine need a `--rpc-socket` each.
+//!
+//! With `--daemon`, the node doesn't read stdin at all, and is only controlled through this
+//! socket, for example with the `chatctl` binary of this crate (`chatctl send <text>`,
+//! `chatctl tail -f`, `chatctl peers`, `chatctl dial <multiaddr>`; see `src/bin/chatctl.rs`).
+//!
+//! `/quit`, SIGTERM and SIGINT stop the node gracefully: it leaves its rooms, which unsubscribes
+//! from their topics, and gives the other nodes `SHUTDOWN_DELAY` to receive the last messages
+//! before closing the connections.
 
+extern crate bytes;
+extern crate chat_common;
 extern crate futures;
+#[cfg(not(target_os = "emscripten"))]
+extern crate get_if_addrs;
+extern crate libp2p_core;
+extern crate libp2p_floodsub;
+extern crate libp2p_identify;
+extern crate libp2p_kad;
+extern crate libp2p_peerstore;
+extern crate libp2p_ping;
+#[cfg(not(target_os = "emscripten"))]
+extern crate net2;
+extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate sha2;
+#[cfg(not(target_os = "emscripten"))]
+extern crate termion;
+#[cfg(not(target_os = "emscripten"))]
+extern crate tokio_core;
 extern crate tokio_io;
+extern crate tokio_timer;
+#[cfg(all(unix, not(target_os = "emscripten")))]
+extern crate tokio_signal;
+#[cfg(all(unix, not(target_os = "emscripten")))]
+extern crate tokio_uds;
 
 #[cfg(target_os = "emscripten")]
 #[macro_use]
 extern crate stdweb;
 
+use chat_common::dedup::{self, SeenCache};
+use chat_common::group::{GroupError, GroupKeys, UpdateOutcome};
+use chat_common::message::{self, ChatMessage, Composer, Kind};
+use chat_common::peer_addr;
+use chat_common::presence::{self, announce, Notice, Presence, Status};
+use chat_common::rooms::{self, Rooms, DEFAULT_ROOM};
+use chat_common::signed::{self, VerifyMode};
+#[cfg(all(unix, not(target_os = "emscripten")))]
+use chat_common::storage;
+use commands::{Command, Input};
+use futures::{future, Future, Stream};
+use futures::future::Loop;
+use futures::sync::{mpsc, oneshot};
+use libp2p_core::{Multiaddr, PeerId, Transport};
+use libp2p_core::upgrade;
+use gossipsub::{GossipSubConfig, GossipSubController, GossipSubUpgrade, PubSubRouter};
+use libp2p_floodsub::{FloodSubController, FloodSubUpgrade, Message};
+use libp2p_identify::{IdentifyOutput, IdentifyProtocolConfig};
+use libp2p_kad::{KademliaConfig, KademliaControllerPrototype, KademliaUpgrade};
+use libp2p_peerstore::Peerstore;
+use libp2p_peerstore::memory_peerstore::MemoryPeerstore;
+use libp2p_ping::Ping;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::iter;
+#[cfg(all(unix, not(target_os = "emscripten")))]
+use std::path::Path;
+#[cfg(all(unix, not(target_os = "emscripten")))]
+use std::process;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::vec;
+
+/// Same as `println!`, but prints in the terminal UI when it's running. See the `ui` module.
+macro_rules! ui_println {
+    ($($arg:tt)*) => {
+        ::ui::print(format!($($arg)*))
+    };
+}
+
+/// Same as `ui_println!`, but for a line about the room, or the other pane, `$pane`. See the
+/// `ui` module.
+macro_rules! ui_println_in {
+    ($pane:expr, $($arg:tt)*) => {
+        ::ui::print_in($pane, format!($($arg)*))
+    };
+}
+
+/// Same as `eprintln!`, but prints in the terminal UI when it's running. See the `ui` module.
+macro_rules! ui_eprintln {
+    ($($arg:tt)*) => {
+        ::ui::eprint(format!($($arg)*))
+    };
+}
+
+mod address_book;
+mod chat_log;
+mod commands;
+mod direct;
+mod discovery;
+mod file_transfer;
+mod gossipsub;
+mod history;
+mod identify;
+#[cfg(not(target_os = "emscripten"))]
+mod mdns;
+mod options;
+mod peers;
 mod platform;
+#[cfg(all(unix, not(target_os = "emscripten")))]
+mod rpc;
+mod ui;
+
+/// Interval between two pings sent to the same node.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long we remember the address of a node we've been connected to.
+const ADDRESS_TTL: Duration = Duration::from_secs(3600);
+
+/// On startup, we dial again the nodes of the address book that we've seen in this period.
+const REDIAL_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// When dialing a node of the address book, how long we wait for the connection before trying its
+/// next address.
+const REDIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval between two saves of the address book.
+const ADDRESS_BOOK_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Interval between two flushes of the chat log to the disk.
+const CHAT_LOG_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Interval between two displays of the messages fetched from the history of other nodes. The
+/// messages received from all the nodes in the meantime are displayed together, in order.
+const HISTORY_DISPLAY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Maximum number of messages printed by `/search`.
+const SEARCH_RESULTS: usize = 20;
+
+/// Number of messages printed by `/history` when no number is given.
+const HISTORY_PAGE_SIZE: usize = 20;
+
+/// Maximum number of background tasks of the commands running at the same time, such as direct
+/// messages waiting for an acknowledgement.
+const MAX_PENDING_TASKS: usize = 32;
+
+/// How long the node keeps running after it has been asked to stop, so that the other nodes
+/// receive the messages announcing that we leave our rooms.
+const SHUTDOWN_DELAY: Duration = Duration::from_secs(1);
+
+/// Delay before resuming an interrupted download.
+const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Number of times we automatically resume a download before giving up.
+const DOWNLOAD_RETRIES: u32 = 5;
+
+/// Name of the floodsub protocol.
+const FLOODSUB_PROTOCOL: &str = "/floodsub/1.0.0";
+
+/// Names of the protocols that the node supports in addition to its publish-subscribe protocol,
+/// as advertised through identify.
+const PROTOCOLS: &[&str] = &[
+    history::PROTOCOL_NAME,
+    direct::PROTOCOL_NAME,
+    file_transfer::PROTOCOL_NAME,
+    "/ipfs/ping/1.0.0",
+    "/ipfs/kad/1.0.0",
+    "/ipfs/id/1.0.0",
+];
+
+/// Output of the protocol negotiated on a substream.
+enum ProtocolOutput<P, K, I> {
+    /// Floodsub or gossipsub, depending on `--pubsub`.
+    PubSub(Box<Future<Item = (), Error = IoError>>),
+    History(Box<Future<Item = (), Error = IoError>>),
+    Direct(Box<Future<Item = (), Error = IoError>>),
+    FileTransfer(Box<Future<Item = (), Error = IoError>>),
+    Ping(P),
+    Kademlia(K),
+    Identify(I),
+}
 
 fn main() {
+    let options = options::Options::from_args();
+
+    // The control socket is opened before anything else, so that a node that can't be controlled
+    // as requested doesn't start at all.
+    #[cfg(all(unix, not(target_os = "emscripten")))]
+    let control_socket = if options.rpc {
+        let path = options.rpc_socket.clone().unwrap_or_else(storage::default_control_socket);
+        match rpc::bind(&path) {
+            Ok(socket) => Some((socket, path)),
+            Err(err) => {
+                eprintln!("Failed to open the control socket {}: {}", path.display(), err);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    // Unless stdin or stdout isn't a terminal, everything happens in a full-screen UI. See the
+    // `ui` module.
+    #[cfg(not(target_os = "emscripten"))]
+    let tui = if options.tui && !options.daemon && ui::is_terminal() {
+        match ui::Tui::start() {
+            Ok(tui) => Some(tui),
+            Err(err) => {
+                eprintln!("Failed to start the terminal UI, printing line by line instead: {}",
+                          err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
     // The `PlatformSpecific` object allows you to handle the transport and stdin in a
     // cross-platform manner.
     let platform = platform::PlatformSpecific::default();
 
-    // This builds an implementation of the `Transport` trait (similar to the `TcpConfig` object in
-    // earlier chapters).
-    let transport = platform.build_transport();
+    // This loads the keypair of the node, or generates one if this is the first time we run.
+    let keypair = platform
+        .keypair(options.key_file.as_ref().map(|p| &**p), options.key_type, options.new_identity)
+        .expect("failed to load or generate the identity of the node");
+    let local_peer_id = keypair.peer_id();
+    ui_println!("Local peer id is {}", local_peer_id.to_base58());
+
+    // The keys of the encrypted rooms we're in.
+    let group_keys = GroupKeys::new(local_peer_id.clone());
 
-    // This builds a stream of messages coming from stdin.
+    // This builds an implementation of the `MuxedTransport` trait. All the connections are
+    // encrypted with secio and multiplexed with yamux or mplex, depending on `--muxer` and on what
+    // the remote supports.
+    let transport = platform.build_transport(&keypair, options.muxers);
+    let keypair = Rc::new(keypair);
+
+    // This builds a stream of messages coming from stdin, or from the input line of the UI. A
+    // daemon doesn't read anything.
+    #[cfg(not(target_os = "emscripten"))]
+    let stdin: Box<Stream<Item = String, Error = IoError>> = match tui {
+        Some(ref tui) => Box::new(tui.input()),
+        None if options.daemon => Box::new(futures::stream::empty()),
+        None => Box::new(platform.stdin()),
+    };
+    #[cfg(target_os = "emscripten")]
     let stdin = platform.stdin();
 
-    // Insert your code here!
+    // The addresses of the nodes we know about. Kademlia fills it with the nodes it discovers, and
+    // we add the nodes we connect to.
+    let peer_store = Arc::new(MemoryPeerstore::empty());
+    let connected = peers::ConnectedPeers::new();
+
+    // The nodes we've been connected to in the past. The browser can't access the filesystem, so
+    // there the address book only lives in memory.
+    let address_book = if cfg!(target_os = "emscripten") {
+        address_book::AddressBook::in_memory()
+    } else {
+        let path = options.peers_file.clone().unwrap_or_else(address_book::default_path);
+        address_book::AddressBook::open(path).expect("failed to open the address book")
+    };
+
+    // Every message we display is also written to the chat log.
+    let chat_log = if cfg!(target_os = "emscripten") {
+        chat_log::ChatLog::in_memory()
+    } else {
+        let path = options.log_file.clone().unwrap_or_else(chat_log::default_path);
+        let chat_log = chat_log::ChatLog::open(path).expect("failed to open the chat log");
+        if chat_log.malformed_lines() > 0 {
+            ui_eprintln!("Skipped {} malformed lines of the chat log", chat_log.malformed_lines());
+        }
+        chat_log
+    };
+
+    // What we tell other nodes about ourselves, and what they told us about themselves.
+    let pubsub_protocol = match options.pubsub {
+        PubSubRouter::FloodSub => FLOODSUB_PROTOCOL,
+        PubSubRouter::GossipSub => gossipsub::PROTOCOL_NAME,
+    };
+    let local_info = identify::LocalInfo::new(
+        keypair.public().to_protobuf_encoding(),
+        iter::once(pubsub_protocol).chain(PROTOCOLS.iter().cloned()).map(String::from).collect(),
+    );
+    let peer_infos = identify::PeerInfoStore::new();
+
+    let timer = tokio_timer::Timer::default();
+
+    // Both publish-subscribe routers are part of the upgrade, but only the one selected with
+    // `--pubsub` is ever negotiated.
+    let (floodsub_upgrade, floodsub_rx) = FloodSubUpgrade::new(local_peer_id.clone());
+    let (gossipsub_upgrade, gossipsub_rx) = {
+        let config = GossipSubConfig {
+            seen: options.dedup,
+            max_hops: options.max_hops,
+            ..Default::default()
+        };
+        GossipSubUpgrade::new(local_peer_id.clone(), config, &timer)
+    };
+    let pubsub_upgrade = {
+        let mut floodsub = upgrade::toggleable(floodsub_upgrade.clone());
+        let mut gossipsub = upgrade::toggleable(gossipsub_upgrade.clone());
+        match options.pubsub {
+            PubSubRouter::FloodSub => gossipsub.disable(),
+            PubSubRouter::GossipSub => floodsub.disable(),
+        }
+        upgrade::or(upgrade::map(gossipsub, box_future), upgrade::map(floodsub, box_future))
+    };
+
+    // The gossipsub heartbeat is driven by `gossipsub_rx`, so we drop it when it's not used.
+    let (rooms, pubsub_rx): (_, Box<Stream<Item = Message, Error = IoError>>) =
+        match options.pubsub {
+            PubSubRouter::FloodSub => {
+                (Rooms::new(FloodSubController::new(&floodsub_upgrade)), Box::new(floodsub_rx))
+            }
+            PubSubRouter::GossipSub => {
+                (Rooms::new(GossipSubController::new(&gossipsub_upgrade)), Box::new(gossipsub_rx))
+            }
+        };
+    rooms.join(DEFAULT_ROOM);
+
+    // The recent messages of the rooms we're in, which we send to the nodes that ask for them.
+    let history = history::History::new(history::DEFAULT_CAPACITY);
+    let (history_upgrade, history_rx) = history::HistoryUpgrade::new(history.clone());
+
+    // Direct messages, and the nicknames that `/msg` accepts.
+    let (direct_upgrade, direct_rx) = direct::DirectUpgrade::new();
+    let nicknames = direct::Nicknames::new();
+
+    // The messages we've recently displayed, in order to drop the copies of them.
+    let seen = Arc::new(Mutex::new(SeenCache::new(options.dedup)));
+
+    // The scripts that receive the messages we display through the control socket.
+    #[cfg(all(unix, not(target_os = "emscripten")))]
+    let rpc_subscriptions = rpc::Subscriptions::new();
+
+    // The files we offer to other nodes, and the ones they offer to us.
+    let download_dir = options
+        .download_dir
+        .clone()
+        .unwrap_or_else(file_transfer::default_download_dir);
+    let (transfers, transfer_events) = file_transfer::Transfers::new(download_dir);
+    let file_upgrade = file_transfer::FileTransferUpgrade::new(transfers.clone());
+
+    let kad_prototype = KademliaControllerPrototype::new(KademliaConfig {
+        parallelism: 3,
+        record_store: (),
+        peer_store: peer_store.clone(),
+        local_peer_id: local_peer_id.clone(),
+        timeout: Duration::from_secs(10),
+    });
+    let kad_upgrade = KademliaUpgrade::from_prototype(&kad_prototype);
+
+    // Each substream negotiates one of pubsub, ping, Kademlia, identify, history, direct messages
+    // or file transfer. When we dial, the first protocol that the remote supports is picked, which
+    // is why we build a separate transport for each protocol.
+    let pubsub_transport = transport
+        .clone()
+        .with_upgrade(upgrade::map(pubsub_upgrade.clone(), ProtocolOutput::PubSub));
+    let ping_transport = transport
+        .clone()
+        .with_upgrade(upgrade::map(Ping, ProtocolOutput::Ping));
+    let kad_transport = transport
+        .clone()
+        .with_upgrade(upgrade::map(kad_upgrade.clone(), ProtocolOutput::Kademlia));
+    let identify_transport = transport
+        .clone()
+        .with_upgrade(upgrade::map(IdentifyProtocolConfig, ProtocolOutput::Identify));
+    let final_transport = transport.clone().with_upgrade(upgrade::or(
+        upgrade::or(
+            upgrade::map(pubsub_upgrade, ProtocolOutput::PubSub),
+            upgrade::map(Ping, ProtocolOutput::Ping),
+        ),
+        upgrade::or(
+            upgrade::map(kad_upgrade, ProtocolOutput::Kademlia),
+            upgrade::or(
+                upgrade::map(IdentifyProtocolConfig, ProtocolOutput::Identify),
+                upgrade::or(
+                    upgrade::map(history_upgrade.clone(), ProtocolOutput::History),
+                    upgrade::or(
+                        upgrade::map(direct_upgrade.clone(), ProtocolOutput::Direct),
+                        upgrade::map(file_upgrade.clone(), ProtocolOutput::FileTransfer),
+                    ),
+                ),
+            ),
+        ),
+    ));
+
+    // The swarm handler sends on this channel the address of every node we get connected to, so
+    // that we identify it.
+    let (new_connections_tx, new_connections_rx) = mpsc::unbounded();
+
+    let (swarm_controller, swarm_future) = libp2p_core::swarm(final_transport, {
+        let timer = timer.clone();
+        let connected = connected.clone();
+        let peer_store = peer_store.clone();
+        let local_info = local_info.clone();
+        let peer_infos = peer_infos.clone();
+        let address_book = address_book.clone();
+        move |output, remote_addr| -> Box<Future<Item = (), Error = IoError>> {
+            match output {
+                ProtocolOutput::PubSub(pubsub_future) => {
+                    // We consider that we're connected to a node as long as we have a pubsub
+                    // substream with it.
+                    let remote_peer_id = match peer_addr::peer_id_of(&remote_addr) {
+                        Some(peer_id) => peer_id,
+                        None => return pubsub_future,
+                    };
+                    let dialable_addr = peer_addr::without_peer_id(&remote_addr);
+                    peer_store
+                        .peer_or_create(&remote_peer_id)
+                        .add_addr(dialable_addr.clone(), ADDRESS_TTL);
+                    if connected.insert(remote_peer_id.clone(), dialable_addr) {
+                        address_book.record_connection(&remote_peer_id);
+                        let _ = new_connections_tx.unbounded_send(remote_addr);
+                    }
+
+                    let connected = connected.clone();
+                    Box::new(pubsub_future.then(move |result| {
+                        connected.remove(&remote_peer_id);
+                        result
+                    }))
+                }
+                ProtocolOutput::Ping((pinger, ping_future)) => {
+                    Box::new(ping_future.select(ping_periodically(pinger, remote_addr, &timer))
+                        .map(|_| ())
+                        .map_err(|(err, _)| err))
+                }
+                ProtocolOutput::Kademlia(kad_future) => Box::new(kad_future),
+                ProtocolOutput::History(history_future) => history_future,
+                ProtocolOutput::Direct(direct_future) => direct_future,
+                ProtocolOutput::FileTransfer(transfer_future) => transfer_future,
+                ProtocolOutput::Identify(IdentifyOutput::RemoteInfo { info, observed_addr }) => {
+                    if let Some(remote_peer_id) = peer_addr::peer_id_of(&remote_addr) {
+                        // The listen addresses are more useful to Kademlia than the address the
+                        // remote connected from.
+                        let mut peer = peer_store.peer_or_create(&remote_peer_id);
+                        for addr in info.listen_addrs.iter() {
+                            peer.add_addr(addr.clone(), ADDRESS_TTL);
+                        }
+                        address_book.record_listen_addrs(&remote_peer_id, &info.listen_addrs);
+                        let info = identify::PeerInfo::from_identify(info, observed_addr);
+                        peer_infos.insert(remote_peer_id, info);
+                    }
+                    Box::new(future::ok(()))
+                }
+                ProtocolOutput::Identify(IdentifyOutput::Sender { sender }) => {
+                    let observed_addr = peer_addr::without_peer_id(&remote_addr);
+                    Box::new(sender.send(local_info.to_identify(), &observed_addr))
+                }
+            }
+        }
+    });
+
+    let (kad_controller, kad_init) = kad_prototype.start(
+        swarm_controller.clone(),
+        kad_transport.clone(),
+        |output| match output {
+            ProtocolOutput::Kademlia(kad_future) => kad_future,
+            _ => unreachable!("kad_transport only negotiates Kademlia"),
+        },
+    );
+
+    if cfg!(not(target_os = "emscripten")) {
+        for addr in options.listen.iter() {
+            let addr: Multiaddr = addr.parse().expect("invalid multiaddress to listen on");
+            let actual_addr = swarm_controller.listen_on(addr).expect("failed to listen");
+            ui_println!("Now listening on {}", actual_addr);
+            local_info.add_listen_addr(actual_addr);
+        }
+    }
+
+    // Opens a pubsub substream with the node at the given address. The other protocols are
+    // opened once the connection is established, see `identify_future`.
+    let dial = {
+        let swarm_controller = swarm_controller.clone();
+        Rc::new(move |addr: Multiaddr| -> Result<(), Multiaddr> {
+            swarm_controller.dial(addr, pubsub_transport.clone())?;
+            Ok(())
+        })
+    };
+
+    // The nodes passed on the command line are the entry points into the DHT.
+    for addr in options.to_dial.iter() {
+        let addr: Multiaddr = match addr.parse() {
+            Ok(addr) => addr,
+            Err(_) => {
+                ui_eprintln!("Invalid multiaddress: {}", addr);
+                continue;
+            }
+        };
+        if let Err(addr) = dial(addr) {
+            ui_eprintln!("Unsupported multiaddress: {}", addr);
+        }
+    }
+
+    // Dial again the nodes we've recently been connected to.
+    let redial_future = {
+        let redials = address_book
+            .recent_peers(REDIAL_MAX_AGE)
+            .into_iter()
+            .filter(|&(_, ref addrs)| !addrs.is_empty())
+            .take(options.target_peers)
+            .map(|(peer_id, addrs)| {
+                address_book.record_dial_attempt(&peer_id);
+                redial(peer_id, addrs, connected.clone(), &timer, dial.clone())
+            })
+            .collect::<Vec<_>>();
+        future::join_all(redials).map(|_| ())
+    };
+
+    // Discover the nodes of the local network.
+    let mdns_future = if options.mdns {
+        let connected = connected.clone();
+        let peer_store = peer_store.clone();
+        let dial = dial.clone();
+        let target_peers = options.target_peers;
+        let on_discovered = move |peer_id, addrs: Vec<Multiaddr>| {
+            for addr in addrs.iter() {
+                peer_store.peer_or_create(&peer_id).add_addr(addr.clone(), ADDRESS_TTL);
+            }
+            if connected.contains(&peer_id) || connected.len() >= target_peers {
+                return;
+            }
+            let _ = addrs
+                .into_iter()
+                .any(|addr| dial(peer_addr::with_peer_id(addr, &peer_id)).is_ok());
+        };
+        mdns_discovery(&platform, &timer, local_peer_id.clone(), local_info.clone(), on_discovered)
+    } else {
+        Box::new(future::empty())
+    };
+
+    let address_book_future = timer
+        .interval(ADDRESS_BOOK_SAVE_INTERVAL)
+        .map_err(|err| IoError::new(IoErrorKind::Other, err))
+        .for_each({
+            let address_book = address_book.clone();
+            move |_| {
+                if let Err(err) = address_book.save() {
+                    ui_eprintln!("Failed to save the address book: {}", err);
+                }
+                Ok(())
+            }
+        });
+
+    // The chat log is flushed a last time when the node stops.
+    let final_chat_log = chat_log.clone();
+    let chat_log_future = timer
+        .interval(CHAT_LOG_SYNC_INTERVAL)
+        .map_err(|err| IoError::new(IoErrorKind::Other, err))
+        .for_each({
+            let chat_log = chat_log.clone();
+            move |_| {
+                if let Err(err) = chat_log.sync() {
+                    ui_eprintln!("Failed to write the chat log: {}", err);
+                }
+                Ok(())
+            }
+        });
+
+    // The addresses to dial in order to open a substream with a node: the address of the existing
+    // connection if there is one, or else all the addresses we know.
+    let known_addrs = {
+        let connected = connected.clone();
+        let peer_store = peer_store.clone();
+        let address_book = address_book.clone();
+        Rc::new(move |peer_id: &PeerId| -> Vec<Multiaddr> {
+            if let Some(addr) = connected.addr(peer_id) {
+                return vec![peer_addr::with_peer_id(addr, peer_id)];
+            }
+            let mut addrs = address_book.addrs(peer_id);
+            if let Some(peer) = peer_store.peer(peer_id) {
+                for addr in peer.addrs() {
+                    if !addrs.contains(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+            }
+            addrs
+                .into_iter()
+                .map(|addr| peer_addr::with_peer_id(addr, peer_id))
+                .collect()
+        })
+    };
+
+    // Sends a direct message to a node, by dialing all its known addresses. Returns `None` if we
+    // don't know any address of the node.
+    let send_direct: Rc<Fn(PeerId, Vec<u8>) -> Option<Box<Future<Item = (), Error = IoError>>>> = {
+        let swarm_controller = swarm_controller.clone();
+        let transport = transport.clone();
+        let known_addrs = known_addrs.clone();
+        let timer = timer.clone();
+        Rc::new(move |recipient: PeerId, data: Vec<u8>| {
+            let addrs = known_addrs(&recipient);
+            let (upgrade, delivery) = direct_upgrade.send(recipient, data, &timer);
+            let direct_transport = transport
+                .clone()
+                .with_upgrade(upgrade::map(upgrade, ProtocolOutput::Direct));
+            let dialed = addrs
+                .into_iter()
+                .filter(|addr| {
+                    swarm_controller.dial(addr.clone(), direct_transport.clone()).is_ok()
+                })
+                .count();
+            if dialed == 0 {
+                return None;
+            }
+            let delivery: Box<Future<Item = (), Error = IoError>> = Box::new(delivery);
+            Some(delivery)
+        })
+    };
+
+    // Downloads the file `id` from its sender, once the offer has been accepted. Unlike direct
+    // messages, we stop at the first address we can dial, as several substreams would download the
+    // file concurrently. Returns a future that interrupts the download if the dial doesn't lead to
+    // a substream, as we aren't told when it fails.
+    let download = {
+        let swarm_controller = swarm_controller.clone();
+        let transport = transport.clone();
+        let transfers = transfers.clone();
+        let timer = timer.clone();
+        Rc::new(move |sender: PeerId, id: u64| -> Box<Future<Item = (), Error = IoError>> {
+            let upgrade = file_upgrade.with_download(sender.clone(), id);
+            let file_transport = transport
+                .clone()
+                .with_upgrade(upgrade::map(upgrade, ProtocolOutput::FileTransfer));
+            let dialed = known_addrs(&sender)
+                .into_iter()
+                .any(|addr| swarm_controller.dial(addr, file_transport.clone()).is_ok());
+            if !dialed {
+                let msg = "we don't know any address of the sender";
+                transfers.interrupt_download(&sender, id, IoError::new(IoErrorKind::NotFound, msg));
+                return Box::new(future::ok(()));
+            }
+            let transfers = transfers.clone();
+            Box::new(timer.sleep(file_transfer::CONNECT_TIMEOUT).then(move |_| {
+                transfers.check_connected(&sender, id);
+                Ok(())
+            }))
+        })
+    };
+
+    // Asks the node at the given address for the messages of the given rooms that are more recent
+    // than the ones in our history.
+    let request_history = {
+        let swarm_controller = swarm_controller.clone();
+        let history = history.clone();
+        Rc::new(move |addr: Multiaddr, rooms: Vec<String>| {
+            let upgrade = history_upgrade.with_request(history.request(rooms));
+            let history_transport = transport
+                .clone()
+                .with_upgrade(upgrade::map(upgrade, ProtocolOutput::History));
+            let _ = swarm_controller.dial(addr, history_transport);
+        })
+    };
+
+    // Ping, identify and add to Kademlia every node we get connected to, and fetch the history of
+    // our rooms from it. The address ends with the `PeerId` of the node, which makes the dials
+    // reuse the existing connection.
+    let identify_future = {
+        let swarm_controller = swarm_controller.clone();
+        let request_history = request_history.clone();
+        let rooms = rooms.clone();
+        new_connections_rx
+            .map_err(|()| -> IoError { unreachable!() })
+            .for_each(move |remote_addr: Multiaddr| {
+                let _ = swarm_controller.dial(remote_addr.clone(), ping_transport.clone());
+                let _ = swarm_controller.dial(remote_addr.clone(), kad_transport.clone());
+                let _ = swarm_controller.dial(remote_addr.clone(), identify_transport.clone());
+                request_history(remote_addr, rooms.names());
+                Ok(())
+            })
+    };
+
+    let discovery_future = discovery::random_walk(
+        discovery::DiscoveryConfig {
+            target_peers: options.target_peers,
+            ..Default::default()
+        },
+        local_peer_id.clone(),
+        move |target| kad_controller.find_node(target),
+        peer_store.clone(),
+        connected.clone(),
+        {
+            let dial = dial.clone();
+            move |addr| dial(addr)
+        },
+        &timer,
+    );
+
+    let composer = Composer::new(local_peer_id.clone());
+    let presence = Presence::new();
+    let verify_mode = options.verify_mode;
+    let pubsub_rx = pubsub_rx.for_each({
+        let rooms = rooms.clone();
+        let composer = composer.clone();
+        let history = history.clone();
+        let chat_log = chat_log.clone();
+        let nicknames = nicknames.clone();
+        let group_keys = group_keys.clone();
+        let keypair = keypair.clone();
+        let presence = presence.clone();
+        let seen = seen.clone();
+        #[cfg(all(unix, not(target_os = "emscripten")))]
+        let rpc_subscriptions = rpc_subscriptions.clone();
+        move |msg| {
+            // Presence announcements only update the list of members of the room.
+            if let Some(room) = rooms.presence_room_of(&msg.topics) {
+                let topic = rooms::presence_topic(&room);
+                let message = signed::verify(&msg.source, &topic, &msg.data)
+                    .ok()
+                    .and_then(|payload| ChatMessage::decode(&payload).ok());
+                match message {
+                    Some(ref message) if message.author == msg.source => {
+                        nicknames.insert(&message.nickname, &message.author);
+                        if let Some(notice) = presence.on_message(&room, message) {
+                            ui_println_in!(notice.room(), "{}", notice);
+                        }
+                    }
+                    _ => {}
+                }
+                return Ok(());
+            }
+
+            let room = match rooms.room_of(&msg.topics) {
+                Some(room) => room,
+                None => return Ok(()),
+            };
+            let topic = rooms::room_topic(&room);
+            let received = match open_message(&msg.source, &topic, &msg.data, verify_mode) {
+                Some(received) => received,
+                None => return Ok(()),
+            };
+
+            if let Some(ref message) = received.message {
+                if is_duplicate(&seen, &msg.source, message, received.verified) {
+                    return Ok(());
+                }
+                composer.observe(message);
+                if received.verified {
+                    nicknames.insert(&message.nickname, &message.author);
+                    history.insert(&room, history::Entry {
+                        source: msg.source.clone(),
+                        data: msg.data.clone(),
+                        message: message.clone(),
+                    });
+                }
+            }
+
+            let received = match received.message.clone() {
+                Some(ref message) if is_key_message(message.kind) => {
+                    if received.verified {
+                        handle_key_message(
+                            &rooms,
+                            &group_keys,
+                            &composer,
+                            &keypair,
+                            &room,
+                            message,
+                        );
+                    }
+                    return Ok(());
+                }
+                Some(ref message) if message.kind == Kind::Encrypted => {
+                    match decrypt_message(&group_keys, &room, message, received.verified) {
+                        Ok(decrypted) => decrypted,
+                        Err(GroupError::NoKey) => {
+                            ui_println_in!(&room, "{} (/requestkey to ask for the key)", received);
+                            return Ok(());
+                        }
+                        Err(err) => {
+                            ui_println_in!(&room, "{} ({})", received, err);
+                            return Ok(());
+                        }
+                    }
+                }
+                _ => received,
+            };
+            ui_println_in!(&room, "{}", received);
+            log_message(&chat_log, &room, &msg.source, &received);
+            #[cfg(all(unix, not(target_os = "emscripten")))]
+            notify_message(&rpc_subscriptions, Some(&room), &msg.source, &received);
+            Ok(())
+        }
+    });
+
+    // The messages that other nodes send from their history. We display the ones we didn't know
+    // about every `HISTORY_DISPLAY_INTERVAL`, sorted, so that the answers of all the nodes are
+    // merged together.
+    let history_future = {
+        let composer = composer.clone();
+        let history = history.clone();
+        let chat_log = chat_log.clone();
+        let nicknames = nicknames.clone();
+        let group_keys = group_keys.clone();
+        let mut backlog = Vec::new();
+        let display = timer
+            .interval(HISTORY_DISPLAY_INTERVAL)
+            .map(|()| None)
+            .map_err(|err| IoError::new(IoErrorKind::Other, err));
+        history_rx
+            .map(Some)
+            .map_err(|()| -> IoError { unreachable!() })
+            .select(display)
+            .for_each(move |reply| {
+                match reply {
+                    Some(reply) => {
+                        let topic = rooms::room_topic(&reply.room);
+                        let received =
+                            match open_message(&reply.source, &topic, &reply.data, verify_mode) {
+                                Some(received) => received,
+                                None => return Ok(()),
+                            };
+                        // Messages that aren't `ChatMessage`s aren't stored in the history.
+                        let message = match received.message {
+                            Some(ref message) if received.verified => message.clone(),
+                            _ => return Ok(()),
+                        };
+                        let entry = history::Entry {
+                            source: reply.source,
+                            data: reply.data,
+                            message: message,
+                        };
+                        if history.insert(&reply.room, entry.clone()) {
+                            backlog.push((reply.room, entry));
+                        }
+                    }
+                    None => {
+                        backlog.sort_by_key(|&(_, ref entry): &(String, history::Entry)| {
+                            (entry.message.lamport, entry.message.timestamp)
+                        });
+                        for (room, entry) in backlog.drain(..) {
+                            let message = entry.message;
+                            composer.observe(&message);
+                            nicknames.insert(&message.nickname, &message.author);
+                            // Catch up with the keys of the encrypted rooms, but don't answer
+                            // requests or departures that might be long over.
+                            let received = match message.kind {
+                                Kind::KeyUpdate => {
+                                    let (author, payload) = (&message.author, &message.payload);
+                                    let outcome = group_keys.on_update(&room, author, payload);
+                                    if outcome == Ok(UpdateOutcome::Offered) {
+                                        print_key_offer(&room, &message);
+                                    }
+                                    continue;
+                                }
+                                kind if is_key_message(kind) => continue,
+                                Kind::Encrypted => {
+                                    match decrypt_message(&group_keys, &room, &message, true) {
+                                        Ok(decrypted) => decrypted,
+                                        Err(_) => {
+                                            ui_println_in!(&room, "(history) {}", message);
+                                            continue;
+                                        }
+                                    }
+                                }
+                                _ => Received {
+                                    text: message.to_string(),
+                                    message: Some(message),
+                                    verified: true,
+                                },
+                            };
+                            ui_println_in!(&room, "(history) {}", received);
+                            log_message(&chat_log, &room, &entry.source, &received);
+                        }
+                    }
+                }
+                Ok(())
+            })
+    };
+
+    // The direct messages that other nodes send us.
+    let direct_future = direct_rx
+        .map_err(|()| -> IoError { unreachable!() })
+        .for_each({
+            let composer = composer.clone();
+            let nicknames = nicknames.clone();
+            let transfers = transfers.clone();
+            let seen = seen.clone();
+            let local_peer_id = local_peer_id.clone();
+            #[cfg(all(unix, not(target_os = "emscripten")))]
+            let rpc_subscriptions = rpc_subscriptions.clone();
+            move |mut incoming| {
+                let topic = direct::signing_topic(&local_peer_id);
+                let received =
+                    match open_message(&incoming.source, &topic, &incoming.data, verify_mode) {
+                        Some(received) => received,
+                        None => return Ok(()),
+                    };
+                // The sender only considers the message delivered if we could verify it.
+                if received.verified {
+                    incoming.acknowledge();
+                }
+                if let Some(ref message) = received.message {
+                    // The sender tries again if our acknowledgement gets lost.
+                    if is_duplicate(&seen, &incoming.source, message, received.verified) {
+                        return Ok(());
+                    }
+                    composer.observe(message);
+                    if received.verified {
+                        nicknames.insert(&message.nickname, &message.author);
+                    }
+                    let offer = match message.kind {
+                        Kind::FileOffer if received.verified => {
+                            file_transfer::Offer::decode(&message.payload)
+                        }
+                        _ => None,
+                    };
+                    if let Some(offer) = offer {
+                        ui_println_in!("direct", "{} offers {} ({} bytes); /accept {:016x} to \
+                                                  download it",
+                                       message::sanitize(&message.nickname),
+                                       message::sanitize(&offer.name), offer.size, offer.id);
+                        transfers.on_offer(message.author.clone(), offer);
+                        return Ok(());
+                    }
+                }
+                ui_println_in!("direct", "{}", received);
+                #[cfg(all(unix, not(target_os = "emscripten")))]
+                notify_message(&rpc_subscriptions, None, &incoming.source, &received);
+                Ok(())
+            }
+        });
+
+    // Runs the background tasks of the commands: reports whether the direct messages we sent have
+    // been acknowledged, sends the offers of the files once they're hashed, and watches the
+    // downloads that are starting.
+    let (tasks_tx, tasks_rx) = mpsc::unbounded::<Box<Future<Item = (), Error = IoError>>>();
+    let tasks_future = tasks_rx
+        .map_err(|()| -> IoError { unreachable!() })
+        .buffer_unordered(MAX_PENDING_TASKS)
+        .for_each(|()| Ok(()));
+
+    // Reports the progress of our downloads, and resumes the interrupted ones.
+    let transfer_events_future = {
+        let transfers = transfers.clone();
+        let download = download.clone();
+        let timer = timer.clone();
+        let mut retries = HashMap::new();
+        transfer_events
+            .map_err(|()| -> IoError { unreachable!() })
+            .map(move |event| -> Box<Future<Item = (), Error = IoError>> {
+                let (sender, id) = match event {
+                    file_transfer::Event::Completed { sender, id, path } => {
+                        ui_println!("Download {:016x} complete: saved as {}", id, path.display());
+                        retries.remove(&(sender, id));
+                        return Box::new(future::ok(()));
+                    }
+                    file_transfer::Event::Interrupted { sender, id, received, error } => {
+                        ui_println!("Download {:016x} interrupted after {} bytes: {}", id, received,
+                                    error);
+                        (sender, id)
+                    }
+                    file_transfer::Event::Corrupted { sender, id } => {
+                        ui_println!("Download {:016x} doesn't match the offer; starting again", id);
+                        (sender, id)
+                    }
+                };
+
+                let attempts = {
+                    let attempts = retries.entry((sender.clone(), id)).or_insert(0);
+                    *attempts += 1;
+                    *attempts
+                };
+                if attempts > DOWNLOAD_RETRIES {
+                    ui_println!("Giving up on download {:016x}; /accept {:016x} {} to resume it",
+                                id, id, sender.to_base58());
+                    retries.remove(&(sender, id));
+                    return Box::new(future::ok(()));
+                }
+                let transfers = transfers.clone();
+                let download = download.clone();
+                Box::new(timer.sleep(DOWNLOAD_RETRY_DELAY).then(move |_| {
+                    match transfers.accept(id, Some(&sender)) {
+                        Ok(sender) => future::Either::A(download(sender, id)),
+                        Err(_) => future::Either::B(future::ok(())),
+                    }
+                }))
+            })
+            .buffer_unordered(MAX_PENDING_TASKS)
+            .for_each(|()| Ok(()))
+    };
+
+    // Every `HEARTBEAT_INTERVAL`, announce that we're still in our rooms, and forget about the
+    // members that didn't do the same for too long.
+    let heartbeat_future = timer
+        .interval(presence::HEARTBEAT_INTERVAL)
+        .map_err(|err| IoError::new(IoErrorKind::Other, err))
+        .for_each({
+            let rooms = rooms.clone();
+            let composer = composer.clone();
+            let keypair = keypair.clone();
+            let presence = presence.clone();
+            let group_keys = group_keys.clone();
+            move |()| {
+                for room in rooms.names() {
+                    announce(&rooms, &keypair, &composer, &room, Status::Here);
+                }
+                for notice in presence.expire() {
+                    ui_println_in!(notice.room(), "{}", notice);
+                    // A member that disappeared without leaving mustn't read what follows either.
+                    if let Notice::TimedOut { ref room, ref peer_id, .. } = notice {
+                        if let Some(update) = group_keys.on_leave(room, peer_id) {
+                            publish_to(&rooms, &composer, &keypair, room, Kind::KeyUpdate, update);
+                        }
+                    }
+                }
+                Ok(())
+            }
+        });
+
+    // `/quit` resolves this future, which makes the node stop.
+    let (quit_tx, quit_rx) = oneshot::channel();
+    let quit_future = quit_rx.then(|result| -> Box<Future<Item = (), Error = IoError>> {
+        match result {
+            Ok(()) => Box::new(future::ok(())),
+            // The sender is dropped when stdin closes, in which case we keep running.
+            Err(_) => Box::new(future::empty()),
+        }
+    });
+    let mut quit_tx = Some(quit_tx);
+
+    // The sidebar and the status bar of the UI.
+    #[cfg(not(target_os = "emscripten"))]
+    {
+        if let Some(ref tui) = tui {
+            let rooms = rooms.clone();
+            let connected = connected.clone();
+            let presence = presence.clone();
+            let local_info = local_info.clone();
+            tui.set_overview(move || {
+                let active_room = rooms.active();
+                ui::Overview {
+                    members: active_room
+                        .as_ref()
+                        .map(|room| presence.who(room))
+                        .unwrap_or_default(),
+                    active_room: active_room,
+                    rooms: rooms.names(),
+                    peers: connected
+                        .list()
+                        .into_iter()
+                        .map(|(peer_id, _)| message::default_nickname(&peer_id))
+                        .collect(),
+                    listen_addrs: local_info.listen_addrs().iter().map(|a| a.to_string()).collect(),
+                }
+            });
+        }
+    }
+
+    // What we do when we join or leave a room, be it with a command or through the control
+    // socket.
+    let on_join: Rc<Fn(&str)> = {
+        let rooms = rooms.clone();
+        let keypair = keypair.clone();
+        let composer = composer.clone();
+        let connected = connected.clone();
+        let request_history = request_history.clone();
+        Rc::new(move |room: &str| {
+            announce(&rooms, &keypair, &composer, room, Status::Here);
+            // Fetch what was said in the room before we joined it.
+            for (peer_id, addr) in connected.list() {
+                let addr = peer_addr::with_peer_id(addr, &peer_id);
+                request_history(addr, vec![room.to_owned()]);
+            }
+        })
+    };
+    let on_leave: Rc<Fn(&str)> = {
+        let rooms = rooms.clone();
+        let keypair = keypair.clone();
+        let composer = composer.clone();
+        let presence = presence.clone();
+        let group_keys = group_keys.clone();
+        Rc::new(move |room: &str| {
+            announce(&rooms, &keypair, &composer, room, Status::Leaving);
+            presence.forget_room(room);
+            // The remaining members must replace the key, so that we can't read what follows.
+            if group_keys.forget(room) {
+                let kind = Kind::KeyLeave;
+                publish_to(&rooms, &composer, &keypair, room, kind, Vec::new());
+            }
+        })
+    };
+
+    // When the node is asked to stop, we leave our rooms, which unsubscribes from their topics,
+    // and keep running for `SHUTDOWN_DELAY` so that the other nodes receive our last messages.
+    // The connections are closed when the node stops.
+    let shutdown_future = quit_future
+        .select(termination_signal(&platform))
+        .map_err(|(err, _)| err)
+        .and_then({
+            let rooms = rooms.clone();
+            let on_leave = on_leave.clone();
+            let timer = timer.clone();
+            move |_| {
+                ui_println!("Leaving all the rooms and closing the connections");
+                for room in rooms.names() {
+                    on_leave(&room);
+                    rooms.leave(&room);
+                }
+                timer.sleep(SHUTDOWN_DELAY).map_err(|err| IoError::new(IoErrorKind::Other, err))
+            }
+        });
+
+    // The requests of the scripts that control the node. See the `rpc` module.
+    #[cfg(all(unix, not(target_os = "emscripten")))]
+    let rpc_future = if let Some((socket, path)) = control_socket {
+        let handler: rpc::Handler = {
+            let rooms = rooms.clone();
+            let group_keys = group_keys.clone();
+            let composer = composer.clone();
+            let history = history.clone();
+            let chat_log = chat_log.clone();
+            let keypair = keypair.clone();
+            let connected = connected.clone();
+            let local_info = local_info.clone();
+            let dial = dial.clone();
+            let on_join = on_join.clone();
+            let on_leave = on_leave.clone();
+            Rc::new(move |request: rpc::Request| match request {
+                rpc::Request::Publish { room, text } => {
+                    let room = match room.or_else(|| rooms.active()) {
+                        Some(ref room) if !rooms.contains(room) => {
+                            return Err(rpc::RpcError::failed(format!("not in room {}", room)));
+                        }
+                        Some(room) => room,
+                        None => return Err(rpc::RpcError::failed("not in any room")),
+                    };
+                    let message = composer.compose(Kind::Text, text.into_bytes());
+                    ui_println_in!(&room, "{}", message);
+                    publish(&rooms, &group_keys, &history, &chat_log, &keypair, &room, message);
+                    Ok(rpc::Reply::Published { room: room })
+                }
+                // Scripts joining rooms mustn't change where the lines we type are sent.
+                rpc::Request::Subscribe(ref room) if !rooms::is_valid_name(room) => {
+                    Err(rpc::RpcError::failed("invalid room name"))
+                }
+                rpc::Request::Subscribe(room) => {
+                    let joined = rooms.join_in_background(&room);
+                    if joined {
+                        ui_println!("Joined room {}", room);
+                        on_join(&room);
+                    }
+                    Ok(rpc::Reply::Subscribed { joined: joined })
+                }
+                rpc::Request::Unsubscribe(room) => {
+                    let left = rooms.contains(&room);
+                    if left {
+                        on_leave(&room);
+                        rooms.leave(&room);
+                        ui_println!("Left room {}", room);
+                    }
+                    Ok(rpc::Reply::Unsubscribed { left: left })
+                }
+                rpc::Request::Rooms => Ok(rpc::Reply::Rooms {
+                    active: rooms.active(),
+                    rooms: rooms.names(),
+                }),
+                rpc::Request::Dial(addr) => match dial(addr.clone()) {
+                    Ok(()) => {
+                        ui_println!("Dialing {}", addr);
+                        Ok(rpc::Reply::Dialing)
+                    }
+                    Err(addr) => {
+                        let msg = format!("unsupported multiaddress: {}", addr);
+                        Err(rpc::RpcError::failed(msg))
+                    }
+                },
+                rpc::Request::Peers => Ok(rpc::Reply::Peers(
+                    connected
+                        .list()
+                        .into_iter()
+                        .map(|(peer_id, addr)| rpc::Peer {
+                            peer_id: peer_id.to_base58(),
+                            addr: addr.to_string(),
+                        })
+                        .collect(),
+                )),
+                rpc::Request::Listeners => Ok(rpc::Reply::Listeners(
+                    local_info.listen_addrs().iter().map(|addr| addr.to_string()).collect(),
+                )),
+            })
+        };
+        rpc_server(&platform, socket, &path, handler, rpc_subscriptions)
+    } else {
+        Box::new(future::empty())
+    };
+    // The browser can't listen on a socket.
+    #[cfg(not(all(unix, not(target_os = "emscripten"))))]
+    let rpc_future = future::empty::<(), IoError>();
+
+    // The lines that can't be read don't stop the node; we report them and carry on.
+    let stdin_future = stdin.then(|line| Ok::<_, IoError>(line)).for_each(move |line| {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                ui_println!("Ignoring a line of stdin: {}", err);
+                return Ok(());
+            }
+        };
+        let command = match commands::parse(&line) {
+            Ok(Input::Message(text)) => {
+                let message = composer.compose(Kind::Text, text.into_bytes());
+                match rooms.active() {
+                    Some(room) => {
+                        publish(&rooms, &group_keys, &history, &chat_log, &keypair, &room, message)
+                    }
+                    None => ui_println!("Not in any room; use /join <room> first"),
+                }
+                return Ok(());
+            }
+            Ok(Input::Command(command)) => command,
+            Err(err) => {
+                ui_println!("{}", err);
+                return Ok(());
+            }
+        };
+
+        match command {
+            Command::Help(None) => {
+                for info in commands::COMMANDS {
+                    ui_println!("  {}", info);
+                }
+                ui_println!("Lines that don't start with a slash are sent to the active room; \
+                             start a line with // to send it with a single slash.");
+            }
+            Command::Help(Some(name)) => match commands::info(&name) {
+                Some(info) => ui_println!("  {}", info),
+                None => ui_println!("Unknown command {}; /help lists the commands", name),
+            },
+            Command::Quit => {
+                if let Some(quit_tx) = quit_tx.take() {
+                    let _ = quit_tx.send(());
+                }
+            }
+            Command::Dial(addr) => match dial(addr.clone()) {
+                Ok(()) => ui_println!("Dialing {}", addr),
+                Err(addr) => ui_println!("Unsupported multiaddress: {}", addr),
+            },
+            Command::Listen(addr) => {
+                if cfg!(target_os = "emscripten") {
+                    ui_println!("The browser can't listen on any address");
+                } else {
+                    match swarm_controller.listen_on(addr) {
+                        Ok(actual_addr) => {
+                            ui_println!("Now listening on {}", actual_addr);
+                            local_info.add_listen_addr(actual_addr);
+                        }
+                        Err(addr) => ui_println!("Can't listen on {}", addr),
+                    }
+                }
+            }
+            Command::Peers => {
+                let peers = connected.list();
+                if peers.is_empty() {
+                    ui_println!("Not connected to any node");
+                }
+                for (peer_id, addr) in peers {
+                    ui_println!("  {} at {}", peer_id.to_base58(), addr);
+                }
+            }
+            Command::PeerInfo(peer_id) => print_peer_info(&peer_infos, &peer_id),
+            Command::Topic(None) => match rooms.active() {
+                Some(room) => ui_println!("Messages are sent to {}", room),
+                None => ui_println!("Not in any room"),
+            },
+            Command::Topic(Some(room)) => {
+                if rooms.contains(&room) {
+                    rooms.join(&room);
+                    ui_println!("Messages are now sent to {}", room);
+                } else {
+                    ui_println!("Not in room {}; /join it first", room);
+                }
+            }
+            Command::Me(action) => {
+                let message = composer.compose(Kind::Action, action.into_bytes());
+                match rooms.active() {
+                    Some(room) => {
+                        publish(&rooms, &group_keys, &history, &chat_log, &keypair, &room, message)
+                    }
+                    None => ui_println!("Not in any room; use /join <room> first"),
+                }
+            }
+            Command::Presence(command) => {
+                for line in presence.execute(command, &rooms, &keypair, &composer) {
+                    ui_println!("{}", line);
+                }
+            }
+            Command::Msg { to, text } => match nicknames.resolve(&to) {
+                Ok(recipient) => {
+                    let message = composer.compose(Kind::Text, text.into_bytes());
+                    let topic = direct::signing_topic(&recipient);
+                    let data = signed::sign(&keypair, &topic, &message.encode());
+                    match send_direct(recipient, data) {
+                        Some(delivery) => {
+                            let report = report_delivery(delivery, "Message", to);
+                            let _ = tasks_tx.unbounded_send(report);
+                        }
+                        None => {
+                            ui_println!("Can't reach {}: we don't know any of its addresses", to)
+                        }
+                    }
+                }
+                Err(err) => ui_println!("Can't send the message: {}", err),
+            },
+            Command::Send { to, path } => match nicknames.resolve(&to) {
+                Ok(recipient) => {
+                    // The offer is sent once the file is hashed, which can take a while.
+                    let composer = composer.clone();
+                    let keypair = keypair.clone();
+                    let send_direct = send_direct.clone();
+                    let offered = transfers.offer(recipient.clone(), &path);
+                    let task = offered.then(move |result| {
+                        let offer = match result {
+                            Ok(offer) => offer,
+                            Err(err) => {
+                                ui_println!("Can't send {}: {}", path.display(), err);
+                                return future::Either::A(future::ok(()));
+                            }
+                        };
+                        let message = composer.compose(Kind::FileOffer, offer.encode());
+                        let topic = direct::signing_topic(&recipient);
+                        let data = signed::sign(&keypair, &topic, &message.encode());
+                        match send_direct(recipient, data) {
+                            Some(delivery) => {
+                                future::Either::B(report_delivery(delivery, "File offer", to))
+                            }
+                            None => {
+                                ui_println!("Can't reach {}: we don't know any of its addresses",
+                                            to);
+                                future::Either::A(future::ok(()))
+                            }
+                        }
+                    });
+                    let _ = tasks_tx.unbounded_send(Box::new(task));
+                }
+                Err(err) => ui_println!("Can't send {}: {}", path.display(), err),
+            },
+            Command::Accept { id, sender } => match transfers.accept(id, sender.as_ref()) {
+                Ok(sender) => {
+                    ui_println!("Downloading {:016x}", id);
+                    let _ = tasks_tx.unbounded_send(download(sender, id));
+                }
+                Err(err) => ui_println!("Can't download {:016x}: {}", id, err),
+            },
+            Command::Transfers => {
+                let lines = transfers.describe();
+                if lines.is_empty() {
+                    ui_println!("No file transfers");
+                }
+                for line in lines {
+                    ui_println!("  {}", line);
+                }
+            }
+            Command::Encrypt => match rooms.active() {
+                Some(ref room) if group_keys.create(room) => {
+                    ui_println!("Messages in {} are now end-to-end encrypted; the nodes that ask \
+                                 for the key can be let in with /invite <peer-id>", room);
+                }
+                Some(room) => ui_println!("{} is already encrypted", room),
+                None => ui_println!("Not in any room"),
+            },
+            Command::Invite(peer_id) => match rooms.active() {
+                Some(room) => match group_keys.invite(&room, &peer_id) {
+                    Ok(update) => {
+                        publish_to(&rooms, &composer, &keypair, &room, Kind::KeyUpdate, update);
+                        ui_println!("Invited {} to {}", peer_id.to_base58(), room);
+                    }
+                    Err(err) => {
+                        ui_println!("Can't invite {} to {}: {}", peer_id.to_base58(), room, err)
+                    }
+                },
+                None => ui_println!("Not in any room"),
+            },
+            Command::Remove(peer_id) => match rooms.active() {
+                Some(room) => match group_keys.remove(&room, &peer_id) {
+                    Ok(update) => {
+                        publish_to(&rooms, &composer, &keypair, &room, Kind::KeyUpdate, update);
+                        ui_println!("Removed {} from {}", peer_id.to_base58(), room);
+                    }
+                    Err(err) => {
+                        ui_println!("Can't remove {} from {}: {}", peer_id.to_base58(), room, err)
+                    }
+                },
+                None => ui_println!("Not in any room"),
+            },
+            Command::RequestKey => match rooms.active() {
+                Some(room) => match group_keys.request(&room) {
+                    Some(request) => {
+                        publish_to(&rooms, &composer, &keypair, &room, Kind::KeyRequest, request);
+                        ui_println!("Asked the members of {} for its key", room);
+                    }
+                    None => ui_println!("We already have the key of {}", room),
+                },
+                None => ui_println!("Not in any room"),
+            },
+            Command::AcceptKey(peer_id) => match rooms.active() {
+                Some(room) => match group_keys.accept(&room, &peer_id) {
+                    Ok(()) => ui_println_in!(&room, "-- messages are now end-to-end encrypted"),
+                    Err(err) => ui_println!("Can't use the key of {}: {}", room, err),
+                },
+                None => ui_println!("Not in any room"),
+            },
+            Command::Members => match rooms.active() {
+                Some(ref room) if group_keys.is_encrypted(room) => {
+                    for peer_id in group_keys.members(room) {
+                        ui_println!("  {}", peer_id.to_base58());
+                    }
+                }
+                Some(room) => ui_println!("{} isn't encrypted; anyone can read it", room),
+                None => ui_println!("Not in any room"),
+            },
+            Command::Search(terms) => {
+                let found = chat_log.search(&terms, SEARCH_RESULTS);
+                if found.is_empty() {
+                    ui_println!("No message of the log matches {}", terms);
+                }
+                for logged in found {
+                    print_logged(&logged);
+                }
+            }
+            Command::History { room, count } => {
+                print_log_page(&chat_log, &room, count.unwrap_or(HISTORY_PAGE_SIZE))
+            }
+            Command::Room(command) => {
+                for line in rooms.execute(command, &*on_join, &*on_leave) {
+                    ui_println!("{}", line);
+                }
+            }
+        }
+        Ok(())
+    });
+
+    let final_future = swarm_future
+        .select(pubsub_rx).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(stdin_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(kad_init).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(discovery_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(identify_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(history_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(direct_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(tasks_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(transfer_events_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(heartbeat_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(address_book_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(chat_log_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(redial_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(mdns_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(rpc_future).map_err(|(err, _)| err).and_then(|(_, n)| n)
+        .select(shutdown_future).map(|_| ()).map_err(|(err, _)| err);
+    platform.run(final_future);
+
+    // The address book is otherwise saved every `ADDRESS_BOOK_SAVE_INTERVAL`.
+    if let Err(err) = address_book.save() {
+        ui_eprintln!("Failed to save the address book: {}", err);
+    }
+    if let Err(err) = final_chat_log.sync() {
+        ui_eprintln!("Failed to write the chat log: {}", err);
+    }
+}
+
+/// Turns the output of a publish-subscribe upgrade into the type that `ProtocolOutput` expects.
+fn box_future<F>(future: F) -> Box<Future<Item = (), Error = IoError>>
+where
+    F: Future<Item = (), Error = IoError> + 'static,
+{
+    Box::new(future)
+}
+
+/// Returns a future that discovers the nodes of the local network with mDNS, and calls
+/// `on_discovered` for each of them.
+///
+/// If mDNS can't be started, prints an error and returns a future that never finishes, as the
+/// node is perfectly usable without it.
+#[cfg(not(target_os = "emscripten"))]
+fn mdns_discovery<F>(
+    platform: &platform::PlatformSpecific,
+    timer: &tokio_timer::Timer,
+    local_peer_id: libp2p_core::PeerId,
+    local_info: identify::LocalInfo,
+    mut on_discovered: F,
+) -> Box<Future<Item = (), Error = IoError>>
+where
+    F: FnMut(libp2p_core::PeerId, Vec<Multiaddr>) + 'static,
+{
+    match mdns::MdnsService::new(&platform.handle(), timer, local_peer_id, local_info) {
+        Ok(service) => Box::new(service.for_each(move |(peer_id, addrs)| {
+            on_discovered(peer_id, addrs);
+            Ok(())
+        })),
+        Err(err) => {
+            ui_eprintln!("Failed to start mDNS: {}", err);
+            Box::new(future::empty())
+        }
+    }
+}
+
+/// mDNS isn't available in the browser.
+#[cfg(target_os = "emscripten")]
+fn mdns_discovery<F>(
+    _: &platform::PlatformSpecific,
+    _: &tokio_timer::Timer,
+    _: libp2p_core::PeerId,
+    _: identify::LocalInfo,
+    _: F,
+) -> Box<Future<Item = (), Error = IoError>>
+where
+    F: FnMut(libp2p_core::PeerId, Vec<Multiaddr>) + 'static,
+{
+    Box::new(future::empty())
+}
+
+/// Returns a future that serves the requests sent on the control socket at `path`.
+///
+/// The control socket was asked for explicitly, so the node stops if it can't serve it.
+#[cfg(all(unix, not(target_os = "emscripten")))]
+fn rpc_server(
+    platform: &platform::PlatformSpecific,
+    socket: rpc::ControlSocket,
+    path: &Path,
+    handler: rpc::Handler,
+    subscriptions: rpc::Subscriptions,
+) -> Box<Future<Item = (), Error = IoError>> {
+    match socket.serve(&platform.handle(), handler, subscriptions) {
+        Ok(server) => {
+            ui_println!("Accepting control requests on {}", path.display());
+            Box::new(server.map_err(|err| {
+                ui_eprintln!("The control socket stopped accepting connections: {}", err);
+                err
+            }))
+        }
+        Err(err) => {
+            ui_eprintln!("Failed to open the control socket {}: {}", path.display(), err);
+            Box::new(future::err(err))
+        }
+    }
+}
+
+/// Returns a future that finishes when the process receives SIGTERM or SIGINT.
+///
+/// If the signals can't be caught, prints an error and returns a future that never finishes.
+#[cfg(all(unix, not(target_os = "emscripten")))]
+fn termination_signal(
+    platform: &platform::PlatformSpecific,
+) -> Box<Future<Item = (), Error = IoError>> {
+    use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+    let handle = platform.handle();
+    let signals = Signal::new(SIGTERM, &handle)
+        .join(Signal::new(SIGINT, &handle))
+        .and_then(|(sigterm, sigint)| sigterm.select(sigint).into_future().map_err(|(err, _)| err))
+        .map(|(signal, _)| {
+            let name = if signal == Some(SIGTERM) { "SIGTERM" } else { "SIGINT" };
+            ui_println!("Received {}", name);
+        })
+        .or_else(|err| {
+            ui_eprintln!("Failed to catch the termination signals: {}", err);
+            future::empty()
+        });
+    Box::new(signals)
+}
+
+/// We only catch the signals on Unix, and there are none in the browser.
+#[cfg(not(all(unix, not(target_os = "emscripten"))))]
+fn termination_signal(_: &platform::PlatformSpecific) -> Box<Future<Item = (), Error = IoError>> {
+    Box::new(future::empty())
+}
+
+/// A message received from the network, verified and decoded.
+struct Received {
+    /// `None` if the payload isn't a `ChatMessage`, for example because the author runs an older
+    /// version of the software.
+    message: Option<ChatMessage>,
+    /// Text to display.
+    text: String,
+    /// False if the signature is invalid, or if the author of the message isn't its signer.
+    verified: bool,
+}
+
+impl fmt::Display for Received {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.verified {
+            write!(f, "[unverified] ")?;
+        }
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Opens the signed envelope of a message published by `source` on `topic`, and decodes its
+/// content.
+///
+/// Returns `None` if the message must be dropped.
+fn open_message(
+    source: &PeerId,
+    topic: &str,
+    data: &[u8],
+    verify_mode: VerifyMode,
+) -> Option<Received> {
+    let (payload, verified) = match signed::verify(source, topic, data) {
+        Ok(payload) => (payload, true),
+        Err(err) => {
+            ui_eprintln!("Message from {} failed verification: {}", source.to_base58(), err);
+            match (verify_mode, err.into_unverified_payload()) {
+                (VerifyMode::Warn, Some(payload)) => (payload, false),
+                _ => return None,
+            }
+        }
+    };
+
+    Some(match ChatMessage::decode(&payload) {
+        Ok(message) => Received {
+            text: message.to_string(),
+            verified: verified && message.author == *source,
+            message: Some(message),
+        },
+        Err(err) => Received {
+            message: None,
+            text: message::describe_undecodable(&payload, &err),
+            verified: verified,
+        },
+    })
+}
+
+/// Same as `dedup::is_duplicate`, for the cache shared by the messages of the rooms and the
+/// direct messages.
+fn is_duplicate(
+    seen: &Mutex<SeenCache<(PeerId, u64)>>,
+    source: &PeerId,
+    message: &ChatMessage,
+    verified: bool,
+) -> bool {
+    dedup::is_duplicate(&mut seen.lock().unwrap(), source, message, verified)
+}
+
+/// Returns a future that prints whether `what`, sent to the node called `name`, has been
+/// acknowledged.
+fn report_delivery(
+    delivery: Box<Future<Item = (), Error = IoError>>,
+    what: &'static str,
+    name: String,
+) -> Box<Future<Item = (), Error = IoError>> {
+    Box::new(delivery.then(move |result| {
+        match result {
+            Ok(()) => ui_println!("{} to {} delivered", what, name),
+            Err(err) => ui_println!("{} to {} not delivered: {}", what, name, err),
+        }
+        Ok(())
+    }))
+}
+
+/// Signs `message`, publishes it in `room`, and adds it to the history of the room and to the
+/// chat log.
+///
+/// If the room is encrypted, what we publish is an `Encrypted` message that contains `message`.
+fn publish(
+    rooms: &Rooms,
+    group_keys: &GroupKeys,
+    history: &history::History,
+    chat_log: &chat_log::ChatLog,
+    keypair: &platform::Keypair,
+    room: &str,
+    message: ChatMessage,
+) {
+    // The envelope of an encrypted message reuses the metadata of the message, so that
+    // publishing it doesn't consume a second sequence number.
+    let published = match group_keys.encrypt(room, &message.encode()) {
+        Some(payload) => ChatMessage {
+            kind: Kind::Encrypted,
+            payload: payload,
+            ..message.clone()
+        },
+        None => message.clone(),
+    };
+    let data = signed::sign(keypair, &rooms::room_topic(room), &published.encode());
+    rooms.publish_to(room, data.clone());
+
+    let source = message.author.clone();
+    let received = Received {
+        text: message.to_string(),
+        message: Some(message),
+        verified: true,
+    };
+    log_message(chat_log, room, &source, &received);
+    history.insert(room, history::Entry {
+        source: source,
+        data: data,
+        message: published,
+    });
+}
+
+/// Signs a message of the given kind and publishes it in `room`. Unlike `publish`, the message
+/// is neither encrypted, nor added to the history or to the chat log.
+fn publish_to(
+    rooms: &Rooms,
+    composer: &Composer,
+    keypair: &platform::Keypair,
+    room: &str,
+    kind: Kind,
+    payload: Vec<u8>,
+) {
+    let message = composer.compose(kind, payload);
+    let data = signed::sign(keypair, &rooms::room_topic(room), &message.encode());
+    rooms.publish_to(room, data);
+}
+
+/// Returns true for the kinds of the messages that manage the keys of encrypted rooms.
+fn is_key_message(kind: Kind) -> bool {
+    kind == Kind::KeyRequest || kind == Kind::KeyUpdate || kind == Kind::KeyLeave
+}
+
+/// Processes a verified message that manages the key of the encrypted room `room`.
+fn handle_key_message(
+    rooms: &Rooms,
+    group_keys: &GroupKeys,
+    composer: &Composer,
+    keypair: &platform::Keypair,
+    room: &str,
+    message: &ChatMessage,
+) {
+    match message.kind {
+        Kind::KeyRequest => {
+            if group_keys.on_request(room, &message.author, &message.payload) {
+                ui_println_in!(room, "{}; use /invite {} to let them in", message,
+                               message.author.to_base58());
+            }
+        }
+        Kind::KeyUpdate => match group_keys.on_update(room, &message.author, &message.payload) {
+            Ok(UpdateOutcome::Offered) => print_key_offer(room, message),
+            Ok(UpdateOutcome::Removed) => {
+                let nickname = &message.nickname;
+                ui_println_in!(room, "-- {} removed us from the encrypted room", nickname);
+            }
+            Ok(UpdateOutcome::Rotated) | Ok(UpdateOutcome::Ignored) => {}
+            Err(err) => {
+                ui_eprintln!("Invalid key update from {}: {}", message.author.to_base58(), err);
+            }
+        },
+        Kind::KeyLeave => {
+            if group_keys.is_encrypted(room) {
+                ui_println_in!(room, "{}", message);
+            }
+            if let Some(update) = group_keys.on_leave(room, &message.author) {
+                publish_to(rooms, composer, keypair, room, Kind::KeyUpdate, update);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Tells the user that the author of a `KeyUpdate` gave us the key of `room`, which the user must
+/// accept before we use it.
+fn print_key_offer(room: &str, update: &ChatMessage) {
+    let author = update.author.to_base58();
+    ui_println_in!(room, "-- {} ({}) gave us the key of the room; if you trust this node, use \
+                          /acceptkey {} to use it", message::sanitize(&update.nickname), author,
+                   author);
+}
+
+/// Decrypts an `Encrypted` message published in `room`, and decodes the message it contains.
+fn decrypt_message(
+    group_keys: &GroupKeys,
+    room: &str,
+    encrypted: &ChatMessage,
+    verified: bool,
+) -> Result<Received, GroupError> {
+    let plaintext = group_keys.decrypt(room, &encrypted.author, &encrypted.payload)?;
+    let message = ChatMessage::decode(&plaintext).map_err(|_| GroupError::Malformed)?;
+    Ok(Received {
+        text: message.to_string(),
+        verified: verified && message.author == encrypted.author,
+        message: Some(message),
+    })
+}
+
+/// Appends a message that we display to the chat log.
+fn log_message(chat_log: &chat_log::ChatLog, room: &str, source: &PeerId, received: &Received) {
+    let result = chat_log.append(
+        room,
+        source,
+        received.message.as_ref(),
+        &received.text,
+        received.verified,
+    );
+    if let Err(err) = result {
+        ui_eprintln!("Failed to write to the chat log: {}", err);
+    }
+}
+
+/// Sends a message that we display to the scripts that subscribed to it through the control
+/// socket. `room` is `None` for direct messages.
+#[cfg(all(unix, not(target_os = "emscripten")))]
+fn notify_message(
+    subscriptions: &rpc::Subscriptions,
+    room: Option<&str>,
+    source: &PeerId,
+    received: &Received,
+) {
+    let message = received.message.as_ref();
+    let event = rpc::MessageEvent::new(room, source, message, &received.text, received.verified);
+    subscriptions.notify(&event);
+}
+
+fn print_logged(logged: &chat_log::LoggedMessage) {
+    let prefix = if logged.verified { "" } else { "[unverified] " };
+    ui_println!("[{}] (log) {}{}", logged.room, prefix, logged.message);
+}
+
+/// Handles the `/history <room> [<n>]` command, which prints `n` messages of the log of the room.
+/// Repeating the command prints the messages that precede the ones printed the previous time,
+/// until the start of the log is reached.
+fn print_log_page(chat_log: &chat_log::ChatLog, room: &str, count: usize) {
+    let page = chat_log.page(room, count);
+    if page.is_empty() {
+        ui_println!("No messages in the log of {}", room);
+    }
+    for logged in page.iter() {
+        print_logged(logged);
+    }
+}
+
+/// Handles the `/peerinfo <peer>` command.
+fn print_peer_info(peer_infos: &identify::PeerInfoStore, peer_id: &PeerId) {
+    let peer = peer_id.to_base58();
+    match peer_infos.get(peer_id) {
+        Some(info) => ui_println!("Information about {}:\n{}", peer, info),
+        None => ui_println!("No information about {}; we haven't identified it yet", peer),
+    }
+}
+
+/// Dials the addresses of `peer_id` one after the other, moving to the next one when we're still
+/// not connected `REDIAL_TIMEOUT` after dialing.
+fn redial<F>(
+    peer_id: PeerId,
+    addrs: Vec<Multiaddr>,
+    connected: peers::ConnectedPeers,
+    timer: &tokio_timer::Timer,
+    dial: Rc<F>,
+) -> Box<Future<Item = (), Error = IoError>>
+where
+    F: Fn(Multiaddr) -> Result<(), Multiaddr> + 'static,
+{
+    type Step = Box<Future<Item = Loop<(), vec::IntoIter<Multiaddr>>, Error = IoError>>;
+    let timer = timer.clone();
+    Box::new(future::loop_fn(addrs.into_iter(), move |mut addrs| -> Step {
+        if connected.contains(&peer_id) {
+            return Box::new(future::ok(Loop::Break(())));
+        }
+        let addr = match addrs.next() {
+            Some(addr) => addr,
+            None => return Box::new(future::ok(Loop::Break(()))),
+        };
+        if dial(peer_addr::with_peer_id(addr, &peer_id)).is_err() {
+            return Box::new(future::ok(Loop::Continue(addrs)));
+        }
+        Box::new(timer
+            .sleep(REDIAL_TIMEOUT)
+            .map_err(|err| IoError::new(IoErrorKind::Other, err))
+            .map(move |()| Loop::Continue(addrs)))
+    }))
+}
 
-    // Instead of `core.run()`, use `platform.run()`.
-    //platform.run(final_future);
+/// Pings the remote every `PING_INTERVAL`, and prints a message if it doesn't answer.
+///
+/// The returned future never finishes unless the substream is closed.
+fn ping_periodically(
+    mut pinger: libp2p_ping::Pinger,
+    remote_addr: Multiaddr,
+    timer: &tokio_timer::Timer,
+) -> impl Future<Item = (), Error = IoError> {
+    timer
+        .interval(PING_INTERVAL)
+        .map_err(|err| IoError::new(IoErrorKind::Other, err))
+        .for_each(move |_| {
+            let remote_addr = remote_addr.clone();
+            pinger.ping().then(move |result| {
+                if let Err(err) = result {
+                    ui_eprintln!("Ping to {} failed: {}", remote_addr, err);
+                }
+                Ok(())
+            })
+        })
 }
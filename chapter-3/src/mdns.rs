@@ -0,0 +1,460 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Discovery of the nodes of the local network through mDNS.
+//!
+//! We follow the libp2p mDNS specification. Every now and then, we send a DNS query for
+//! `_p2p._udp.local` to the mDNS multicast address. Nodes that receive this query answer with
+//! their `PeerId` and their listen addresses, in the form of TXT records that contain
+//! `dnsaddr=<multiaddr>/ipfs/<peer-id>`. Answers are multicast as well, so that everybody on the
+//! network learns about the node that answered.
+//!
+//! The socket enables multicast loopback and port reuse, which means that several nodes running
+//! on the same machine discover each other too.
+//!
+//! This isn't available in the browser.
+
+use chat_common::peer_addr;
+use futures::{Async, Poll, Stream};
+use identify::LocalInfo;
+use libp2p_core::{Multiaddr, PeerId};
+use net2;
+use std::collections::VecDeque;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::Handle;
+use tokio_timer::{Interval, Timer};
+
+/// Multicast address and port of mDNS.
+const MDNS_IPV4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// Name of the service that libp2p nodes advertise.
+const SERVICE_NAME: &str = "_p2p._udp.local";
+
+/// Interval between two queries.
+const QUERY_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Time-to-live of the records we send, in seconds.
+const RECORD_TTL: u32 = 120;
+
+/// DNS record types and classes that we use.
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+/// Stream of the nodes discovered on the local network, along with their addresses.
+///
+/// Also answers the queries of the other nodes as long as it is polled.
+pub struct MdnsService {
+    socket: UdpSocket,
+    query_interval: Interval,
+    local_peer_id: PeerId,
+    local_info: LocalInfo,
+    /// Packets waiting to be sent.
+    send_queue: VecDeque<Vec<u8>>,
+    /// Nodes that we've discovered but not yet produced.
+    discovered: VecDeque<(PeerId, Vec<Multiaddr>)>,
+    recv_buffer: Vec<u8>,
+}
+
+impl MdnsService {
+    /// Binds the mDNS socket and sends a first query. Our listen addresses are read from
+    /// `local_info` every time we answer a query.
+    pub fn new(
+        handle: &Handle,
+        timer: &Timer,
+        local_peer_id: PeerId,
+        local_info: LocalInfo,
+    ) -> Result<MdnsService, IoError> {
+        let builder = net2::UdpBuilder::new_v4()?;
+        builder.reuse_address(true)?;
+        #[cfg(unix)]
+        net2::unix::UnixUdpBuilderExt::reuse_port(&builder, true)?;
+        let socket = builder.bind((Ipv4Addr::new(0, 0, 0, 0), MDNS_PORT))?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_multicast_ttl_v4(255)?;
+        socket.join_multicast_v4(&MDNS_IPV4, &Ipv4Addr::new(0, 0, 0, 0))?;
+
+        let mut send_queue = VecDeque::new();
+        send_queue.push_back(build_query());
+
+        Ok(MdnsService {
+            socket: UdpSocket::from_socket(socket, handle)?,
+            query_interval: timer.interval(QUERY_INTERVAL),
+            local_peer_id: local_peer_id,
+            local_info: local_info,
+            send_queue: send_queue,
+            discovered: VecDeque::new(),
+            recv_buffer: vec![0; 4096],
+        })
+    }
+
+    /// Handles a packet received on the socket.
+    fn on_packet(&mut self, packet: &[u8]) {
+        match parse_packet(packet) {
+            Some(Packet::Query) => {
+                let addrs = self.local_info.dialable_addrs();
+                if !addrs.is_empty() {
+                    self.send_queue.push_back(build_response(&self.local_peer_id, &addrs));
+                }
+            }
+            Some(Packet::Response(addrs)) => {
+                let mut by_peer: Vec<(PeerId, Vec<Multiaddr>)> = Vec::new();
+                for addr in addrs {
+                    let peer_id = match peer_addr::peer_id_of(&addr) {
+                        Some(peer_id) => peer_id,
+                        None => continue,
+                    };
+                    if peer_id == self.local_peer_id {
+                        continue;
+                    }
+                    let addr = peer_addr::without_peer_id(&addr);
+                    match by_peer.iter_mut().position(|&mut (ref p, _)| *p == peer_id) {
+                        Some(pos) => by_peer[pos].1.push(addr),
+                        None => by_peer.push((peer_id, vec![addr])),
+                    }
+                }
+                self.discovered.extend(by_peer);
+            }
+            None => (),
+        }
+    }
+}
+
+impl Stream for MdnsService {
+    type Item = (PeerId, Vec<Multiaddr>);
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, IoError> {
+        loop {
+            if let Some(discovered) = self.discovered.pop_front() {
+                return Ok(Async::Ready(Some(discovered)));
+            }
+
+            while let Async::Ready(Some(_)) = self
+                .query_interval
+                .poll()
+                .map_err(|err| IoError::new(IoErrorKind::Other, err))?
+            {
+                self.send_queue.push_back(build_query());
+            }
+
+            let mdns_addr = SocketAddr::new(IpAddr::V4(MDNS_IPV4), MDNS_PORT);
+            while let Some(packet) = self.send_queue.pop_front() {
+                match self.socket.send_to(&packet, &mdns_addr) {
+                    Ok(_) => (),
+                    Err(ref err) if err.kind() == IoErrorKind::WouldBlock => {
+                        self.send_queue.push_front(packet);
+                        break;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let mut buffer = ::std::mem::replace(&mut self.recv_buffer, Vec::new());
+            let result = self.socket.recv_from(&mut buffer);
+            match result {
+                Ok((len, _)) => self.on_packet(&buffer[..len]),
+                Err(ref err) if err.kind() == IoErrorKind::WouldBlock => {
+                    self.recv_buffer = buffer;
+                    return Ok(Async::NotReady);
+                }
+                Err(err) => return Err(err),
+            }
+            self.recv_buffer = buffer;
+        }
+    }
+}
+
+/// A DNS packet that we're interested in.
+#[derive(Debug)]
+enum Packet {
+    /// A query for `SERVICE_NAME`.
+    Query,
+    /// A response, with the addresses found in its `dnsaddr` TXT records.
+    Response(Vec<Multiaddr>),
+}
+
+/// Builds a query for the PTR records of `SERVICE_NAME`.
+fn build_query() -> Vec<u8> {
+    let mut out = Vec::with_capacity(33);
+    // Transaction id, flags, one question, no answer/authority/additional record.
+    out.extend_from_slice(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    append_name(&mut out, SERVICE_NAME);
+    append_u16(&mut out, TYPE_PTR);
+    append_u16(&mut out, CLASS_IN);
+    out
+}
+
+/// Builds a response that advertises `peer_id` and `addrs`.
+fn build_response(peer_id: &PeerId, addrs: &[Multiaddr]) -> Vec<u8> {
+    let peer_name = format!("{}.{}", peer_id.to_base58(), SERVICE_NAME);
+
+    // The addresses that don't fit in a TXT string are skipped, so we only know the number of
+    // additional records once they're written.
+    let mut additional = Vec::new();
+    let mut additional_count = 0u16;
+    for addr in addrs {
+        let txt = format!("dnsaddr={}/ipfs/{}", addr, peer_id.to_base58());
+        if txt.len() > 255 || additional_count == u16::max_value() {
+            continue;
+        }
+        let mut txt_data = Vec::with_capacity(txt.len() + 1);
+        txt_data.push(txt.len() as u8);
+        txt_data.extend_from_slice(txt.as_bytes());
+        append_record(&mut additional, &peer_name, TYPE_TXT, &txt_data);
+        additional_count += 1;
+    }
+
+    let mut out = Vec::with_capacity(512);
+    // Transaction id, flags (authoritative response), no question, one answer, no authority,
+    // one additional record per address.
+    out.extend_from_slice(&[0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0]);
+    append_u16(&mut out, additional_count);
+
+    let mut ptr_data = Vec::new();
+    append_name(&mut ptr_data, &peer_name);
+    append_record(&mut out, SERVICE_NAME, TYPE_PTR, &ptr_data);
+
+    out.extend_from_slice(&additional);
+    out
+}
+
+fn append_u16(out: &mut Vec<u8>, value: u16) {
+    out.push((value >> 8) as u8);
+    out.push(value as u8);
+}
+
+fn append_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+fn append_record(out: &mut Vec<u8>, name: &str, ty: u16, data: &[u8]) {
+    append_name(out, name);
+    append_u16(out, ty);
+    append_u16(out, CLASS_IN);
+    out.extend_from_slice(&[
+        (RECORD_TTL >> 24) as u8,
+        (RECORD_TTL >> 16) as u8,
+        (RECORD_TTL >> 8) as u8,
+        RECORD_TTL as u8,
+    ]);
+    append_u16(out, data.len() as u16);
+    out.extend_from_slice(data);
+}
+
+/// Parses a DNS packet. Returns `None` if it is malformed or irrelevant to us.
+fn parse_packet(packet: &[u8]) -> Option<Packet> {
+    let is_response = (*packet.get(2)? & 0x80) != 0;
+    let questions = read_u16(packet, 4)?;
+    let records = read_u16(packet, 6)? as usize
+        + read_u16(packet, 8)? as usize
+        + read_u16(packet, 10)? as usize;
+
+    let mut offset = 12;
+    let mut is_our_query = false;
+    for _ in 0..questions {
+        let (name, next) = read_name(packet, offset)?;
+        let ty = read_u16(packet, next)?;
+        offset = next + 4;
+        if name.eq_ignore_ascii_case(SERVICE_NAME) && (ty == TYPE_PTR || ty == TYPE_ANY) {
+            is_our_query = true;
+        }
+    }
+
+    if !is_response {
+        return if is_our_query { Some(Packet::Query) } else { None };
+    }
+
+    let mut addrs = Vec::new();
+    for _ in 0..records {
+        let (_, next) = read_name(packet, offset)?;
+        let ty = read_u16(packet, next)?;
+        let data_len = read_u16(packet, next + 8)? as usize;
+        let data_start = next + 10;
+        let data = packet.get(data_start..data_start + data_len)?;
+        offset = data_start + data_len;
+
+        if ty != TYPE_TXT {
+            continue;
+        }
+
+        // The data of a TXT record is a list of length-prefixed strings.
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = data[pos] as usize;
+            let txt = data.get(pos + 1..pos + 1 + len)?;
+            pos += 1 + len;
+            if txt.starts_with(b"dnsaddr=") {
+                if let Some(addr) = ::std::str::from_utf8(&txt[8..])
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                {
+                    addrs.push(addr);
+                }
+            }
+        }
+    }
+
+    if addrs.is_empty() {
+        None
+    } else {
+        Some(Packet::Response(addrs))
+    }
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    let bytes = packet.get(offset..offset + 2)?;
+    Some(((bytes[0] as u16) << 8) | bytes[1] as u16)
+}
+
+/// Reads the name that starts at `offset`, following compression pointers.
+///
+/// Returns the name and the offset right after it.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds the number of pointers we follow, so that a malicious packet can't make us loop.
+    let mut jumps = 0;
+
+    loop {
+        let len = *packet.get(offset)? as usize;
+        if len == 0 {
+            break;
+        } else if len & 0xc0 == 0xc0 {
+            let pointer = ((len & 0x3f) << 8) | *packet.get(offset + 1)? as usize;
+            if end.is_none() {
+                end = Some(offset + 2);
+            }
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            offset = pointer;
+        } else {
+            let label = packet.get(offset + 1..offset + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            offset += 1 + len;
+        }
+    }
+
+    Some((labels.join("."), end.unwrap_or(offset + 1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_common::key::{KeyType, Keypair};
+
+    fn random_peer_id() -> PeerId {
+        Keypair::generate(KeyType::Ed25519).unwrap().peer_id()
+    }
+
+    #[test]
+    fn query_is_recognized() {
+        match parse_packet(&build_query()) {
+            Some(Packet::Query) => (),
+            other => panic!("unexpected packet: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let peer_id = random_peer_id();
+        let addrs = vec![
+            "/ip4/192.168.1.2/tcp/4001".parse::<Multiaddr>().unwrap(),
+            "/ip6/fe80::1/tcp/4001/ws".parse().unwrap(),
+        ];
+        let packet = build_response(&peer_id, &addrs);
+        assert_eq!(read_u16(&packet, 10), Some(2));
+        match parse_packet(&packet) {
+            Some(Packet::Response(parsed)) => {
+                let expected = addrs
+                    .into_iter()
+                    .map(|addr| peer_addr::with_peer_id(addr, &peer_id))
+                    .collect::<Vec<_>>();
+                assert_eq!(parsed, expected);
+            }
+            other => panic!("unexpected packet: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversized_addresses_are_not_counted() {
+        let peer_id = random_peer_id();
+        let long = format!("/ip4/10.0.0.1/tcp/1{}", "/ws".repeat(80));
+        let addrs = vec![
+            long.parse::<Multiaddr>().unwrap(),
+            "/ip4/10.0.0.1/tcp/4001".parse().unwrap(),
+        ];
+        let packet = build_response(&peer_id, &addrs);
+        // The header must announce exactly the records that follow, or the parser reads past
+        // the end of the packet.
+        assert_eq!(read_u16(&packet, 10), Some(1));
+        match parse_packet(&packet) {
+            Some(Packet::Response(parsed)) => {
+                assert_eq!(parsed, vec![peer_addr::with_peer_id(addrs[1].clone(), &peer_id)]);
+            }
+            other => panic!("unexpected packet: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated_packets_are_rejected() {
+        let packet = build_response(&random_peer_id(), &["/ip4/10.0.0.1/tcp/1".parse().unwrap()]);
+        for len in 0..packet.len() {
+            assert!(parse_packet(&packet[..len]).is_none(), "accepted {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn other_queries_are_ignored() {
+        let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        append_name(&mut packet, "_other._udp.local");
+        append_u16(&mut packet, TYPE_PTR);
+        append_u16(&mut packet, CLASS_IN);
+        assert!(parse_packet(&packet).is_none());
+    }
+
+    #[test]
+    fn name_compression() {
+        let mut packet = vec![0; 12];
+        append_name(&mut packet, SERVICE_NAME);
+        // A name made of a label followed by a pointer to the name above.
+        packet.extend_from_slice(&[3, b'f', b'o', b'o', 0xc0, 12]);
+        let end = packet.len();
+        let start = end - 6;
+        assert_eq!(read_name(&packet, start), Some((format!("foo.{}", SERVICE_NAME), end)));
+    }
+
+    #[test]
+    fn pointer_loops_are_rejected() {
+        let packet = [0xc0, 0];
+        assert_eq!(read_name(&packet, 0), None);
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Options passed on the command line.
+//!
+//! In the browser there is no command line, and all the options keep their default value.
+
+use chat_common::KeyType;
+use chat_common::dedup::DedupConfig;
+use chat_common::signed::VerifyMode;
+use discovery::DEFAULT_TARGET_PEERS;
+use gossipsub::{PubSubRouter, DEFAULT_MAX_HOPS};
+use platform::MuxerConfig;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+/// Options of the chat node.
+pub struct Options {
+    /// Path to the file that contains the keypair of the node. `None` for the default path.
+    pub key_file: Option<PathBuf>,
+    /// If true, discard the existing keypair and generate a new one.
+    pub new_identity: bool,
+    /// Type of keypair to generate if we don't have one yet.
+    pub key_type: KeyType,
+    /// What to do with messages whose signature is invalid.
+    pub verify_mode: VerifyMode,
+    /// Stream multiplexers to support.
+    pub muxers: MuxerConfig,
+    /// Publish-subscribe protocol used for chatting. All the nodes of a network must use the same.
+    pub pubsub: PubSubRouter,
+    /// Size and expiry of the caches of the messages we've seen, used to drop the duplicates.
+    pub dedup: DedupConfig,
+    /// Number of hops the messages we publish can travel. Only used by gossipsub, as floodsub
+    /// messages have no room for it.
+    pub max_hops: u8,
+    /// Number of nodes we try to stay connected to. Discovered nodes are dialed until we reach it.
+    pub target_peers: usize,
+    /// If false, don't discover nodes on the local network with mDNS. Ignored in the browser.
+    pub mdns: bool,
+    /// Path to the address book. `None` for the default path. Ignored in the browser.
+    pub peers_file: Option<PathBuf>,
+    /// Path to the chat log. `None` for the default path. Ignored in the browser.
+    pub log_file: Option<PathBuf>,
+    /// Directory where the files we receive are saved. `None` for the default directory.
+    pub download_dir: Option<PathBuf>,
+    /// If false, print line by line instead of running the full-screen terminal UI. The UI also
+    /// doesn't run in the browser, or when stdin or stdout isn't a terminal.
+    pub tui: bool,
+    /// If true, accept requests on the control socket. Implied by `daemon` and `rpc_socket`.
+    /// Ignored in the browser.
+    pub rpc: bool,
+    /// If true, don't read stdin, and only accept requests on the control socket. Ignored in the
+    /// browser.
+    pub daemon: bool,
+    /// Path to the control socket. `None` for the default path. See the `rpc` module.
+    pub rpc_socket: Option<PathBuf>,
+    /// Multiaddresses to listen on. Ignored in the browser.
+    pub listen: Vec<String>,
+    /// Multiaddresses to dial on startup.
+    pub to_dial: Vec<String>,
+}
+
+impl Options {
+    /// Parses `std::env::args()`. Prints the usage and exits the process if they are invalid.
+    pub fn from_args() -> Options {
+        let mut options = Options {
+            key_file: None,
+            new_identity: false,
+            key_type: KeyType::default(),
+            verify_mode: VerifyMode::default(),
+            muxers: MuxerConfig::default(),
+            pubsub: PubSubRouter::default(),
+            dedup: DedupConfig::default(),
+            max_hops: DEFAULT_MAX_HOPS,
+            target_peers: DEFAULT_TARGET_PEERS,
+            mdns: true,
+            peers_file: None,
+            log_file: None,
+            download_dir: None,
+            tui: true,
+            rpc: false,
+            daemon: false,
+            rpc_socket: None,
+            listen: Vec::new(),
+            to_dial: Vec::new(),
+        };
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_ref() {
+                "--key-file" => match args.next() {
+                    Some(path) => options.key_file = Some(PathBuf::from(path)),
+                    None => Options::usage_and_exit(),
+                },
+                "--new-identity" => options.new_identity = true,
+                "--key-type" => match args.next().map(|ty| ty.parse()) {
+                    Some(Ok(key_type)) => options.key_type = key_type,
+                    _ => Options::usage_and_exit(),
+                },
+                "--verify" => match args.next().map(|mode| mode.parse()) {
+                    Some(Ok(mode)) => options.verify_mode = mode,
+                    _ => Options::usage_and_exit(),
+                },
+                "--muxer" => match args.next().map(|muxers| muxers.parse()) {
+                    Some(Ok(muxers)) => options.muxers = muxers,
+                    _ => Options::usage_and_exit(),
+                },
+                "--pubsub" => match args.next().map(|router| router.parse()) {
+                    Some(Ok(router)) => options.pubsub = router,
+                    _ => Options::usage_and_exit(),
+                },
+                "--dedup-capacity" => match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) if n > 0 => options.dedup.capacity = n,
+                    _ => Options::usage_and_exit(),
+                },
+                "--dedup-ttl" => match args.next().map(|secs| secs.parse()) {
+                    Some(Ok(secs)) if secs > 0 => options.dedup.ttl = Duration::from_secs(secs),
+                    _ => Options::usage_and_exit(),
+                },
+                "--max-hops" => match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) if n > 0 => options.max_hops = n,
+                    _ => Options::usage_and_exit(),
+                },
+                "--target-peers" => match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) => options.target_peers = n,
+                    _ => Options::usage_and_exit(),
+                },
+                "--peers-file" => match args.next() {
+                    Some(path) => options.peers_file = Some(PathBuf::from(path)),
+                    None => Options::usage_and_exit(),
+                },
+                "--log-file" => match args.next() {
+                    Some(path) => options.log_file = Some(PathBuf::from(path)),
+                    None => Options::usage_and_exit(),
+                },
+                "--download-dir" => match args.next() {
+                    Some(path) => options.download_dir = Some(PathBuf::from(path)),
+                    None => Options::usage_and_exit(),
+                },
+                "--no-mdns" => options.mdns = false,
+                "--no-tui" => options.tui = false,
+                "--rpc" => options.rpc = true,
+                "--daemon" => {
+                    options.daemon = true;
+                    options.rpc = true;
+                }
+                "--rpc-socket" => match args.next() {
+                    Some(path) => {
+                        options.rpc_socket = Some(PathBuf::from(path));
+                        options.rpc = true;
+                    }
+                    None => Options::usage_and_exit(),
+                },
+                "--listen" => match args.next() {
+                    Some(addr) => options.listen.push(addr),
+                    None => Options::usage_and_exit(),
+                },
+                "--help" | "-h" => Options::usage_and_exit(),
+                _ if arg.starts_with("--") => Options::usage_and_exit(),
+                _ => options.to_dial.push(arg),
+            }
+        }
+
+        if options.listen.is_empty() {
+            // By default, listen on TCP for other native nodes, and on websockets for browsers.
+            options.listen.push("/ip4/0.0.0.0/tcp/0".to_owned());
+            options.listen.push("/ip4/0.0.0.0/tcp/0/ws".to_owned());
+        }
+
+        options
+    }
+
+    fn usage_and_exit() -> ! {
+        eprintln!("Usage: chapter-3 [--key-file <path>] [--new-identity] \
+                   [--key-type ed25519|secp256k1] [--verify warn|strict] \
+                   [--muxer mplex,yamux] [--pubsub floodsub|gossipsub] \
+                   [--dedup-capacity <n>] [--dedup-ttl <seconds>] [--max-hops <n>] \
+                   [--target-peers <n>] [--peers-file <path>] [--log-file <path>] \
+                   [--download-dir <path>] [--no-mdns] [--no-tui] [--rpc] \
+                   [--rpc-socket <path>] [--daemon] [--listen <multiaddr>]... [<multiaddr>...]");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Registry of the nodes we're currently connected to.
+
+use libp2p_core::{Multiaddr, PeerId};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// List of the nodes we have an open pubsub substream with, and the address of each of them.
+///
+/// Cloning a `ConnectedPeers` gives access to the same list.
+#[derive(Clone, Default)]
+pub struct ConnectedPeers {
+    inner: Arc<Mutex<HashMap<PeerId, Connection>>>,
+}
+
+struct Connection {
+    addr: Multiaddr,
+    /// Number of substreams open with this node. Both sides might have dialed each other.
+    substreams: usize,
+}
+
+impl ConnectedPeers {
+    /// Creates an empty list.
+    #[inline]
+    pub fn new() -> ConnectedPeers {
+        ConnectedPeers::default()
+    }
+
+    /// Records that a substream with `peer_id` has been opened.
+    ///
+    /// Returns true if we weren't connected to this node before.
+    pub fn insert(&self, peer_id: PeerId, addr: Multiaddr) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let connection = inner.entry(peer_id).or_insert(Connection {
+            addr: addr,
+            substreams: 0,
+        });
+        connection.substreams += 1;
+        connection.substreams == 1
+    }
+
+    /// Records that a substream with `peer_id` has been closed.
+    ///
+    /// Returns true if this was the last substream with this node.
+    pub fn remove(&self, peer_id: &PeerId) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let closed = match inner.get_mut(peer_id) {
+            Some(connection) => {
+                connection.substreams -= 1;
+                connection.substreams == 0
+            }
+            None => return false,
+        };
+        if closed {
+            inner.remove(peer_id);
+        }
+        closed
+    }
+
+    /// Returns true if we're connected to `peer_id`.
+    #[inline]
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.inner.lock().unwrap().contains_key(peer_id)
+    }
+
+    /// Returns the address of `peer_id`, if we're connected to it.
+    pub fn addr(&self, peer_id: &PeerId) -> Option<Multiaddr> {
+        let inner = self.inner.lock().unwrap();
+        inner.get(peer_id).map(|connection| connection.addr.clone())
+    }
+
+    /// Returns the number of nodes we're connected to.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    /// Returns the list of nodes we're connected to, with their address.
+    pub fn list(&self) -> Vec<(PeerId, Multiaddr)> {
+        self.inner
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(peer_id, connection)| (peer_id.clone(), connection.addr.clone()))
+            .collect()
+    }
+}
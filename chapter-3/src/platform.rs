@@ -18,6 +18,8 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
+extern crate bytes;
+extern crate chat_common;
 extern crate futures;
 extern crate libp2p_core;
 extern crate libp2p_floodsub;
@@ -25,23 +27,33 @@ extern crate libp2p_identify;
 extern crate libp2p_kad;
 extern crate libp2p_mplex;
 extern crate libp2p_peerstore;
+extern crate libp2p_secio;
 #[cfg(not(target_os = "emscripten"))]
 extern crate libp2p_tcp_transport;
 extern crate libp2p_websocket;
+extern crate libp2p_yamux;
 extern crate rand;
 #[cfg(not(target_os = "emscripten"))]
 extern crate tokio_core;
 extern crate tokio_io;
-extern crate tokio_stdin;
 extern crate tokio_timer;
+extern crate yamux;
 
+pub use self::chat_common::{KeyType, Keypair, PublicKey};
+use bytes::BytesMut;
 use futures::{Future, Stream};
-#[cfg(not(target_os = "emscripten"))]
-use self::libp2p_core::Transport;
+use self::chat_common::peer_addr;
+use self::libp2p_core::either::EitherOutput;
+use self::libp2p_core::{upgrade, Multiaddr, MuxedTransport, Transport};
+use self::libp2p_secio::SecioConfig;
 use std::fmt::Debug;
-use std::io::Error as IoError;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::path::Path;
+use std::str::FromStr;
 #[cfg(target_os = "emscripten")]
 use stdweb;
+use tokio_io::codec::Decoder;
+use tokio_io::{AsyncRead, AsyncWrite};
 
 #[cfg(not(target_os = "emscripten"))]
 pub struct PlatformSpecific {
@@ -65,34 +77,310 @@ impl Default for PlatformSpecific {
     }
 }
 
+/// Maximum length of a line typed on stdin, in bytes. Longer lines are reported as errors.
+pub const MAX_LINE_LENGTH: usize = 16 * 1024;
+
+/// Number of lines read from stdin that can wait for the node to handle them. When it's reached,
+/// we stop reading stdin until the node catches up.
+#[cfg(not(target_os = "emscripten"))]
+const STDIN_BUFFER: usize = 16;
+
+/// Splits what is typed on stdin into lines.
+///
+/// Lines end with `\n` or `\r\n`, and empty lines are skipped. A line that isn't valid UTF-8, or
+/// that is longer than the maximum length, produces an error, after which the decoding can
+/// continue with the next line.
+pub struct LineCodec {
+    max_length: usize,
+    /// Number of bytes at the start of the buffer that we know don't contain a newline.
+    scanned: usize,
+    /// True if we're skipping the end of a line that was too long.
+    discarding: bool,
+}
+
+impl LineCodec {
+    /// Creates a codec that accepts lines of up to `max_length` bytes.
+    pub fn new(max_length: usize) -> LineCodec {
+        LineCodec {
+            max_length: max_length,
+            scanned: 0,
+            discarding: false,
+        }
+    }
+
+    /// Decodes all the lines in `buf`, including the last one if it isn't terminated and `eof`
+    /// is true.
+    pub fn decode_all(&mut self, buf: &mut BytesMut, eof: bool) -> Vec<Result<String, IoError>> {
+        let mut lines = Vec::new();
+        loop {
+            let result = if eof { self.decode_eof(buf) } else { self.decode(buf) };
+            match result {
+                Ok(Some(line)) => lines.push(Ok(line)),
+                Ok(None) => return lines,
+                Err(err) => lines.push(Err(err)),
+            }
+        }
+    }
+
+    /// Checks and decodes a line, without its `\n`. Returns `None` if it's empty.
+    fn finish_line(&self, line: &[u8]) -> Result<Option<String>, IoError> {
+        let line = match line.split_last() {
+            Some((&b'\r', line)) => line,
+            _ => line,
+        };
+        if line.is_empty() {
+            return Ok(None);
+        }
+        if line.len() > self.max_length {
+            return Err(line_too_long(self.max_length));
+        }
+        match String::from_utf8(line.to_vec()) {
+            Ok(line) => Ok(Some(line)),
+            Err(_) => Err(IoError::new(IoErrorKind::InvalidData, "the line isn't valid UTF-8")),
+        }
+    }
+}
+
+impl Decoder for LineCodec {
+    type Item = String;
+    type Error = IoError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, IoError> {
+        loop {
+            let newline = buf[self.scanned..].iter().position(|&byte| byte == b'\n');
+            let line = match newline {
+                Some(pos) => {
+                    let line = buf.split_to(self.scanned + pos + 1);
+                    self.scanned = 0;
+                    line
+                }
+                None if self.discarding => {
+                    buf.clear();
+                    self.scanned = 0;
+                    return Ok(None);
+                }
+                // A `\r` at the end may be the start of a CRLF, which doesn't count in the length.
+                None if buf.len() - buf.ends_with(b"\r") as usize > self.max_length => {
+                    buf.clear();
+                    self.scanned = 0;
+                    self.discarding = true;
+                    return Err(line_too_long(self.max_length));
+                }
+                None => {
+                    self.scanned = buf.len();
+                    return Ok(None);
+                }
+            };
+
+            // The first part of this line was already reported as too long.
+            if self.discarding {
+                self.discarding = false;
+                continue;
+            }
+            if let Some(line) = self.finish_line(&line[..line.len() - 1])? {
+                return Ok(Some(line));
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<String>, IoError> {
+        if let Some(line) = self.decode(buf)? {
+            return Ok(Some(line));
+        }
+
+        // What remains is the last line, which doesn't end with a newline.
+        let line = buf.take();
+        self.scanned = 0;
+        if self.discarding {
+            self.discarding = false;
+            return Ok(None);
+        }
+        self.finish_line(&line)
+    }
+}
+
+fn line_too_long(max_length: usize) -> IoError {
+    let msg = format!("the line is longer than {} bytes", max_length);
+    IoError::new(IoErrorKind::InvalidData, msg)
+}
+
+/// Stream multiplexers that the node supports.
+///
+/// When both are enabled, yamux is proposed first and mplex is only used if the remote doesn't
+/// support yamux.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MuxerConfig {
+    /// Support for mplex, which is simple but has no flow control.
+    pub mplex: bool,
+    /// Support for yamux, which has a window-based flow control per substream. This prevents one
+    /// large transfer from starving the other substreams of the same connection.
+    pub yamux: bool,
+}
+
+impl Default for MuxerConfig {
+    fn default() -> MuxerConfig {
+        MuxerConfig {
+            mplex: true,
+            yamux: true,
+        }
+    }
+}
+
+impl FromStr for MuxerConfig {
+    type Err = ();
+
+    /// Parses a comma-separated list of multiplexers, for example `mplex,yamux`.
+    fn from_str(s: &str) -> Result<MuxerConfig, ()> {
+        let mut config = MuxerConfig {
+            mplex: false,
+            yamux: false,
+        };
+        for muxer in s.split(',') {
+            match muxer.trim() {
+                "mplex" => config.mplex = true,
+                "yamux" => config.yamux = true,
+                _ => return Err(()),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Size of the receive window of each yamux substream.
+const YAMUX_RECEIVE_WINDOW: u32 = 256 * 1024;
+
+/// Upgrades every connection of `transport` with secio then with a stream multiplexer.
+///
+/// The returned transport opens a substream on the existing connection whenever we dial a peer
+/// we're already connected to, so that all the protocols share a single connection. The address
+/// of the remote that it produces ends with `/ipfs/<peer-id>`, where `<peer-id>` was
+/// authenticated by the secio handshake. See the `peer_addr` module of `chat-common`.
+///
+/// Connections are reused by address, so the address to dial a node we're connected to is the
+/// one that the transport produced, suffix included. Dialing an address that ends with
+/// `/ipfs/<peer-id>` fails if the remote turns out to be another node.
+fn secure_and_mux<T>(
+    transport: T,
+    keypair: &Keypair,
+    muxers: MuxerConfig,
+) -> impl MuxedTransport<Output = impl AsyncRead + AsyncWrite> + Clone
+where
+    T: Transport + Clone + 'static,
+    T::Output: AsyncRead + AsyncWrite,
+{
+    assert!(muxers.mplex || muxers.yamux, "at least one multiplexer must be enabled");
+
+    let secio = SecioConfig {
+        key: keypair.to_secio_keypair(),
+    };
+
+    // Both multiplexers are always part of the upgrade, but a disabled one is never negotiated.
+    let mut yamux = upgrade::toggleable({
+        let mut config = yamux::Config::default();
+        config.set_receive_window(YAMUX_RECEIVE_WINDOW);
+        libp2p_yamux::Config::new(config)
+    });
+    if !muxers.yamux {
+        yamux.disable();
+    }
+    let mut mplex = upgrade::toggleable(libp2p_mplex::BufferedMultiplexConfig::<[_; 256]>::new());
+    if !muxers.mplex {
+        mplex.disable();
+    }
+
+    peer_addr::StripPeerId::new(transport)
+        .with_upgrade(secio)
+        .and_then(|out, _endpoint, remote_addr| {
+            let remote_peer_id = out.remote_key.into_peer_id();
+            peer_addr::authenticated(remote_addr, &remote_peer_id).map(|addr| (out.stream, addr))
+        })
+        .with_upgrade(upgrade::or(
+            upgrade::map(yamux, EitherOutput::First),
+            upgrade::map(mplex, EitherOutput::Second),
+        ))
+        .into_connection_reuse()
+}
+
 #[cfg(not(target_os = "emscripten"))]
 impl PlatformSpecific {
+    /// Builds a transport that supports TCP and websockets, that encrypts all the connections
+    /// with secio and multiplexes them with the multiplexers enabled in `muxers`.
     pub fn build_transport(
         &self,
-    ) -> libp2p_core::transport::OrTransport<
-        libp2p_websocket::WsConfig<libp2p_tcp_transport::TcpConfig>,
-        libp2p_tcp_transport::TcpConfig,
-    > {
+        keypair: &Keypair,
+        muxers: MuxerConfig,
+    ) -> impl MuxedTransport<Output = impl AsyncRead + AsyncWrite> + Clone {
         let tcp = libp2p_tcp_transport::TcpConfig::new(self.core.handle());
-        libp2p_websocket::WsConfig::new(tcp.clone()).or_transport(tcp)
+        let ws_or_tcp = libp2p_websocket::WsConfig::new(tcp.clone()).or_transport(tcp);
+        secure_and_mux(ws_or_tcp, keypair, muxers)
+    }
+
+    /// Returns a handle to the events loop, for the code that needs to create sockets itself.
+    pub fn handle(&self) -> tokio_core::reactor::Handle {
+        self.core.handle()
+    }
+
+    /// Loads the keypair of the node from `path`, or from the default key file if `None`.
+    ///
+    /// A new keypair of type `key_type` is generated and stored if the file doesn't exist or if
+    /// `regenerate` is true.
+    pub fn keypair(
+        &self,
+        path: Option<&Path>,
+        key_type: KeyType,
+        regenerate: bool,
+    ) -> Result<Keypair, IoError> {
+        use self::chat_common::identity;
+        match path {
+            Some(path) => identity::load_or_generate(path, key_type, regenerate),
+            None => identity::load_or_generate(&identity::default_key_path(), key_type, regenerate),
+        }
     }
 
+    /// Returns the lines typed on stdin.
+    ///
+    /// The lines are read by a thread of their own, as reading stdin blocks. The invalid lines
+    /// are reported as errors, which don't end the stream. The stream ends when stdin is closed.
     pub fn stdin(&self) -> impl Stream<Item = String, Error = IoError> {
-        use std::mem;
-
-        let mut buffer = Vec::new();
-        tokio_stdin::spawn_stdin_stream_unbounded()
-            .map_err(|_| -> IoError { panic!() })
-            .filter_map(move |msg| {
-                if msg != b'\r' && msg != b'\n' {
-                    buffer.push(msg);
-                    return None;
-                } else if buffer.is_empty() {
-                    return None;
+        use futures::Sink;
+        use futures::sync::mpsc;
+        use std::io::{self, Read};
+        use std::thread;
+
+        // The channel is bounded, so that the thread waits while the node is busy instead of
+        // buffering whatever is typed or piped in the meanwhile.
+        let (mut tx, rx) = mpsc::channel(STDIN_BUFFER);
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            let mut stdin = stdin.lock();
+            let mut codec = LineCodec::new(MAX_LINE_LENGTH);
+            let mut buf = BytesMut::new();
+            let mut chunk = [0; 4096];
+            loop {
+                let (lines, eof) = match stdin.read(&mut chunk) {
+                    Ok(0) => (codec.decode_all(&mut buf, true), true),
+                    Ok(len) => {
+                        buf.extend_from_slice(&chunk[..len]);
+                        (codec.decode_all(&mut buf, false), false)
+                    }
+                    Err(ref err) if err.kind() == IoErrorKind::Interrupted => continue,
+                    Err(err) => (vec![Err(err)], true),
+                };
+                for line in lines {
+                    tx = match tx.send(line).wait() {
+                        Ok(tx) => tx,
+                        // The node has stopped.
+                        Err(_) => return,
+                    };
+                }
+                if eof {
+                    return;
                 }
+            }
+        });
 
-                Some(String::from_utf8(mem::replace(&mut buffer, Vec::new())).unwrap())
-            })
+        rx.map_err(|()| -> IoError { unreachable!() })
+            .and_then(|line| line)
     }
 
     pub fn run<F>(mut self, future: F)
@@ -105,17 +393,42 @@ impl PlatformSpecific {
 }
 #[cfg(target_os = "emscripten")]
 impl PlatformSpecific {
-    pub fn build_transport(&self) -> libp2p_websocket::BrowserWsConfig {
+    /// Builds a transport that supports websockets, that encrypts all the connections with
+    /// secio and multiplexes them with the multiplexers enabled in `muxers`.
+    pub fn build_transport(
+        &self,
+        keypair: &Keypair,
+        muxers: MuxerConfig,
+    ) -> impl MuxedTransport<Output = impl AsyncRead + AsyncWrite> + Clone {
         stdweb::initialize();
-        libp2p_websocket::BrowserWsConfig::new()
+        secure_and_mux(libp2p_websocket::BrowserWsConfig::new(), keypair, muxers)
     }
 
+    /// The browser doesn't give us access to the filesystem, so a new keypair is generated every
+    /// time the page is loaded.
+    pub fn keypair(
+        &self,
+        _path: Option<&Path>,
+        key_type: KeyType,
+        _regenerate: bool,
+    ) -> Result<Keypair, IoError> {
+        Keypair::generate(key_type)
+    }
+
+    /// Returns the lines submitted with the form of `browser.html`.
+    ///
+    /// The browser can't wait for us to handle a line before accepting the next one, so the lines
+    /// are buffered. The form only submits one line at a time, which keeps this buffer small.
     pub fn stdin(&self) -> impl Stream<Item = String, Error = IoError> {
         use futures::sync::mpsc;
         let (tx, rx) = mpsc::unbounded();
 
         let cb = move |txt: String| {
-            let _ = tx.unbounded_send(txt);
+            let mut codec = LineCodec::new(MAX_LINE_LENGTH);
+            let mut buf = BytesMut::from(txt.into_bytes());
+            for line in codec.decode_all(&mut buf, true) {
+                let _ = tx.unbounded_send(line);
+            }
         };
 
         js! {
@@ -131,6 +444,7 @@ impl PlatformSpecific {
         };
 
         rx.map_err(|_| -> IoError { unreachable!() })
+            .and_then(|line| line)
     }
 
     pub fn run<F>(self, future: F)
@@ -185,3 +499,104 @@ impl PlatformSpecific {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(codec: &mut LineCodec, buf: &mut BytesMut, eof: bool) -> Vec<Result<String, String>> {
+        codec
+            .decode_all(buf, eof)
+            .into_iter()
+            .map(|line| line.map_err(|err| err.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn lines_end_with_lf_or_crlf() {
+        let mut codec = LineCodec::new(16);
+        let mut buf = BytesMut::from(&b"one\ntwo\r\n\r\n\nthree\rfour\npartial"[..]);
+        assert_eq!(
+            decode(&mut codec, &mut buf, false),
+            vec![Ok("one".to_owned()), Ok("two".to_owned()), Ok("three\rfour".to_owned())]
+        );
+        assert_eq!(&buf[..], b"partial");
+
+        // The rest of the line arrives later.
+        buf.extend_from_slice(b" line\r");
+        assert!(decode(&mut codec, &mut buf, false).is_empty());
+        buf.extend_from_slice(b"\n");
+        assert_eq!(decode(&mut codec, &mut buf, false), vec![Ok("partial line".to_owned())]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn crlf_split_across_reads() {
+        let mut codec = LineCodec::new(4);
+        let mut buf = BytesMut::from(&b"abcd\r"[..]);
+        assert!(decode(&mut codec, &mut buf, false).is_empty());
+        buf.extend_from_slice(b"\n");
+        assert_eq!(decode(&mut codec, &mut buf, false), vec![Ok("abcd".to_owned())]);
+
+        // A line that is too long is still too long with its `\r`.
+        let mut buf = BytesMut::from(&b"abcde\r"[..]);
+        assert_eq!(decode(&mut codec, &mut buf, false), vec![Err(line_too_long(4).to_string())]);
+    }
+
+    #[test]
+    fn overlong_lines_are_discarded() {
+        let mut codec = LineCodec::new(4);
+        let too_long = Err(line_too_long(4).to_string());
+
+        // Too long, but complete: only this line is reported.
+        let mut buf = BytesMut::from(&b"abcdefg\nabcd\n"[..]);
+        assert_eq!(
+            decode(&mut codec, &mut buf, false),
+            vec![too_long.clone(), Ok("abcd".to_owned())]
+        );
+
+        // The CR of a CRLF doesn't count in the length.
+        let mut buf = BytesMut::from(&b"abcd\r\n"[..]);
+        assert_eq!(decode(&mut codec, &mut buf, false), vec![Ok("abcd".to_owned())]);
+
+        // Too long before the end of the line arrives: the end of the line is skipped as it comes.
+        let mut buf = BytesMut::from(&b"abcdef"[..]);
+        assert_eq!(decode(&mut codec, &mut buf, false), vec![too_long.clone()]);
+        assert!(buf.is_empty());
+        buf.extend_from_slice(b"ghijkl");
+        assert!(decode(&mut codec, &mut buf, false).is_empty());
+        buf.extend_from_slice(b"mn\nok\n");
+        assert_eq!(decode(&mut codec, &mut buf, false), vec![Ok("ok".to_owned())]);
+    }
+
+    #[test]
+    fn invalid_utf8_is_an_error() {
+        let mut codec = LineCodec::new(16);
+        let mut buf = BytesMut::from(&b"\xff\xfe\nok\n"[..]);
+        let lines = decode(&mut codec, &mut buf, false);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].is_err());
+        assert_eq!(lines[1], Ok("ok".to_owned()));
+    }
+
+    #[test]
+    fn last_line_is_decoded_at_eof() {
+        let mut codec = LineCodec::new(16);
+        let mut buf = BytesMut::from(&b"one\ntwo\r"[..]);
+        assert_eq!(
+            decode(&mut codec, &mut buf, true),
+            vec![Ok("one".to_owned()), Ok("two".to_owned())]
+        );
+        assert!(buf.is_empty());
+        assert_eq!(codec.decode_eof(&mut buf).unwrap(), None);
+
+        // The end of an overlong line is dropped rather than reported as a line of its own.
+        let mut codec = LineCodec::new(4);
+        let mut buf = BytesMut::from(&b"abcdef"[..]);
+        assert_eq!(decode(&mut codec, &mut buf, false).len(), 1);
+        buf.extend_from_slice(b"gh");
+        assert!(decode(&mut codec, &mut buf, true).is_empty());
+        buf.extend_from_slice(b"ok");
+        assert_eq!(decode(&mut codec, &mut buf, true), vec![Ok("ok".to_owned())]);
+    }
+}
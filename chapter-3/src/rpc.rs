@@ -0,0 +1,737 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Local control API, for scripting a running node.
+//!
+//! Outside of the browser, the node accepts JSON-RPC 2.0 requests on a Unix domain socket, which
+//! only the current user can connect to. The `chatctl` binary is a client for it. Requests,
+//! responses and notifications are JSON objects written on a line each:
+//!
+//! ```text
+//! --> {"jsonrpc": "2.0", "id": 1, "method": "publish", "params": {"room": "rust", "text": "hi"}}
+//! <-- {"jsonrpc":"2.0","id":1,"result":{"room":"rust"}}
+//! ```
+//!
+//! The methods are:
+//!
+//! - `publish {text, room?}` publishes a message in `room`, or in the active room.
+//! - `subscribe {room}` and `unsubscribe {room}` join and leave a room. Joining a room this way
+//!   doesn't change the active room.
+//! - `rooms` returns the rooms we're in, and the active room.
+//! - `dial {addr}` dials a multiaddress.
+//! - `peers` returns the nodes we're connected to, and `listeners` the addresses we listen on.
+//! - `messages_subscribe {room?}` returns the identifier of a subscription, after which the
+//!   messages that we receive in `room`, or all the messages that we receive (including the
+//!   direct ones) if there is no `room`, are sent on the connection as `message` notifications:
+//!   `{"jsonrpc":"2.0","method":"message","params":{"subscription":1,"message":{...}}}`. The
+//!   fields of the message are those of `MessageEvent`.
+//! - `messages_unsubscribe {subscription}` ends a subscription. They also end when the
+//!   connection closes.
+//!
+//! A client that doesn't read its notifications as fast as they arrive is disconnected once
+//! `NOTIFICATION_BUFFER` of them are waiting, instead of making the node hold on to all of them.
+//!
+//! All the methods except the subscriptions are carried out by the `Handler` passed to
+//! `ControlSocket::serve`, with the same controllers as the commands typed on stdin.
+
+use chat_common::message::{self, ChatMessage, Kind};
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Sink, Stream};
+use libp2p_core::{Multiaddr, PeerId};
+use platform::LineCodec;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Map, Value};
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use tokio_core::reactor::Handle;
+use tokio_io::codec::FramedRead;
+use tokio_io::{io, AsyncRead, AsyncWrite};
+use tokio_uds::UnixListener;
+
+/// Maximum length of a request, in bytes.
+pub const MAX_REQUEST_LENGTH: usize = 64 * 1024;
+
+/// Number of lines that can wait to be written on a connection before the node disconnects the
+/// client.
+pub const NOTIFICATION_BUFFER: usize = 256;
+
+/// The request isn't valid JSON.
+pub const PARSE_ERROR: i64 = -32700;
+/// The method doesn't exist.
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// The parameters of the method are missing or invalid.
+pub const INVALID_PARAMS: i64 = -32602;
+/// The node couldn't carry out the request, for example because it isn't in the room.
+pub const REQUEST_FAILED: i64 = -32000;
+
+/// A request that the node must carry out.
+#[derive(Debug, Clone)]
+pub enum Request {
+    /// Publish `text`, in the active room if `room` is `None`.
+    Publish { room: Option<String>, text: String },
+    Subscribe(String),
+    Unsubscribe(String),
+    Rooms,
+    Dial(Multiaddr),
+    Peers,
+    Listeners,
+}
+
+/// What the node answers to a `Request`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Reply {
+    /// The message has been published in `room`.
+    Published { room: String },
+    /// `joined` is false if we were already in the room.
+    Subscribed { joined: bool },
+    /// `left` is false if we weren't in the room.
+    Unsubscribed { left: bool },
+    Rooms { active: Option<String>, rooms: Vec<String> },
+    Dialing,
+    Peers(Vec<Peer>),
+    /// The multiaddresses we listen on.
+    Listeners(Vec<String>),
+}
+
+/// A node we're connected to.
+#[derive(Debug, Clone, Serialize)]
+pub struct Peer {
+    /// Base58 representation of the `PeerId` of the node.
+    pub peer_id: String,
+    pub addr: String,
+}
+
+/// Error returned in the response to a request.
+#[derive(Debug, Clone, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new<M: ToString>(code: i64, message: M) -> RpcError {
+        RpcError {
+            code: code,
+            message: message.to_string(),
+        }
+    }
+
+    /// Error for the requests that the node couldn't carry out.
+    #[inline]
+    pub fn failed<M: ToString>(message: M) -> RpcError {
+        RpcError::new(REQUEST_FAILED, message)
+    }
+}
+
+/// Carries out the requests received on the socket.
+pub type Handler = Rc<Fn(Request) -> Result<Reply, RpcError>>;
+
+/// A message that we received, as sent to the subscribers.
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageEvent {
+    /// Room the message was published in, or `None` for a direct message.
+    pub room: Option<String>,
+    /// Base58 representation of the `PeerId` of the author.
+    pub author: String,
+    pub nickname: String,
+    /// Time of the message, in milliseconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub kind: u32,
+    /// Content of the message. Invalid UTF-8 is replaced.
+    pub text: String,
+    /// The message as the node displays it.
+    pub display: String,
+    /// False if the signature of the message was invalid.
+    pub verified: bool,
+}
+
+impl MessageEvent {
+    /// Describes a message published by `source`. `message` is `None` if the message isn't a
+    /// `ChatMessage`, in which case `display` is also used as its text.
+    pub fn new(
+        room: Option<&str>,
+        source: &PeerId,
+        message: Option<&ChatMessage>,
+        display: &str,
+        verified: bool,
+    ) -> MessageEvent {
+        match message {
+            Some(message) => MessageEvent {
+                room: room.map(|room| room.to_owned()),
+                author: message.author.to_base58(),
+                nickname: message.nickname.clone(),
+                timestamp: message.timestamp,
+                kind: message.kind.to_u32(),
+                text: String::from_utf8_lossy(&message.payload).into_owned(),
+                display: display.to_owned(),
+                verified: verified,
+            },
+            None => MessageEvent {
+                room: room.map(|room| room.to_owned()),
+                author: source.to_base58(),
+                nickname: message::default_nickname(source),
+                timestamp: message::now_millis(),
+                kind: Kind::Text.to_u32(),
+                text: display.to_owned(),
+                display: display.to_owned(),
+                verified: verified,
+            },
+        }
+    }
+}
+
+/// The subscriptions to the messages, of all the connections.
+///
+/// Cloning a `Subscriptions` gives access to the same subscriptions.
+#[derive(Clone, Default)]
+pub struct Subscriptions {
+    inner: Arc<Mutex<SubscriptionsInner>>,
+}
+
+#[derive(Default)]
+struct SubscriptionsInner {
+    /// Identifier of the last subscription.
+    last_id: u64,
+    /// Identifier of the last connection.
+    last_connection: u64,
+    list: Vec<Subscription>,
+    /// Closes a connection, for the connections that are open.
+    closers: HashMap<u64, oneshot::Sender<()>>,
+}
+
+struct Subscription {
+    id: u64,
+    connection: u64,
+    /// If `None`, all the messages, including the direct ones.
+    room: Option<String>,
+    /// Lines to write on the connection.
+    sender: mpsc::Sender<String>,
+}
+
+impl Subscriptions {
+    /// Creates an empty list of subscriptions.
+    #[inline]
+    pub fn new() -> Subscriptions {
+        Subscriptions::default()
+    }
+
+    /// Sends `message` to the connections that subscribed to it.
+    ///
+    /// The connections whose queue is full are closed.
+    pub fn notify(&self, message: &MessageEvent) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut closed = Vec::new();
+        for subscription in inner.list.iter_mut() {
+            match subscription.room {
+                Some(ref room) if message.room.as_ref() != Some(room) => continue,
+                _ => {}
+            }
+            let notification = Notification {
+                jsonrpc: "2.0",
+                method: "message",
+                params: NotificationParams {
+                    subscription: subscription.id,
+                    message: message,
+                },
+            };
+            // Sending fails if the connection is already closed, or if the client is too slow.
+            if subscription.sender.try_send(to_line(&notification)).is_err() {
+                closed.push(subscription.connection);
+            }
+        }
+        for connection in closed {
+            inner.list.retain(|sub| sub.connection != connection);
+            if let Some(closer) = inner.closers.remove(&connection) {
+                let _ = closer.send(());
+            }
+        }
+    }
+
+    /// Returns the identifier of a new connection, and a future that finishes if the connection
+    /// must be closed.
+    fn new_connection(&self) -> (u64, oneshot::Receiver<()>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_connection += 1;
+        let connection = inner.last_connection;
+        let (closer, closed) = oneshot::channel();
+        inner.closers.insert(connection, closer);
+        (connection, closed)
+    }
+
+    /// Adds a subscription of `connection`, and returns its identifier.
+    fn add(
+        &self,
+        connection: u64,
+        room: Option<String>,
+        sender: mpsc::Sender<String>,
+    ) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_id += 1;
+        let id = inner.last_id;
+        inner.list.push(Subscription {
+            id: id,
+            connection: connection,
+            room: room,
+            sender: sender,
+        });
+        id
+    }
+
+    /// Removes the subscription `id` of `connection`. Returns false if there is no such
+    /// subscription.
+    fn remove(&self, connection: u64, id: u64) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let len = inner.list.len();
+        inner.list.retain(|sub| sub.id != id || sub.connection != connection);
+        inner.list.len() != len
+    }
+
+    /// Removes all the subscriptions of `connection`.
+    fn remove_connection(&self, connection: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.list.retain(|sub| sub.connection != connection);
+        inner.closers.remove(&connection);
+    }
+}
+
+/// A control socket that is bound, but doesn't accept connections yet.
+pub struct ControlSocket {
+    listener: StdUnixListener,
+    file: SocketFile,
+}
+
+/// Binds the Unix domain socket `path`, which only the current user can connect to.
+///
+/// A socket left behind by a node that didn't stop cleanly is replaced, but not one that another
+/// node is listening on.
+pub fn bind(path: &Path) -> Result<ControlSocket, IoError> {
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    fs::create_dir_all(&parent)?;
+    if path.exists() {
+        if StdUnixStream::connect(path).is_ok() {
+            let msg = "another node is listening on this socket";
+            return Err(IoError::new(IoErrorKind::AddrInUse, msg));
+        }
+        fs::remove_file(path)?;
+    }
+
+    // The socket is created with the permissions given by the umask. To make sure that nobody
+    // else connects before they are restricted, it is bound in a directory that only we can
+    // enter, and only moved to `path` afterwards.
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("socket");
+    let private_dir = parent.join(format!(".{}.{}", name, process::id()));
+    DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let listener = bind_in(&private_dir, path);
+    let _ = fs::remove_dir_all(&private_dir);
+
+    Ok(ControlSocket {
+        listener: listener?,
+        file: SocketFile(path.to_owned()),
+    })
+}
+
+/// Binds a socket in `dir`, restricts it to the current user and moves it to `path`.
+fn bind_in(dir: &Path, path: &Path) -> Result<StdUnixListener, IoError> {
+    let tmp_path = dir.join("socket");
+    let listener = StdUnixListener::bind(&tmp_path)?;
+    fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))?;
+    fs::rename(&tmp_path, path)?;
+    Ok(listener)
+}
+
+impl ControlSocket {
+    /// Returns a future that serves the connections to the socket.
+    pub fn serve(
+        self,
+        handle: &Handle,
+        handler: Handler,
+        subscriptions: Subscriptions,
+    ) -> Result<impl Future<Item = (), Error = IoError>, IoError> {
+        let listener = UnixListener::from_listener(self.listener, handle)?;
+        let socket_file = self.file;
+
+        let handle = handle.clone();
+        let server = listener.incoming().for_each(move |(stream, _)| {
+            // A client going away isn't worth reporting.
+            let connection = serve(stream, handler.clone(), subscriptions.clone());
+            handle.spawn(connection.then(|_| Ok::<(), ()>(())));
+            Ok(())
+        });
+        Ok(server.then(move |result| {
+            drop(socket_file);
+            result
+        }))
+    }
+}
+
+/// Removes the socket when dropped, which happens when the server stops, so that the clients
+/// don't connect to a node that has stopped.
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Answers the requests received on `stream`, and writes the notifications of its
+/// subscriptions.
+fn serve<S>(
+    stream: S,
+    handler: Handler,
+    subscriptions: Subscriptions,
+) -> impl Future<Item = (), Error = IoError>
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let (connection, closed) = subscriptions.new_connection();
+    let (reader, writer) = stream.split();
+    let (tx, rx) = mpsc::channel::<String>(NOTIFICATION_BUFFER);
+
+    let requests = FramedRead::new(reader, LineCodec::new(MAX_REQUEST_LENGTH))
+        // The lines that can't be decoded are answered like invalid JSON.
+        .then(|result| match result {
+            Err(ref err) if err.kind() == IoErrorKind::InvalidData => Ok(Err(err.to_string())),
+            result => result.map(Ok),
+        })
+        .for_each({
+            let tx = tx.clone();
+            let subscriptions = subscriptions.clone();
+            move |line| {
+                let response = match line {
+                    Ok(line) => handle_line(&line, connection, &*handler, &subscriptions, &tx),
+                    Err(err) => {
+                        Some(Response::new(Value::Null, Err(RpcError::new(PARSE_ERROR, err))))
+                    }
+                };
+                // Waiting for room in the queue stops reading the requests of a client that
+                // doesn't read the responses.
+                match response {
+                    Some(response) => future::Either::A(
+                        tx.clone().send(to_line(&response)).then(|_| Ok::<(), IoError>(())),
+                    ),
+                    None => future::Either::B(future::ok(())),
+                }
+            }
+        })
+        .then(move |result| {
+            // Once all the senders are dropped, the responses that are still queued are written
+            // and the connection closes.
+            subscriptions.remove_connection(connection);
+            drop(tx);
+            result
+        });
+
+    let responses = rx
+        .map_err(|()| -> IoError { unreachable!() })
+        .fold(writer, |writer, line| {
+            io::write_all(writer, line.into_bytes()).map(|(writer, _)| writer)
+        })
+        .map(|_| ());
+
+    // `closed` finishes with an error once the connection is removed from `subscriptions`, which
+    // only happens after the requests.
+    let closed = closed.or_else(|_| future::empty::<(), IoError>());
+    requests.join(responses).map(|_| ()).select(closed).map(|_| ()).map_err(|(err, _)| err)
+}
+
+/// Carries out the request on `line`, and returns the response to it. Returns `None` if the
+/// request is a notification, which doesn't expect a response.
+fn handle_line(
+    line: &str,
+    connection: u64,
+    handler: &Fn(Request) -> Result<Reply, RpcError>,
+    subscriptions: &Subscriptions,
+    sender: &mpsc::Sender<String>,
+) -> Option<Response> {
+    let request: RawRequest = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(err) => return Some(Response::new(Value::Null, Err(RpcError::new(PARSE_ERROR, err)))),
+    };
+
+    let result = match request.method.as_str() {
+        "messages_subscribe" => parse_params(request.params).map(|params: MessagesParams| {
+            Value::from(subscriptions.add(connection, params.room, sender.clone()))
+        }),
+        "messages_unsubscribe" => parse_params(request.params).map(|params: SubscriptionParams| {
+            Value::Bool(subscriptions.remove(connection, params.subscription))
+        }),
+        method => Request::parse(method, request.params)
+            .and_then(|request| handler(request))
+            .map(|reply| serde_json::to_value(reply).expect("replies are always serializable")),
+    };
+    request.id.map(|id| Response::new(id, result))
+}
+
+impl Request {
+    /// Parses the request for `method`. The subscriptions aren't `Request`s.
+    fn parse(method: &str, params: Value) -> Result<Request, RpcError> {
+        Ok(match method {
+            "publish" => {
+                let params: PublishParams = parse_params(params)?;
+                Request::Publish {
+                    room: params.room,
+                    text: params.text,
+                }
+            }
+            "subscribe" => Request::Subscribe(parse_params::<RoomParams>(params)?.room),
+            "unsubscribe" => Request::Unsubscribe(parse_params::<RoomParams>(params)?.room),
+            "rooms" => Request::Rooms,
+            "dial" => {
+                let params: DialParams = parse_params(params)?;
+                match params.addr.parse() {
+                    Ok(addr) => Request::Dial(addr),
+                    Err(_) => {
+                        let msg = format!("invalid multiaddress: {}", params.addr);
+                        return Err(RpcError::new(INVALID_PARAMS, msg));
+                    }
+                }
+            }
+            "peers" => Request::Peers,
+            "listeners" => Request::Listeners,
+            _ => return Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {}", method))),
+        })
+    }
+}
+
+/// Deserializes the parameters of a method.
+fn parse_params<T>(params: Value) -> Result<T, RpcError>
+where
+    T: DeserializeOwned,
+{
+    // The methods whose parameters are all optional can be called without any.
+    let params = if params.is_null() { Value::Object(Map::new()) } else { params };
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err))
+}
+
+/// Serializes `value` on a line.
+fn to_line<T: ::serde::Serialize>(value: &T) -> String {
+    let mut line = serde_json::to_string(value).expect("messages are always serializable");
+    line.push('\n');
+    line
+}
+
+#[derive(Deserialize)]
+struct RawRequest {
+    /// Absent for notifications.
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct PublishParams {
+    text: String,
+    #[serde(default)]
+    room: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RoomParams {
+    room: String,
+}
+
+#[derive(Deserialize)]
+struct DialParams {
+    addr: String,
+}
+
+#[derive(Deserialize)]
+struct MessagesParams {
+    #[serde(default)]
+    room: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SubscriptionParams {
+    subscription: u64,
+}
+
+#[derive(Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl Response {
+    fn new(id: Value, result: Result<Value, RpcError>) -> Response {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Response {
+            jsonrpc: "2.0",
+            id: id,
+            result: result,
+            error: error,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    jsonrpc: &'static str,
+    method: &'static str,
+    params: NotificationParams<'a>,
+}
+
+#[derive(Serialize)]
+struct NotificationParams<'a> {
+    subscription: u64,
+    message: &'a MessageEvent,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_common::key::{KeyType, Keypair};
+
+    /// Answers `line` with a handler that only knows `rooms`, and returns the response as JSON.
+    fn answer(line: &str) -> Option<Value> {
+        let handler = |request| match request {
+            Request::Rooms => Ok(Reply::Rooms {
+                active: None,
+                rooms: Vec::new(),
+            }),
+            _ => Err(RpcError::failed("unexpected request")),
+        };
+        let (tx, _rx) = mpsc::channel(NOTIFICATION_BUFFER);
+        handle_line(line, 1, &handler, &Subscriptions::new(), &tx)
+            .map(|response| serde_json::to_value(response).unwrap())
+    }
+
+    fn error_code(response: Option<Value>) -> Option<i64> {
+        response.and_then(|response| response["error"]["code"].as_i64())
+    }
+
+    fn event(room: Option<&str>) -> MessageEvent {
+        let source = Keypair::generate(KeyType::Ed25519).unwrap().peer_id();
+        MessageEvent::new(room, &source, None, "hello", true)
+    }
+
+    /// Returns the subscriptions that the notifications written on `rx` are for.
+    fn received(rx: mpsc::Receiver<String>) -> Vec<u64> {
+        rx.collect()
+            .wait()
+            .unwrap()
+            .iter()
+            .map(|line| {
+                let notification: Value = serde_json::from_str(line).unwrap();
+                notification["params"]["subscription"].as_u64().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn valid_request() {
+        let response = answer(r#"{"jsonrpc": "2.0", "id": 7, "method": "rooms"}"#).unwrap();
+        assert_eq!(response["id"], json!(7));
+        assert_eq!(response["result"], json!({"active": null, "rooms": []}));
+        assert!(response.get("error").is_none());
+    }
+
+    #[test]
+    fn invalid_json() {
+        let response = answer("{\"jsonrpc\": \"2.0\", \"id\": 7,");
+        assert_eq!(response.as_ref().map(|response| &response["id"]), Some(&Value::Null));
+        assert_eq!(error_code(response), Some(PARSE_ERROR));
+    }
+
+    #[test]
+    fn unknown_method() {
+        let response = answer(r#"{"jsonrpc": "2.0", "id": 1, "method": "reboot"}"#);
+        assert_eq!(error_code(response), Some(METHOD_NOT_FOUND));
+    }
+
+    #[test]
+    fn invalid_params() {
+        for line in [
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "publish"}"#,
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "publish", "params": {"text": 5}}"#,
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "dial", "params": {"addr": "nowhere"}}"#,
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "messages_unsubscribe", "params": {}}"#,
+        ].iter()
+        {
+            assert_eq!(error_code(answer(line)), Some(INVALID_PARAMS), "{}", line);
+        }
+    }
+
+    #[test]
+    fn handler_errors_are_returned() {
+        let response = answer(r#"{"jsonrpc": "2.0", "id": 1, "method": "peers"}"#);
+        assert_eq!(error_code(response), Some(REQUEST_FAILED));
+    }
+
+    #[test]
+    fn notifications_are_not_answered() {
+        assert!(answer(r#"{"jsonrpc": "2.0", "method": "rooms"}"#).is_none());
+        assert!(answer(r#"{"jsonrpc": "2.0", "method": "reboot"}"#).is_none());
+    }
+
+    #[test]
+    fn notify_filters_by_room() {
+        let subscriptions = Subscriptions::new();
+        let (connection, _closed) = subscriptions.new_connection();
+        let (tx, rx) = mpsc::channel(NOTIFICATION_BUFFER);
+        let rust = subscriptions.add(connection, Some("rust".to_owned()), tx.clone());
+        let all = subscriptions.add(connection, None, tx);
+
+        subscriptions.notify(&event(Some("rust")));
+        subscriptions.notify(&event(Some("general")));
+        subscriptions.notify(&event(None));
+        assert!(subscriptions.remove(connection, rust));
+        subscriptions.notify(&event(Some("rust")));
+
+        subscriptions.remove_connection(connection);
+        assert_eq!(received(rx), vec![rust, all, all, all, all]);
+    }
+
+    #[test]
+    fn slow_connection_is_closed() {
+        let subscriptions = Subscriptions::new();
+        let (connection, closed) = subscriptions.new_connection();
+        let (tx, _rx) = mpsc::channel(NOTIFICATION_BUFFER);
+        subscriptions.add(connection, None, tx);
+
+        // Nothing reads the notifications.
+        for _ in 0..NOTIFICATION_BUFFER + 2 {
+            subscriptions.notify(&event(Some("rust")));
+        }
+        assert_eq!(closed.wait(), Ok(()));
+        assert!(subscriptions.inner.lock().unwrap().list.is_empty());
+    }
+}
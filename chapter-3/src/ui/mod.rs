@@ -0,0 +1,92 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Output of the node, and its full-screen terminal UI.
+//!
+//! Everything the node prints goes through `print`, `print_in` and `eprint`, usually with the
+//! `ui_println!`, `ui_println_in!` and `ui_eprintln!` macros. When the terminal UI is running, the
+//! lines are shown in its panes. Otherwise they are written to stdout and stderr, one per line,
+//! which is what happens in the browser, when stdout or stdin isn't a terminal, with `--no-tui`,
+//! and when the terminal UI fails to start.
+//!
+//! The terminal UI (see `Tui`) has a pane per room with its own scrollback, a sidebar with the
+//! nodes we're connected to and the members of the active room, a status bar with our listen
+//! addresses and the number of connections, and an input line with editing and history. A line
+//! printed with `print_in` goes to the pane it names: the pane of a room, or the `direct` pane of
+//! the direct messages. Outside of the terminal UI, it is printed as `[<name>] <text>`. The other
+//! lines, such as the output of the commands, go to the pane being viewed.
+//!
+//! Keys of the terminal UI:
+//!
+//! - Enter sends the input line, and Up and Down browse the lines sent before.
+//! - Left, Right, Home, End, Backspace, Delete, Ctrl-A, Ctrl-E, Ctrl-U and Ctrl-W edit the line.
+//! - Tab and Ctrl-P view the next and the previous pane. Viewing the pane of a room makes it the
+//!   active room.
+//! - Page Up and Page Down scroll the pane.
+//! - Ctrl-C, or Ctrl-D on an empty line, runs `/quit`.
+
+#[cfg(not(target_os = "emscripten"))]
+mod terminal;
+
+#[cfg(not(target_os = "emscripten"))]
+pub use self::terminal::{is_terminal, Overview, Tui};
+
+/// Prints a line of output, in the terminal UI if it's running or on stdout otherwise.
+#[cfg(not(target_os = "emscripten"))]
+pub fn print(line: String) {
+    if let Err(line) = terminal::try_print(None, line, false) {
+        println!("{}", line);
+    }
+}
+
+/// Prints a line of output about the room, or the other pane, called `pane`. In the terminal UI,
+/// it goes to the pane. Otherwise, it is printed on stdout as `[<pane>] <line>`.
+#[cfg(not(target_os = "emscripten"))]
+pub fn print_in(pane: &str, line: String) {
+    if let Err(line) = terminal::try_print(Some(pane), line, false) {
+        println!("[{}] {}", pane, line);
+    }
+}
+
+/// Prints a line about an error, in the terminal UI if it's running or on stderr otherwise.
+#[cfg(not(target_os = "emscripten"))]
+pub fn eprint(line: String) {
+    if let Err(line) = terminal::try_print(None, line, true) {
+        eprintln!("{}", line);
+    }
+}
+
+/// Prints a line of output. The browser has no terminal UI.
+#[cfg(target_os = "emscripten")]
+pub fn print(line: String) {
+    println!("{}", line);
+}
+
+/// Prints a line of output about the room, or the other pane, called `pane`.
+#[cfg(target_os = "emscripten")]
+pub fn print_in(pane: &str, line: String) {
+    println!("[{}] {}", pane, line);
+}
+
+/// Prints a line about an error. The browser has no terminal UI.
+#[cfg(target_os = "emscripten")]
+pub fn eprint(line: String) {
+    eprintln!("{}", line);
+}
@@ -0,0 +1,565 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! The full-screen terminal UI. See the parent module for what it looks like.
+
+use futures::sync::mpsc;
+use futures::{Future, Sink, Stream};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Error as IoError, Stdout, Write};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+use termion;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::{IntoRawMode, RawTerminal};
+use termion::screen::AlternateScreen;
+use termion::{clear, cursor, style};
+
+/// Number of lines kept in the scrollback of each pane.
+const SCROLLBACK: usize = 1000;
+
+/// Number of lines sent before that Up and Down can browse.
+const INPUT_HISTORY: usize = 100;
+
+/// Width of the sidebar, separator included.
+const SIDEBAR_WIDTH: u16 = 30;
+
+/// Below this width, the sidebar is hidden.
+const MIN_WIDTH_FOR_SIDEBAR: u16 = 80;
+
+/// Interval between two refreshes of the sidebar and of the status bar, which show things that
+/// change without anything being printed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of key presses that can wait for the node to handle them.
+const KEYS_BUFFER: usize = 64;
+
+/// Name of the pane that exists before we join any room.
+const STATUS_PANE: &str = "status";
+
+thread_local! {
+    /// The running UI, if any. The node prints from the thread of the events loop only.
+    static SCREEN: RefCell<Option<Rc<RefCell<Screen>>>> = RefCell::new(None);
+}
+
+/// Returns true if stdin and stdout are both terminals, which the UI needs.
+pub fn is_terminal() -> bool {
+    termion::is_tty(&io::stdin()) && termion::is_tty(&io::stdout())
+}
+
+/// Shows `line` in the UI, in the pane called `pane` or in the pane being viewed. Gives it back if
+/// the UI isn't running.
+pub fn try_print(pane: Option<&str>, line: String, error: bool) -> Result<(), String> {
+    SCREEN.with(|current| match *current.borrow() {
+        Some(ref screen) => {
+            let mut screen = screen.borrow_mut();
+            screen.push_line(pane, line, error);
+            screen.draw();
+            Ok(())
+        }
+        None => Err(line),
+    })
+}
+
+/// What the sidebar and the status bar show.
+#[derive(Debug, Clone, Default)]
+pub struct Overview {
+    /// Room where the lines that aren't commands are published.
+    pub active_room: Option<String>,
+    /// Rooms we're in.
+    pub rooms: Vec<String>,
+    /// Nodes we're connected to.
+    pub peers: Vec<String>,
+    /// Members of the active room, apart from us.
+    pub members: Vec<String>,
+    /// Addresses we're listening on.
+    pub listen_addrs: Vec<String>,
+}
+
+/// The running UI. The terminal is restored when it's dropped.
+pub struct Tui {
+    screen: Rc<RefCell<Screen>>,
+}
+
+impl Tui {
+    /// Switches the terminal to the UI. From now on, `ui::print` and `ui::eprint` print in it.
+    pub fn start() -> Result<Tui, IoError> {
+        let out = AlternateScreen::from(io::stdout().into_raw_mode()?);
+        let screen = Rc::new(RefCell::new(Screen {
+            out: out,
+            overview_source: Box::new(Overview::default),
+            overview: Overview::default(),
+            followed: None,
+            panes: vec![Pane::new(STATUS_PANE)],
+            viewed: 0,
+            input: Vec::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            browsing: None,
+        }));
+        screen.borrow_mut().draw();
+        SCREEN.with(|current| *current.borrow_mut() = Some(screen.clone()));
+        Ok(Tui { screen: screen })
+    }
+
+    /// Sets where the sidebar and the status bar get their content. `source` is called at each
+    /// redraw.
+    pub fn set_overview<F>(&self, source: F)
+    where
+        F: Fn() -> Overview + 'static,
+    {
+        let mut screen = self.screen.borrow_mut();
+        screen.overview_source = Box::new(source);
+        screen.draw();
+    }
+
+    /// Returns the lines typed in the input line.
+    ///
+    /// The keys are read by a thread of their own, as reading stdin blocks, and another thread
+    /// wakes the UI up every `REFRESH_INTERVAL` so that it redraws the sidebar.
+    pub fn input(&self) -> impl Stream<Item = String, Error = IoError> {
+        let (keys_tx, rx) = mpsc::channel(KEYS_BUFFER);
+        let ticks_tx = keys_tx.clone();
+
+        thread::spawn(move || {
+            let mut tx = keys_tx;
+            for key in io::stdin().keys() {
+                let key = match key {
+                    Ok(key) => key,
+                    Err(_) => return,
+                };
+                tx = match tx.send(Event::Key(key)).wait() {
+                    Ok(tx) => tx,
+                    // The node has stopped.
+                    Err(_) => return,
+                };
+            }
+        });
+
+        thread::spawn(move || {
+            let mut tx = ticks_tx;
+            loop {
+                thread::sleep(REFRESH_INTERVAL);
+                tx = match tx.send(Event::Tick).wait() {
+                    Ok(tx) => tx,
+                    Err(_) => return,
+                };
+            }
+        });
+
+        let screen = self.screen.clone();
+        rx.map_err(|()| -> IoError { unreachable!() })
+            .filter_map(move |event| {
+                let mut screen = screen.borrow_mut();
+                let line = match event {
+                    Event::Key(key) => screen.on_key(key),
+                    Event::Tick => None,
+                };
+                screen.draw();
+                line
+            })
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        SCREEN.with(|current| current.borrow_mut().take());
+    }
+}
+
+enum Event {
+    Key(Key),
+    Tick,
+}
+
+struct Screen {
+    /// Dropping it leaves the alternate screen and the raw mode.
+    out: AlternateScreen<RawTerminal<Stdout>>,
+    overview_source: Box<Fn() -> Overview>,
+    /// What `overview_source` returned at the last redraw.
+    overview: Overview,
+    /// The active room the last time we made it the viewed pane.
+    followed: Option<String>,
+    panes: Vec<Pane>,
+    /// Index within `panes` of the pane being viewed.
+    viewed: usize,
+    input: Vec<char>,
+    /// Position of the cursor within `input`.
+    cursor: usize,
+    /// Lines sent before, most recent last.
+    history: VecDeque<String>,
+    /// While browsing `history` with Up and Down, the position in it and the line that was being
+    /// typed.
+    browsing: Option<(usize, Vec<char>)>,
+}
+
+struct Pane {
+    name: String,
+    lines: VecDeque<String>,
+    /// Number of lines added while the pane wasn't viewed.
+    unread: usize,
+    /// Number of rows the pane is scrolled up by.
+    scroll: usize,
+}
+
+impl Pane {
+    fn new(name: &str) -> Pane {
+        Pane {
+            name: name.to_owned(),
+            lines: VecDeque::new(),
+            unread: 0,
+            scroll: 0,
+        }
+    }
+}
+
+impl Screen {
+    fn push_line(&mut self, pane: Option<&str>, line: String, error: bool) {
+        let index = match pane {
+            Some(name) => self.pane(name),
+            None => self.viewed,
+        };
+        let text = if error { format!("! {}", line) } else { line };
+
+        let viewed = index == self.viewed;
+        let pane = &mut self.panes[index];
+        pane.lines.push_back(text);
+        if pane.lines.len() > SCROLLBACK {
+            pane.lines.pop_front();
+        }
+        if !viewed {
+            pane.unread += 1;
+        } else if pane.scroll > 0 {
+            // Keep showing the same lines.
+            pane.scroll += 1;
+        }
+    }
+
+    /// Returns the index of the pane called `name`, which is created if needed.
+    fn pane(&mut self, name: &str) -> usize {
+        match self.panes.iter().position(|pane| pane.name == name) {
+            Some(index) => index,
+            None => {
+                self.panes.push(Pane::new(name));
+                self.panes.len() - 1
+            }
+        }
+    }
+
+    /// Handles a key press. Returns the line to send, if any.
+    fn on_key(&mut self, key: Key) -> Option<String> {
+        match key {
+            Key::Char('\n') => return self.submit(),
+            Key::Char('\t') => return self.view_next(1),
+            Key::Ctrl('p') => {
+                let previous = self.panes.len() - 1;
+                return self.view_next(previous);
+            }
+            Key::Ctrl('c') => return Some("/quit".to_owned()),
+            Key::Ctrl('d') if self.input.is_empty() => return Some("/quit".to_owned()),
+            Key::Char(c) if !c.is_control() => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            Key::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            Key::Left if self.cursor > 0 => self.cursor -= 1,
+            Key::Right if self.cursor < self.input.len() => self.cursor += 1,
+            Key::Home | Key::Ctrl('a') => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.input.len(),
+            Key::Ctrl('u') => {
+                self.input.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::Ctrl('w') => {
+                let end = self.cursor;
+                while self.cursor > 0 && self.input[self.cursor - 1] == ' ' {
+                    self.cursor -= 1;
+                }
+                while self.cursor > 0 && self.input[self.cursor - 1] != ' ' {
+                    self.cursor -= 1;
+                }
+                self.input.drain(self.cursor..end);
+            }
+            Key::Up => self.browse_history(true),
+            Key::Down => self.browse_history(false),
+            Key::PageUp => {
+                let page = self.page_height();
+                let pane = &mut self.panes[self.viewed];
+                pane.scroll += page;
+            }
+            Key::PageDown => {
+                let page = self.page_height();
+                let pane = &mut self.panes[self.viewed];
+                pane.scroll = pane.scroll.saturating_sub(page);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Sends the input line. It is echoed in the pane being viewed, as the terminal would do in
+    /// line mode.
+    fn submit(&mut self) -> Option<String> {
+        let line = self.input.drain(..).collect::<String>();
+        self.cursor = 0;
+        self.browsing = None;
+        if line.trim().is_empty() {
+            return None;
+        }
+
+        if self.history.back() != Some(&line) {
+            self.history.push_back(line.clone());
+            if self.history.len() > INPUT_HISTORY {
+                self.history.pop_front();
+            }
+        }
+        let pane = &mut self.panes[self.viewed];
+        pane.scroll = 0;
+        pane.lines.push_back(format!("> {}", line));
+        Some(line)
+    }
+
+    /// Views the pane `offset` positions after the current one. If it's the pane of a room we're
+    /// in, returns the command that makes it the active room.
+    fn view_next(&mut self, offset: usize) -> Option<String> {
+        self.viewed = (self.viewed + offset) % self.panes.len();
+        let name = &self.panes[self.viewed].name;
+        if self.overview.rooms.contains(name) {
+            Some(format!("/topic {}", name))
+        } else {
+            None
+        }
+    }
+
+    fn browse_history(&mut self, older: bool) {
+        let position = match (self.browsing.as_ref().map(|&(pos, _)| pos), older) {
+            (None, true) if !self.history.is_empty() => self.history.len() - 1,
+            (Some(pos), true) if pos > 0 => pos - 1,
+            (Some(pos), false) if pos + 1 < self.history.len() => pos + 1,
+            (Some(_), false) => {
+                // Back to the line that was being typed.
+                if let Some((_, draft)) = self.browsing.take() {
+                    self.input = draft;
+                    self.cursor = self.input.len();
+                }
+                return;
+            }
+            _ => return,
+        };
+
+        if self.browsing.is_none() {
+            self.browsing = Some((position, self.input.clone()));
+        }
+        if let Some(browsing) = self.browsing.as_mut() {
+            browsing.0 = position;
+        }
+        self.input = self.history[position].chars().collect();
+        self.cursor = self.input.len();
+    }
+
+    /// Number of rows of the panes.
+    fn page_height(&self) -> usize {
+        let (_, height) = termion::terminal_size().unwrap_or((80, 24));
+        height.saturating_sub(3) as usize
+    }
+
+    /// Redraws the whole screen.
+    fn draw(&mut self) {
+        self.overview = (self.overview_source)();
+        self.follow_active_room();
+        self.panes[self.viewed].unread = 0;
+
+        let (width, height) = termion::terminal_size().unwrap_or((80, 24));
+        if width < 20 || height < 4 {
+            return;
+        }
+        let sidebar = if width >= MIN_WIDTH_FOR_SIDEBAR { SIDEBAR_WIDTH } else { 0 };
+        let pane_width = (width - sidebar) as usize;
+        let pane_height = (height - 3) as usize;
+
+        let mut frame = String::new();
+        let _ = write!(frame, "{}{}", cursor::Hide, clear::All);
+        self.draw_tabs(&mut frame, width as usize);
+        self.draw_pane(&mut frame, pane_width, pane_height);
+        if sidebar > 0 {
+            self.draw_sidebar(&mut frame, pane_width as u16 + 1, pane_height);
+        }
+        self.draw_status(&mut frame, width as usize, height - 1);
+        self.draw_input(&mut frame, width as usize, height);
+
+        let _ = self.out.write_all(frame.as_bytes());
+        let _ = self.out.flush();
+    }
+
+    /// Creates the panes of the rooms we're in, and views the pane of the active room when it
+    /// changes, for example after `/join`.
+    fn follow_active_room(&mut self) {
+        for room in self.overview.rooms.clone() {
+            self.pane(&room);
+        }
+        if self.overview.active_room != self.followed {
+            self.followed = self.overview.active_room.clone();
+            if let Some(active) = self.followed.clone() {
+                self.viewed = self.pane(&active);
+            }
+        }
+    }
+
+    fn draw_tabs(&self, frame: &mut String, width: usize) {
+        let _ = write!(frame, "{}", cursor::Goto(1, 1));
+        let mut used = 0;
+        for (index, pane) in self.panes.iter().enumerate() {
+            let label = match pane.unread {
+                0 => format!(" {} ", pane.name),
+                unread => format!(" {} ({}) ", pane.name, unread),
+            };
+            let label = truncate(&label, width.saturating_sub(used));
+            used += label.chars().count();
+            if index == self.viewed {
+                let _ = write!(frame, "{}{}{}", style::Invert, label, style::Reset);
+            } else {
+                frame.push_str(&label);
+            }
+        }
+    }
+
+    fn draw_pane(&self, frame: &mut String, width: usize, height: usize) {
+        let pane = &self.panes[self.viewed];
+
+        // The rows at the bottom of the pane, from the bottom up.
+        let mut rows = Vec::new();
+        for line in pane.lines.iter().rev() {
+            rows.extend(wrap(line, width).into_iter().rev());
+            if rows.len() >= height + pane.scroll {
+                break;
+            }
+        }
+        let scroll = pane.scroll.min(rows.len().saturating_sub(height));
+
+        for (n, row) in rows.iter().skip(scroll).take(height).enumerate() {
+            let y = (1 + height - n) as u16;
+            let _ = write!(frame, "{}{}", cursor::Goto(1, y), row);
+        }
+    }
+
+    fn draw_sidebar(&self, frame: &mut String, x: u16, height: usize) {
+        let overview = &self.overview;
+        let mut rows = vec![format!("Peers ({})", overview.peers.len())];
+        rows.extend(overview.peers.iter().map(|peer| format!("  {}", peer)));
+        if let Some(ref room) = overview.active_room {
+            rows.push(String::new());
+            rows.push(format!("Members of {}", room));
+            rows.extend(overview.members.iter().map(|member| format!("  {}", member)));
+        }
+
+        let width = (SIDEBAR_WIDTH - 1) as usize;
+        for n in 0..height {
+            let y = (2 + n) as u16;
+            let row = rows.get(n).map(|row| truncate(row, width)).unwrap_or_default();
+            let _ = write!(frame, "{}|{}", cursor::Goto(x, y), row);
+        }
+    }
+
+    fn draw_status(&self, frame: &mut String, width: usize, y: u16) {
+        let overview = &self.overview;
+        let listening = if overview.listen_addrs.is_empty() {
+            "not listening".to_owned()
+        } else {
+            format!("listening on {}", overview.listen_addrs.join(", "))
+        };
+        let mut status = format!(" {} connection(s) | {}", overview.peers.len(), listening);
+        if self.panes[self.viewed].scroll > 0 {
+            status = format!(" [scrolled]{}", status);
+        }
+        let status = format!("{:<width$}", truncate(&status, width), width = width);
+        let _ = write!(frame, "{}{}{}{}", cursor::Goto(1, y), style::Invert, status, style::Reset);
+    }
+
+    fn draw_input(&self, frame: &mut String, width: usize, y: u16) {
+        // Scroll the line horizontally so that the cursor is always visible.
+        let visible = width.saturating_sub(3);
+        let start = (self.cursor + 1).saturating_sub(visible);
+        let text = self.input[start..].iter().take(visible).collect::<String>();
+        let cursor_x = (3 + self.cursor - start) as u16;
+        let _ = write!(frame, "{}> {}{}{}", cursor::Goto(1, y), text, cursor::Goto(cursor_x, y),
+                       cursor::Show);
+    }
+}
+
+/// Returns the first `width` characters of `text`, leaving out its control characters. Like
+/// `wrap`, it gets text from other nodes, such as their nicknames, which mustn't be able to send
+/// escape sequences to the terminal.
+fn truncate(text: &str, width: usize) -> String {
+    text.chars().filter(|c| !c.is_control()).take(width).collect()
+}
+
+/// Splits `text` into rows of `width` characters.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let chars = text.chars().filter(|c| !c.is_control()).collect::<Vec<_>>();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars
+        .chunks(width.max(1))
+        .map(|row| row.iter().collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidebar_entries_lose_their_control_characters() {
+        // Nicknames and addresses come from other nodes. Without its ESC, an ANSI escape is only
+        // text.
+        let entries = [
+            ("  \u{1b}[2J\u{1b}[31mmallory", "  [2J[31mmallory"),
+            ("  bell\u{7}\rcarriage\u{0}", "  bellcarriage"),
+            ("  c1\u{9b}31m\u{85}\u{9f}", "  c131m"),
+            ("  tab\tnewline\n\u{7f}", "  tabnewline"),
+        ];
+        for &(entry, expected) in &entries {
+            assert_eq!(truncate(entry, 100), expected, "{:?}", entry);
+        }
+    }
+
+    #[test]
+    fn truncate_counts_the_characters_that_are_kept() {
+        assert_eq!(truncate("\u{1b}\u{1b}\u{1b}abcdef", 3), "abc");
+        assert_eq!(truncate("né\u{9b}é", 3), "néé");
+    }
+
+    #[test]
+    fn wrapped_lines_lose_their_control_characters() {
+        assert_eq!(wrap("ab\u{1b}[2Jcd\u{9b}", 4), vec!["ab[2", "Jcd"]);
+        assert_eq!(wrap("\u{1b}\u{7}", 4), vec![String::new()]);
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Helpers for the binary formats of the chat: big endian integers and byte strings prefixed
+//! with their length as a big endian `u16`.
+//!
+//! `Reader` fails with `InvalidData` when the data is shorter than announced. It converts into an
+//! `io::Error` of kind `InvalidData`, for the formats that are read from substreams.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+/// Appends `value` as a big endian `u16`.
+pub fn push_u16(out: &mut Vec<u8>, value: usize) {
+    debug_assert!(value <= u16::max_value() as usize);
+    out.push((value >> 8) as u8);
+    out.push(value as u8);
+}
+
+/// Appends `value` as a big endian `u32`.
+pub fn push_u32(out: &mut Vec<u8>, value: usize) {
+    debug_assert!(value <= u32::max_value() as usize);
+    for shift in [24, 16, 8, 0].iter() {
+        out.push((value >> shift) as u8);
+    }
+}
+
+/// Appends `value` as a big endian `u64`.
+pub fn push_u64(out: &mut Vec<u8>, value: u64) {
+    for shift in (0..8).rev() {
+        out.push((value >> (shift * 8)) as u8);
+    }
+}
+
+/// Appends `data`, prefixed with its length as a big endian `u16`.
+pub fn push_short(out: &mut Vec<u8>, data: &[u8]) {
+    push_u16(out, data.len());
+    out.extend_from_slice(data);
+}
+
+/// Reads a big endian integer of up to 8 bytes.
+pub fn read_u64(data: &[u8]) -> u64 {
+    debug_assert!(data.len() <= 8);
+    data.iter().fold(0, |acc, &b| (acc << 8) | u64::from(b))
+}
+
+/// Reads the fields of a buffer one by one, from the front.
+pub struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    /// Starts reading `data`.
+    #[inline]
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader(data)
+    }
+
+    /// Returns the bytes that haven't been read yet.
+    #[inline]
+    pub fn rest(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// Returns true if everything has been read.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Reads `len` bytes.
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], InvalidData> {
+        if self.0.len() < len {
+            return Err(InvalidData);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    /// Reads a single byte.
+    pub fn u8(&mut self) -> Result<u8, InvalidData> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// Reads a big endian `u16`.
+    pub fn u16(&mut self) -> Result<usize, InvalidData> {
+        let bytes = self.bytes(2)?;
+        Ok(((bytes[0] as usize) << 8) | (bytes[1] as usize))
+    }
+
+    /// Reads a big endian `u32`.
+    pub fn u32(&mut self) -> Result<usize, InvalidData> {
+        let bytes = self.bytes(4)?;
+        Ok(bytes.iter().fold(0, |acc, &byte| (acc << 8) | byte as usize))
+    }
+
+    /// Reads a big endian `u64`.
+    pub fn u64(&mut self) -> Result<u64, InvalidData> {
+        Ok(read_u64(self.bytes(8)?))
+    }
+
+    /// Reads a byte string written by `push_short`.
+    pub fn short(&mut self) -> Result<&'a [u8], InvalidData> {
+        let len = self.u16()?;
+        self.bytes(len)
+    }
+
+    /// Reads a UTF-8 string written by `push_short`.
+    pub fn string(&mut self) -> Result<String, InvalidData> {
+        String::from_utf8(self.short()?.to_vec()).map_err(|_| InvalidData)
+    }
+}
+
+/// The data is truncated or otherwise malformed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InvalidData;
+
+impl fmt::Display for InvalidData {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl Error for InvalidData {
+    #[inline]
+    fn description(&self) -> &str {
+        "invalid data"
+    }
+}
+
+impl From<InvalidData> for IoError {
+    #[inline]
+    fn from(err: InvalidData) -> IoError {
+        IoError::new(IoErrorKind::InvalidData, err)
+    }
+}
+
+/// Returns the error to report when a frame read from a substream is malformed.
+#[inline]
+pub fn invalid() -> IoError {
+    InvalidData.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut out = Vec::new();
+        push_u16(&mut out, 0x1234);
+        push_u32(&mut out, 0x1234_5678);
+        push_u64(&mut out, 0x0102_0304_0506_0708);
+        push_short(&mut out, b"hello");
+        push_short(&mut out, "héllo".as_bytes());
+        out.push(7);
+        out.extend_from_slice(b"rest");
+        assert_eq!(&out[..2], &[0x12, 0x34]);
+
+        let mut reader = Reader::new(&out);
+        assert_eq!(reader.u16(), Ok(0x1234));
+        assert_eq!(reader.u32(), Ok(0x1234_5678));
+        assert_eq!(reader.u64(), Ok(0x0102_0304_0506_0708));
+        assert_eq!(reader.short(), Ok(&b"hello"[..]));
+        assert_eq!(reader.string(), Ok("héllo".to_owned()));
+        assert_eq!(reader.u8(), Ok(7));
+        assert!(!reader.is_empty());
+        assert_eq!(reader.rest(), b"rest");
+        assert_eq!(reader.bytes(4), Ok(&b"rest"[..]));
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut out = Vec::new();
+        push_short(&mut out, b"hello");
+        for len in 0..out.len() {
+            assert_eq!(Reader::new(&out[..len]).short(), Err(InvalidData));
+        }
+        assert_eq!(Reader::new(&[0; 7]).u64(), Err(InvalidData));
+        assert_eq!(Reader::new(&[]).u8(), Err(InvalidData));
+
+        // A failed read doesn't consume anything.
+        let mut reader = Reader::new(&[1, 2, 3]);
+        assert_eq!(reader.u32(), Err(InvalidData));
+        assert_eq!(reader.rest(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let mut out = Vec::new();
+        push_short(&mut out, &[0xff, 0xfe]);
+        assert_eq!(Reader::new(&out).string(), Err(InvalidData));
+    }
+
+    #[test]
+    fn io_error() {
+        assert_eq!(invalid().kind(), IoErrorKind::InvalidData);
+        assert_eq!(read_u64(&[1, 0]), 256);
+    }
+}
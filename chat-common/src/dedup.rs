@@ -0,0 +1,254 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Detection of the messages that we have already seen.
+//!
+//! The same message can reach us several times: through several peers of the mesh, or because
+//! a peer forwarded it back to us. Every message carries an identifier (for chat messages, the
+//! node that signed it and its sequence number, see `is_duplicate`), and the identifiers of the
+//! messages we have seen are kept in a `SeenCache` so that the duplicates are neither displayed
+//! nor forwarded again.
+//!
+//! The cache is bounded both in time and in size: an identifier is forgotten after the TTL, or
+//! earlier if more than `capacity` identifiers were inserted after it. A message whose
+//! identifier was forgotten is accepted again, so the TTL should be longer than the time a
+//! message needs to cross the network.
+
+use libp2p_core::PeerId;
+use message::ChatMessage;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Default number of identifiers remembered by a `SeenCache`.
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// Default time during which an identifier is remembered by a `SeenCache`.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(120);
+
+/// Size and expiry of a `SeenCache`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DedupConfig {
+    /// Maximum number of identifiers remembered.
+    pub capacity: usize,
+    /// Time after which an identifier is forgotten.
+    pub ttl: Duration,
+}
+
+impl Default for DedupConfig {
+    fn default() -> DedupConfig {
+        DedupConfig {
+            capacity: DEFAULT_CAPACITY,
+            ttl: DEFAULT_TTL,
+        }
+    }
+}
+
+/// Returns true if `message`, signed by `source`, is a copy of a chat message we've already seen.
+/// Otherwise, records it in `seen` if `verified`.
+///
+/// `source` must be the node whose signature we checked, rather than the author written inside
+/// of the message, which anybody can forge. Messages whose signature couldn't be verified are
+/// never recorded, so that a forged copy sent ahead of a real message can't make us drop it. The
+/// messages of nodes that don't number their messages all have the sequence number zero, and are
+/// never considered duplicates.
+pub fn is_duplicate(
+    seen: &mut SeenCache<(PeerId, u64)>,
+    source: &PeerId,
+    message: &ChatMessage,
+    verified: bool,
+) -> bool {
+    if message.seq == 0 {
+        return false;
+    }
+    let key = (source.clone(), message.seq);
+    if verified {
+        !seen.insert(key)
+    } else {
+        seen.contains(&key)
+    }
+}
+
+/// Identifiers of the messages seen recently.
+#[derive(Debug, Clone)]
+pub struct SeenCache<K> {
+    config: DedupConfig,
+    /// Time at which each identifier was inserted.
+    entries: HashMap<K, Instant>,
+    /// The identifiers, from the oldest to the most recent.
+    order: VecDeque<K>,
+}
+
+impl<K> SeenCache<K>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Creates an empty cache.
+    pub fn new(config: DedupConfig) -> SeenCache<K> {
+        SeenCache {
+            config: config,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns the configuration of the cache.
+    pub fn config(&self) -> DedupConfig {
+        self.config
+    }
+
+    /// Records `key` as seen. Returns false if it had already been seen, in which case the
+    /// message is a duplicate.
+    pub fn insert(&mut self, key: K) -> bool {
+        let now = Instant::now();
+        self.expire_at(now);
+        if self.entries.contains_key(&key) {
+            return false;
+        }
+
+        while self.order.len() >= self.config.capacity.max(1) {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key.clone(), now);
+        self.order.push_back(key);
+        true
+    }
+
+    /// Returns true if `key` was seen and hasn't been forgotten yet.
+    pub fn contains(&self, key: &K) -> bool {
+        match self.entries.get(key) {
+            Some(inserted) => inserted.elapsed() < self.config.ttl,
+            None => false,
+        }
+    }
+
+    /// Forgets the identifiers older than the TTL. `insert` already does this, but calling it
+    /// regularly releases the memory of a cache that doesn't receive anything.
+    pub fn expire(&mut self) {
+        self.expire_at(Instant::now());
+    }
+
+    /// Returns the number of identifiers remembered.
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    /// Returns true if no identifier is remembered.
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    fn expire_at(&mut self, now: Instant) {
+        loop {
+            let expired = match self.order.front() {
+                Some(oldest) => now.duration_since(self.entries[oldest]) >= self.config.ttl,
+                None => false,
+            };
+            if !expired {
+                break;
+            }
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use key::{KeyType, Keypair};
+    use message::{Composer, Kind};
+    use std::thread;
+
+    fn cache(capacity: usize, ttl: Duration) -> SeenCache<u32> {
+        SeenCache::new(DedupConfig { capacity: capacity, ttl: ttl })
+    }
+
+    fn random_peer_id() -> PeerId {
+        Keypair::generate(KeyType::Ed25519).unwrap().peer_id()
+    }
+
+    #[test]
+    fn duplicates_are_detected() {
+        let mut seen = cache(8, DEFAULT_TTL);
+        assert!(seen.insert(1));
+        assert!(seen.insert(2));
+        assert!(!seen.insert(1));
+        assert!(seen.contains(&2));
+        assert!(!seen.contains(&3));
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn oldest_entries_are_evicted_beyond_the_capacity() {
+        let mut seen = cache(2, DEFAULT_TTL);
+        seen.insert(1);
+        seen.insert(2);
+        seen.insert(3);
+        assert_eq!(seen.len(), 2);
+        assert!(!seen.contains(&1));
+        assert!(seen.contains(&2) && seen.contains(&3));
+        // A forgotten identifier is accepted again.
+        assert!(seen.insert(1));
+    }
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let mut seen = cache(8, Duration::from_millis(20));
+        seen.insert(1);
+        thread::sleep(Duration::from_millis(40));
+        assert!(!seen.contains(&1));
+        seen.expire();
+        assert!(seen.is_empty());
+        assert!(seen.insert(1));
+    }
+
+    #[test]
+    fn chat_messages_are_keyed_on_the_verified_source() {
+        let mut seen = SeenCache::new(DedupConfig::default());
+        let (victim, mallory) = (random_peer_id(), random_peer_id());
+        let message = Composer::new(victim.clone()).compose(Kind::Text, b"hi".to_vec());
+
+        // A copy that claims to come from the victim but fails verification isn't recorded.
+        assert!(!is_duplicate(&mut seen, &victim, &message, false));
+        assert!(!is_duplicate(&mut seen, &victim, &message, false));
+        // Nor does a copy signed by somebody else count as the victim's.
+        assert!(!is_duplicate(&mut seen, &mallory, &message, true));
+        assert!(!is_duplicate(&mut seen, &victim, &message, true));
+
+        // Once recorded, every other copy is a duplicate.
+        assert!(is_duplicate(&mut seen, &victim, &message, true));
+        assert!(is_duplicate(&mut seen, &victim, &message, false));
+    }
+
+    #[test]
+    fn unnumbered_messages_are_never_duplicates() {
+        let mut seen = SeenCache::new(DedupConfig::default());
+        let source = random_peer_id();
+        let mut message = Composer::new(source.clone()).compose(Kind::Text, Vec::new());
+        message.seq = 0;
+        assert!(!is_duplicate(&mut seen, &source, &message, true));
+        assert!(!is_duplicate(&mut seen, &source, &message, true));
+        assert!(seen.is_empty());
+    }
+}
@@ -0,0 +1,794 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! End-to-end encrypted rooms.
+//!
+//! Floodsub relays messages through nodes that aren't necessarily meant to read them. In an
+//! encrypted room, the payload of every message is encrypted with a *group key* that only the
+//! members of the room know. Relays still forward the messages unchanged: the signed envelope and
+//! the outer `ChatMessage` stay readable, only their payload is opaque.
+//!
+//! Every node has an X25519 keypair, generated on startup. Its public key is sent, inside a signed
+//! `KeyRequest` message, to ask the members of a room for the group key. A member can then invite
+//! the node, which generates a new group key and publishes it in a `KeyUpdate` message. The update
+//! contains the group key encrypted for each member, with a key derived from the Diffie-Hellman
+//! exchange between the X25519 keys of the member and of the node that generated the group key.
+//! Anyone can publish an update, so the node that asked for the key only uses the one it receives
+//! once its user has accepted the member that sent it. After that, the updates of the members
+//! are trusted.
+//!
+//! When a member leaves (which it announces with a `KeyLeave` message) or stops announcing its
+//! presence, the remaining member with the smallest `PeerId` generates and distributes a new key,
+//! so that the one that left can't read what follows. Any member can also remove another one,
+//! which rotates the key the same way. Each key has an *epoch*, incremented at every rotation.
+//! The most recent keys are kept in order to decrypt the messages that were sent just before a
+//! rotation.
+//!
+//! The payloads are encoded as follows, with integers in big endian:
+//!
+//! - `KeyRequest`: the X25519 public key of the author.
+//! - `KeyUpdate`: the epoch as a `u64`, the number of members as a `u16`, then for each member the
+//!   length of its `PeerId` as a `u16`, its `PeerId`, its X25519 public key, and the group key
+//!   encrypted for it (nonce, ChaCha20-Poly1305 tag and ciphertext).
+//! - `KeyLeave`: empty.
+//! - `Encrypted`: the epoch of the key as a `u64`, a nonce, a ChaCha20-Poly1305 tag, and the
+//!   encrypted `ChatMessage`.
+
+use chacha20_poly1305_aead;
+use codec::{self, push_short, push_u16, push_u64, Reader};
+use libp2p_core::PeerId;
+use rand::{OsRng, Rng};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use x25519_dalek;
+
+/// Length of the group keys, and of the X25519 keys.
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Number of keys we keep after they've been replaced.
+const OLD_KEYS: usize = 4;
+
+/// Label mixed into the keys that encrypt the group keys.
+const WRAP_LABEL: &[u8] = b"rustfest-chat group key";
+
+/// Group keys of the encrypted rooms we're in. Cloning a `GroupKeys` gives access to the same keys.
+#[derive(Clone)]
+pub struct GroupKeys {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    local_peer_id: PeerId,
+    secret: [u8; KEY_LEN],
+    public: [u8; KEY_LEN],
+    rooms: HashMap<String, RoomKeys>,
+    /// Rooms in which we've asked for the key.
+    requested: HashSet<String>,
+    /// Keys that the members of the rooms in `requested` gave us, until one of them is accepted.
+    /// Indexed by room, then by the bytes of the `PeerId` of the member.
+    offers: HashMap<String, HashMap<Vec<u8>, RoomKeys>>,
+}
+
+struct RoomKeys {
+    epoch: u64,
+    key: [u8; KEY_LEN],
+    /// Node that generated the current key.
+    distributor: Vec<u8>,
+    /// X25519 public key of each member, indexed by the bytes of its `PeerId`.
+    members: BTreeMap<Vec<u8>, [u8; KEY_LEN]>,
+    /// Keys of the previous epochs, the most recent last.
+    old_keys: VecDeque<(u64, [u8; KEY_LEN])>,
+    /// X25519 public key of the nodes that asked for the key and that we haven't invited yet.
+    requests: HashMap<Vec<u8>, [u8; KEY_LEN]>,
+}
+
+/// What a `KeyUpdate` changed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    /// A member gave us the key of a room we asked the key of. We only use it once accepted
+    /// with `accept`.
+    Offered,
+    /// The key of the room has been replaced.
+    Rotated,
+    /// We're no longer a member of the room.
+    Removed,
+    /// The update is outdated, or doesn't concern us.
+    Ignored,
+}
+
+impl GroupKeys {
+    /// Creates an empty set of keys, with a new X25519 keypair.
+    pub fn new(local_peer_id: PeerId) -> GroupKeys {
+        let secret = random_key();
+        let public = x25519_dalek::generate_public(&secret).to_bytes();
+        GroupKeys {
+            inner: Arc::new(Mutex::new(Inner {
+                local_peer_id: local_peer_id,
+                secret: secret,
+                public: public,
+                rooms: HashMap::new(),
+                requested: HashSet::new(),
+                offers: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Returns true if we have a key for `room`.
+    pub fn is_encrypted(&self, room: &str) -> bool {
+        self.inner.lock().unwrap().rooms.contains_key(room)
+    }
+
+    /// Starts encrypting `room` with a new key, of which we're the only member.
+    ///
+    /// Returns false if we already have a key for this room.
+    pub fn create(&self, room: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.rooms.contains_key(room) {
+            return false;
+        }
+        inner.requested.remove(room);
+        inner.offers.remove(room);
+        let local = inner.local_peer_id.as_bytes().to_vec();
+        let mut members = BTreeMap::new();
+        members.insert(local.clone(), inner.public);
+        inner.rooms.insert(room.to_owned(), RoomKeys {
+            epoch: 0,
+            key: random_key(),
+            distributor: local,
+            members: members,
+            old_keys: VecDeque::new(),
+            requests: HashMap::new(),
+        });
+        true
+    }
+
+    /// Forgets the key of `room`. Returns false if we didn't have one.
+    pub fn forget(&self, room: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.requested.remove(room);
+        inner.offers.remove(room);
+        inner.rooms.remove(room).is_some()
+    }
+
+    /// Returns the members of `room`, or an empty list if we don't have its key.
+    pub fn members(&self, room: &str) -> Vec<PeerId> {
+        let inner = self.inner.lock().unwrap();
+        match inner.rooms.get(room) {
+            Some(keys) => keys
+                .members
+                .keys()
+                .filter_map(|peer_id| PeerId::from_bytes(peer_id.clone()).ok())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Encrypts `plaintext`, written by the local node, into the payload of an `Encrypted` message
+    /// for `room`. Returns `None` if we don't have a key for this room.
+    pub fn encrypt(&self, room: &str, plaintext: &[u8]) -> Option<Vec<u8>> {
+        let inner = self.inner.lock().unwrap();
+        let keys = inner.rooms.get(room)?;
+        let aad = message_aad(room, &inner.local_peer_id);
+
+        let mut out = Vec::with_capacity(8 + NONCE_LEN + TAG_LEN + plaintext.len());
+        push_u64(&mut out, keys.epoch);
+        out.extend_from_slice(&seal(&keys.key, &aad, plaintext));
+        Some(out)
+    }
+
+    /// Decrypts the payload of an `Encrypted` message published by `author` in `room`.
+    pub fn decrypt(
+        &self,
+        room: &str,
+        author: &PeerId,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, GroupError> {
+        let inner = self.inner.lock().unwrap();
+        let keys = inner.rooms.get(room).ok_or(GroupError::NoKey)?;
+        let mut reader = Reader::new(payload);
+        let epoch = reader.u64()?;
+        let key = if epoch == keys.epoch {
+            &keys.key
+        } else {
+            keys.old_keys
+                .iter()
+                .find(|&&(e, _)| e == epoch)
+                .map(|&(_, ref key)| key)
+                .ok_or(GroupError::UnknownEpoch(epoch))?
+        };
+        open(key, &message_aad(room, author), reader.rest())
+    }
+
+    /// Returns the payload of a `KeyRequest` message, and remembers that we asked for the key of
+    /// `room`, so that we consider the keys that its members give us.
+    ///
+    /// Returns `None` if we already have the key of this room.
+    pub fn request(&self, room: &str) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.rooms.contains_key(room) {
+            return None;
+        }
+        inner.requested.insert(room.to_owned());
+        Some(inner.public.to_vec())
+    }
+
+    /// Records a `KeyRequest` that `author` published in `room`.
+    ///
+    /// Returns true if we're a member of the room and `author` isn't, in which case we can invite
+    /// it.
+    pub fn on_request(&self, room: &str, author: &PeerId, payload: &[u8]) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let keys = match inner.rooms.get_mut(room) {
+            Some(keys) => keys,
+            None => return false,
+        };
+        if payload.len() != KEY_LEN || keys.members.contains_key(author.as_bytes()) {
+            return false;
+        }
+        let mut public = [0; KEY_LEN];
+        public.copy_from_slice(payload);
+        if is_low_order(&inner.secret, &public) {
+            return false;
+        }
+        keys.requests.insert(author.as_bytes().to_vec(), public);
+        true
+    }
+
+    /// Adds `peer_id`, which must have published a `KeyRequest`, to the members of `room`.
+    ///
+    /// On success, returns the payload of the `KeyUpdate` message to publish in the room.
+    pub fn invite(&self, room: &str, peer_id: &PeerId) -> Result<Vec<u8>, GroupError> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let keys = inner.rooms.get_mut(room).ok_or(GroupError::NoKey)?;
+        if keys.members.contains_key(peer_id.as_bytes()) {
+            return Err(GroupError::AlreadyMember);
+        }
+        let public = keys.requests.remove(peer_id.as_bytes()).ok_or(GroupError::NoRequest)?;
+        keys.members.insert(peer_id.as_bytes().to_vec(), public);
+        Ok(rotate(keys, room, &inner.local_peer_id, &inner.secret))
+    }
+
+    /// Removes `peer_id` from the members of `room`.
+    ///
+    /// On success, returns the payload of the `KeyUpdate` message that gives a new key to the
+    /// remaining members.
+    pub fn remove(&self, room: &str, peer_id: &PeerId) -> Result<Vec<u8>, GroupError> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let keys = inner.rooms.get_mut(room).ok_or(GroupError::NoKey)?;
+        if *peer_id == inner.local_peer_id {
+            return Err(GroupError::RemoveSelf);
+        }
+        keys.members.remove(peer_id.as_bytes()).ok_or(GroupError::NotMember)?;
+        Ok(rotate(keys, room, &inner.local_peer_id, &inner.secret))
+    }
+
+    /// Records that `author` left `room`, or that it stopped announcing its presence.
+    ///
+    /// If we're the remaining member in charge of rotating the key, returns the payload of the
+    /// `KeyUpdate` message to publish in the room.
+    pub fn on_leave(&self, room: &str, author: &PeerId) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let keys = inner.rooms.get_mut(room)?;
+        keys.requests.remove(author.as_bytes());
+        keys.members.remove(author.as_bytes())?;
+        let is_leader = match keys.members.keys().next() {
+            Some(leader) => *leader == inner.local_peer_id.as_bytes(),
+            None => false,
+        };
+        if !is_leader {
+            return None;
+        }
+        Some(rotate(keys, room, &inner.local_peer_id, &inner.secret))
+    }
+
+    /// Processes a `KeyUpdate` message that `author` published in `room`.
+    pub fn on_update(
+        &self,
+        room: &str,
+        author: &PeerId,
+        payload: &[u8],
+    ) -> Result<UpdateOutcome, GroupError> {
+        let update = KeyUpdate::decode(payload)?;
+        // The next rotation increments the epoch, which can't go any higher.
+        if update.epoch == u64::max_value() {
+            return Err(GroupError::Malformed);
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let local = inner.local_peer_id.as_bytes().to_vec();
+        let author = author.as_bytes().to_vec();
+
+        // Only the members can change the key. If we don't have a key yet, the key is only
+        // offered to the user, and only if we asked for it.
+        match inner.rooms.get(room) {
+            Some(keys) => {
+                let newer = update.epoch > keys.epoch
+                    || (update.epoch == keys.epoch && author < keys.distributor);
+                if !keys.members.contains_key(&author) || !newer {
+                    return Ok(UpdateOutcome::Ignored);
+                }
+            }
+            None if !inner.requested.contains(room) => return Ok(UpdateOutcome::Ignored),
+            None => {}
+        }
+
+        // A member with a key of small order would make the group key readable by anyone.
+        if update.members.iter().any(|m| is_low_order(&inner.secret, &m.public)) {
+            return Err(GroupError::WeakKey);
+        }
+
+        let entry = match update.members.iter().find(|m| m.peer_id == local) {
+            Some(entry) => entry,
+            None => {
+                return Ok(match inner.rooms.remove(room) {
+                    Some(_) => UpdateOutcome::Removed,
+                    None => UpdateOutcome::Ignored,
+                })
+            }
+        };
+        let distributor = update
+            .members
+            .iter()
+            .find(|m| m.peer_id == author)
+            .ok_or(GroupError::Malformed)?;
+        let wrapping_key = wrapping_key(
+            &x25519_dalek::diffie_hellman(&inner.secret, &distributor.public),
+            room,
+            update.epoch,
+        );
+        let key = open(&wrapping_key, &local, &entry.wrapped_key)?;
+        if key.len() != KEY_LEN {
+            return Err(GroupError::Malformed);
+        }
+        let mut group_key = [0; KEY_LEN];
+        group_key.copy_from_slice(&key);
+
+        let members = update
+            .members
+            .iter()
+            .map(|m| (m.peer_id.clone(), m.public))
+            .collect::<BTreeMap<_, _>>();
+        if let Some(keys) = inner.rooms.get_mut(room) {
+            keys.old_keys.push_back((keys.epoch, keys.key));
+            if keys.old_keys.len() > OLD_KEYS {
+                keys.old_keys.pop_front();
+            }
+            for peer_id in members.keys() {
+                keys.requests.remove(peer_id);
+            }
+            keys.epoch = update.epoch;
+            keys.key = group_key;
+            keys.distributor = author;
+            keys.members = members;
+            return Ok(UpdateOutcome::Rotated);
+        }
+
+        let offers = inner.offers.entry(room.to_owned()).or_insert_with(HashMap::new);
+        offers.insert(author.clone(), RoomKeys {
+            epoch: update.epoch,
+            key: group_key,
+            distributor: author,
+            members: members,
+            old_keys: VecDeque::new(),
+            requests: HashMap::new(),
+        });
+        Ok(UpdateOutcome::Offered)
+    }
+
+    /// Starts using the key of `room` that `peer_id` gave us, which `on_update` reported as
+    /// `Offered`. The keys that other nodes gave us are discarded.
+    pub fn accept(&self, room: &str, peer_id: &PeerId) -> Result<(), GroupError> {
+        let mut inner = self.inner.lock().unwrap();
+        let keys = inner
+            .offers
+            .get_mut(room)
+            .and_then(|offers| offers.remove(peer_id.as_bytes()))
+            .ok_or(GroupError::NoOffer)?;
+        inner.offers.remove(room);
+        inner.requested.remove(room);
+        inner.rooms.insert(room.to_owned(), keys);
+        Ok(())
+    }
+}
+
+/// Replaces the key of a room with a new one, and returns the `KeyUpdate` payload that gives it
+/// to the members.
+fn rotate(keys: &mut RoomKeys, room: &str, local: &PeerId, secret: &[u8; KEY_LEN]) -> Vec<u8> {
+    keys.old_keys.push_back((keys.epoch, keys.key));
+    if keys.old_keys.len() > OLD_KEYS {
+        keys.old_keys.pop_front();
+    }
+    keys.epoch += 1;
+    keys.key = random_key();
+    keys.distributor = local.as_bytes().to_vec();
+
+    let members = keys
+        .members
+        .iter()
+        .map(|(peer_id, public)| {
+            let shared = x25519_dalek::diffie_hellman(secret, public);
+            let wrapping_key = wrapping_key(&shared, room, keys.epoch);
+            Member {
+                peer_id: peer_id.clone(),
+                public: *public,
+                wrapped_key: seal(&wrapping_key, peer_id, &keys.key),
+            }
+        })
+        .collect();
+    KeyUpdate {
+        epoch: keys.epoch,
+        members: members,
+    }.encode()
+}
+
+/// Content of a `KeyUpdate` message.
+struct KeyUpdate {
+    epoch: u64,
+    members: Vec<Member>,
+}
+
+struct Member {
+    peer_id: Vec<u8>,
+    public: [u8; KEY_LEN],
+    wrapped_key: Vec<u8>,
+}
+
+impl KeyUpdate {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        push_u64(&mut out, self.epoch);
+        push_u16(&mut out, self.members.len());
+        for member in self.members.iter() {
+            push_short(&mut out, &member.peer_id);
+            out.extend_from_slice(&member.public);
+            out.extend_from_slice(&member.wrapped_key);
+        }
+        out
+    }
+
+    fn decode(data: &[u8]) -> Result<KeyUpdate, GroupError> {
+        let mut reader = Reader::new(data);
+        let epoch = reader.u64()?;
+        let count = reader.u16()?;
+        let mut members = Vec::with_capacity(count);
+        for _ in 0..count {
+            let peer_id = reader.short()?.to_vec();
+            let mut public = [0; KEY_LEN];
+            public.copy_from_slice(reader.bytes(KEY_LEN)?);
+            let wrapped_key = reader.bytes(NONCE_LEN + TAG_LEN + KEY_LEN)?.to_vec();
+            members.push(Member {
+                peer_id: peer_id,
+                public: public,
+                wrapped_key: wrapped_key,
+            });
+        }
+        Ok(KeyUpdate {
+            epoch: epoch,
+            members: members,
+        })
+    }
+}
+
+/// Returns true if `public` is an X25519 point of small order, with which the Diffie-Hellman
+/// exchange gives a shared secret that doesn't depend on the secret key.
+///
+/// The secret keys are multiples of the cofactor, so the exchange with such a point gives the
+/// identity, which is encoded as zero.
+fn is_low_order(secret: &[u8; KEY_LEN], public: &[u8; KEY_LEN]) -> bool {
+    x25519_dalek::diffie_hellman(secret, public).iter().all(|&byte| byte == 0)
+}
+
+/// Derives the key that encrypts the group key of `room` at `epoch` for one member.
+fn wrapping_key(shared_secret: &[u8], room: &str, epoch: u64) -> [u8; KEY_LEN] {
+    let mut data = WRAP_LABEL.to_vec();
+    data.extend_from_slice(shared_secret);
+    data.extend_from_slice(room.as_bytes());
+    push_u64(&mut data, epoch);
+    let mut key = [0; KEY_LEN];
+    key.copy_from_slice(&Sha256::digest(&data));
+    key
+}
+
+/// Additional data authenticated with the messages, so that they can't be replayed in another
+/// room or attributed to another author.
+fn message_aad(room: &str, author: &PeerId) -> Vec<u8> {
+    let mut aad = Vec::new();
+    push_short(&mut aad, room.as_bytes());
+    aad.extend_from_slice(author.as_bytes());
+    aad
+}
+
+/// Encrypts `plaintext` with a random nonce. Returns the nonce, the tag and the ciphertext.
+fn seal(key: &[u8; KEY_LEN], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut nonce = vec![0; NONCE_LEN];
+    fill_random(&mut nonce);
+    let mut ciphertext = Vec::with_capacity(plaintext.len());
+    let tag = chacha20_poly1305_aead::encrypt(key, &nonce, aad, plaintext, &mut ciphertext)
+        .expect("writing to a Vec never fails");
+
+    let mut out = nonce;
+    out.extend_from_slice(&tag);
+    out.extend_from_slice(&ciphertext);
+    out
+}
+
+/// Decrypts the output of `seal`.
+fn open(key: &[u8; KEY_LEN], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, GroupError> {
+    let mut reader = Reader::new(sealed);
+    let nonce = reader.bytes(NONCE_LEN)?;
+    let tag = reader.bytes(TAG_LEN)?;
+    let mut plaintext = Vec::with_capacity(reader.rest().len());
+    chacha20_poly1305_aead::decrypt(key, nonce, aad, reader.rest(), tag, &mut plaintext)
+        .map_err(|_| GroupError::BadCiphertext)?;
+    Ok(plaintext)
+}
+
+fn random_key() -> [u8; KEY_LEN] {
+    let mut key = [0; KEY_LEN];
+    fill_random(&mut key);
+    key
+}
+
+/// Fills `bytes` from the random number generator of the operating system, like the keypairs of
+/// the `key` module. Panics if it is unavailable, as `rand::random` would.
+fn fill_random(bytes: &mut [u8]) {
+    OsRng::new()
+        .expect("the random number generator of the operating system is unavailable")
+        .fill_bytes(bytes);
+}
+
+/// Error related to the keys of an encrypted room.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GroupError {
+    /// We don't have the key of the room.
+    NoKey,
+    /// The message was encrypted with a key we don't have (anymore).
+    UnknownEpoch(u64),
+    /// The payload is malformed.
+    Malformed,
+    /// Decryption failed: the message has been tampered with, or isn't meant for us.
+    BadCiphertext,
+    /// The node to invite hasn't asked for the key.
+    NoRequest,
+    /// The node to invite is already a member.
+    AlreadyMember,
+    /// The node to remove isn't a member.
+    NotMember,
+    /// We tried to remove ourselves from the members.
+    RemoveSelf,
+    /// The node hasn't given us the key of the room.
+    NoOffer,
+    /// A member has an X25519 public key of small order.
+    WeakKey,
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GroupError::UnknownEpoch(epoch) => write!(f, "no key for epoch {}", epoch),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for GroupError {
+    #[inline]
+    fn description(&self) -> &str {
+        match *self {
+            GroupError::NoKey => "we don't have the key of the room",
+            GroupError::UnknownEpoch(_) => "no key for this epoch",
+            GroupError::Malformed => "malformed payload",
+            GroupError::BadCiphertext => "decryption failed",
+            GroupError::NoRequest => "the node hasn't asked for the key of the room",
+            GroupError::AlreadyMember => "the node is already a member of the room",
+            GroupError::NotMember => "the node isn't a member of the room",
+            GroupError::RemoveSelf => "we can't remove ourselves from the room",
+            GroupError::NoOffer => "the node hasn't given us the key of the room",
+            GroupError::WeakKey => "a member has an invalid public key",
+        }
+    }
+}
+
+impl From<codec::InvalidData> for GroupError {
+    #[inline]
+    fn from(_: codec::InvalidData) -> GroupError {
+        GroupError::Malformed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use key::{KeyType, Keypair};
+
+    fn random_peer_id() -> PeerId {
+        Keypair::generate(KeyType::Ed25519).unwrap().peer_id()
+    }
+
+    /// Returns the keys of a node that created `room`, and of a node that got its key.
+    fn two_members(room: &str) -> (PeerId, GroupKeys, PeerId, GroupKeys) {
+        let (alice_id, bob_id) = (random_peer_id(), random_peer_id());
+        let (alice, bob) = (GroupKeys::new(alice_id.clone()), GroupKeys::new(bob_id.clone()));
+        assert!(alice.create(room));
+        let request = bob.request(room).unwrap();
+        assert!(alice.on_request(room, &bob_id, &request));
+        let update = alice.invite(room, &bob_id).unwrap();
+        assert_eq!(bob.on_update(room, &alice_id, &update), Ok(UpdateOutcome::Offered));
+        bob.accept(room, &alice_id).unwrap();
+        (alice_id, alice, bob_id, bob)
+    }
+
+    #[test]
+    fn key_update_round_trip() {
+        let update = KeyUpdate {
+            epoch: 0x0102_0304_0506_0708,
+            members: vec![
+                Member {
+                    peer_id: vec![1, 2, 3],
+                    public: [4; KEY_LEN],
+                    wrapped_key: vec![5; NONCE_LEN + TAG_LEN + KEY_LEN],
+                },
+                Member {
+                    peer_id: vec![6; 40],
+                    public: [7; KEY_LEN],
+                    wrapped_key: vec![8; NONCE_LEN + TAG_LEN + KEY_LEN],
+                },
+            ],
+        };
+        let encoded = update.encode();
+        assert_eq!(&encoded[..10], &[1, 2, 3, 4, 5, 6, 7, 8, 0, 2]);
+
+        let decoded = KeyUpdate::decode(&encoded).unwrap();
+        assert_eq!(decoded.epoch, update.epoch);
+        assert_eq!(decoded.members.len(), 2);
+        for (decoded, member) in decoded.members.iter().zip(update.members.iter()) {
+            assert_eq!(decoded.peer_id, member.peer_id);
+            assert_eq!(decoded.public, member.public);
+            assert_eq!(decoded.wrapped_key, member.wrapped_key);
+        }
+    }
+
+    #[test]
+    fn truncated_key_update_is_rejected() {
+        let update = KeyUpdate {
+            epoch: 1,
+            members: vec![Member {
+                peer_id: vec![1, 2, 3],
+                public: [4; KEY_LEN],
+                wrapped_key: vec![5; NONCE_LEN + TAG_LEN + KEY_LEN],
+            }],
+        };
+        let encoded = update.encode();
+        for len in 0..encoded.len() {
+            assert!(KeyUpdate::decode(&encoded[..len]).is_err(), "length {}", len);
+        }
+    }
+
+    #[test]
+    fn invited_node_decrypts_once_it_accepts() {
+        let (alice_id, alice, bob_id, bob) = two_members("rust");
+        let payload = alice.encrypt("rust", b"secret").unwrap();
+        assert_eq!(bob.decrypt("rust", &alice_id, &payload), Ok(b"secret".to_vec()));
+        let payload = bob.encrypt("rust", b"reply").unwrap();
+        assert_eq!(alice.decrypt("rust", &bob_id, &payload), Ok(b"reply".to_vec()));
+        // The message is bound to its author.
+        assert_eq!(alice.decrypt("rust", &alice_id, &payload), Err(GroupError::BadCiphertext));
+    }
+
+    #[test]
+    fn offered_key_is_unused_until_accepted() {
+        let (alice_id, bob_id) = (random_peer_id(), random_peer_id());
+        let (alice, bob) = (GroupKeys::new(alice_id.clone()), GroupKeys::new(bob_id.clone()));
+        alice.create("rust");
+        let request = bob.request("rust").unwrap();
+        alice.on_request("rust", &bob_id, &request);
+        let update = alice.invite("rust", &bob_id).unwrap();
+
+        assert_eq!(bob.on_update("rust", &alice_id, &update), Ok(UpdateOutcome::Offered));
+        assert!(!bob.is_encrypted("rust"));
+        assert_eq!(bob.accept("rust", &random_peer_id()), Err(GroupError::NoOffer));
+        assert_eq!(bob.accept("rust", &alice_id), Ok(()));
+        assert!(bob.is_encrypted("rust"));
+    }
+
+    #[test]
+    fn update_for_a_room_we_did_not_request_is_ignored() {
+        let (alice_id, bob_id) = (random_peer_id(), random_peer_id());
+        let (alice, bob) = (GroupKeys::new(alice_id.clone()), GroupKeys::new(bob_id.clone()));
+        alice.create("rust");
+        // Bob's request was made by another `GroupKeys`, for example before a restart.
+        let request = GroupKeys::new(bob_id.clone()).request("rust").unwrap();
+        alice.on_request("rust", &bob_id, &request);
+        let update = alice.invite("rust", &bob_id).unwrap();
+        assert_eq!(bob.on_update("rust", &alice_id, &update), Ok(UpdateOutcome::Ignored));
+        assert_eq!(bob.accept("rust", &alice_id), Err(GroupError::NoOffer));
+    }
+
+    #[test]
+    fn low_order_public_keys_are_rejected() {
+        let (alice_id, bob_id) = (random_peer_id(), random_peer_id());
+        let (alice, bob) = (GroupKeys::new(alice_id.clone()), GroupKeys::new(bob_id.clone()));
+        alice.create("rust");
+        let mut one = [0; KEY_LEN];
+        one[0] = 1;
+        for public in [[0; KEY_LEN], one].iter() {
+            assert!(!alice.on_request("rust", &bob_id, public));
+        }
+
+        bob.request("rust");
+        let update = KeyUpdate {
+            epoch: 1,
+            members: vec![Member {
+                peer_id: bob_id.as_bytes().to_vec(),
+                public: one,
+                wrapped_key: vec![0; NONCE_LEN + TAG_LEN + KEY_LEN],
+            }],
+        };
+        let outcome = bob.on_update("rust", &alice_id, &update.encode());
+        assert_eq!(outcome, Err(GroupError::WeakKey));
+    }
+
+    #[test]
+    fn last_epoch_is_rejected() {
+        let (alice_id, _, _, bob) = two_members("rust");
+        let update = KeyUpdate {
+            epoch: u64::max_value(),
+            members: Vec::new(),
+        };
+        let outcome = bob.on_update("rust", &alice_id, &update.encode());
+        assert_eq!(outcome, Err(GroupError::Malformed));
+        assert!(bob.is_encrypted("rust"));
+    }
+
+    #[test]
+    fn removed_member_loses_the_key() {
+        let (alice_id, alice, bob_id, bob) = two_members("rust");
+        assert_eq!(alice.remove("rust", &alice_id), Err(GroupError::RemoveSelf));
+        assert_eq!(alice.remove("rust", &random_peer_id()), Err(GroupError::NotMember));
+
+        let update = alice.remove("rust", &bob_id).unwrap();
+        assert_eq!(alice.members("rust"), vec![alice_id.clone()]);
+        assert_eq!(bob.on_update("rust", &alice_id, &update), Ok(UpdateOutcome::Removed));
+        assert!(!bob.is_encrypted("rust"));
+    }
+
+    #[test]
+    fn leader_rotates_the_key_when_a_member_leaves() {
+        let (alice_id, alice, bob_id, bob) = two_members("rust");
+        let (departed, remaining, remaining_id) = if alice_id.as_bytes() < bob_id.as_bytes() {
+            (bob_id, alice, alice_id)
+        } else {
+            (alice_id, bob, bob_id)
+        };
+        let update = remaining.on_leave("rust", &departed).unwrap();
+        assert_eq!(KeyUpdate::decode(&update).unwrap().epoch, 2);
+        assert_eq!(remaining.members("rust"), vec![remaining_id]);
+        assert_eq!(remaining.on_leave("rust", &departed), None);
+    }
+}
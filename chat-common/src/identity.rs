@@ -0,0 +1,128 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Persistent identity of the local node.
+//!
+//! The keypair of the node is generated the first time the program runs and is then saved to a
+//! file. Reloading it on startup means that we keep the same `PeerId` across restarts, and other
+//! nodes can recognize us.
+
+use key::{KeyType, Keypair};
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read};
+use std::path::{Path, PathBuf};
+use storage;
+
+/// Returns the path of the key file to use when none is passed on the command line.
+///
+/// This is `identity.key` in `storage::config_dir()`.
+pub fn default_key_path() -> PathBuf {
+    storage::config_dir().join("identity.key")
+}
+
+/// Loads the keypair stored in `path`.
+///
+/// If the file doesn't exist, or if `regenerate` is true, a new keypair of type `key_type` is
+/// generated and written to `path` instead. The type of an existing keypair is kept as it is.
+pub fn load_or_generate(
+    path: &Path,
+    key_type: KeyType,
+    regenerate: bool,
+) -> Result<Keypair, IoError> {
+    if !regenerate {
+        match load(path) {
+            Ok(keypair) => return Ok(keypair),
+            Err(ref err) if err.kind() == IoErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+    }
+
+    let keypair = Keypair::generate(key_type)?;
+    save(path, &keypair)?;
+    Ok(keypair)
+}
+
+/// Reads a keypair previously written with `save`.
+fn load(path: &Path) -> Result<Keypair, IoError> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Keypair::from_bytes(&bytes).map_err(|err| {
+        IoError::new(
+            IoErrorKind::InvalidData,
+            format!("invalid key file {}: {}", path.display(), err),
+        )
+    })
+}
+
+/// Writes `keypair` to `path`. Nobody but us should be able to read the private key.
+fn save(path: &Path, keypair: &Keypair) -> Result<(), IoError> {
+    storage::write_atomic(path, &keypair.to_bytes(), true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir()
+            .join(format!("chat-common-identity-{}", process::id()))
+            .join(name)
+    }
+
+    #[test]
+    fn generated_key_is_reloaded() {
+        let path = temp_path("reload.key");
+        let first = load_or_generate(&path, KeyType::Secp256k1, false).unwrap();
+        // The type of the stored key wins over the requested one.
+        let second = load_or_generate(&path, KeyType::Ed25519, false).unwrap();
+        assert_eq!(second.key_type(), KeyType::Secp256k1);
+        assert_eq!(first.peer_id(), second.peer_id());
+
+        let regenerated = load_or_generate(&path, KeyType::Ed25519, true).unwrap();
+        assert_eq!(regenerated.key_type(), KeyType::Ed25519);
+        assert_ne!(regenerated.peer_id(), first.peer_id());
+        assert_eq!(load(&path).unwrap().peer_id(), regenerated.peer_id());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_key_file_is_an_error() {
+        let path = temp_path("corrupted.key");
+        storage::write_atomic(&path, b"not a key", true).unwrap();
+        let err = load_or_generate(&path, KeyType::Ed25519, false).err().unwrap();
+        assert_eq!(err.kind(), IoErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path("private.key");
+        load_or_generate(&path, KeyType::Ed25519, false).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_file(&path).unwrap();
+    }
+}
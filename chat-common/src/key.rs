@@ -0,0 +1,393 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Public-key cryptography used to identify nodes.
+//!
+//! A node owns a `Keypair`. Its `PeerId` is the hash of the protobuf encoding of the public half
+//! of the keypair, as defined by the libp2p specifications. Since only the owner of the private
+//! half can produce valid signatures, anyone that knows the public key of a node can verify that
+//! a piece of data was indeed produced by that node.
+
+use ed25519_dalek;
+use libp2p_core::PeerId;
+use libp2p_secio::SecioKeyPair;
+use rand::{OsRng, Rng};
+use secp256k1;
+use sha2::{Digest, Sha256, Sha512};
+use std::error::Error;
+use std::fmt;
+use std::io::Error as IoError;
+use std::str::FromStr;
+
+/// Cryptographic algorithm of a keypair.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyType {
+    /// Ed25519 signatures. This is the default.
+    Ed25519,
+    /// ECDSA signatures over the secp256k1 curve.
+    Secp256k1,
+}
+
+impl KeyType {
+    /// Value of the `KeyType` enum of the libp2p protobuf definitions.
+    fn protobuf_tag(&self) -> u8 {
+        match *self {
+            KeyType::Ed25519 => 1,
+            KeyType::Secp256k1 => 2,
+        }
+    }
+
+    fn from_protobuf_tag(tag: u8) -> Option<KeyType> {
+        match tag {
+            1 => Some(KeyType::Ed25519),
+            2 => Some(KeyType::Secp256k1),
+            _ => None,
+        }
+    }
+}
+
+impl Default for KeyType {
+    #[inline]
+    fn default() -> KeyType {
+        KeyType::Ed25519
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<KeyType, KeyError> {
+        match s {
+            "ed25519" => Ok(KeyType::Ed25519),
+            "secp256k1" => Ok(KeyType::Secp256k1),
+            _ => Err(KeyError::UnknownKeyType),
+        }
+    }
+}
+
+/// A private key and its corresponding public key.
+pub enum Keypair {
+    Ed25519(ed25519_dalek::Keypair),
+    Secp256k1 {
+        secret: secp256k1::key::SecretKey,
+        public: secp256k1::key::PublicKey,
+    },
+}
+
+impl Keypair {
+    /// Generates a new keypair of the given type from the random number generator of the
+    /// operating system.
+    pub fn generate(key_type: KeyType) -> Result<Keypair, IoError> {
+        let mut rng = OsRng::new()?;
+        Ok(match key_type {
+            KeyType::Ed25519 => {
+                // ed25519-dalek wants a random number generator of a newer version of `rand`, so
+                // we generate the secret key ourselves.
+                let mut bytes = [0; ed25519_dalek::SECRET_KEY_LENGTH];
+                rng.fill_bytes(&mut bytes);
+                let secret = ed25519_dalek::SecretKey::from_bytes(&bytes)
+                    .expect("any 32 bytes are a valid ed25519 secret key");
+                let public = ed25519_dalek::PublicKey::from_secret::<Sha512>(&secret);
+                Keypair::Ed25519(ed25519_dalek::Keypair { secret, public })
+            }
+            KeyType::Secp256k1 => {
+                let (secret, public) = secp256k1::Secp256k1::new().generate_keypair(&mut rng);
+                Keypair::Secp256k1 { secret, public }
+            }
+        })
+    }
+
+    /// Returns the type of this keypair.
+    pub fn key_type(&self) -> KeyType {
+        match *self {
+            Keypair::Ed25519(_) => KeyType::Ed25519,
+            Keypair::Secp256k1 { .. } => KeyType::Secp256k1,
+        }
+    }
+
+    /// Returns the public half of this keypair.
+    pub fn public(&self) -> PublicKey {
+        match *self {
+            Keypair::Ed25519(ref keypair) => PublicKey::Ed25519(keypair.public),
+            Keypair::Secp256k1 { ref public, .. } => PublicKey::Secp256k1(public.clone()),
+        }
+    }
+
+    /// Returns the `PeerId` of the node that owns this keypair.
+    #[inline]
+    pub fn peer_id(&self) -> PeerId {
+        self.public().peer_id()
+    }
+
+    /// Builds the keypair to pass to the secio handshake.
+    ///
+    /// Secio computes the `PeerId` of the remote from the same protobuf encoding as us, so the
+    /// identity that it authenticates is the one returned by `peer_id()`.
+    pub fn to_secio_keypair(&self) -> SecioKeyPair {
+        let keypair = match *self {
+            Keypair::Ed25519(ref keypair) => {
+                SecioKeyPair::ed25519_raw_key(keypair.secret.as_bytes())
+            }
+            Keypair::Secp256k1 { ref secret, .. } => SecioKeyPair::secp256k1_raw_key(&secret[..]),
+        };
+        keypair.expect("secio accepts all the keys that we can generate")
+    }
+
+    /// Signs `data` with the private key.
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        match *self {
+            Keypair::Ed25519(ref keypair) => keypair.sign::<Sha512>(data).to_bytes().to_vec(),
+            Keypair::Secp256k1 { ref secret, .. } => {
+                let secp = secp256k1::Secp256k1::signing_only();
+                let message = secp256k1_message(data);
+                secp.sign(&message, secret).serialize_der(&secp)
+            }
+        }
+    }
+
+    /// Serializes the keypair in order to store it.
+    ///
+    /// The first byte is the protobuf tag of the key type, and the rest is the private key (and,
+    /// for ed25519, the public key).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![self.key_type().protobuf_tag()];
+        match *self {
+            Keypair::Ed25519(ref keypair) => out.extend_from_slice(&keypair.to_bytes()),
+            Keypair::Secp256k1 { ref secret, .. } => out.extend_from_slice(&secret[..]),
+        }
+        out
+    }
+
+    /// Parses the output of `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Keypair, KeyError> {
+        // Key files written before the key type was stored contain a raw ed25519 keypair.
+        if bytes.len() == ed25519_dalek::KEYPAIR_LENGTH {
+            return ed25519_dalek::Keypair::from_bytes(bytes)
+                .map(Keypair::Ed25519)
+                .map_err(|_| KeyError::InvalidKey);
+        }
+
+        let (tag, key) = match bytes.split_first() {
+            Some((tag, key)) => (*tag, key),
+            None => return Err(KeyError::InvalidKey),
+        };
+
+        match KeyType::from_protobuf_tag(tag) {
+            Some(KeyType::Ed25519) => ed25519_dalek::Keypair::from_bytes(key)
+                .map(Keypair::Ed25519)
+                .map_err(|_| KeyError::InvalidKey),
+            Some(KeyType::Secp256k1) => {
+                let secp = secp256k1::Secp256k1::new();
+                let secret = secp256k1::key::SecretKey::from_slice(&secp, key)
+                    .map_err(|_| KeyError::InvalidKey)?;
+                let public = secp256k1::key::PublicKey::from_secret_key(&secp, &secret);
+                Ok(Keypair::Secp256k1 { secret, public })
+            }
+            None => Err(KeyError::UnknownKeyType),
+        }
+    }
+}
+
+/// The public half of a `Keypair`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicKey {
+    Ed25519(ed25519_dalek::PublicKey),
+    Secp256k1(secp256k1::key::PublicKey),
+}
+
+impl PublicKey {
+    /// Returns the type of this key.
+    pub fn key_type(&self) -> KeyType {
+        match *self {
+            PublicKey::Ed25519(_) => KeyType::Ed25519,
+            PublicKey::Secp256k1(_) => KeyType::Secp256k1,
+        }
+    }
+
+    /// Encodes the key as a `PublicKey` protobuf message of the libp2p specifications.
+    ///
+    /// This is the encoding that is hashed in order to obtain a `PeerId`.
+    pub fn to_protobuf_encoding(&self) -> Vec<u8> {
+        let data = match *self {
+            PublicKey::Ed25519(ref key) => key.as_bytes().to_vec(),
+            PublicKey::Secp256k1(ref key) => key.serialize().to_vec(),
+        };
+
+        // Field 1 (`Type`) is a varint, field 2 (`Data`) is length-delimited. All our key types
+        // and lengths fit in a single varint byte.
+        debug_assert!(data.len() < 0x80);
+        let mut out = Vec::with_capacity(4 + data.len());
+        out.push(0x08);
+        out.push(self.key_type().protobuf_tag());
+        out.push(0x12);
+        out.push(data.len() as u8);
+        out.extend_from_slice(&data);
+        out
+    }
+
+    /// Decodes the output of `to_protobuf_encoding`.
+    pub fn from_protobuf_encoding(bytes: &[u8]) -> Result<PublicKey, KeyError> {
+        if bytes.len() < 4 || bytes[0] != 0x08 || bytes[2] != 0x12 {
+            return Err(KeyError::InvalidKey);
+        }
+        let data = &bytes[4..];
+        if data.len() != bytes[3] as usize {
+            return Err(KeyError::InvalidKey);
+        }
+
+        match KeyType::from_protobuf_tag(bytes[1]) {
+            Some(KeyType::Ed25519) => ed25519_dalek::PublicKey::from_bytes(data)
+                .map(PublicKey::Ed25519)
+                .map_err(|_| KeyError::InvalidKey),
+            Some(KeyType::Secp256k1) => {
+                let secp = secp256k1::Secp256k1::verification_only();
+                secp256k1::key::PublicKey::from_slice(&secp, data)
+                    .map(PublicKey::Secp256k1)
+                    .map_err(|_| KeyError::InvalidKey)
+            }
+            None => Err(KeyError::UnknownKeyType),
+        }
+    }
+
+    /// Returns the `PeerId` corresponding to this key.
+    #[inline]
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from_public_key(&self.to_protobuf_encoding())
+    }
+
+    /// Returns true if `signature` is a valid signature of `data` by the private key matching
+    /// this public key.
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        match *self {
+            PublicKey::Ed25519(ref key) => match ed25519_dalek::Signature::from_bytes(signature) {
+                Ok(signature) => key.verify::<Sha512>(data, &signature).is_ok(),
+                Err(_) => false,
+            },
+            PublicKey::Secp256k1(ref key) => {
+                let secp = secp256k1::Secp256k1::verification_only();
+                match secp256k1::Signature::from_der(&secp, signature) {
+                    Ok(signature) => secp
+                        .verify(&secp256k1_message(data), &signature, key)
+                        .is_ok(),
+                    Err(_) => false,
+                }
+            }
+        }
+    }
+}
+
+/// secp256k1 signs 32-byte digests. Like the rest of libp2p, we sign the SHA-256 of the data.
+fn secp256k1_message(data: &[u8]) -> secp256k1::Message {
+    let digest = Sha256::digest(data);
+    secp256k1::Message::from_slice(&digest).expect("a SHA-256 digest is always 32 bytes long")
+}
+
+/// Error while decoding a key.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    /// The key type is not supported.
+    UnknownKeyType,
+    /// The bytes don't represent a valid key.
+    InvalidKey,
+}
+
+impl fmt::Display for KeyError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl Error for KeyError {
+    #[inline]
+    fn description(&self) -> &str {
+        match *self {
+            KeyError::UnknownKeyType => "unknown key type",
+            KeyError::InvalidKey => "invalid key",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keypair_bytes_round_trip() {
+        for &key_type in &[KeyType::Ed25519, KeyType::Secp256k1] {
+            let keypair = Keypair::generate(key_type).unwrap();
+            let decoded = Keypair::from_bytes(&keypair.to_bytes()).unwrap();
+            assert_eq!(decoded.key_type(), key_type);
+            assert_eq!(decoded.public(), keypair.public());
+            assert_eq!(decoded.peer_id(), keypair.peer_id());
+        }
+    }
+
+    #[test]
+    fn raw_ed25519_keypair_is_accepted() {
+        let keypair = Keypair::generate(KeyType::Ed25519).unwrap();
+        let bytes = keypair.to_bytes();
+        let raw = &bytes[1..];
+        assert_eq!(raw.len(), ed25519_dalek::KEYPAIR_LENGTH);
+        assert_eq!(Keypair::from_bytes(raw).unwrap().public(), keypair.public());
+    }
+
+    #[test]
+    fn invalid_keypair_bytes_are_rejected() {
+        assert_eq!(Keypair::from_bytes(&[]).err(), Some(KeyError::InvalidKey));
+        assert_eq!(Keypair::from_bytes(&[7, 1, 2, 3]).err(), Some(KeyError::UnknownKeyType));
+        assert_eq!(Keypair::from_bytes(&[1, 1, 2, 3]).err(), Some(KeyError::InvalidKey));
+        assert_eq!(Keypair::from_bytes(&[2, 1, 2, 3]).err(), Some(KeyError::InvalidKey));
+    }
+
+    #[test]
+    fn public_key_protobuf_round_trip() {
+        for &key_type in &[KeyType::Ed25519, KeyType::Secp256k1] {
+            let public = Keypair::generate(key_type).unwrap().public();
+            let encoded = public.to_protobuf_encoding();
+            assert_eq!(PublicKey::from_protobuf_encoding(&encoded).unwrap(), public);
+
+            let mut truncated = encoded.clone();
+            truncated.pop();
+            assert!(PublicKey::from_protobuf_encoding(&truncated).is_err());
+        }
+    }
+
+    #[test]
+    fn signatures_verify() {
+        for &key_type in &[KeyType::Ed25519, KeyType::Secp256k1] {
+            let keypair = Keypair::generate(key_type).unwrap();
+            let signature = keypair.sign(b"hello");
+            assert!(keypair.public().verify(b"hello", &signature));
+            assert!(!keypair.public().verify(b"hellp", &signature));
+            assert!(!keypair.public().verify(b"hello", &signature[1..]));
+
+            let other = Keypair::generate(key_type).unwrap();
+            assert!(!other.public().verify(b"hello", &signature));
+        }
+    }
+
+    #[test]
+    fn parse_key_type() {
+        assert_eq!("ed25519".parse::<KeyType>(), Ok(KeyType::Ed25519));
+        assert_eq!("secp256k1".parse::<KeyType>(), Ok(KeyType::Secp256k1));
+        assert_eq!("rsa".parse::<KeyType>(), Err(KeyError::UnknownKeyType));
+    }
+}
@@ -0,0 +1,49 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Code shared between the chapters of the workshop.
+//!
+//! You don't need to read this crate in order to follow the workshop, but feel free to do so!
+
+extern crate bs58;
+extern crate chacha20_poly1305_aead;
+extern crate ed25519_dalek;
+extern crate futures;
+extern crate libp2p_core;
+extern crate libp2p_floodsub;
+extern crate libp2p_secio;
+extern crate rand;
+extern crate secp256k1;
+extern crate sha2;
+extern crate x25519_dalek;
+
+pub mod codec;
+pub mod dedup;
+pub mod identity;
+pub mod group;
+pub mod key;
+pub mod message;
+pub mod peer_addr;
+pub mod presence;
+pub mod rooms;
+pub mod signed;
+pub mod storage;
+
+pub use key::{KeyError, KeyType, Keypair, PublicKey};
@@ -0,0 +1,650 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Structured chat messages.
+//!
+//! The payload of the signed envelope of every message that we publish (see the `signed` module)
+//! is a `ChatMessage`, encoded as the following protobuf message:
+//!
+//! ```protobuf
+//! message ChatMessage {
+//!     uint32 version = 1;
+//!     bytes id = 2;
+//!     bytes author = 3;       // PeerId of the author
+//!     string nickname = 4;
+//!     uint64 lamport = 5;     // Lamport time
+//!     uint64 timestamp = 6;   // Wall-clock time, in milliseconds since the UNIX epoch
+//!     uint32 kind = 7;
+//!     bytes payload = 8;
+//!     uint64 seq = 9;         // Sequence number, increasing for each message of the author
+//! }
+//! ```
+//!
+//! New fields can be added without bumping the version, as decoders skip the fields they don't
+//! know. The version is only bumped for changes that older nodes can't understand. Similarly,
+//! nodes display messages of a kind they don't know instead of dropping them.
+
+use libp2p_core::PeerId;
+use rand;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the encoding produced by this module.
+pub const VERSION: u32 = 1;
+
+/// Maximum length of a nickname, in characters.
+pub const MAX_NICKNAME_LEN: usize = 32;
+
+/// What a message represents.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kind {
+    /// Something the author said.
+    Text,
+    /// Something the author did, as in `/me waves`.
+    Action,
+    /// A notice produced by the software of the author rather than typed by a human.
+    System,
+    /// The author offers to send a file.
+    FileOffer,
+    /// A message of an encrypted room. See the `group` module.
+    Encrypted,
+    /// The author asks for the key of an encrypted room.
+    KeyRequest,
+    /// A new key for an encrypted room.
+    KeyUpdate,
+    /// The author leaves an encrypted room, whose key must be replaced.
+    KeyLeave,
+    /// The author announces whether it is in a room. See the `presence` module.
+    Presence,
+    /// A kind introduced by a more recent version of the software.
+    Unknown(u32),
+}
+
+impl Kind {
+    /// Returns the number that represents this kind in the encoding.
+    pub fn to_u32(&self) -> u32 {
+        match *self {
+            Kind::Text => 0,
+            Kind::Action => 1,
+            Kind::System => 2,
+            Kind::FileOffer => 3,
+            Kind::Encrypted => 4,
+            Kind::KeyRequest => 5,
+            Kind::KeyUpdate => 6,
+            Kind::KeyLeave => 7,
+            Kind::Presence => 8,
+            Kind::Unknown(kind) => kind,
+        }
+    }
+
+    /// Inverse of `to_u32`.
+    pub fn from_u32(kind: u32) -> Kind {
+        match kind {
+            0 => Kind::Text,
+            1 => Kind::Action,
+            2 => Kind::System,
+            3 => Kind::FileOffer,
+            4 => Kind::Encrypted,
+            5 => Kind::KeyRequest,
+            6 => Kind::KeyUpdate,
+            7 => Kind::KeyLeave,
+            8 => Kind::Presence,
+            kind => Kind::Unknown(kind),
+        }
+    }
+}
+
+/// A message published in a room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    /// Identifier of the message, chosen by its author.
+    pub id: Vec<u8>,
+    /// Node that wrote the message.
+    pub author: PeerId,
+    /// Nickname of the author at the time it wrote the message.
+    pub nickname: String,
+    /// Lamport time of the message. Orders messages consistently even if the clocks of the nodes
+    /// disagree.
+    pub lamport: u64,
+    /// Wall-clock time of the author when it wrote the message, in milliseconds since the UNIX
+    /// epoch.
+    pub timestamp: u64,
+    pub kind: Kind,
+    /// Content of the message. For text and actions, this is UTF-8.
+    pub payload: Vec<u8>,
+    /// Sequence number of the message among the messages of its author. Together with `author`,
+    /// it identifies the message in the seen-caches (see the `dedup` module). Zero for messages
+    /// of nodes that predate this field.
+    pub seq: u64,
+}
+
+impl ChatMessage {
+    /// Encodes the message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64 + self.nickname.len() + self.payload.len());
+        push_varint_field(&mut out, 1, VERSION as u64);
+        push_bytes_field(&mut out, 2, &self.id);
+        push_bytes_field(&mut out, 3, self.author.as_bytes());
+        push_bytes_field(&mut out, 4, self.nickname.as_bytes());
+        push_varint_field(&mut out, 5, self.lamport);
+        push_varint_field(&mut out, 6, self.timestamp);
+        push_varint_field(&mut out, 7, self.kind.to_u32() as u64);
+        push_bytes_field(&mut out, 8, &self.payload);
+        push_varint_field(&mut out, 9, self.seq);
+        out
+    }
+
+    /// Decodes the output of `encode`.
+    pub fn decode(mut data: &[u8]) -> Result<ChatMessage, DecodeError> {
+        let mut version = None;
+        let mut id = Vec::new();
+        let mut author = None;
+        let mut nickname = String::new();
+        let (mut lamport, mut timestamp, mut kind, mut seq) = (0, 0, 0, 0);
+        let mut payload = Vec::new();
+
+        while !data.is_empty() {
+            let key = read_varint(&mut data)?;
+            let value = match key & 0x7 {
+                0 => Field::Varint(read_varint(&mut data)?),
+                1 => {
+                    read_slice(&mut data, 8)?;
+                    Field::Other
+                }
+                2 => {
+                    let len = read_varint(&mut data)? as usize;
+                    Field::Bytes(read_slice(&mut data, len)?)
+                }
+                5 => {
+                    read_slice(&mut data, 4)?;
+                    Field::Other
+                }
+                _ => return Err(DecodeError::Malformed),
+            };
+
+            match (key >> 3, value) {
+                (1, Field::Varint(v)) => version = Some(v),
+                (2, Field::Bytes(v)) => id = v.to_vec(),
+                (3, Field::Bytes(v)) => {
+                    let peer_id = PeerId::from_bytes(v.to_vec());
+                    author = Some(peer_id.map_err(|_| DecodeError::Malformed)?);
+                }
+                (4, Field::Bytes(v)) => {
+                    nickname = String::from_utf8(v.to_vec()).map_err(|_| DecodeError::Malformed)?
+                }
+                (5, Field::Varint(v)) => lamport = v,
+                (6, Field::Varint(v)) => timestamp = v,
+                (7, Field::Varint(v)) => kind = v,
+                (8, Field::Bytes(v)) => payload = v.to_vec(),
+                (9, Field::Varint(v)) => seq = v,
+                (1..=9, _) => return Err(DecodeError::Malformed),
+                _ => (),
+            }
+        }
+
+        match version {
+            Some(v) if v == VERSION as u64 => (),
+            Some(v) => return Err(DecodeError::UnsupportedVersion(v)),
+            None => return Err(DecodeError::Malformed),
+        }
+
+        Ok(ChatMessage {
+            id: id,
+            author: author.ok_or(DecodeError::Malformed)?,
+            nickname: nickname,
+            lamport: lamport,
+            timestamp: timestamp,
+            kind: Kind::from_u32(kind as u32),
+            payload: payload,
+            seq: seq,
+        })
+    }
+}
+
+impl fmt::Display for ChatMessage {
+    /// Formats the message for the terminal, for example `12:34 <alice> hello`. The time is in
+    /// UTC. The nickname and the text come from the author, so their control characters are
+    /// replaced (see `sanitize`).
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let minutes = self.timestamp / 60_000;
+        write!(f, "{:02}:{:02} ", (minutes / 60) % 24, minutes % 60)?;
+
+        let nickname = sanitize(&self.nickname);
+        let text = sanitize(&String::from_utf8_lossy(&self.payload));
+        match self.kind {
+            Kind::Text => write!(f, "<{}> {}", nickname, text),
+            Kind::Action => write!(f, "* {} {}", nickname, text),
+            Kind::System => write!(f, "-- {}: {}", nickname, text),
+            Kind::FileOffer => write!(f, "<{}> offers a file", nickname),
+            Kind::Encrypted => write!(f, "<{}> sent an encrypted message", nickname),
+            Kind::KeyRequest => write!(f, "-- {} asks for the key of the room", nickname),
+            Kind::KeyUpdate => write!(f, "-- {} changed the key of the room", nickname),
+            Kind::KeyLeave => write!(f, "-- {} left the encrypted room", nickname),
+            Kind::Presence => write!(f, "-- {} announced its presence", nickname),
+            Kind::Unknown(kind) => {
+                write!(f, "<{}> sent a message of unknown kind {}", nickname, kind)
+            }
+        }
+    }
+}
+
+/// Describes a payload that `ChatMessage::decode` couldn't decode, for display.
+///
+/// Nodes that predate `ChatMessage` publish raw UTF-8 text, which is displayed as is.
+pub fn describe_undecodable(payload: &[u8], err: &DecodeError) -> String {
+    match (err, String::from_utf8(payload.to_vec())) {
+        (&DecodeError::Malformed, Ok(text)) => sanitize(&text),
+        (&DecodeError::Malformed, Err(_)) => "undecodable message".to_owned(),
+        (&DecodeError::UnsupportedVersion(version), _) => {
+            format!("message encoded with unsupported version {}", version)
+        }
+    }
+}
+
+/// Replaces the control characters of `text`, such as line breaks and the escape character, with
+/// U+FFFD. Text written by other nodes goes through this function before being displayed, so
+/// that they can't forge lines or send escape sequences to the terminal.
+pub fn sanitize(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { '\u{fffd}' } else { c })
+        .collect()
+}
+
+/// Produces the messages of the local node.
+///
+/// Cloning a `Composer` gives access to the same nickname and Lamport clock.
+#[derive(Clone)]
+pub struct Composer {
+    author: PeerId,
+    inner: Arc<Mutex<ComposerInner>>,
+}
+
+struct ComposerInner {
+    nickname: String,
+    lamport: u64,
+    seq: u64,
+}
+
+impl Composer {
+    /// Creates a composer for the messages written by `author`, with the default nickname.
+    pub fn new(author: PeerId) -> Composer {
+        Composer {
+            inner: Arc::new(Mutex::new(ComposerInner {
+                nickname: default_nickname(&author),
+                lamport: 0,
+                // Starting from the clock rather than from zero keeps the sequence numbers of a
+                // restarted node away from the ones its peers still remember.
+                seq: now_millis(),
+            })),
+            author: author,
+        }
+    }
+
+    /// Returns the nickname of the local node.
+    pub fn nickname(&self) -> String {
+        self.inner.lock().unwrap().nickname.clone()
+    }
+
+    /// Changes the nickname of the local node, if `check_nickname` accepts it.
+    pub fn set_nickname(&self, nickname: String) -> Result<(), NicknameError> {
+        check_nickname(&nickname)?;
+        self.inner.lock().unwrap().nickname = nickname;
+        Ok(())
+    }
+
+    /// Builds a new message.
+    pub fn compose(&self, kind: Kind, payload: Vec<u8>) -> ChatMessage {
+        let mut inner = self.inner.lock().unwrap();
+        inner.lamport += 1;
+        inner.seq += 1;
+        ChatMessage {
+            id: (0..16).map(|_| rand::random::<u8>()).collect(),
+            author: self.author.clone(),
+            nickname: inner.nickname.clone(),
+            lamport: inner.lamport,
+            timestamp: now_millis(),
+            kind: kind,
+            payload: payload,
+            seq: inner.seq,
+        }
+    }
+
+    /// Advances the Lamport clock after receiving `message`.
+    pub fn observe(&self, message: &ChatMessage) {
+        let mut inner = self.inner.lock().unwrap();
+        if message.lamport > inner.lamport {
+            inner.lamport = message.lamport;
+        }
+    }
+}
+
+/// Nickname of a node that didn't choose one: the last characters of its `PeerId`, as the first
+/// ones are the same for all the nodes.
+pub fn default_nickname(peer_id: &PeerId) -> String {
+    let peer_id = peer_id.to_base58();
+    let start = peer_id.len().saturating_sub(8);
+    peer_id[start..].to_owned()
+}
+
+/// Checks that `nickname` can be chosen by the local node: it must be a single word of at most
+/// `MAX_NICKNAME_LEN` printable characters.
+///
+/// Other nodes don't necessarily apply the same rules, so the nicknames we receive still go
+/// through `sanitize`.
+pub fn check_nickname(nickname: &str) -> Result<(), NicknameError> {
+    if nickname.is_empty() {
+        return Err(NicknameError::Empty);
+    }
+    if nickname.chars().count() > MAX_NICKNAME_LEN {
+        return Err(NicknameError::TooLong);
+    }
+    match nickname.chars().find(|c| c.is_control() || c.is_whitespace()) {
+        Some(c) => Err(NicknameError::InvalidCharacter(c)),
+        None => Ok(()),
+    }
+}
+
+/// Returns the current wall-clock time, in milliseconds since the UNIX epoch.
+pub fn now_millis() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() * 1000 + u64::from(now.subsec_nanos() / 1_000_000)
+}
+
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    /// A fixed-size field. We don't have any, but newer versions might.
+    Other,
+}
+
+fn push_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn push_varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+    push_varint(out, field << 3);
+    push_varint(out, value);
+}
+
+fn push_bytes_field(out: &mut Vec<u8>, field: u64, data: &[u8]) {
+    push_varint(out, (field << 3) | 2);
+    push_varint(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<u64, DecodeError> {
+    let mut value = 0u64;
+    for (n, &byte) in data.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * n);
+        if byte & 0x80 == 0 {
+            *data = &data[n + 1..];
+            return Ok(value);
+        }
+    }
+    Err(DecodeError::Malformed)
+}
+
+fn read_slice<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], DecodeError> {
+    if data.len() < len {
+        return Err(DecodeError::Malformed);
+    }
+    let (slice, rest) = data.split_at(len);
+    *data = rest;
+    Ok(slice)
+}
+
+/// Error while decoding a `ChatMessage`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The data isn't a valid `ChatMessage`.
+    Malformed,
+    /// The message was encoded with a version of the format that we don't support.
+    UnsupportedVersion(u64),
+}
+
+impl fmt::Display for DecodeError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Malformed => write!(f, "{}", self.description()),
+            DecodeError::UnsupportedVersion(v) => write!(f, "{} {}", self.description(), v),
+        }
+    }
+}
+
+impl Error for DecodeError {
+    #[inline]
+    fn description(&self) -> &str {
+        match *self {
+            DecodeError::Malformed => "malformed chat message",
+            DecodeError::UnsupportedVersion(_) => "unsupported chat message version",
+        }
+    }
+}
+
+/// Reason why `check_nickname` refused a nickname.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NicknameError {
+    /// The nickname is empty.
+    Empty,
+    /// The nickname is longer than `MAX_NICKNAME_LEN` characters.
+    TooLong,
+    /// The nickname contains a whitespace or control character.
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for NicknameError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NicknameError::TooLong => {
+                write!(f, "{} (at most {} characters)", self.description(), MAX_NICKNAME_LEN)
+            }
+            NicknameError::InvalidCharacter(c) => {
+                write!(f, "{} {:?}", self.description(), c)
+            }
+            NicknameError::Empty => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for NicknameError {
+    #[inline]
+    fn description(&self) -> &str {
+        match *self {
+            NicknameError::Empty => "empty nickname",
+            NicknameError::TooLong => "nickname too long",
+            NicknameError::InvalidCharacter(_) => "invalid character in nickname",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use key::{KeyType, Keypair};
+
+    fn random_peer_id() -> PeerId {
+        Keypair::generate(KeyType::Ed25519).unwrap().peer_id()
+    }
+
+    fn message(kind: Kind, payload: &[u8]) -> ChatMessage {
+        ChatMessage {
+            id: vec![1, 2, 3],
+            author: random_peer_id(),
+            nickname: "alice".to_owned(),
+            lamport: 300,
+            // 12:34 UTC.
+            timestamp: (12 * 60 + 34) * 60_000,
+            kind: kind,
+            payload: payload.to_vec(),
+            seq: 1 << 40,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for kind in (0..10).chain(Some(1000)).map(Kind::from_u32) {
+            let message = message(kind, b"hello");
+            assert_eq!(ChatMessage::decode(&message.encode()), Ok(message));
+        }
+    }
+
+    #[test]
+    fn unknown_kinds_are_preserved() {
+        assert_eq!(Kind::from_u32(42), Kind::Unknown(42));
+        assert_eq!(Kind::Unknown(42).to_u32(), 42);
+        let message = message(Kind::Unknown(42), b"");
+        assert_eq!(ChatMessage::decode(&message.encode()).unwrap().kind, Kind::Unknown(42));
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let message = message(Kind::Text, b"hello");
+        let mut data = message.encode();
+        push_varint_field(&mut data, 20, 7);
+        push_bytes_field(&mut data, 21, b"from the future");
+        // Fixed-size fields of 64 and 32 bits.
+        push_varint(&mut data, (22 << 3) | 1);
+        data.extend_from_slice(&[0; 8]);
+        push_varint(&mut data, (23 << 3) | 5);
+        data.extend_from_slice(&[0; 4]);
+        assert_eq!(ChatMessage::decode(&data), Ok(message));
+    }
+
+    #[test]
+    fn missing_seq_defaults_to_zero() {
+        let message = message(Kind::Text, b"hello");
+        let mut data = Vec::new();
+        push_varint_field(&mut data, 1, VERSION as u64);
+        push_bytes_field(&mut data, 3, message.author.as_bytes());
+        push_bytes_field(&mut data, 8, b"hello");
+        let decoded = ChatMessage::decode(&data).unwrap();
+        assert_eq!(decoded.seq, 0);
+        assert_eq!(decoded.kind, Kind::Text);
+        assert_eq!(decoded.payload, b"hello".to_vec());
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut data = Vec::new();
+        push_varint_field(&mut data, 1, 2);
+        push_bytes_field(&mut data, 3, random_peer_id().as_bytes());
+        assert_eq!(ChatMessage::decode(&data), Err(DecodeError::UnsupportedVersion(2)));
+    }
+
+    #[test]
+    fn malformed() {
+        let data = message(Kind::Text, b"hello").encode();
+        for len in 1..data.len() {
+            // Cutting the message right after a field gives a valid message without the next
+            // fields, anything else is malformed.
+            if let Err(err) = ChatMessage::decode(&data[..len]) {
+                assert_eq!(err, DecodeError::Malformed);
+            }
+        }
+        assert_eq!(ChatMessage::decode(b""), Err(DecodeError::Malformed));
+        assert_eq!(ChatMessage::decode(b"hello world"), Err(DecodeError::Malformed));
+        assert_eq!(ChatMessage::decode(&[0xff; 11]), Err(DecodeError::Malformed));
+
+        // A field with the wrong wire type.
+        let mut data = Vec::new();
+        push_varint_field(&mut data, 1, VERSION as u64);
+        push_varint_field(&mut data, 3, 5);
+        assert_eq!(ChatMessage::decode(&data), Err(DecodeError::Malformed));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(message(Kind::Text, b"hello").to_string(), "12:34 <alice> hello");
+        assert_eq!(message(Kind::Action, b"waves").to_string(), "12:34 * alice waves");
+        assert_eq!(
+            message(Kind::Unknown(42), b"").to_string(),
+            "12:34 <alice> sent a message of unknown kind 42"
+        );
+    }
+
+    #[test]
+    fn display_replaces_control_characters() {
+        let mut message = message(Kind::Text, b"hi\n12:35 <bob> \x1b[2Jbye\r");
+        message.nickname = "ali\x07ce".to_owned();
+        let displayed = message.to_string();
+        assert_eq!(displayed, "12:34 <ali\u{fffd}ce> hi\u{fffd}12:35 <bob> \u{fffd}[2Jbye\u{fffd}");
+        assert!(!displayed.chars().any(char::is_control));
+    }
+
+    #[test]
+    fn undecodable_payloads() {
+        let err = DecodeError::Malformed;
+        assert_eq!(describe_undecodable(b"plain text", &err), "plain text");
+        assert_eq!(describe_undecodable(b"evil\x1b[0m", &err), "evil\u{fffd}[0m");
+        assert_eq!(describe_undecodable(&[0xff, 0xfe], &err), "undecodable message");
+        assert_eq!(
+            describe_undecodable(b"", &DecodeError::UnsupportedVersion(7)),
+            "message encoded with unsupported version 7"
+        );
+    }
+
+    #[test]
+    fn composer() {
+        let author = random_peer_id();
+        let composer = Composer::new(author.clone());
+        assert_eq!(composer.nickname(), default_nickname(&author));
+        composer.set_nickname("alice".to_owned()).unwrap();
+
+        let first = composer.compose(Kind::Text, b"one".to_vec());
+        let second = composer.compose(Kind::Text, b"two".to_vec());
+        assert_eq!(first.author, author);
+        assert_eq!(first.nickname, "alice");
+        assert_eq!(second.lamport, first.lamport + 1);
+        assert_eq!(second.seq, first.seq + 1);
+        assert_ne!(first.id, second.id);
+
+        // The clock jumps past the messages we receive, but never goes back.
+        let mut remote = message(Kind::Text, b"");
+        remote.lamport = 1000;
+        composer.observe(&remote);
+        assert_eq!(composer.compose(Kind::Text, Vec::new()).lamport, 1001);
+        remote.lamport = 5;
+        composer.observe(&remote);
+        assert_eq!(composer.compose(Kind::Text, Vec::new()).lamport, 1002);
+    }
+
+    #[test]
+    fn nicknames_are_checked() {
+        assert_eq!(check_nickname("alice"), Ok(()));
+        assert_eq!(check_nickname("élodie"), Ok(()));
+        assert_eq!(check_nickname(""), Err(NicknameError::Empty));
+        assert_eq!(check_nickname("bob smith"), Err(NicknameError::InvalidCharacter(' ')));
+        assert_eq!(check_nickname("bob\x1b[2J"), Err(NicknameError::InvalidCharacter('\x1b')));
+        assert_eq!(check_nickname(&"a".repeat(MAX_NICKNAME_LEN)), Ok(()));
+        assert_eq!(check_nickname(&"a".repeat(MAX_NICKNAME_LEN + 1)), Err(NicknameError::TooLong));
+
+        let composer = Composer::new(random_peer_id());
+        assert!(composer.set_nickname("bob\nalice".to_owned()).is_err());
+        assert_eq!(composer.nickname(), default_nickname(&composer.author));
+    }
+}
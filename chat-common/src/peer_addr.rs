@@ -0,0 +1,203 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Attaching the identity of a peer to its multiaddress.
+//!
+//! Once a connection is multiplexed, the protocols negotiated on its substreams only know the
+//! address of the remote and not the `PeerId` that secio authenticated. We solve this by
+//! appending `/ipfs/<peer-id>` to the address of the remote right after the secio handshake.
+//!
+//! The addresses that we dial can end with `/ipfs/<peer-id>` as well, which the underlying
+//! transports don't understand. `StripPeerId` dials them without the suffix, and `authenticated`
+//! checks that the remote is the node that we dialed once secio knows who it is.
+
+use bs58::FromBase58;
+use futures::{Future, IntoFuture};
+use libp2p_core::{AddrComponent, Multiaddr, PeerId, Transport};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+/// Appends `/ipfs/<peer_id>` to `addr`, unless it already ends with an `/ipfs/` component.
+pub fn with_peer_id(mut addr: Multiaddr, peer_id: &PeerId) -> Multiaddr {
+    if let Some(AddrComponent::IPFS(_)) = addr.iter().last() {
+        return addr;
+    }
+    addr.append(AddrComponent::IPFS(peer_id.clone().into_bytes()));
+    addr
+}
+
+/// Returns the address of a remote after the secio handshake authenticated it as `remote`.
+///
+/// Fails if `addr` ends with `/ipfs/<peer-id>` and `<peer-id>` isn't `remote`, which means that
+/// the node we dialed isn't the one we expected. Otherwise, `/ipfs/<remote>` is appended.
+pub fn authenticated(addr: Multiaddr, remote: &PeerId) -> Result<Multiaddr, IoError> {
+    match peer_id_of(&addr) {
+        Some(ref expected) if expected != remote => Err(IoError::new(
+            IoErrorKind::PermissionDenied,
+            "the remote isn't the node that we dialed",
+        )),
+        _ => Ok(with_peer_id(addr, remote)),
+    }
+}
+
+/// Extracts the `PeerId` from an address produced by `with_peer_id`.
+pub fn peer_id_of(addr: &Multiaddr) -> Option<PeerId> {
+    match addr.iter().last() {
+        Some(AddrComponent::IPFS(bytes)) => PeerId::from_bytes(bytes).ok(),
+        _ => None,
+    }
+}
+
+/// Returns `addr` without its trailing `/ipfs/<peer-id>` component, if any.
+///
+/// This is the address to dial in order to reach the peer again.
+pub fn without_peer_id(addr: &Multiaddr) -> Multiaddr {
+    let mut components = addr.iter().collect::<Vec<_>>();
+    if let Some(&AddrComponent::IPFS(_)) = components.last() {
+        components.pop();
+    }
+    components.into_iter().collect()
+}
+
+/// Returns true if other nodes may be able to dial `addr`.
+///
+/// Unspecified IP addresses (`0.0.0.0` and `::`) and port 0 are only meaningful when listening.
+pub fn is_dialable(addr: &Multiaddr) -> bool {
+    addr.iter().all(|component| match component {
+        AddrComponent::IP4(ip) => !ip.is_unspecified(),
+        AddrComponent::IP6(ip) => !ip.is_unspecified(),
+        AddrComponent::TCP(port) | AddrComponent::UDP(port) => port != 0,
+        _ => true,
+    })
+}
+
+/// Parses a `PeerId` from its base58 representation, as returned by `PeerId::to_base58()`.
+pub fn parse_peer_id(s: &str) -> Option<PeerId> {
+    s.from_base58()
+        .ok()
+        .and_then(|bytes| PeerId::from_bytes(bytes).ok())
+}
+
+/// Transport that dials the addresses that end with `/ipfs/<peer-id>` without this suffix.
+///
+/// The address that a dial produces is the one that was dialed, suffix included, so that
+/// `authenticated` can check the identity of the remote.
+#[derive(Debug, Clone)]
+pub struct StripPeerId<T>(T);
+
+impl<T> StripPeerId<T> {
+    /// Wraps around `transport`.
+    #[inline]
+    pub fn new(transport: T) -> StripPeerId<T> {
+        StripPeerId(transport)
+    }
+}
+
+impl<T> Transport for StripPeerId<T>
+where
+    T: Transport + 'static,
+    T::Output: 'static,
+    <T::Dial as IntoFuture>::Future: 'static,
+{
+    type Output = T::Output;
+    type Listener = T::Listener;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = Box<Future<Item = (T::Output, Multiaddr), Error = IoError>>;
+
+    #[inline]
+    fn listen_on(self, addr: Multiaddr) -> Result<(Self::Listener, Multiaddr), (Self, Multiaddr)> {
+        self.0
+            .listen_on(addr)
+            .map_err(|(inner, addr)| (StripPeerId(inner), addr))
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, (Self, Multiaddr)> {
+        match self.0.dial(without_peer_id(&addr)) {
+            Ok(dial) => Ok(Box::new(dial.into_future().map(move |(out, _)| (out, addr)))),
+            Err((inner, _)) => Err((StripPeerId(inner), addr)),
+        }
+    }
+
+    #[inline]
+    fn nat_traversal(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.0.nat_traversal(server, observed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use key::{KeyType, Keypair};
+
+    fn random_peer_id() -> PeerId {
+        Keypair::generate(KeyType::Ed25519).unwrap().peer_id()
+    }
+
+    #[test]
+    fn peer_id_round_trip() {
+        let peer_id = random_peer_id();
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/5".parse().unwrap();
+        let with = with_peer_id(addr.clone(), &peer_id);
+        assert_eq!(peer_id_of(&with), Some(peer_id));
+        assert_eq!(without_peer_id(&with), addr);
+        assert_eq!(peer_id_of(&addr), None);
+        assert_eq!(without_peer_id(&addr), addr);
+    }
+
+    #[test]
+    fn peer_id_is_appended_only_once() {
+        let peer_id = random_peer_id();
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/5".parse().unwrap();
+        let with = with_peer_id(addr, &peer_id);
+        assert_eq!(with_peer_id(with.clone(), &peer_id), with);
+        // An address that designates another node keeps designating it.
+        assert_eq!(with_peer_id(with.clone(), &random_peer_id()), with);
+    }
+
+    #[test]
+    fn remote_must_be_the_node_we_dialed() {
+        let (dialed, other) = (random_peer_id(), random_peer_id());
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/5".parse().unwrap();
+        let expected = with_peer_id(addr.clone(), &dialed);
+
+        assert_eq!(authenticated(addr, &dialed).unwrap(), expected);
+        assert_eq!(authenticated(expected.clone(), &dialed).unwrap(), expected);
+        let err = authenticated(expected, &other).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn unspecified_addresses_are_not_dialable() {
+        let dialable = |s: &str| is_dialable(&s.parse::<Multiaddr>().unwrap());
+        assert!(dialable("/ip4/1.2.3.4/tcp/5"));
+        assert!(dialable("/ip6/::1/tcp/5"));
+        assert!(dialable("/ip4/127.0.0.1/tcp/5/ws"));
+        assert!(!dialable("/ip4/0.0.0.0/tcp/5"));
+        assert!(!dialable("/ip6/::/tcp/5"));
+        assert!(!dialable("/ip4/1.2.3.4/tcp/0"));
+    }
+
+    #[test]
+    fn parse_base58() {
+        let peer_id = random_peer_id();
+        assert_eq!(parse_peer_id(&peer_id.to_base58()), Some(peer_id));
+        assert_eq!(parse_peer_id("not base58!"), None);
+        assert_eq!(parse_peer_id("abc"), None);
+    }
+}
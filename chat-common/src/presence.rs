@@ -0,0 +1,393 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Presence of the members of the rooms.
+//!
+//! Every node regularly publishes a `Presence` message on the presence topic of each room it is
+//! in (see the `rooms` module), every `HEARTBEAT_INTERVAL`. It also publishes one as soon as it
+//! joins a room or changes its nickname, and one that says that it's leaving when it leaves a
+//! room. The payload of the message is a single byte: 0 if the author is in the room, 1 if it is
+//! leaving.
+//!
+//! A member that we haven't heard of for `PRESENCE_TTL` is considered gone. This is the case of
+//! the nodes that crash or lose their connection.
+//!
+//! Nothing prevents two nodes from choosing the same nickname. When several members of a room
+//! use the same one, they are displayed with the end of their `PeerId` in parentheses, as in
+//! `alice (3NvDk1Zq)`, so that one can't pass for the other.
+
+use key::Keypair;
+use libp2p_core::PeerId;
+use message::{self, ChatMessage, Composer, Kind};
+use rooms::{self, Rooms};
+use signed;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Interval between two presence announcements in the same room.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a member stays present after its last announcement. This is a bit more than three
+/// heartbeats, so that a lost message doesn't make a member disappear.
+pub const PRESENCE_TTL: Duration = Duration::from_secs(100);
+
+/// What a `Presence` message announces.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// The author is in the room.
+    Here,
+    /// The author is leaving the room.
+    Leaving,
+}
+
+impl Status {
+    /// Encodes the status as the payload of a `Presence` message.
+    pub fn encode(self) -> Vec<u8> {
+        match self {
+            Status::Here => vec![0],
+            Status::Leaving => vec![1],
+        }
+    }
+
+    /// Decodes the payload of a `Presence` message.
+    pub fn decode(payload: &[u8]) -> Option<Status> {
+        match (payload.len(), payload.first()) {
+            (1, Some(&0)) => Some(Status::Here),
+            (1, Some(&1)) => Some(Status::Leaving),
+            _ => None,
+        }
+    }
+}
+
+/// A command typed by the user about the members of the rooms. See `Presence::execute`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PresenceCommand {
+    /// `/nick <name>`: changes our nickname and announces it in all our rooms.
+    Nick(String),
+    /// `/who [<room>]`: lists the members present in a room, or in all our rooms if none is given.
+    Who(Option<String>),
+}
+
+/// Publishes a `Presence` message on the presence topic of `room`.
+pub fn announce(rooms: &Rooms, keypair: &Keypair, composer: &Composer, room: &str, status: Status) {
+    let message = composer.compose(Kind::Presence, status.encode());
+    let data = signed::sign(keypair, &rooms::presence_topic(room), &message.encode());
+    rooms.publish_presence(room, data);
+}
+
+/// A change in the members of a room, for display. The room isn't part of the displayed text,
+/// see `room`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notice {
+    Joined { room: String, nickname: String },
+    Renamed { room: String, old: String, new: String },
+    Left { room: String, nickname: String },
+    TimedOut { room: String, nickname: String, peer_id: PeerId },
+}
+
+impl Notice {
+    /// Returns the room whose members changed.
+    pub fn room(&self) -> &str {
+        match *self {
+            Notice::Joined { ref room, .. }
+            | Notice::Renamed { ref room, .. }
+            | Notice::Left { ref room, .. }
+            | Notice::TimedOut { ref room, .. } => room,
+        }
+    }
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Notice::Joined { ref nickname, .. } => write!(f, "-- {} joined the room", nickname),
+            Notice::Renamed { ref old, ref new, .. } => {
+                write!(f, "-- {} is now known as {}", old, new)
+            }
+            Notice::Left { ref nickname, .. } => write!(f, "-- {} left the room", nickname),
+            Notice::TimedOut { ref nickname, .. } => {
+                write!(f, "-- {} left the room (timed out)", nickname)
+            }
+        }
+    }
+}
+
+/// The members present in each room. Cloning a `Presence` gives access to the same members.
+///
+/// The local node isn't tracked: it's up to the caller to add itself to the output of `who`.
+#[derive(Clone, Default)]
+pub struct Presence {
+    inner: Arc<Mutex<HashMap<String, HashMap<PeerId, Member>>>>,
+}
+
+struct Member {
+    nickname: String,
+    last_seen: Instant,
+}
+
+impl Presence {
+    /// Creates an empty list of members.
+    #[inline]
+    pub fn new() -> Presence {
+        Presence::default()
+    }
+
+    /// Processes a verified `Presence` message received on the presence topic of `room`.
+    ///
+    /// Returns a notice if the members of the room changed.
+    pub fn on_message(&self, room: &str, message: &ChatMessage) -> Option<Notice> {
+        if message.kind != Kind::Presence {
+            return None;
+        }
+        let status = Status::decode(&message.payload)?;
+        let mut inner = self.inner.lock().unwrap();
+        let members = inner.entry(room.to_owned()).or_insert_with(HashMap::new);
+
+        if status == Status::Leaving {
+            let nickname = display_name(members, &message.author, &[])?;
+            members.remove(&message.author);
+            return Some(Notice::Left {
+                room: room.to_owned(),
+                nickname: nickname,
+            });
+        }
+
+        // The nickname ends up in the notices and in `who`, which are displayed as is.
+        let nickname = message::sanitize(&message.nickname);
+        let old = display_name(members, &message.author, &[]);
+        let renamed = members
+            .get(&message.author)
+            .map_or(false, |member| member.nickname != nickname);
+        let member = Member {
+            nickname: nickname,
+            last_seen: Instant::now(),
+        };
+        members.insert(message.author.clone(), member);
+        let new = display_name(members, &message.author, &[])?;
+        match old {
+            None => Some(Notice::Joined {
+                room: room.to_owned(),
+                nickname: new,
+            }),
+            Some(old) if renamed => Some(Notice::Renamed {
+                room: room.to_owned(),
+                old: old,
+                new: new,
+            }),
+            Some(_) => None,
+        }
+    }
+
+    /// Removes the members we haven't heard of for `PRESENCE_TTL`, and returns a notice for each
+    /// of them.
+    pub fn expire(&self) -> Vec<Notice> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let mut notices = Vec::new();
+        for (room, members) in inner.iter_mut() {
+            let expired = members
+                .iter()
+                .filter(|&(_, member)| now.duration_since(member.last_seen) > PRESENCE_TTL)
+                .map(|(peer_id, _)| peer_id.clone())
+                .collect::<Vec<_>>();
+            let expired = expired
+                .into_iter()
+                .filter_map(|peer_id| Some((display_name(members, &peer_id, &[])?, peer_id)))
+                .collect::<Vec<_>>();
+            for (nickname, peer_id) in expired {
+                members.remove(&peer_id);
+                notices.push(Notice::TimedOut {
+                    room: room.clone(),
+                    nickname: nickname,
+                    peer_id: peer_id,
+                });
+            }
+        }
+        notices
+    }
+
+    /// Forgets the members of `room`, for example after we left it.
+    pub fn forget_room(&self, room: &str) {
+        self.inner.lock().unwrap().remove(room);
+    }
+
+    /// Returns the nicknames of the members present in `room`, sorted.
+    #[inline]
+    pub fn who(&self, room: &str) -> Vec<String> {
+        self.who_besides(room, &[])
+    }
+
+    /// Same as `who`, but the members whose nickname is in `taken` are also disambiguated.
+    fn who_besides(&self, room: &str, taken: &[&str]) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        let mut nicknames = inner
+            .get(room)
+            .map(|members| {
+                members
+                    .keys()
+                    .filter_map(|peer_id| display_name(members, peer_id, taken))
+                    .collect()
+            })
+            .unwrap_or_else(Vec::new);
+        nicknames.sort();
+        nicknames
+    }
+
+    /// Executes `command`, and returns the lines to show to the user.
+    pub fn execute(
+        &self,
+        command: PresenceCommand,
+        rooms: &Rooms,
+        keypair: &Keypair,
+        composer: &Composer,
+    ) -> Vec<String> {
+        match command {
+            PresenceCommand::Nick(nickname) => match composer.set_nickname(nickname.clone()) {
+                Ok(()) => {
+                    for room in rooms.names() {
+                        announce(rooms, keypair, composer, &room, Status::Here);
+                    }
+                    vec![format!("You are now known as {}", nickname)]
+                }
+                Err(err) => vec![format!("Invalid nickname: {}", err)],
+            },
+            PresenceCommand::Who(room) => {
+                let names = match room {
+                    Some(room) => vec![room],
+                    None => rooms.names(),
+                };
+                let local = composer.nickname();
+                names
+                    .into_iter()
+                    .map(|room| {
+                        if !rooms.contains(&room) {
+                            return format!("Not in room {}", room);
+                        }
+                        let mut members = self.who_besides(&room, &[&local]);
+                        members.push(format!("{} (you)", local));
+                        format!("[{}] {}", room, members.join(", "))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Returns the nickname of the member `peer_id` of a room, followed by the end of its `PeerId` if
+/// another member of the room uses the same nickname or if it is in `taken`. Returns `None` if
+/// `peer_id` isn't a member.
+fn display_name(
+    members: &HashMap<PeerId, Member>,
+    peer_id: &PeerId,
+    taken: &[&str],
+) -> Option<String> {
+    let nickname = &members.get(peer_id)?.nickname;
+    let shared = taken.contains(&nickname.as_str()) || members
+        .iter()
+        .any(|(other, member)| other != peer_id && &member.nickname == nickname);
+    if shared {
+        Some(format!("{} ({})", nickname, message::default_nickname(peer_id)))
+    } else {
+        Some(nickname.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use key::KeyType;
+
+    fn member(nickname: &str) -> Composer {
+        let composer = Composer::new(Keypair::generate(KeyType::Ed25519).unwrap().peer_id());
+        composer.set_nickname(nickname.to_owned()).unwrap();
+        composer
+    }
+
+    fn announcement(composer: &Composer, status: Status) -> ChatMessage {
+        composer.compose(Kind::Presence, status.encode())
+    }
+
+    #[test]
+    fn join_rename_and_leave() {
+        let presence = Presence::new();
+        let alice = member("alice");
+        let notice = presence.on_message("a", &announcement(&alice, Status::Here));
+        let joined = Notice::Joined { room: "a".to_owned(), nickname: "alice".to_owned() };
+        assert_eq!(notice, Some(joined));
+        assert_eq!(presence.on_message("a", &announcement(&alice, Status::Here)), None);
+
+        alice.set_nickname("alicia".to_owned()).unwrap();
+        let notice = presence.on_message("a", &announcement(&alice, Status::Here));
+        assert_eq!(notice, Some(Notice::Renamed {
+            room: "a".to_owned(),
+            old: "alice".to_owned(),
+            new: "alicia".to_owned(),
+        }));
+        assert_eq!(presence.who("a"), vec!["alicia"]);
+
+        let notice = presence.on_message("a", &announcement(&alice, Status::Leaving));
+        let left = Notice::Left { room: "a".to_owned(), nickname: "alicia".to_owned() };
+        assert_eq!(notice, Some(left));
+        assert!(presence.who("a").is_empty());
+        assert_eq!(presence.on_message("a", &announcement(&alice, Status::Leaving)), None);
+    }
+
+    #[test]
+    fn duplicate_nicknames_are_disambiguated() {
+        let presence = Presence::new();
+        let (alice, mallory) = (member("alice"), member("alice"));
+        presence.on_message("a", &announcement(&alice, Status::Here));
+        let joined = presence.on_message("a", &announcement(&mallory, Status::Here));
+
+        let mallory_id = announcement(&mallory, Status::Here).author;
+        let mallory_name = format!("alice ({})", message::default_nickname(&mallory_id));
+        let expected = Notice::Joined { room: "a".to_owned(), nickname: mallory_name.clone() };
+        assert_eq!(joined, Some(expected));
+        let who = presence.who("a");
+        assert_eq!(who.len(), 2);
+        assert!(who.contains(&mallory_name));
+        assert!(who.iter().all(|name| name != "alice"));
+
+        // Members of other rooms don't count.
+        presence.on_message("b", &announcement(&alice, Status::Here));
+        assert_eq!(presence.who("b"), vec!["alice"]);
+        assert_eq!(presence.who_besides("b", &["alice"]).len(), 1);
+        assert_ne!(presence.who_besides("b", &["alice"]), vec!["alice"]);
+    }
+
+    #[test]
+    fn remote_nicknames_are_sanitized() {
+        let presence = Presence::new();
+        let mut message = announcement(&member("bob"), Status::Here);
+        message.nickname = "bob\x1b[2J".to_owned();
+        presence.on_message("a", &message);
+        assert_eq!(presence.who("a"), vec!["bob\u{fffd}[2J"]);
+    }
+
+    #[test]
+    fn other_kinds_are_ignored() {
+        let presence = Presence::new();
+        let message = member("bob").compose(Kind::Text, Status::Here.encode());
+        assert_eq!(presence.on_message("a", &message), None);
+        assert!(presence.who("a").is_empty());
+    }
+}
@@ -0,0 +1,497 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Chat rooms.
+//!
+//! Each room is a floodsub topic. We can be in any number of rooms at the same time, and one of
+//! them is the *active* room: the one that we publish to when the user types a message.
+//!
+//! Each room also has a presence topic, named after the room with a ` presence` suffix, on which
+//! the members announce that they are in the room (see the `presence` module). It is separate
+//! from the topic of the room so that nodes that don't know about presence don't see these
+//! announcements. Room names can't contain whitespace (see `is_valid_name`), so the presence
+//! topic of a room is never the topic of another room.
+//!
+//! Rooms work on top of any publish-subscribe protocol that implements the `PubSub` trait, such
+//! as floodsub.
+
+use libp2p_floodsub::{FloodSubController, Topic, TopicBuilder, TopicHash};
+use std::sync::{Arc, Mutex};
+
+/// Operations of a publish-subscribe protocol that rooms need.
+pub trait PubSub {
+    /// Starts receiving the messages of `topic`.
+    fn subscribe(&self, topic: &Topic);
+    /// Stops receiving the messages of `topic`.
+    fn unsubscribe(&self, topic: &Topic);
+    /// Sends `data` to the nodes subscribed to `topic`.
+    fn publish(&self, topic: &Topic, data: Vec<u8>);
+}
+
+impl PubSub for FloodSubController {
+    #[inline]
+    fn subscribe(&self, topic: &Topic) {
+        FloodSubController::subscribe(self, topic)
+    }
+
+    #[inline]
+    fn unsubscribe(&self, topic: &Topic) {
+        FloodSubController::unsubscribe(self, topic)
+    }
+
+    #[inline]
+    fn publish(&self, topic: &Topic, data: Vec<u8>) {
+        FloodSubController::publish(self, topic, data)
+    }
+}
+
+/// A command typed by the user to manage the rooms we're in. See `Rooms::execute`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomCommand {
+    /// `/join <room>`: joins a room and makes it the active room. Joining a room we're already in
+    /// only makes it the active room.
+    Join(String),
+    /// `/leave [<room>]`: leaves a room, or the active room if none is given.
+    Leave(Option<String>),
+    /// `/rooms`: lists the rooms we're in, the active one marked with a `*`.
+    List,
+}
+
+/// Room that we join on startup.
+///
+/// This is the topic that the first version of chapter 2 used, so that we can still talk to
+/// nodes running it.
+pub const DEFAULT_ROOM: &str = "workshop-chapter2-topic";
+
+/// The rooms we are in. Cloning a `Rooms` gives access to the same rooms.
+#[derive(Clone)]
+pub struct Rooms {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    controller: Box<PubSub>,
+    /// Rooms we're in, in the order we joined them.
+    joined: Vec<Room>,
+    /// Index within `joined` of the active room.
+    active: Option<usize>,
+}
+
+/// Returns the name of the topic on which the messages of the room `name` are published.
+#[inline]
+pub fn room_topic(name: &str) -> String {
+    name.to_owned()
+}
+
+/// Returns the name of the topic on which the members of the room `name` announce themselves.
+pub fn presence_topic(name: &str) -> String {
+    format!("{} presence", name)
+}
+
+/// Returns true if `name` can be the name of a room: it must be non-empty and can't contain
+/// whitespace or control characters.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+struct Room {
+    name: String,
+    topic: Topic,
+    presence_topic: Topic,
+}
+
+impl Inner {
+    /// Subscribes to the topics of the room `name` if we aren't in it yet.
+    ///
+    /// Returns the index of the room within `joined`, and true if we weren't in it before.
+    fn add(&mut self, name: &str) -> (usize, bool) {
+        if let Some(pos) = self.joined.iter().position(|room| room.name == name) {
+            return (pos, false);
+        }
+
+        let room = Room {
+            name: name.to_owned(),
+            topic: TopicBuilder::new(room_topic(name)).build(),
+            presence_topic: TopicBuilder::new(presence_topic(name)).build(),
+        };
+        self.controller.subscribe(&room.topic);
+        self.controller.subscribe(&room.presence_topic);
+        self.joined.push(room);
+        (self.joined.len() - 1, true)
+    }
+}
+
+impl Rooms {
+    /// Creates the list of rooms. We aren't in any room yet.
+    pub fn new<C>(controller: C) -> Rooms
+    where
+        C: PubSub + 'static,
+    {
+        Rooms {
+            inner: Arc::new(Mutex::new(Inner {
+                controller: Box::new(controller),
+                joined: Vec::new(),
+                active: None,
+            })),
+        }
+    }
+
+    /// Joins the room `name` if we aren't in it yet, and makes it the active room. The caller
+    /// must check that the name is valid with `is_valid_name`.
+    ///
+    /// Returns true if we weren't in the room before.
+    pub fn join(&self, name: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let (pos, joined) = inner.add(name);
+        inner.active = Some(pos);
+        joined
+    }
+
+    /// Joins the room `name` if we aren't in it yet. Unlike `join`, the active room doesn't
+    /// change, unless we weren't in any room.
+    ///
+    /// Returns true if we weren't in the room before.
+    pub fn join_in_background(&self, name: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let (pos, joined) = inner.add(name);
+        if inner.active.is_none() {
+            inner.active = Some(pos);
+        }
+        joined
+    }
+
+    /// Leaves the room `name`. If it was the active room, the room we joined last becomes the
+    /// active room.
+    ///
+    /// Returns false if we weren't in the room.
+    pub fn leave(&self, name: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let pos = match inner.joined.iter().position(|room| room.name == name) {
+            Some(pos) => pos,
+            None => return false,
+        };
+
+        let room = inner.joined.remove(pos);
+        inner.controller.unsubscribe(&room.topic);
+        inner.controller.unsubscribe(&room.presence_topic);
+        let active = match inner.active {
+            Some(active) if active == pos => inner.joined.len().checked_sub(1),
+            Some(active) if active > pos => Some(active - 1),
+            other => other,
+        };
+        inner.active = active;
+        true
+    }
+
+    /// Executes `command`, and returns the lines to show to the user.
+    ///
+    /// `on_join` is called after joining a room we weren't in, and `on_leave` right before
+    /// leaving a room, so that the caller can announce it to the other members.
+    pub fn execute(
+        &self,
+        command: RoomCommand,
+        on_join: &Fn(&str),
+        on_leave: &Fn(&str),
+    ) -> Vec<String> {
+        match command {
+            RoomCommand::Join(ref room) if !is_valid_name(room) => {
+                vec![format!("Invalid room name {:?}", room)]
+            }
+            RoomCommand::Join(room) => {
+                if self.join(&room) {
+                    on_join(&room);
+                    vec![format!("Joined room {}", room)]
+                } else {
+                    vec![format!("Switched to room {}", room)]
+                }
+            }
+            RoomCommand::Leave(room) => match room.or_else(|| self.active()) {
+                Some(ref room) if self.contains(room) => {
+                    on_leave(room);
+                    self.leave(room);
+                    vec![format!("Left room {}", room)]
+                }
+                Some(room) => vec![format!("Not in room {}", room)],
+                None => vec!["Not in any room".to_owned()],
+            },
+            RoomCommand::List => {
+                let active = self.active();
+                self.names()
+                    .into_iter()
+                    .map(|room| {
+                        let marker = if Some(&room) == active.as_ref() { "*" } else { " " };
+                        format!("{} {}", marker, room)
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Returns the name of the active room, if we are in any room.
+    pub fn active(&self) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner.active.map(|pos| inner.joined[pos].name.clone())
+    }
+
+    /// Returns the names of the rooms we're in, in the order we joined them.
+    pub fn names(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        inner.joined.iter().map(|room| room.name.clone()).collect()
+    }
+
+    /// Returns true if we're in the room `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.joined.iter().any(|room| room.name == name)
+    }
+
+    /// Returns the name of the first room we're in among `topics`. This is the room that a
+    /// message published on these topics is displayed in.
+    pub fn room_of(&self, topics: &[TopicHash]) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .joined
+            .iter()
+            .find(|room| topics.iter().any(|hash| hash == room.topic.hash()))
+            .map(|room| room.name.clone())
+    }
+
+    /// Same as `room_of`, but for the presence topics of the rooms.
+    pub fn presence_room_of(&self, topics: &[TopicHash]) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .joined
+            .iter()
+            .find(|room| topics.iter().any(|hash| hash == room.presence_topic.hash()))
+            .map(|room| room.name.clone())
+    }
+
+    /// Publishes `data` in the active room.
+    ///
+    /// Returns the name of the room, or `None` if we aren't in any room.
+    pub fn publish(&self, data: Vec<u8>) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        let pos = inner.active?;
+        let room = &inner.joined[pos];
+        inner.controller.publish(&room.topic, data);
+        Some(room.name.clone())
+    }
+
+    /// Publishes `data` in the room `name`, even if it isn't the active room.
+    ///
+    /// Returns false if we aren't in the room.
+    pub fn publish_to(&self, name: &str, data: Vec<u8>) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.joined.iter().find(|room| room.name == name) {
+            Some(room) => {
+                inner.controller.publish(&room.topic, data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Publishes `data` on the presence topic of the room `name`.
+    ///
+    /// Returns false if we aren't in the room.
+    pub fn publish_presence(&self, name: &str, data: Vec<u8>) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.joined.iter().find(|room| room.name == name) {
+            Some(room) => {
+                inner.controller.publish(&room.presence_topic, data);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Records the calls that `Rooms` makes.
+    #[derive(Clone, Default)]
+    struct FakePubSub {
+        calls: Arc<Mutex<Vec<(&'static str, TopicHash)>>>,
+    }
+
+    impl FakePubSub {
+        fn take_calls(&self) -> Vec<(&'static str, TopicHash)> {
+            ::std::mem::replace(&mut *self.calls.lock().unwrap(), Vec::new())
+        }
+    }
+
+    impl PubSub for FakePubSub {
+        fn subscribe(&self, topic: &Topic) {
+            self.calls.lock().unwrap().push(("subscribe", topic.hash().clone()));
+        }
+
+        fn unsubscribe(&self, topic: &Topic) {
+            self.calls.lock().unwrap().push(("unsubscribe", topic.hash().clone()));
+        }
+
+        fn publish(&self, topic: &Topic, _: Vec<u8>) {
+            self.calls.lock().unwrap().push(("publish", topic.hash().clone()));
+        }
+    }
+
+    fn hash(topic: String) -> TopicHash {
+        TopicBuilder::new(topic).build().hash().clone()
+    }
+
+    fn execute(rooms: &Rooms, command: RoomCommand) -> (Vec<String>, Vec<String>, Vec<String>) {
+        let joined = RefCell::new(Vec::new());
+        let left = RefCell::new(Vec::new());
+        let lines = rooms.execute(
+            command,
+            &|room| joined.borrow_mut().push(room.to_owned()),
+            &|room| left.borrow_mut().push(room.to_owned()),
+        );
+        (lines, joined.into_inner(), left.into_inner())
+    }
+
+    #[test]
+    fn join_subscribes_once() {
+        let pubsub = FakePubSub::default();
+        let rooms = Rooms::new(pubsub.clone());
+
+        let (lines, joined, _) = execute(&rooms, RoomCommand::Join("a".to_owned()));
+        assert_eq!(lines, vec!["Joined room a"]);
+        assert_eq!(joined, vec!["a"]);
+        assert_eq!(
+            pubsub.take_calls(),
+            vec![
+                ("subscribe", hash(room_topic("a"))),
+                ("subscribe", hash(presence_topic("a"))),
+            ]
+        );
+
+        execute(&rooms, RoomCommand::Join("b".to_owned()));
+        pubsub.take_calls();
+        let (lines, joined, _) = execute(&rooms, RoomCommand::Join("a".to_owned()));
+        assert_eq!(lines, vec!["Switched to room a"]);
+        assert!(joined.is_empty());
+        assert!(pubsub.take_calls().is_empty());
+        assert_eq!(rooms.active(), Some("a".to_owned()));
+    }
+
+    #[test]
+    fn leave_the_active_room() {
+        let pubsub = FakePubSub::default();
+        let rooms = Rooms::new(pubsub.clone());
+        rooms.join("a");
+        rooms.join("b");
+        pubsub.take_calls();
+
+        let (lines, _, left) = execute(&rooms, RoomCommand::Leave(None));
+        assert_eq!(lines, vec!["Left room b"]);
+        assert_eq!(left, vec!["b"]);
+        assert_eq!(
+            pubsub.take_calls(),
+            vec![
+                ("unsubscribe", hash(room_topic("b"))),
+                ("unsubscribe", hash(presence_topic("b"))),
+            ]
+        );
+        assert_eq!(rooms.active(), Some("a".to_owned()));
+
+        execute(&rooms, RoomCommand::Leave(None));
+        let (lines, _, left) = execute(&rooms, RoomCommand::Leave(None));
+        assert_eq!(lines, vec!["Not in any room"]);
+        assert!(left.is_empty());
+    }
+
+    #[test]
+    fn leave_a_room_we_are_not_in() {
+        let pubsub = FakePubSub::default();
+        let rooms = Rooms::new(pubsub.clone());
+        rooms.join("a");
+        pubsub.take_calls();
+
+        let (lines, _, left) = execute(&rooms, RoomCommand::Leave(Some("b".to_owned())));
+        assert_eq!(lines, vec!["Not in room b"]);
+        assert!(left.is_empty());
+        assert!(pubsub.take_calls().is_empty());
+        assert_eq!(rooms.names(), vec!["a"]);
+    }
+
+    #[test]
+    fn leaving_a_background_room_keeps_the_active_one() {
+        let rooms = Rooms::new(FakePubSub::default());
+        rooms.join("a");
+        rooms.join("b");
+        rooms.join("c");
+        rooms.join("b");
+
+        execute(&rooms, RoomCommand::Leave(Some("a".to_owned())));
+        assert_eq!(rooms.active(), Some("b".to_owned()));
+        assert_eq!(rooms.names(), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn list_marks_the_active_room() {
+        let rooms = Rooms::new(FakePubSub::default());
+        assert!(execute(&rooms, RoomCommand::List).0.is_empty());
+        rooms.join("a");
+        rooms.join("b");
+        rooms.join_in_background("c");
+        assert_eq!(execute(&rooms, RoomCommand::List).0, vec!["  a", "* b", "  c"]);
+    }
+
+    #[test]
+    fn messages_are_routed_by_topic() {
+        let pubsub = FakePubSub::default();
+        let rooms = Rooms::new(pubsub.clone());
+        rooms.join("a");
+        rooms.join("b");
+        pubsub.take_calls();
+
+        assert!(rooms.publish_to("a", Vec::new()));
+        assert!(rooms.publish_presence("b", Vec::new()));
+        assert!(!rooms.publish_to("c", Vec::new()));
+        assert_eq!(
+            pubsub.take_calls(),
+            vec![
+                ("publish", hash(room_topic("a"))),
+                ("publish", hash(presence_topic("b"))),
+            ]
+        );
+
+        assert_eq!(rooms.room_of(&[hash(room_topic("b"))]), Some("b".to_owned()));
+        assert_eq!(rooms.room_of(&[hash(presence_topic("b"))]), None);
+        assert_eq!(rooms.presence_room_of(&[hash(presence_topic("a"))]), Some("a".to_owned()));
+    }
+
+    #[test]
+    fn invalid_room_names_are_refused() {
+        let pubsub = FakePubSub::default();
+        let rooms = Rooms::new(pubsub.clone());
+        for name in &["", "a presence", "a\tb", "a\x1bb"] {
+            let (lines, joined, _) = execute(&rooms, RoomCommand::Join(name.to_string()));
+            assert_eq!(lines, vec![format!("Invalid room name {:?}", name)]);
+            assert!(joined.is_empty());
+        }
+        assert!(pubsub.take_calls().is_empty());
+        assert!(is_valid_name("a/presence"));
+        assert!(!is_valid_name(&presence_topic("a")));
+    }
+}
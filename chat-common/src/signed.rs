@@ -0,0 +1,213 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Signed envelopes around the payloads that we publish.
+//!
+//! Floodsub lets any node relay messages, and nothing prevents a node from pretending that a
+//! message comes from someone else. To solve this, the payload of every message is wrapped in an
+//! envelope that contains the public key of the author and a signature of the payload.
+//!
+//! The signature also covers the topic that the envelope is published on, so that a node can't
+//! take a message that we published in one room and replay it in another one. The topic itself
+//! isn't part of the envelope, since the recipient knows which topic it received the message on.
+//!
+//! The envelope is encoded as follows:
+//!
+//! - The length of the public key as a big endian `u16`, followed with the protobuf encoding of
+//!   the public key.
+//! - The length of the signature as a big endian `u16`, followed with the signature.
+//! - The payload itself, until the end of the message.
+
+use codec::{self, push_short, Reader};
+use key::{Keypair, PublicKey};
+use libp2p_core::PeerId;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// What to do with messages whose signature doesn't verify.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Display the message anyway, but flag it as unverified.
+    Warn,
+    /// Drop the message.
+    Strict,
+}
+
+impl Default for VerifyMode {
+    #[inline]
+    fn default() -> VerifyMode {
+        VerifyMode::Warn
+    }
+}
+
+impl FromStr for VerifyMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<VerifyMode, ()> {
+        match s {
+            "warn" => Ok(VerifyMode::Warn),
+            "strict" => Ok(VerifyMode::Strict),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Wraps `payload` in an envelope signed with `keypair`, to be published on `topic`.
+pub fn sign(keypair: &Keypair, topic: &str, payload: &[u8]) -> Vec<u8> {
+    let public_key = keypair.public().to_protobuf_encoding();
+    let signature = keypair.sign(&signed_bytes(topic, payload));
+
+    let mut out = Vec::with_capacity(4 + public_key.len() + signature.len() + payload.len());
+    push_short(&mut out, &public_key);
+    push_short(&mut out, &signature);
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Opens an envelope produced by `sign`, and verifies that it was signed by `source` for `topic`.
+///
+/// On success, returns the payload.
+pub fn verify(source: &PeerId, topic: &str, message: &[u8]) -> Result<Vec<u8>, VerifyError> {
+    let mut reader = Reader::new(message);
+    let public_key = reader.short()?;
+    let signature = reader.short()?;
+    let payload = reader.rest();
+
+    let public_key =
+        PublicKey::from_protobuf_encoding(public_key).map_err(|_| VerifyError::Malformed)?;
+    if public_key.peer_id() != *source {
+        return Err(VerifyError::WrongAuthor(payload.to_vec()));
+    }
+    if !public_key.verify(&signed_bytes(topic, payload), signature) {
+        return Err(VerifyError::BadSignature(payload.to_vec()));
+    }
+
+    Ok(payload.to_vec())
+}
+
+/// Returns the bytes that the signature of an envelope covers: the topic, prefixed with its
+/// length, followed with the payload.
+fn signed_bytes(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(2 + topic.len() + payload.len());
+    push_short(&mut out, topic.as_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Error while verifying an envelope.
+///
+/// When the envelope could be parsed, the variant contains the unverified payload so that it can
+/// still be displayed in `VerifyMode::Warn`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The message is not a valid envelope.
+    Malformed,
+    /// The public key in the envelope doesn't match the `PeerId` of the author.
+    WrongAuthor(Vec<u8>),
+    /// The signature doesn't match the payload.
+    BadSignature(Vec<u8>),
+}
+
+impl VerifyError {
+    /// Returns the unverified payload, if the envelope could be parsed.
+    pub fn into_unverified_payload(self) -> Option<Vec<u8>> {
+        match self {
+            VerifyError::Malformed => None,
+            VerifyError::WrongAuthor(payload) | VerifyError::BadSignature(payload) => Some(payload),
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl Error for VerifyError {
+    #[inline]
+    fn description(&self) -> &str {
+        match *self {
+            VerifyError::Malformed => "malformed envelope",
+            VerifyError::WrongAuthor(_) => "public key doesn't match the author of the message",
+            VerifyError::BadSignature(_) => "invalid signature",
+        }
+    }
+}
+
+impl From<codec::InvalidData> for VerifyError {
+    #[inline]
+    fn from(_: codec::InvalidData) -> VerifyError {
+        VerifyError::Malformed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use key::KeyType;
+
+    #[test]
+    fn envelope_round_trip() {
+        let keypair = Keypair::generate(KeyType::Ed25519).unwrap();
+        let envelope = sign(&keypair, "room", b"hello");
+        assert_eq!(verify(&keypair.peer_id(), "room", &envelope), Ok(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn replay_on_another_topic_is_rejected() {
+        let keypair = Keypair::generate(KeyType::Secp256k1).unwrap();
+        let envelope = sign(&keypair, "room", b"hello");
+        assert_eq!(
+            verify(&keypair.peer_id(), "room/presence", &envelope),
+            Err(VerifyError::BadSignature(b"hello".to_vec()))
+        );
+    }
+
+    #[test]
+    fn wrong_author_is_rejected() {
+        let keypair = Keypair::generate(KeyType::Ed25519).unwrap();
+        let other = Keypair::generate(KeyType::Ed25519).unwrap();
+        let envelope = sign(&keypair, "room", b"hello");
+        assert_eq!(
+            verify(&other.peer_id(), "room", &envelope),
+            Err(VerifyError::WrongAuthor(b"hello".to_vec()))
+        );
+    }
+
+    #[test]
+    fn tampered_payload_is_rejected() {
+        let keypair = Keypair::generate(KeyType::Ed25519).unwrap();
+        let mut envelope = sign(&keypair, "room", b"hello");
+        *envelope.last_mut().unwrap() ^= 1;
+        let err = verify(&keypair.peer_id(), "room", &envelope).unwrap_err();
+        assert_eq!(err.into_unverified_payload(), Some(b"helln".to_vec()));
+    }
+
+    #[test]
+    fn malformed_envelopes() {
+        let peer_id = Keypair::generate(KeyType::Ed25519).unwrap().peer_id();
+        assert_eq!(verify(&peer_id, "room", &[]), Err(VerifyError::Malformed));
+        assert_eq!(verify(&peer_id, "room", &[0, 5, 1]), Err(VerifyError::Malformed));
+        assert_eq!(verify(&peer_id, "room", &[0, 1, 1, 0, 0]), Err(VerifyError::Malformed));
+    }
+}
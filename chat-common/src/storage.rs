@@ -0,0 +1,74 @@
+// Copyright 2018 Pierre Krieger
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Helpers to store the state of the node on disk.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{Error as IoError, Write};
+use std::path::{Path, PathBuf};
+
+/// Returns the directory where the node stores its files by default.
+///
+/// This is `$XDG_CONFIG_HOME/rustfest-workshop`, falling back to `~/.config` if the environment
+/// variable isn't set.
+pub fn config_dir() -> PathBuf {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::home_dir().map(|home| home.join(".config")))
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("rustfest-workshop")
+}
+
+/// Returns the path of the socket on which a node accepts control requests, and that `chatctl`
+/// connects to, when none is passed on the command line.
+///
+/// This is `control.sock` in `config_dir()`.
+pub fn default_control_socket() -> PathBuf {
+    config_dir().join("control.sock")
+}
+
+/// Writes `data` to `path`, creating the parent directories if necessary.
+///
+/// The data is first written to a temporary file which is then renamed, so that a crash in the
+/// middle of the write can't leave a truncated file behind. If `private` is true, only the
+/// current user can read the file.
+pub fn write_atomic(path: &Path, data: &[u8], private: bool) -> Result<(), IoError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(if private { 0o600 } else { 0o644 });
+        }
+        #[cfg(not(unix))]
+        let _ = private;
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}